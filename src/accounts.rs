@@ -0,0 +1,220 @@
+// Gitter accounts signed in on this computer. Each is listed by name in config.yaml,
+// with its token in the keyring, or next to its name when there is no keyring.
+
+use std::path::PathBuf;
+
+use api::{self, Endpoints, Protocol};
+use config;
+use config::{AccountEntry, Config};
+use keyring::{Keyring, KeyringError};
+use store;
+
+// The account of a config.yaml from before multiple accounts, whose single token becomes this account
+pub const LEGACY_ACCOUNT: &'static str = "default";
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    // The Gitter username it was signed in as
+    pub name: String,
+    pub token: String,
+    pub endpoints: Endpoints,
+}
+
+// Accounts in the order they were added; those without a token, or whose token was rejected, are left out
+pub fn load(config: &Config) -> Vec<Account> {
+    let keyring = Keyring::connect();
+    if let Err(ref e) = keyring {
+        warn!("No keyring available, reading tokens from config.yaml -> {}", e);
+    }
+
+    config.accounts.iter().filter(|entry| !entry.needs_sign_in).filter_map(|entry| {
+        let name = entry.name.clone();
+
+        let keyring_token = match keyring {
+            Ok(ref keyring) => match keyring.load_token(&name[..]) {
+                Ok(token) => token,
+                Err(e) => {
+                    error!("Reading the token of {} from the keyring -> {}", name, e);
+                    None
+                },
+            },
+            Err(_) => None,
+        };
+
+        match keyring_token.or(entry.token.clone()) {
+            Some(ref token) if token.len() > 0 => Some(Account {
+                name: name,
+                token: token.clone(),
+                endpoints: entry_endpoints(entry),
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+// Adds the account, or updates its token when it is already signed in
+pub fn save(name: &str, token: &str) {
+    save_with(name, token, |_| ());
+}
+
+// Adds an account on a Matrix homeserver, named by its user id
+pub fn save_matrix(name: &str, token: &str, homeserver: &str) {
+    save_with(name, token, |entry| {
+        entry.protocol = Protocol::Matrix;
+        entry.api_base = Some(String::from(homeserver));
+    });
+}
+
+fn save_with<F>(name: &str, token: &str, update: F) where F: FnOnce(&mut AccountEntry) {
+    let file_token = match Keyring::connect().and_then(|keyring| keyring.save_token(name, token)) {
+        Ok(()) => None,
+        Err(e) => {
+            warn!("No keyring available, the token is stored in plaintext in config.yaml -> {}", e);
+            Some(token)
+        },
+    };
+
+    update_config(|config| {
+        set_entry(config, name, file_token);
+
+        if let Some(entry) = config.accounts.iter_mut().find(|account| account.name == name) {
+            entry.needs_sign_in = false;
+            update(entry);
+        }
+    });
+}
+
+// Signs the account out, forgetting its token
+pub fn remove(name: &str) {
+    if let Err(e) = Keyring::connect().and_then(|keyring| keyring.delete_token(name)) {
+        error!("Removing the token of {} from the keyring -> {}", name, e);
+    }
+
+    update_config(|config| config.accounts.retain(|account| account.name != name));
+}
+
+// The server rejected the token, so the account has to sign in again. The token and servers are
+// kept until then; load leaves the account out meanwhile. A server given with --api-base is not
+// the account's own, so what it rejects says nothing about the token.
+pub fn mark_needs_sign_in(name: &str) {
+    if api::has_override() {
+        warn!("The server of --api-base rejected the token of {}, keeping it", name);
+        return;
+    }
+
+    update_config(|config| {
+        if let Some(entry) = config.accounts.iter_mut().find(|account| account.name == name) {
+            entry.needs_sign_in = true;
+        }
+    });
+}
+
+// The servers of a signed in account; gitter.im for an account not in config.yaml
+pub fn endpoints(config: &Config, name: &str) -> Endpoints {
+    match config.accounts.iter().find(|account| account.name == name) {
+        Some(entry) => entry_endpoints(entry),
+        None => Endpoints::default().or_override(),
+    }
+}
+
+// Moves plaintext tokens into the keyring, including the top-level token of a config.yaml from before
+// multiple accounts. Returns true when the config changed and needs writing back.
+// `connect` is only called when there is a token to move, normally Keyring::connect.
+pub fn migrate_tokens<F>(config: &mut Config, connect: F) -> bool
+where F: FnOnce() -> Result<Keyring, KeyringError>
+{
+    let mut changed = false;
+
+    if let Some(token) = config.token.take() {
+        if token.len() > 0 && !config.accounts.iter().any(|account| account.name == LEGACY_ACCOUNT) {
+            set_entry(config, LEGACY_ACCOUNT, Some(&token[..]));
+        }
+
+        changed = true;
+    }
+
+    let plaintext: Vec<(String, String)> = config.accounts.iter()
+        .filter_map(|account| account.token.clone().map(|token| (account.name.clone(), token)))
+        .collect();
+
+    if plaintext.len() == 0 {
+        return changed;
+    }
+
+    let keyring = match connect() {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            warn!("No keyring available, tokens stay in plaintext in config.yaml -> {}", e);
+            return changed;
+        },
+    };
+
+    for (name, token) in plaintext {
+        match keyring.save_token(&name[..], &token[..]) {
+            Ok(()) => {
+                set_entry(config, &name[..], None);
+                info!("Moved the token of {} from config.yaml into the keyring", name);
+                changed = true;
+            },
+            Err(e) => error!("Moving the token of {} into the keyring -> {}", name, e),
+        };
+    }
+
+    changed
+}
+
+// Each account keeps its own cache; the legacy account keeps the cache it had before
+pub fn cache_path(name: &str) -> Option<PathBuf> {
+    if name == LEGACY_ACCOUNT {
+        store::default_path()
+    } else {
+        store::account_path(name)
+    }
+}
+
+// Missing bases are gitter.im's, except that the stream is served next to a self-hosted API.
+// --api-base only replaces the servers of Gitter accounts.
+fn entry_endpoints(entry: &AccountEntry) -> Endpoints {
+    if entry.protocol == Protocol::Matrix {
+        return Endpoints::matrix(&entry.api_base.clone().unwrap_or(String::new())[..]);
+    }
+
+    let api_base = entry.api_base.clone().unwrap_or(String::from(api::DEFAULT_API_BASE));
+    let stream_base = match (&entry.stream_base, &entry.api_base) {
+        (&Some(ref stream_base), _) => stream_base.clone(),
+        (&None, &Some(ref api_base)) => api_base.clone(),
+        (&None, &None) => String::from(api::DEFAULT_STREAM_BASE),
+    };
+    let websocket_base = entry.websocket_base.clone().unwrap_or(String::from(api::DEFAULT_WEBSOCKET_BASE));
+
+    Endpoints::new(&api_base[..], &stream_base[..], &websocket_base[..]).or_override()
+}
+
+// Keeps the servers of an account which signs in again
+fn set_entry(config: &mut Config, name: &str, token: Option<&str>) {
+    match config.accounts.iter().position(|account| account.name == name) {
+        Some(index) => config.accounts[index].token = token.map(String::from),
+        None => config.accounts.push(AccountEntry {
+            name: String::from(name),
+            token: token.map(String::from),
+            ..AccountEntry::default()
+        }),
+    };
+}
+
+// Re-reads config.yaml so other settings are kept; a file that cannot be read is left alone
+fn update_config<F>(update: F) where F: FnOnce(&mut Config) {
+    let mut config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Not updating the accounts in config.yaml -> {}", e);
+            return;
+        },
+    };
+
+    update(&mut config);
+
+    if let Err(e) = config::save(&config) {
+        error!("Saving config.yaml -> {}", e);
+    }
+}
@@ -0,0 +1,612 @@
+// Requests to the Gitter.im REST API, performed blocking or by the backend core
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use curl;
+use curl::easy::{Easy, List};
+use serde;
+use serde_json;
+use tokio_curl::PerformError;
+
+use chat::{Call, ChatBackend};
+use logging;
+use network;
+
+use {Group, Message, Room, User};
+
+pub const DEFAULT_API_BASE: &'static str = "https://api.gitter.im/v1";
+pub const DEFAULT_STREAM_BASE: &'static str = "https://stream.gitter.im/v1";
+pub const DEFAULT_WEBSOCKET_BASE: &'static str = "wss://ws.gitter.im/faye";
+
+// Most messages Gitter returns for one request
+const HISTORY_PAGE_SIZE: u32 = 100;
+
+lazy_static! {
+    // Set by --api-base; used by every account instead of what config.yaml says
+    static ref OVERRIDE: RwLock<Option<Endpoints>> = RwLock::new(None);
+}
+
+// How an account talks to its server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    // Gitter's REST API and message stream
+    Gitter,
+    // The Matrix client-server API of a homeserver, e.g. one bridged to Gitter
+    Matrix,
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol::Gitter
+    }
+}
+
+impl Protocol {
+    pub fn is_gitter(&self) -> bool {
+        *self == Protocol::Gitter
+    }
+}
+
+// Where an account's server answers; every URL is built from these
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    pub protocol: Protocol,
+    // The homeserver for Matrix, such as https://matrix.example.com
+    pub api: String,
+    pub stream: String,
+    pub websocket: String,
+}
+
+impl Endpoints {
+    pub fn new(api: &str, stream: &str, websocket: &str) -> Endpoints {
+        Endpoints {
+            protocol: Protocol::Gitter,
+            api: String::from(api.trim_right_matches('/')),
+            stream: String::from(stream.trim_right_matches('/')),
+            websocket: String::from(websocket.trim_right_matches('/')),
+        }
+    }
+
+    // Matrix serves everything from the homeserver
+    pub fn matrix(homeserver: &str) -> Endpoints {
+        Endpoints {
+            protocol: Protocol::Matrix,
+            ..Endpoints::new(homeserver, homeserver, homeserver)
+        }
+    }
+
+    // --api-base when given, otherwise these
+    pub fn or_override(self) -> Endpoints {
+        OVERRIDE.read().unwrap().clone().unwrap_or(self)
+    }
+}
+
+// gitter.im
+impl Default for Endpoints {
+    fn default() -> Endpoints {
+        Endpoints::new(DEFAULT_API_BASE, DEFAULT_STREAM_BASE, DEFAULT_WEBSOCKET_BASE)
+    }
+}
+
+pub fn set_override(endpoints: Endpoints) {
+    *OVERRIDE.write().unwrap() = Some(endpoints);
+}
+
+// Whether --api-base points every account at another server than its own
+pub fn has_override() -> bool {
+    OVERRIDE.read().unwrap().is_some()
+}
+
+// Why a request to the Gitter.im API did not succeed
+#[derive(Debug)]
+pub enum ApiError {
+    Network(io::Error),
+    Http(u32),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApiError::Network(ref e) => write!(f, "network error: {}", e),
+            ApiError::Http(code) => write!(f, "server responded with HTTP {}", code),
+            ApiError::Json(ref e) => write!(f, "invalid JSON: {}", e),
+        }
+    }
+}
+
+impl From<curl::Error> for ApiError {
+    fn from(e: curl::Error) -> ApiError {
+        ApiError::Network(e.into())
+    }
+}
+
+impl From<PerformError> for ApiError {
+    fn from(e: PerformError) -> ApiError {
+        ApiError::Network(e.into_error())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        ApiError::Json(e)
+    }
+}
+
+// Body for creating or editing a message on gitter.im
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug, Clone)]
+struct NewMessage {
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parentId: Option<String>,
+}
+
+// Body for marking messages as read
+#[derive(Serialize, Debug, Clone)]
+struct ReadMessages {
+    chat: Vec<String>,
+}
+
+// Handles of finished blocking requests. curl keeps a handle's connections open, so
+// taking one from here skips the TCP and TLS handshakes to a host it already talked to.
+// The backend core performs its requests through one curl multi handle, which shares
+// its connections the same way.
+const IDLE_HANDLES_KEPT: usize = 4;
+
+// Bodies kept for conditional requests, across all accounts
+const CACHED_RESPONSES: usize = 200;
+
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+// Gitter sends a heartbeat on the message stream every few seconds, so a longer silence means it is dead
+const STREAM_IDLE_SECS: u64 = 90;
+
+lazy_static! {
+    static ref IDLE_HANDLES: Mutex<Vec<Easy>> = Mutex::new(vec![]);
+    // ETag and body of the last response to a GET, by token and URL, to answer HTTP 304 with
+    static ref CACHED: Mutex<HashMap<String, (String, Vec<u8>)>> = Mutex::new(HashMap::new());
+}
+
+// A handle with the options every request shares; `timeout` limits the whole request
+fn new_handle(timeout: Option<Duration>) -> Result<Easy, ApiError> {
+    let mut easy = match IDLE_HANDLES.lock().unwrap().pop() {
+        Some(mut easy) => {
+            easy.reset();
+            easy
+        },
+        None => Easy::new(),
+    };
+
+    network::apply(&mut easy)?;
+
+    // curl sends Accept-Encoding and decompresses the response
+    easy.accept_encoding("gzip")?;
+    easy.connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+
+    match timeout {
+        Some(timeout) => easy.timeout(timeout)?,
+        None => {
+            easy.low_speed_limit(1)?;
+            easy.low_speed_time(Duration::from_secs(STREAM_IDLE_SECS))?;
+        },
+    };
+
+    Ok(easy)
+}
+
+// Hands a handle back once its request finished, keeping its connections for the next
+fn recycle(easy: Easy) {
+    let mut idle = IDLE_HANDLES.lock().unwrap();
+
+    if idle.len() < IDLE_HANDLES_KEPT {
+        idle.push(easy);
+    }
+}
+
+// A prepared curl handle plus the buffers its response is written into.
+// Perform it blocking with `perform`, or hand `into_parts` to the async core.
+pub struct Request {
+    easy: Easy,
+    response: Response,
+}
+
+// What a request received; `parse` turns it into a result once the request finished
+pub struct Response {
+    body: Arc<Mutex<Vec<u8>>>,
+    // Of the last response, as there may be several with redirects; names in lower case
+    headers: Arc<Mutex<Vec<(String, String)>>>,
+    // Set for GETs, whose responses are cached when they have an ETag
+    cache_key: Option<String>,
+}
+
+impl Request {
+    pub fn get(url: &String, token: &String) -> Result<Request, ApiError> {
+        debug!("GET {}", logging::url(url));
+        let mut easy = new_handle(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+        easy.url(&url)?;
+
+        let cache_key = format!("{} {}", token, url);
+        let etag = CACHED.lock().unwrap().get(&cache_key).map(|&(ref etag, _)| etag.clone());
+
+        let mut list = List::new();
+        list.append("Accept: application/json")?;
+        list.append(&(format!("Authorization: Bearer {}", token)))?;
+        if let Some(etag) = etag {
+            list.append(&format!("If-None-Match: {}", etag))?;
+        }
+        easy.http_headers(list)?;
+
+        Request::with_response_buffer(easy, Some(cache_key))
+    }
+
+    pub fn json(method: &str, url: &String, token: &String, json: String) -> Result<Request, ApiError> {
+        debug!("{} {}", method, logging::url(url));
+        let mut easy = new_handle(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+        easy.url(&url)?;
+        easy.post(true)?;
+        easy.custom_request(method)?;
+        // Copied into the handle, so the request can be performed again after an HTTP 429
+        easy.post_fields_copy(json.as_bytes())?;
+
+        let mut list = List::new();
+        list.append("Content-Type: application/json")?;
+        list.append("Accept: application/json")?;
+        list.append(&(format!("Authorization: Bearer {}", token)))?;
+        easy.http_headers(list)?;
+
+        Request::with_response_buffer(easy, None)
+    }
+
+    fn with_response_buffer(mut easy: Easy, cache_key: Option<String>) -> Result<Request, ApiError> {
+        let response = Response {
+            body: Arc::new(Mutex::new(vec![])),
+            headers: Arc::new(Mutex::new(vec![])),
+            cache_key: cache_key,
+        };
+
+        {
+            let body = response.body.clone();
+            easy.write_function(move |new_data| {
+                body.lock().unwrap().extend(new_data.iter());
+                Ok(new_data.len())
+            })?;
+        }
+
+        {
+            let headers = response.headers.clone();
+            easy.header_function(move |header| {
+                let header = String::from_utf8_lossy(header);
+                let mut headers = headers.lock().unwrap();
+
+                // Each response starts with its status line
+                if header.starts_with("HTTP/") {
+                    headers.clear();
+                }
+
+                let mut parts = header.splitn(2, ':');
+                if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    headers.push((name.trim().to_lowercase(), String::from(value.trim())));
+                }
+
+                true
+            })?;
+        }
+
+        Ok(Request {
+            easy: easy,
+            response: response,
+        })
+    }
+
+    pub fn into_parts(self) -> (Easy, Response) {
+        (self.easy, self.response)
+    }
+
+    // Blocks the calling thread until the response arrives
+    pub fn perform<T>(self) -> Result<T, ApiError>
+    where T: serde::de::DeserializeOwned
+    {
+        let (mut easy, response) = self.into_parts();
+        easy.perform()?;
+
+        let result = response.parse(&mut easy);
+        recycle(easy);
+
+        result
+    }
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers.lock().unwrap().iter()
+            .find(|&&(ref header, _)| header == name)
+            .map(|&(_, ref value)| value.clone())
+    }
+
+    // Forgets what was received, before performing the request again
+    pub fn clear(&self) {
+        self.body.lock().unwrap().clear();
+        self.headers.lock().unwrap().clear();
+    }
+
+    // HTTP 304 is answered from the body cached with the ETag
+    pub fn parse<T>(&self, easy: &mut Easy) -> Result<T, ApiError>
+    where T: serde::de::DeserializeOwned
+    {
+        let code = easy.response_code()?;
+
+        if let Some(ref cache_key) = self.cache_key {
+            let mut cached = CACHED.lock().unwrap();
+
+            if code == 304 {
+                debug!("HTTP 304 from {}, using the cached response", logging::url(easy.effective_url().ok().and_then(|url| url).unwrap_or("?")));
+
+                return match cached.get(cache_key) {
+                    Some(&(_, ref body)) => Ok(serde_json::from_slice(&body[..])?),
+                    None => Err(ApiError::Http(304)),
+                };
+            }
+
+            if let (200, Some(etag)) = (code, self.header("etag")) {
+                if cached.len() >= CACHED_RESPONSES && !cached.contains_key(cache_key) {
+                    cached.clear();
+                }
+
+                cached.insert(cache_key.clone(), (etag, self.body.lock().unwrap().clone()));
+            }
+        }
+
+        let body = self.body.lock().unwrap();
+        parse_response(easy, &body[..])
+    }
+}
+
+// Checks the status code of a finished request and parses its body
+pub fn parse_response<T>(easy: &mut Easy, response: &[u8]) -> Result<T, ApiError>
+where T: serde::de::DeserializeOwned
+{
+    let code = easy.response_code()?;
+    debug!("HTTP {} from {}", code, logging::url(easy.effective_url().ok().and_then(|url| url).unwrap_or("?")));
+
+    match code {
+        200...299 => (),
+        code => return Err(ApiError::Http(code)),
+    };
+
+    Ok(serde_json::from_slice(response)?)
+}
+
+// Used where the response is read by hand, such as the token exchange of auth.rs
+pub fn new_request_handle() -> Result<Easy, ApiError> {
+    new_handle(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))
+}
+
+// Builds the requests for one account; current_room_id is the room being read
+#[derive(Clone)]
+pub struct MessageHandler {
+    pub current_room_id: String,
+    token: String,
+    endpoints: Endpoints,
+}
+
+impl MessageHandler {
+    // Talks to gitter.im, or the server of --api-base
+    pub fn new(room_id: &String, token: &String) -> MessageHandler {
+        MessageHandler::with_endpoints(room_id, token, Endpoints::default().or_override())
+    }
+
+    // Talks to another server than gitter.im, e.g. a self-hosted one or the mock server in the tests
+    pub fn with_endpoints(room_id: &String, token: &String, endpoints: Endpoints) -> MessageHandler {
+        MessageHandler {
+            current_room_id: room_id.clone(),
+            token: token.clone(),
+            endpoints: endpoints,
+        }
+    }
+
+    pub fn user_request(&self) -> Result<Request, ApiError> {
+        Request::get(&format!("{}/user", &self.endpoints.api), &self.token)
+    }
+
+    pub fn rooms_request(&self) -> Result<Request, ApiError> {
+        Request::get(&format!("{}/rooms", &self.endpoints.api), &self.token)
+    }
+
+    pub fn groups_request(&self) -> Result<Request, ApiError> {
+        Request::get(&format!("{}/groups", &self.endpoints.api), &self.token)
+    }
+
+    pub fn messages_request(&self) -> Result<Request, ApiError> {
+        let url = format!("{}/rooms/{}/chatMessages?limit=15", &self.endpoints.api, &self.current_room_id);
+        Request::get(&url, &self.token)
+    }
+
+    pub fn search_request(&self, query: &String) -> Result<Request, ApiError> {
+        let url = format!(
+            "{}/rooms/{}/chatMessages?q={}&limit=50",
+            &self.endpoints.api,
+            &self.current_room_id,
+            url_encode(query)
+        );
+        Request::get(&url, &self.token)
+    }
+
+    // Gitter answers with at most HISTORY_PAGE_SIZE messages whatever the limit
+    pub fn history_request(&self, before_id: Option<&String>) -> Result<Request, ApiError> {
+        let url = match before_id {
+            Some(before_id) => format!(
+                "{}/rooms/{}/chatMessages?beforeId={}&limit={}",
+                &self.endpoints.api,
+                &self.current_room_id,
+                before_id,
+                HISTORY_PAGE_SIZE
+            ),
+            None => format!("{}/rooms/{}/chatMessages?limit={}", &self.endpoints.api, &self.current_room_id, HISTORY_PAGE_SIZE),
+        };
+        Request::get(&url, &self.token)
+    }
+
+    // History on both sides of a message, used when jumping to a search result
+    pub fn around_request(&self, message_id: &String) -> Result<Request, ApiError> {
+        let url = format!(
+            "{}/rooms/{}/chatMessages?aroundId={}&limit=30",
+            &self.endpoints.api,
+            &self.current_room_id,
+            message_id
+        );
+        Request::get(&url, &self.token)
+    }
+
+    // Tells Gitter the user has seen these messages of the current room
+    pub fn mark_read_request(&self, user_id: &String, message_ids: Vec<String>) -> Result<Request, ApiError> {
+        let url = format!("{}/user/{}/rooms/{}/unreadItems", &self.endpoints.api, user_id, &self.current_room_id);
+
+        let body = ReadMessages {
+            chat: message_ids,
+        };
+
+        Request::json("POST", &url, &self.token, serde_json::to_string(&body)?)
+    }
+
+    // The response is the created message, whose id confirms delivery once it is echoed back
+    pub fn send_request(&self, room_id: &String, message: String, parent_id: Option<String>) -> Result<Request, ApiError> {
+        let url = format!("{}/rooms/{}/chatMessages", &self.endpoints.api, room_id);
+
+        let body = NewMessage {
+            text: message,
+            parentId: parent_id,
+        };
+
+        Request::json("POST", &url, &self.token, serde_json::to_string(&body)?)
+    }
+
+    pub fn update_request(&self, room_id: &String, id: &String, message: String) -> Result<Request, ApiError> {
+        let url = format!("{}/rooms/{}/chatMessages/{}", &self.endpoints.api, room_id, id);
+
+        let body = NewMessage {
+            text: message,
+            parentId: None,
+        };
+
+        Request::json("PUT", &url, &self.token, serde_json::to_string(&body)?)
+    }
+}
+
+impl ChatBackend for MessageHandler {
+    fn current_room_id(&self) -> &String {
+        &self.current_room_id
+    }
+
+    fn set_current_room_id(&mut self, id: String) {
+        self.current_room_id = id;
+    }
+
+    fn set_token(&mut self, token: String) {
+        self.token = token;
+    }
+
+    fn for_room(&self, room_id: &String) -> Box<ChatBackend> {
+        Box::new(MessageHandler::with_endpoints(room_id, &self.token, self.endpoints.clone()))
+    }
+
+    // Gitter answers with an empty list when the token does not belong to a user
+    fn user_call(&self) -> Result<Call<User>, ApiError> {
+        Ok(Call::new(self.user_request()?, |users: Vec<User>| users.into_iter().next().ok_or(ApiError::Http(401))))
+    }
+
+    fn rooms_call(&self) -> Result<Call<Vec<Room>>, ApiError> {
+        Ok(Call::json(self.rooms_request()?))
+    }
+
+    fn groups_call(&self) -> Result<Call<Vec<Group>>, ApiError> {
+        Ok(Call::json(self.groups_request()?))
+    }
+
+    fn messages_call(&self) -> Result<Call<Vec<Message>>, ApiError> {
+        Ok(Call::json(self.messages_request()?))
+    }
+
+    fn search_call(&self, query: &String) -> Result<Call<Vec<Message>>, ApiError> {
+        Ok(Call::json(self.search_request(query)?))
+    }
+
+    fn history_call(&self, before_id: Option<&String>) -> Result<Call<Vec<Message>>, ApiError> {
+        Ok(Call::json(self.history_request(before_id)?))
+    }
+
+    fn around_call(&self, message_id: &String) -> Result<Call<Vec<Message>>, ApiError> {
+        Ok(Call::json(self.around_request(message_id)?))
+    }
+
+    fn mark_read_call(&self, user_id: &String, message_ids: Vec<String>) -> Result<Call<()>, ApiError> {
+        Ok(Call::new(self.mark_read_request(user_id, message_ids)?, |_: serde_json::Value| Ok(())))
+    }
+
+    // Gitter takes no client id; retries are confirmed by their echo instead
+    fn send_call(&self, room_id: &String, text: String, parent_id: Option<String>, _client_id: &String) -> Result<Call<Message>, ApiError> {
+        Ok(Call::json(self.send_request(room_id, text, parent_id)?))
+    }
+
+    fn update_call(&self, room_id: &String, id: &String, text: String, _client_id: &String) -> Result<Call<Message>, ApiError> {
+        Ok(Call::json(self.update_request(room_id, id, text)?))
+    }
+
+    // Long-lived request which receives new messages as newline separated JSON;
+    // `on_message` is called from inside curl as each message arrives
+    fn stream_request(&self, mut on_message: Box<FnMut(Message) + Send>) -> Result<Easy, ApiError> {
+        let url = format!("{}/rooms/{}/chatMessages", &self.endpoints.stream, &self.current_room_id);
+        debug!("Opening message stream {}", url);
+
+        // Open for as long as the room is, so only silence counts as a timeout
+        let mut easy = new_handle(None)?;
+
+        easy.url(&url)?;
+
+        let mut list = List::new();
+        list.append("Accept: application/json")?;
+        list.append(&(format!("Authorization: Bearer {}", &self.token)))?;
+        easy.http_headers(list)?;
+
+        let mut buffer: Vec<u8> = vec![];
+        easy.write_function(move |new_data| {
+            buffer.extend(new_data.iter());
+
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..end + 1).collect();
+
+                // Gitter keeps the connection alive with lines holding a single space
+                if line.iter().all(|byte| (*byte as char).is_whitespace()) {
+                    continue;
+                }
+
+                match serde_json::from_slice::<Message>(&line[..]) {
+                    Ok(message) => on_message(message),
+                    Err(e) => error!("Reading streamed message -> {}", e),
+                };
+            }
+
+            Ok(new_data.len())
+        })?;
+
+        Ok(easy)
+    }
+}
+
+// Percent-encodes text for use in a query string
+pub fn url_encode(text: &String) -> String {
+    Easy::new().url_encode(text.as_bytes())
+}
+
+// Decodes a value of a query string, where + stands for a space
+pub fn url_decode(text: &str) -> String {
+    let decoded = Easy::new().url_decode(&text.replace('+', " ")[..]);
+    String::from_utf8_lossy(&decoded[..]).into_owned()
+}
@@ -0,0 +1,202 @@
+// Archive mode: a JSON Lines export or the cache database of an account, opened read-only and
+// browsed in the usual window. Nothing is signed in to and nothing is fetched, so it works
+// while Gitter is unreachable.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use serde_json;
+
+use store::{SqliteStore, Store};
+use {Message, Room, User};
+
+// First bytes of every SQLite database
+const SQLITE_HEADER: &'static [u8] = b"SQLite format 3\0";
+
+// Id of the only room of a JSON Lines archive, which does not say which room it was
+const JSON_LINES_ROOM_ID: &'static str = "archive";
+
+pub struct Archive {
+    // Shown in the header bar, the file name
+    pub name: String,
+    pub rooms: Vec<Room>,
+    pub store: SqliteStore,
+    // Whoever the cache belonged to; nobody for a JSON Lines archive
+    pub user: User,
+}
+
+// Tells the two kinds apart by content rather than by file extension
+pub fn open(path: &Path) -> Result<Archive, String> {
+    let mut header = [0u8; 16];
+    let read = File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    let is_database = read.is_ok() && &header[..] == SQLITE_HEADER;
+
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(String::from("archive"));
+
+    if is_database {
+        open_database(path, name)
+    } else {
+        open_json_lines(path, name)
+    }
+}
+
+fn open_database(path: &Path, name: String) -> Result<Archive, String> {
+    let store = SqliteStore::open_read_only(&path.to_path_buf()).map_err(|e| e.to_string())?;
+
+    let rooms = store.load_rooms().map_err(|e| e.to_string())?;
+    let user = store.load_current_user().map_err(|e| e.to_string())?.unwrap_or_else(nobody);
+
+    Ok(Archive {
+        name: name,
+        rooms: rooms,
+        store: store,
+        user: user,
+    })
+}
+
+// The messages are loaded into a store in memory, so the window reads them like a cache
+fn open_json_lines(path: &Path, name: String) -> Result<Archive, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let messages = read_messages(BufReader::new(file))?;
+
+    let room_name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or(name.clone());
+    let room = archive_room(&room_name[..]);
+
+    let store = SqliteStore::open_in_memory().map_err(|e| e.to_string())?;
+    store.save_rooms(&vec![room.clone()])
+        .and_then(|_| store.save_messages(&room.id, &messages))
+        .map_err(|e| e.to_string())?;
+
+    Ok(Archive {
+        name: name,
+        rooms: vec![room],
+        store: store,
+        user: nobody(),
+    })
+}
+
+// One message per line as written by the JSON Lines export; blank lines are skipped
+pub fn read_messages<R: BufRead>(reader: R) -> Result<Vec<Message>, String> {
+    let mut messages = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+
+        if line.trim().len() == 0 {
+            continue;
+        }
+
+        let message: Message = serde_json::from_str(&line[..]).map_err(|e| format!("Line {} is not a message: {}", i + 1, e))?;
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+fn archive_room(name: &str) -> Room {
+    Room {
+        id: String::from(JSON_LINES_ROOM_ID),
+        name: String::from(name),
+        topic: String::new(),
+        url: String::new(),
+        oneToOne: false,
+        mentions: 0,
+        unreadItems: 0,
+        favourite: None,
+        groupId: None,
+        githubType: String::new(),
+        lurk: false,
+    }
+}
+
+fn nobody() -> User {
+    User {
+        id: String::new(),
+        username: String::new(),
+        displayName: String::new(),
+        url: String::new(),
+        avatarUrlSmall: String::new(),
+        avatarUrlMedium: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+
+    use rand;
+    use rand::Rng;
+
+    use super::*;
+    use export;
+    use export::Format;
+    use tests::fixtures::message;
+
+    fn messages() -> Vec<Message> {
+        let author = nobody();
+
+        vec![
+            message("m1", &author, "2017-11-15T09:00:00.000Z", "first"),
+            message("m2", &author, "2017-11-16T10:00:00.000Z", "second"),
+            message("m3", &author, "2017-11-18T08:30:00.000Z", "third"),
+        ]
+    }
+
+    fn ids(messages: &Vec<Message>) -> Vec<String> {
+        messages.iter().map(|message| message.id.clone()).collect()
+    }
+
+    #[test]
+    fn reads_what_the_json_lines_export_writes() {
+        let mut written = vec![];
+        export::write(Format::JsonLines, "org/repo", &messages(), &mut written).unwrap();
+        written.extend_from_slice(b"\n\n");
+
+        let read = read_messages(Cursor::new(written)).unwrap();
+        assert_eq!(ids(&read), vec!["m1", "m2", "m3"]);
+        assert_eq!(read[1].text, "second");
+    }
+
+    #[test]
+    fn names_the_line_which_is_not_a_message() {
+        let text = "{\"not\": \"a message\"}\n";
+
+        match read_messages(Cursor::new(text)) {
+            Err(e) => assert!(e.starts_with("Line 1 ")),
+            Ok(_) => panic!("expected an error"),
+        };
+    }
+
+    #[test]
+    fn opens_json_lines_and_cache_databases() {
+        let dir = env::temp_dir().join(format!("gitter_gtk_archive_test_{}", rand::thread_rng().gen::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let jsonl = dir.join("org-repo.jsonl");
+        export::write_file(&jsonl, Format::JsonLines, "org/repo", &messages()).unwrap();
+
+        let archive = open(&jsonl).unwrap();
+        assert_eq!(archive.name, "org-repo.jsonl");
+        assert_eq!(archive.rooms[0].name, "org-repo");
+        assert_eq!(ids(&archive.store.load_messages(&archive.rooms[0].id, 10).unwrap()), vec!["m1", "m2", "m3"]);
+
+        let db = dir.join("cache.db");
+        {
+            let cache = SqliteStore::open(&db).unwrap();
+            cache.save_rooms(&vec![archive_room("org/repo")]).unwrap();
+            cache.save_messages(&String::from(JSON_LINES_ROOM_ID), &messages()).unwrap();
+        }
+
+        let archive = open(&db).unwrap();
+        assert_eq!(archive.rooms[0].name, "org/repo");
+        assert_eq!(archive.store.search_messages(None, "third", 10).unwrap().len(), 1);
+
+        // Nothing is written to a cache opened as an archive
+        assert!(archive.store.save_rooms(&vec![]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+// Signing in to Gitter with the OAuth2 authorization code flow.
+// The browser is sent to gitter.im and redirected back to a listener on localhost,
+// whose code is exchanged for the token the app uses from then on.
+
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use curl::easy::List;
+use rand;
+use rand::Rng;
+use api;
+use api::{ApiError, MessageHandler};
+use chat::ChatBackend;
+use config::Config;
+use User;
+
+const AUTHORIZE_URL: &'static str = "https://gitter.im/login/oauth/authorize";
+const TOKEN_URL: &'static str = "https://gitter.im/login/oauth/token";
+
+// Must match the redirect URL registered for the OAuth application
+const REDIRECT_PORT: u16 = 48621;
+const REDIRECT_PATH: &'static str = "/callback";
+
+// Give up waiting for the browser after this long
+const LOGIN_TIMEOUT_SECS: u64 = 300;
+
+// Shown in the browser once it has been redirected back
+const SIGNED_IN_PAGE: &'static str = "<html><body><h2>Signed in to GtkGitter</h2>\
+    <p>You can close this tab and return to the app.</p></body></html>";
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    // Gitter redirected back with an error, e.g. the user pressed "Deny"
+    Denied(String),
+    // The redirect did not carry the state we sent, so it was not started by us
+    StateMismatch,
+    TimedOut,
+    Api(ApiError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::Io(ref e) => write!(f, "could not listen for the sign-in redirect: {}", e),
+            AuthError::Denied(ref reason) => write!(f, "Gitter denied the sign-in ({})", reason),
+            AuthError::StateMismatch => write!(f, "the sign-in redirect did not match this request"),
+            AuthError::TimedOut => write!(f, "timed out waiting for the browser"),
+            AuthError::Api(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for AuthError {
+    fn from(e: io::Error) -> AuthError {
+        AuthError::Io(e)
+    }
+}
+
+impl From<ApiError> for AuthError {
+    fn from(e: ApiError) -> AuthError {
+        AuthError::Api(e)
+    }
+}
+
+// Credentials of the OAuth application registered on developer.gitter.im.
+// A desktop app cannot keep its secret, so it is only as private as the binary.
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl OAuthClient {
+    // Taken from config.yaml, else from the environment the app was built in
+    pub fn from_config(config: &Config) -> Option<OAuthClient> {
+        let client_id = config.oauth_client_id.as_ref().map(|id| &id[..]).or(option_env!("GITTER_OAUTH_CLIENT_ID"));
+        let client_secret = config.oauth_client_secret.as_ref().map(|secret| &secret[..]).or(option_env!("GITTER_OAUTH_CLIENT_SECRET"));
+
+        match (client_id, client_secret) {
+            (Some(id), Some(secret)) if id.len() > 0 && secret.len() > 0 => Some(OAuthClient {
+                client_id: String::from(id),
+                client_secret: String::from(secret),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+}
+
+// One sign-in attempt: the listener is bound before the browser is opened
+pub struct SignIn {
+    client: OAuthClient,
+    listener: TcpListener,
+    state: String,
+}
+
+impl SignIn {
+    pub fn start(client: OAuthClient) -> Result<SignIn, AuthError> {
+        let listener = TcpListener::bind(("127.0.0.1", REDIRECT_PORT))?;
+        let state: String = rand::thread_rng().gen_ascii_chars().take(32).collect();
+
+        Ok(SignIn {
+            client: client,
+            listener: listener,
+            state: state,
+        })
+    }
+
+    pub fn redirect_uri(&self) -> String {
+        format!("http://localhost:{}{}", REDIRECT_PORT, REDIRECT_PATH)
+    }
+
+    // The page to open in the browser
+    pub fn authorize_url(&self) -> String {
+        format!(
+            "{}?client_id={}&response_type=code&redirect_uri={}&state={}",
+            AUTHORIZE_URL,
+            api::url_encode(&self.client.client_id),
+            api::url_encode(&self.redirect_uri()),
+            api::url_encode(&self.state)
+        )
+    }
+
+    // Blocks until the browser comes back, then trades the code for a token and loads the user.
+    // `cancelled` is checked while waiting so the GUI can abandon the attempt.
+    pub fn finish(self, cancelled: Arc<Mutex<bool>>) -> Result<(String, User), AuthError> {
+        let code = self.wait_for_code(cancelled)?;
+        let token = self.exchange_code(&code)?;
+
+        let (user, _, _) = MessageHandler::new(&String::new(), &token).fetch_account()?;
+
+        Ok((token, user))
+    }
+
+    fn wait_for_code(&self, cancelled: Arc<Mutex<bool>>) -> Result<String, AuthError> {
+        self.listener.set_nonblocking(true)?;
+        let deadline = Instant::now() + Duration::from_secs(LOGIN_TIMEOUT_SECS);
+
+        loop {
+            if *cancelled.lock().unwrap() || Instant::now() > deadline {
+                return Err(AuthError::TimedOut);
+            }
+
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+
+                    // Browsers also ask for /favicon.ico and the like; keep waiting for the redirect
+                    if let Some(result) = self.handle_redirect(stream)? {
+                        return result;
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(200));
+                },
+                Err(e) => return Err(AuthError::Io(e)),
+            };
+        }
+    }
+
+    fn handle_redirect(&self, stream: TcpStream) -> Result<Option<Result<String, AuthError>>, AuthError> {
+        let mut writer = stream.try_clone()?;
+        let mut request_line = String::new();
+        BufReader::new(stream).read_line(&mut request_line)?;
+
+        // e.g. "GET /callback?code=...&state=... HTTP/1.1"
+        let target = request_line.split_whitespace().nth(1).unwrap_or("");
+        let (path, query) = match target.find('?') {
+            Some(index) => (&target[..index], &target[index + 1..]),
+            None => (target, ""),
+        };
+
+        if path != REDIRECT_PATH {
+            writer.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+            return Ok(None);
+        }
+
+        let mut code = None;
+        let mut state = None;
+        let mut error = None;
+
+        for pair in query.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = api::url_decode(parts.next().unwrap_or(""));
+
+            match key {
+                "code" => code = Some(value),
+                "state" => state = Some(value),
+                "error" => error = Some(value),
+                _ => (),
+            };
+        }
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            SIGNED_IN_PAGE.len(),
+            SIGNED_IN_PAGE
+        );
+        writer.write_all(response.as_bytes())?;
+
+        let result = if let Some(error) = error {
+            Err(AuthError::Denied(error))
+        } else if state.as_ref() != Some(&self.state) {
+            Err(AuthError::StateMismatch)
+        } else {
+            match code {
+                Some(code) => Ok(code),
+                None => Err(AuthError::Denied(String::from("no code in redirect"))),
+            }
+        };
+
+        Ok(Some(result))
+    }
+
+    fn exchange_code(&self, code: &String) -> Result<String, AuthError> {
+        let body = format!(
+            "client_id={}&client_secret={}&code={}&redirect_uri={}&grant_type=authorization_code",
+            api::url_encode(&self.client.client_id),
+            api::url_encode(&self.client.client_secret),
+            api::url_encode(code),
+            api::url_encode(&self.redirect_uri())
+        );
+
+        let mut easy = api::new_request_handle()?;
+        easy.url(TOKEN_URL).map_err(ApiError::from)?;
+        easy.post(true).map_err(ApiError::from)?;
+        easy.post_fields_copy(body.as_bytes()).map_err(ApiError::from)?;
+
+        let mut list = List::new();
+        list.append("Accept: application/json").map_err(ApiError::from)?;
+        list.append("Content-Type: application/x-www-form-urlencoded").map_err(ApiError::from)?;
+        easy.http_headers(list).map_err(ApiError::from)?;
+
+        let mut response = vec![];
+        {
+            let mut transfer = easy.transfer();
+            transfer.write_function(|new_data| {
+                response.extend_from_slice(new_data);
+                Ok(new_data.len())
+            }).map_err(ApiError::from)?;
+            transfer.perform().map_err(ApiError::from)?;
+        }
+
+        let token: TokenResponse = api::parse_response(&mut easy, &response[..])?;
+
+        Ok(token.access_token)
+    }
+}
+
+// Checks a personal access token pasted by the user
+pub fn verify_token(token: &String) -> Result<User, AuthError> {
+    let (user, _, _) = MessageHandler::new(&String::new(), token).fetch_account()?;
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    // A sign-in listening on any free port, waiting for the state "s+t/1"
+    fn sign_in() -> SignIn {
+        SignIn {
+            client: OAuthClient {
+                client_id: String::from("id"),
+                client_secret: String::from("secret"),
+            },
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            state: String::from("s+t/1"),
+        }
+    }
+
+    // Sends one request line to the listener and has the sign-in handle it
+    fn redirect(sign_in: &SignIn, target: &str) -> (Option<Result<String, AuthError>>, String) {
+        let mut browser = TcpStream::connect(sign_in.listener.local_addr().unwrap()).unwrap();
+        browser.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).as_bytes()).unwrap();
+
+        let (stream, _) = sign_in.listener.accept().unwrap();
+        let result = sign_in.handle_redirect(stream).unwrap();
+
+        let mut response = String::new();
+        browser.read_to_string(&mut response).unwrap();
+
+        (result, response)
+    }
+
+    #[test]
+    fn redirect_gives_the_decoded_code() {
+        let sign_in = sign_in();
+
+        let (result, response) = redirect(&sign_in, "/callback?code=ab%2Fc%3D%3D&state=s%2Bt%2F1");
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        match result {
+            Some(Ok(code)) => assert_eq!(code, "ab/c=="),
+            other => panic!("expected the code, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn redirect_with_another_state_is_rejected() {
+        let sign_in = sign_in();
+
+        match redirect(&sign_in, "/callback?code=abc&state=other").0 {
+            Some(Err(AuthError::StateMismatch)) => (),
+            other => panic!("expected a state mismatch, got {:?}", other),
+        };
+
+        // A + in the query is a space, not the + of the state
+        match redirect(&sign_in, "/callback?code=abc&state=s+t/1").0 {
+            Some(Err(AuthError::StateMismatch)) => (),
+            other => panic!("expected a state mismatch, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn denied_sign_in_reports_the_error() {
+        let sign_in = sign_in();
+
+        match redirect(&sign_in, "/callback?error=access%20denied&state=s%2Bt%2F1").0 {
+            Some(Err(AuthError::Denied(reason))) => assert_eq!(reason, "access denied"),
+            other => panic!("expected a denied sign-in, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn other_paths_are_not_the_redirect() {
+        let sign_in = sign_in();
+
+        let (result, response) = redirect(&sign_in, "/favicon.ico");
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
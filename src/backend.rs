@@ -0,0 +1,985 @@
+// The backend core: one thread running an event loop which performs every request
+// through the rate limit aware scheduler, follows the message stream of the open
+// room, delivers the outbox and exports room history.
+// The GUI drives it with Commands and is told what happened through Events.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Stream};
+use notify_rust;
+use serde_json::Value;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use curl::easy::Easy;
+use tokio_curl::{PerformError, Session};
+
+use api::{ApiError, Endpoints, Response};
+use chat;
+use chat::{Call, ChatBackend};
+use connection::{ConnectionMonitor, ConnectionState};
+use export;
+use export::{ExportEvent, ExportRequest, History};
+use logging;
+use outbox;
+use outbox::{DeliveryState, OutboxEvent, OutboxItem};
+use scheduler;
+use scheduler::{Priority, Scheduler};
+use search::SearchResult;
+use store::{SqliteStore, Store};
+use ui_channel::EventSender;
+use {open_cache, save_account, Group, Message, MessageStore, OutgoingMessage, Room, User};
+
+// Wait before reopening the message stream after it drops
+const RECONNECT_MILLIS: u64 = 5000;
+
+// Wait before retrying the account refresh after starting from cache
+const ACCOUNT_RETRY_MILLIS: u64 = 30000;
+
+// How often the outbox checks for messages due to be sent
+const OUTBOX_TICK_MILLIS: u64 = 500;
+
+// A request refused with HTTP 429 this often fails instead of waiting again
+const MAX_RATE_LIMITED_ATTEMPTS: u32 = 5;
+
+// Prevent multiple notifications filling up too quickly
+const NOTIFICATION_INTERVAL_SECS: u64 = 5;
+
+// Requests from the GUI
+#[derive(Debug, Clone)]
+pub enum Command {
+    SwitchRoom(String),
+    Send(OutboxItem),
+    RetryMessage(String),
+    DiscardMessage(String),
+    Search { room_id: String, query: String },
+    // History around a message, used when jumping to a search result
+    LoadAround { room_id: String, message_id: String },
+    RetryNow,
+    NetworkAvailable(bool),
+    // Changed in the Preferences dialog or in config.yaml
+    UpdateSettings { poll_interval_secs: u64, notifications: bool },
+    // Signs in again with a new token, e.g. after the old one was rejected
+    SetToken(String),
+    // Fetches the whole history of a room, or of a date range, and writes it to a file
+    Export(ExportRequest),
+    // Stops the running export, if any
+    CancelExport,
+    // Stops the core, e.g. when signing out; pending requests are dropped
+    Shutdown,
+}
+
+// Reports to the GUI, delivered on the GTK thread as soon as they are sent
+#[derive(Debug, Clone)]
+pub enum Event {
+    MessagesAdded { room_id: String, messages: Vec<Message> },
+    RoomsUpdated { rooms: Vec<Room>, groups: Vec<Group> },
+    SearchResults(Vec<SearchResult>),
+    ContextLoaded { room_id: String, message_id: String, messages: Vec<Message> },
+    Outbox(OutboxEvent),
+    ConnectionChanged(ConnectionState),
+    Export(ExportEvent),
+}
+
+pub type CommandSender = mpsc::UnboundedSender<Command>;
+
+pub struct BackendConfig {
+    pub token: String,
+    // The servers of the account
+    pub endpoints: Endpoints,
+    pub user: User,
+    pub room_id: String,
+    pub cache_path: Option<PathBuf>,
+    // The window started from cache, so the user and rooms still need refreshing
+    pub refresh_account: bool,
+    // How often rooms and unread counts are refreshed
+    pub poll_interval_secs: u64,
+    pub notifications: bool,
+}
+
+// Starts the backend core on its own thread; it stops on Command::Shutdown or once every CommandSender is dropped
+pub fn start(config: BackendConfig, events: EventSender) -> CommandSender {
+    let (commands, command_receiver) = mpsc::unbounded();
+
+    ::std::thread::spawn(move || {
+        let mut core = match Core::new() {
+            Ok(core) => core,
+            Err(e) => {
+                error!("Starting backend event loop -> {}", e);
+                return;
+            },
+        };
+
+        let backend = Backend::new(config, core.handle(), events);
+        backend.start();
+
+        let commands_done = command_receiver.for_each(move |command| {
+            // Ending the stream with an error stops the event loop
+            if let Command::Shutdown = command {
+                return Err(());
+            }
+
+            backend.handle_command(command);
+            Ok(())
+        });
+
+        core.run(commands_done).unwrap_or(());
+    });
+
+    commands
+}
+
+// A request waiting in the scheduler; `done` gets the handle back once it was performed
+struct Job {
+    priority: Priority,
+    easy: Easy,
+    response: Response,
+    // HTTP 429s so far
+    attempts: u32,
+    done: oneshot::Sender<Result<(Easy, Response), ApiError>>,
+}
+
+struct State {
+    handler: Box<ChatBackend>,
+    user: User,
+    message_store: MessageStore,
+    connection: ConnectionMonitor,
+    reported_state: Option<ConnectionState>,
+    outbox: Vec<OutboxItem>,
+    // Client ids of outbox items with a request in flight
+    sending: HashSet<String>,
+    cache: Option<SqliteStore>,
+    // Dropping the sender closes the message stream of the previous room
+    stream_cancel: Option<oneshot::Sender<()>>,
+    // Bumped whenever the room is reopened, so stale reconnect timers do nothing
+    room_generation: u64,
+    account_pending: bool,
+    // Rooms and groups as last reported to the frontend, so an unchanged poll is not reported again
+    room_list: Option<(Vec<Room>, Vec<Group>)>,
+    poll_interval_secs: u64,
+    // Bumped whenever the poll interval changes, so the timer of the old interval stops
+    poll_generation: u64,
+    notifications: bool,
+    last_notification: Option<Instant>,
+    scheduler: Scheduler<Job>,
+    // When the timer for requests held back by the rate limit fires
+    scheduler_wake: Option<Instant>,
+    // Bumped when an export starts or is cancelled, so the pages of an older one are dropped
+    export_generation: u64,
+}
+
+#[derive(Clone)]
+struct Backend {
+    handle: Handle,
+    session: Session,
+    events: EventSender,
+    state: Rc<RefCell<State>>,
+}
+
+impl Backend {
+    fn new(config: BackendConfig, handle: Handle, events: EventSender) -> Backend {
+        let state = State {
+            handler: chat::connect(&config.room_id, &config.token, &config.endpoints),
+            user: config.user,
+            message_store: MessageStore::new(),
+            connection: ConnectionMonitor::new(),
+            reported_state: None,
+            outbox: vec![],
+            sending: HashSet::new(),
+            cache: open_cache(&config.cache_path),
+            stream_cancel: None,
+            room_generation: 0,
+            account_pending: config.refresh_account,
+            room_list: None,
+            poll_interval_secs: config.poll_interval_secs,
+            poll_generation: 0,
+            notifications: config.notifications,
+            last_notification: None,
+            scheduler: Scheduler::new(),
+            scheduler_wake: None,
+            export_generation: 0,
+        };
+
+        Backend {
+            session: Session::new(handle.clone()),
+            handle: handle,
+            events: events,
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    fn start(&self) {
+        self.emit_connection_state();
+
+        // Messages left in the outbox by a previous session
+        let queued = match self.state.borrow().cache {
+            Some(ref cache) => cache.load_outbox().unwrap_or(vec![]),
+            None => vec![],
+        };
+
+        for item in queued.iter() {
+            self.events.send(Event::Outbox(OutboxEvent::Queued(item.clone())));
+        }
+
+        self.state.borrow_mut().outbox = queued;
+
+        match Interval::new(Duration::from_millis(OUTBOX_TICK_MILLIS), &self.handle) {
+            Ok(interval) => {
+                let backend = self.clone();
+                self.handle.spawn(interval.for_each(move |_| {
+                    backend.send_due_messages();
+                    Ok(())
+                }).map_err(|e| error!("Outbox timer -> {}", e)));
+            },
+            Err(e) => error!("Creating outbox timer -> {}", e),
+        };
+
+        if self.state.borrow().account_pending {
+            self.refresh_account(Priority::Refresh);
+        }
+
+        self.schedule_poll();
+        self.open_room();
+    }
+
+    fn handle_command(&self, command: Command) {
+        match command {
+            Command::SwitchRoom(room_id) => {
+                debug!("Switching to room {}", room_id);
+                {
+                    let mut state = self.state.borrow_mut();
+                    state.handler.set_current_room_id(room_id);
+                    state.message_store = MessageStore::new();
+                }
+                self.open_room();
+            },
+            Command::Send(item) => {
+                let text = match item.message {
+                    OutgoingMessage::New { ref text, .. } | OutgoingMessage::Edit { ref text, .. } => text.clone(),
+                };
+                debug!("Queued message {} for room {}: {}", item.client_id, item.room_id, logging::private(text));
+
+                self.save_outbox_item(&item);
+                self.state.borrow_mut().outbox.push(item);
+                self.send_due_messages();
+            },
+            Command::RetryMessage(client_id) => {
+                let item = {
+                    let mut state = self.state.borrow_mut();
+                    state.outbox.iter_mut().find(|item| item.client_id == client_id).map(|item| {
+                        item.retry();
+                        item.clone()
+                    })
+                };
+
+                if let Some(item) = item {
+                    self.save_outbox_item(&item);
+                }
+                self.send_due_messages();
+            },
+            Command::DiscardMessage(client_id) => {
+                self.state.borrow_mut().outbox.retain(|item| item.client_id != client_id);
+                self.remove_outbox_item(&client_id);
+            },
+            Command::Search { room_id, query } => self.search(room_id, query),
+            Command::LoadAround { room_id, message_id } => self.load_around(room_id, message_id),
+            Command::RetryNow => {
+                self.state.borrow_mut().connection.request_retry();
+                self.retry_now();
+            },
+            Command::NetworkAvailable(available) => {
+                let came_back = self.state.borrow_mut().connection.set_network_available(available);
+
+                if came_back {
+                    self.retry_now();
+                } else {
+                    self.emit_connection_state();
+                }
+            },
+            Command::UpdateSettings { poll_interval_secs, notifications } => {
+                let interval_changed = {
+                    let mut state = self.state.borrow_mut();
+                    let changed = state.poll_interval_secs != poll_interval_secs;
+                    state.poll_interval_secs = poll_interval_secs;
+                    state.notifications = notifications;
+                    changed
+                };
+
+                if interval_changed {
+                    self.schedule_poll();
+                }
+            },
+            Command::SetToken(token) => {
+                {
+                    let mut state = self.state.borrow_mut();
+                    state.handler.set_token(token);
+                    // Failures of the old token no longer apply
+                    state.connection.request_retry();
+                    state.account_pending = true;
+                }
+
+                self.retry_now();
+            },
+            Command::Export(request) => self.export(request),
+            Command::CancelExport => self.state.borrow_mut().export_generation += 1,
+            // Handled by the event loop in start
+            Command::Shutdown => (),
+        };
+    }
+
+    // Skips every wait: reconnects the stream, sends the outbox and refreshes the account
+    fn retry_now(&self) {
+        self.emit_connection_state();
+
+        for item in self.state.borrow_mut().outbox.iter_mut().filter(|item| item.state == DeliveryState::Sending) {
+            item.next_attempt = 0;
+        }
+
+        if self.state.borrow().account_pending {
+            self.refresh_account(Priority::Refresh);
+        }
+
+        self.open_room();
+        self.send_due_messages();
+    }
+
+    // Queues the request of a call in the scheduler and records the outcome in the connection state
+    fn perform<T: 'static>(&self, priority: Priority, call: Result<Call<T>, ApiError>) -> Box<Future<Item = T, Error = ApiError>> {
+        let (request, read) = match call {
+            Ok(Call::Request(request, read)) => (request, read),
+            Ok(Call::Ready(value)) => return Box::new(future::ok(value)),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (easy, response) = request.into_parts();
+
+        let (done, performed) = oneshot::channel();
+        self.state.borrow_mut().scheduler.push(priority, Job {
+            priority: priority,
+            easy: easy,
+            response: response,
+            attempts: 0,
+            done: done,
+        });
+        self.run_scheduled();
+
+        let backend = self.clone();
+
+        Box::new(performed
+            // Only dropped when the core stops
+            .map_err(|_| ApiError::Network(io::Error::new(io::ErrorKind::Other, "request dropped")))
+            .and_then(|result| result)
+            .and_then(|(mut easy, response)| response.parse::<Value>(&mut easy))
+            .and_then(move |value| read(value))
+            .then(move |result| {
+                backend.report(&result);
+                result
+            }))
+    }
+
+    // Starts every request the scheduler lets go now, and wakes up when the others may go
+    fn run_scheduled(&self) {
+        loop {
+            let job = self.state.borrow_mut().scheduler.next(Instant::now());
+            let job = match job {
+                Some(job) => job,
+                None => break,
+            };
+
+            let Job { priority, easy, response, attempts, done } = job;
+            let backend = self.clone();
+
+            self.handle.spawn(self.session.perform(easy).then(move |result| {
+                backend.finish_job(priority, response, attempts, done, result);
+                Ok(())
+            }));
+        }
+
+        let now = Instant::now();
+        // One timer is enough, unless the new wait ends sooner
+        let wake = {
+            let mut state = self.state.borrow_mut();
+            let at = state.scheduler.wake_at(now);
+
+            match (at, state.scheduler_wake) {
+                (Some(at), Some(pending)) if pending <= at => None,
+                (Some(at), _) => {
+                    state.scheduler_wake = Some(at);
+                    Some(at)
+                },
+                (None, _) => None,
+            }
+        };
+
+        if let Some(at) = wake {
+            let backend = self.clone();
+            let wait = at - now;
+            self.after(wait.as_secs() * 1000 + wait.subsec_nanos() as u64 / 1000000 + 1, move || {
+                backend.state.borrow_mut().scheduler_wake = None;
+                backend.run_scheduled();
+            });
+        }
+    }
+
+    // Tells the scheduler how a request went; one refused with HTTP 429 waits in the queue again
+    fn finish_job(
+        &self,
+        priority: Priority,
+        response: Response,
+        attempts: u32,
+        done: oneshot::Sender<Result<(Easy, Response), ApiError>>,
+        result: Result<Easy, PerformError>
+    ) {
+        let now = Instant::now();
+
+        let mut easy = match result {
+            Ok(easy) => easy,
+            Err(e) => {
+                self.state.borrow_mut().scheduler.finished(now, None, None, None);
+                done.send(Err(ApiError::from(e))).unwrap_or(());
+                self.run_scheduled();
+                return;
+            },
+        };
+
+        let status = easy.response_code().ok();
+        let rate_limit = scheduler::parse_rate_limit(
+            response.header("x-ratelimit-limit"),
+            response.header("x-ratelimit-remaining"),
+            response.header("x-ratelimit-reset"),
+            now
+        );
+        let retry_after = scheduler::parse_retry_after(response.header("retry-after"));
+
+        self.state.borrow_mut().scheduler.finished(now, status, rate_limit, retry_after);
+
+        if status == Some(429) && attempts + 1 < MAX_RATE_LIMITED_ATTEMPTS {
+            warn!("Rate limited by Gitter, waiting before trying again (attempt {})", attempts + 1);
+            response.clear();
+
+            self.state.borrow_mut().scheduler.push(priority, Job {
+                priority: priority,
+                easy: easy,
+                response: response,
+                attempts: attempts + 1,
+                done: done,
+            });
+        } else {
+            done.send(Ok((easy, response))).unwrap_or(());
+        }
+
+        self.run_scheduled();
+    }
+
+    fn report<T>(&self, result: &Result<T, ApiError>) {
+        self.state.borrow_mut().connection.report(result);
+        self.emit_connection_state();
+    }
+
+    fn emit_connection_state(&self) {
+        let state = {
+            let mut state = self.state.borrow_mut();
+            let current = state.connection.state();
+
+            if state.reported_state == Some(current) {
+                return;
+            }
+
+            state.reported_state = Some(current);
+            current
+        };
+
+        self.events.send(Event::ConnectionChanged(state));
+    }
+
+    // Runs `f` on the event loop after `millis`
+    fn after<F>(&self, millis: u64, f: F)
+    where F: FnOnce() + 'static
+    {
+        match Timeout::new(Duration::from_millis(millis), &self.handle) {
+            Ok(timeout) => self.handle.spawn(timeout.then(move |_| -> Result<(), ()> {
+                f();
+                Ok(())
+            })),
+            Err(e) => error!("Creating timer -> {}", e),
+        };
+    }
+
+    // Refreshes rooms and unread counts every poll interval, replacing the timer of an earlier interval
+    fn schedule_poll(&self) {
+        let (generation, millis) = {
+            let mut state = self.state.borrow_mut();
+            state.poll_generation += 1;
+            (state.poll_generation, state.poll_interval_secs * 1000)
+        };
+
+        self.poll_after(generation, millis);
+    }
+
+    fn poll_after(&self, generation: u64, millis: u64) {
+        let backend = self.clone();
+        self.after(millis, move || {
+            if backend.state.borrow().poll_generation != generation {
+                return;
+            }
+
+            // A refresh still retrying is left to finish
+            let already_pending = backend.state.borrow().account_pending;
+            if !already_pending {
+                backend.state.borrow_mut().account_pending = true;
+                backend.refresh_account(Priority::Background);
+            }
+
+            backend.poll_after(generation, millis);
+        });
+    }
+
+    // Loads the latest messages of the current room, then follows its message stream
+    fn open_room(&self) {
+        let (generation, cancel_receiver, room_id, request) = {
+            let mut state = self.state.borrow_mut();
+
+            let (cancel_sender, cancel_receiver) = oneshot::channel();
+
+            // Replacing the sender drops the previous one, which closes the old stream
+            state.stream_cancel = Some(cancel_sender);
+            state.room_generation += 1;
+
+            (state.room_generation, cancel_receiver, state.handler.current_room_id().clone(), state.handler.messages_call())
+        };
+
+        if room_id.len() == 0 {
+            return;
+        }
+
+        let backend = self.clone();
+        let latest = self.perform::<Vec<Message>>(Priority::Interactive, request).then(move |result| {
+            match result {
+                Ok(messages) => backend.receive_messages(&room_id, messages),
+                Err(e) => error!("Loading messages -> {}", e),
+            };
+
+            if backend.state.borrow().room_generation == generation {
+                backend.follow_stream(generation, cancel_receiver);
+            }
+
+            Ok(())
+        });
+
+        self.handle.spawn(latest);
+    }
+
+    fn follow_stream(&self, generation: u64, cancel_receiver: oneshot::Receiver<()>) {
+        let (message_sender, message_receiver) = mpsc::unbounded();
+
+        let (room_id, request) = {
+            let state = self.state.borrow();
+            let request = state.handler.stream_request(Box::new(move |message: Message| {
+                message_sender.unbounded_send(message).unwrap_or(());
+            }));
+
+            (state.handler.current_room_id().clone(), request)
+        };
+
+        let easy = match request {
+            Ok(easy) => easy,
+            Err(e) => {
+                error!("Opening message stream -> {}", e);
+                return;
+            },
+        };
+
+        // Messages are parsed inside curl's write callback, or once a long poll finished, and handled here, on the event loop
+        {
+            let backend = self.clone();
+            let room_id = room_id.clone();
+            self.handle.spawn(message_receiver.for_each(move |message| {
+                backend.receive_messages(&room_id, vec![message]);
+                Ok(())
+            }));
+        }
+
+        let backend = self.clone();
+        let stream = self.session.perform(easy).select2(cancel_receiver).then(move |result| {
+            match result {
+                // A long poll answers once per batch of messages, so it is asked again straight away
+                Ok(future::Either::A((mut easy, cancel_receiver))) => match easy.response_code() {
+                    Ok(200...299) if backend.state.borrow().handler.stream_is_long_poll() => {
+                        backend.state.borrow().handler.stream_finished();
+                        backend.follow_stream(generation, cancel_receiver);
+                        return Ok(());
+                    },
+                    Ok(200...299) => info!("Message stream for {} closed by the server", room_id),
+                    Ok(code) => backend.report::<()>(&Err(ApiError::Http(code))),
+                    Err(e) => error!("Reading message stream status -> {}", e),
+                },
+                Err(future::Either::A((e, _))) => backend.report::<()>(&Err(ApiError::from(e))),
+                // Cancelled because the room changed
+                _ => return Ok(()),
+            };
+
+            // Reopening also loads whatever was missed while disconnected
+            let backend_clone = backend.clone();
+            backend.after(RECONNECT_MILLIS, move || {
+                if backend_clone.state.borrow().room_generation == generation {
+                    backend_clone.open_room();
+                }
+            });
+
+            Ok(())
+        });
+
+        self.handle.spawn(stream);
+    }
+
+    // Caches new messages, tells the GUI and notifies about mentions
+    fn receive_messages(&self, room_id: &String, messages: Vec<Message>) {
+        let new_messages = {
+            let mut state = self.state.borrow_mut();
+
+            // Still arriving from a room the user has left
+            if state.handler.current_room_id() != room_id {
+                return;
+            }
+
+            if let Some(ref cache) = state.cache {
+                if let Err(e) = cache.save_messages(room_id, &messages) {
+                    error!("Caching messages -> {}", e);
+                }
+            }
+
+            state.message_store.set_messages(messages);
+            state.message_store.messages.clone()
+        };
+
+        if new_messages.len() == 0 {
+            return;
+        }
+
+        for message in new_messages.iter() {
+            self.notify_mention(message);
+        }
+
+        self.mark_read(&new_messages);
+
+        self.events.send(Event::MessagesAdded {
+            room_id: room_id.clone(),
+            messages: new_messages,
+        });
+    }
+
+    // Messages shown in the open room count as read on Gitter too
+    fn mark_read(&self, messages: &Vec<Message>) {
+        let unread: Vec<String> = messages.iter()
+            .filter(|message| message.unread)
+            .map(|message| message.id.clone())
+            .collect();
+
+        if unread.len() == 0 {
+            return;
+        }
+
+        let request = {
+            let state = self.state.borrow();
+            state.handler.mark_read_call(&state.user.id, unread)
+        };
+
+        self.handle.spawn(self.perform::<()>(Priority::Refresh, request).then(|result| {
+            if let Err(e) = result {
+                error!("Marking messages as read -> {}", e);
+            }
+
+            Ok(())
+        }));
+    }
+
+    fn notify_mention(&self, message: &Message) {
+        let mut state = self.state.borrow_mut();
+
+        if !state.notifications {
+            return;
+        }
+
+        let mentions_user = message.mentions.len() > 0 && &message.mentions[0].screenName == &state.user.username;
+        if !mentions_user {
+            return;
+        }
+
+        let recently_notified = state.last_notification
+            .map(|last| last.elapsed() < Duration::from_secs(NOTIFICATION_INTERVAL_SECS))
+            .unwrap_or(false);
+        if recently_notified {
+            return;
+        }
+
+        state.last_notification = Some(Instant::now());
+
+        // Several accounts may be signed in, so say which one was mentioned
+        let summary = format!("New message for @{}", state.user.username);
+        let body = format!("Message from user {}!", message.fromUser.username);
+        let result = notify_rust::Notification::new()
+            .summary(&summary[..])
+            .body(&body[..])
+            .icon("email")
+            .timeout(5000)
+            .show();
+
+        if let Err(e) = result {
+            error!("Showing notification -> {}", e);
+        }
+    }
+
+    fn search(&self, room_id: String, query: String) {
+        let request = self.state.borrow().handler.for_room(&room_id).search_call(&query);
+
+        let events = self.events.clone();
+        self.handle.spawn(self.perform::<Vec<Message>>(Priority::Interactive, request).then(move |result| {
+            let messages = result.unwrap_or_else(|e| {
+                error!("Searching messages -> {}", e);
+                vec![]
+            });
+
+            let results: Vec<SearchResult> = messages.into_iter()
+                .map(|message| (room_id.clone(), message))
+                .collect();
+
+            events.send(Event::SearchResults(results));
+            Ok(())
+        }));
+    }
+
+    fn load_around(&self, room_id: String, message_id: String) {
+        let request = self.state.borrow().handler.for_room(&room_id).around_call(&message_id);
+
+        let events = self.events.clone();
+        self.handle.spawn(self.perform::<Vec<Message>>(Priority::Interactive, request).then(move |result| {
+            match result {
+                Ok(messages) => events.send(Event::ContextLoaded {
+                    room_id: room_id,
+                    message_id: message_id,
+                    messages: messages,
+                }),
+                Err(e) => error!("Loading messages around {} -> {}", message_id, e),
+            };
+
+            Ok(())
+        }));
+    }
+
+    // Only one export runs at a time; starting another cancels it
+    fn export(&self, request: ExportRequest) {
+        let (generation, handler) = {
+            let mut state = self.state.borrow_mut();
+            state.export_generation += 1;
+            (state.export_generation, state.handler.for_room(&request.room_id))
+        };
+
+        info!("Exporting {} to {}", request.room_name, request.path.display());
+        let history = History::new(request.range.clone());
+        self.export_page(generation, handler, request, history);
+    }
+
+    // Pages wait behind everything the user is looking at, so a long export does not use up the rate limit
+    fn export_page(&self, generation: u64, handler: Box<ChatBackend>, request: ExportRequest, mut history: History) {
+        let call = handler.history_call(history.before_id());
+
+        let backend = self.clone();
+        self.handle.spawn(self.perform::<Vec<Message>>(Priority::Background, call).then(move |result| {
+            if backend.state.borrow().export_generation != generation {
+                return Ok(());
+            }
+
+            match result {
+                Ok(page) => history.add_page(page),
+                Err(e) => {
+                    error!("Exporting {} -> {}", request.room_name, e);
+                    backend.events.send(Event::Export(ExportEvent::Failed(format!("Could not load the history: {}", e))));
+                    return Ok(());
+                },
+            };
+
+            if history.is_complete() {
+                backend.finish_export(request, history);
+            } else {
+                backend.events.send(Event::Export(ExportEvent::Progress {
+                    fetched: history.fetched(),
+                    reached: history.reached(),
+                }));
+                backend.export_page(generation, handler, request, history);
+            }
+
+            Ok(())
+        }));
+    }
+
+    fn finish_export(&self, request: ExportRequest, history: History) {
+        let messages = history.into_messages();
+
+        let event = match export::write_file(&request.path, request.format, &request.room_name[..], &messages[..]) {
+            Ok(()) => {
+                info!("Exported {} messages of {}", messages.len(), request.room_name);
+                ExportEvent::Finished { path: request.path, written: messages.len() }
+            },
+            Err(e) => {
+                error!("Writing {} -> {}", request.path.display(), e);
+                ExportEvent::Failed(format!("Could not write {}: {}", request.path.display(), e))
+            },
+        };
+
+        self.events.send(Event::Export(event));
+    }
+
+    // Refreshes the user, rooms and groups after starting from cache and on every poll, retrying while offline
+    fn refresh_account(&self, priority: Priority) {
+        let (user_call, rooms_call, groups_call) = {
+            let state = self.state.borrow();
+            (state.handler.user_call(), state.handler.rooms_call(), state.handler.groups_call())
+        };
+
+        let groups = self.perform::<Vec<Group>>(priority, groups_call).or_else(|e| {
+            error!("Requesting groups -> {}", e);
+            Ok::<Vec<Group>, ApiError>(vec![])
+        });
+
+        let account = self.perform::<User>(priority, user_call)
+            .join3(self.perform::<Vec<Room>>(priority, rooms_call), groups);
+
+        let backend = self.clone();
+        self.handle.spawn(account.then(move |result| {
+            if !backend.state.borrow().account_pending {
+                return Ok(());
+            }
+
+            match result {
+                Ok((user, rooms, groups)) => {
+                    let changed = {
+                        let mut state = backend.state.borrow_mut();
+                        state.account_pending = false;
+                        save_account(&state.cache, &user, &rooms, &groups);
+                        state.user = user;
+
+                        let room_list = Some((rooms.clone(), groups.clone()));
+                        let changed = state.room_list != room_list;
+                        state.room_list = room_list;
+                        changed
+                    };
+
+                    if changed {
+                        backend.events.send(Event::RoomsUpdated { rooms: rooms, groups: groups });
+                    }
+                },
+                Err(ApiError::Http(401)) => (),
+                Err(e) => {
+                    error!("Refreshing rooms -> {}", e);
+
+                    let backend_clone = backend.clone();
+                    backend.after(ACCOUNT_RETRY_MILLIS, move || {
+                        if backend_clone.state.borrow().account_pending {
+                            backend_clone.refresh_account(Priority::Refresh);
+                        }
+                    });
+                },
+            };
+
+            Ok(())
+        }));
+    }
+
+    // Delivers queued messages which are due, retrying with backoff; the queue is kept in the cache
+    fn send_due_messages(&self) {
+        let now = outbox::now_secs();
+
+        let due: Vec<(OutboxItem, Result<Call<Message>, ApiError>)> = {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+
+            let mut due = vec![];
+
+            for item in state.outbox.iter().filter(|item| item.is_due(now)) {
+                if !state.sending.insert(item.client_id.clone()) {
+                    continue;
+                }
+
+                let request = match item.message {
+                    OutgoingMessage::New { ref text, ref parent_id } => {
+                        state.handler.send_call(&item.room_id, text.clone(), parent_id.clone(), &item.client_id)
+                    },
+                    OutgoingMessage::Edit { ref id, ref text } => {
+                        state.handler.update_call(&item.room_id, id, text.clone(), &item.client_id)
+                    },
+                };
+
+                due.push((item.clone(), request));
+            }
+
+            due
+        };
+
+        for (item, request) in due.into_iter() {
+            let backend = self.clone();
+
+            // Both requests answer with the message, whose id confirms delivery once it is echoed back.
+            // A retry keeps the client id, which Matrix takes as the transaction id, so an attempt
+            // whose answer was lost is not posted twice
+            self.handle.spawn(self.perform::<Message>(Priority::User, request).then(move |result| {
+                backend.state.borrow_mut().sending.remove(&item.client_id);
+                backend.finish_delivery(&item.client_id, result);
+                Ok(())
+            }));
+        }
+    }
+
+    fn finish_delivery(&self, client_id: &String, result: Result<Message, ApiError>) {
+        match result {
+            Ok(sent) => {
+                self.state.borrow_mut().outbox.retain(|item| &item.client_id != client_id);
+                self.remove_outbox_item(client_id);
+
+                self.events.send(Event::Outbox(OutboxEvent::Sent {
+                    client_id: client_id.clone(),
+                    server_id: sent.id,
+                }));
+            },
+            Err(e) => {
+                let item = {
+                    let mut state = self.state.borrow_mut();
+
+                    // Discarded while the request was in flight
+                    let item = match state.outbox.iter_mut().find(|item| &item.client_id == client_id) {
+                        Some(item) => item,
+                        None => return,
+                    };
+
+                    error!("Sending message {} (attempt {}) -> {}", client_id, item.attempts + 1, e);
+                    item.record_failure(outbox::now_secs());
+                    item.clone()
+                };
+
+                self.save_outbox_item(&item);
+
+                let event = if item.state == DeliveryState::Failed {
+                    OutboxEvent::Failed { client_id: item.client_id.clone() }
+                } else {
+                    OutboxEvent::Retrying { client_id: item.client_id.clone(), attempts: item.attempts }
+                };
+                self.events.send(Event::Outbox(event));
+            },
+        };
+    }
+
+    fn save_outbox_item(&self, item: &OutboxItem) {
+        if let Some(ref cache) = self.state.borrow().cache {
+            cache.save_outbox_item(item).unwrap_or_else(|e| error!("Saving outbox -> {}", e));
+        }
+    }
+
+    fn remove_outbox_item(&self, client_id: &String) {
+        if let Some(ref cache) = self.state.borrow().cache {
+            cache.remove_outbox_item(client_id).unwrap_or_else(|e| error!("Saving outbox -> {}", e));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// What the backend core and the command line need from a chat service. Gitter's REST
+// API (api::MessageHandler) and the Matrix client-server API (matrix::MatrixHandler)
+// both answer in their own JSON, which each turns into the app's Room, Message and User.
+
+use curl::easy::Easy;
+use serde;
+use serde_json;
+use serde_json::Value;
+
+use api::{ApiError, Endpoints, MessageHandler, Protocol, Request};
+use matrix::MatrixHandler;
+use {Group, Message, Room, User};
+
+// A request and how to read its answer, or an answer known without asking
+pub enum Call<T> {
+    Request(Request, Box<Fn(Value) -> Result<T, ApiError>>),
+    Ready(T),
+}
+
+impl<T: 'static> Call<T> {
+    // Reads the answer as R, then turns it into T
+    pub fn new<R, F>(request: Request, read: F) -> Call<T>
+    where R: serde::de::DeserializeOwned, F: Fn(R) -> Result<T, ApiError> + 'static
+    {
+        Call::Request(request, Box::new(move |value| read(serde_json::from_value(value)?)))
+    }
+
+    // Blocks the calling thread until the answer arrives
+    pub fn perform(self) -> Result<T, ApiError> {
+        match self {
+            Call::Request(request, read) => read(request.perform::<Value>()?),
+            Call::Ready(value) => Ok(value),
+        }
+    }
+}
+
+impl<T: serde::de::DeserializeOwned + 'static> Call<T> {
+    // The answer is already in the app's shape
+    pub fn json(request: Request) -> Call<T> {
+        Call::new(request, |value: T| Ok(value))
+    }
+}
+
+// One account's view of its chat service; current_room_id is the room being read
+pub trait ChatBackend: Send {
+    fn current_room_id(&self) -> &String;
+    fn set_current_room_id(&mut self, id: String);
+    // Used when the token is changed in config.yaml during the session
+    fn set_token(&mut self, token: String);
+
+    // The same account reading another room
+    fn for_room(&self, room_id: &String) -> Box<ChatBackend>;
+
+    // The signed in user; an unknown token fails with HTTP 401
+    fn user_call(&self) -> Result<Call<User>, ApiError>;
+    fn rooms_call(&self) -> Result<Call<Vec<Room>>, ApiError>;
+    fn groups_call(&self) -> Result<Call<Vec<Group>>, ApiError>;
+
+    // The latest messages of the current room, oldest first
+    fn messages_call(&self) -> Result<Call<Vec<Message>>, ApiError>;
+    fn search_call(&self, query: &String) -> Result<Call<Vec<Message>>, ApiError>;
+    // A page of older history of the current room, oldest first, ending before `before_id`,
+    // or the newest page when None; an empty page means the room starts there
+    fn history_call(&self, before_id: Option<&String>) -> Result<Call<Vec<Message>>, ApiError>;
+    // History on both sides of a message, used when jumping to a search result
+    fn around_call(&self, message_id: &String) -> Result<Call<Vec<Message>>, ApiError>;
+    // Tells the server the user has seen these messages of the current room
+    fn mark_read_call(&self, user_id: &String, message_ids: Vec<String>) -> Result<Call<()>, ApiError>;
+
+    // The answer is the message, whose id confirms delivery once it is echoed back. `client_id`
+    // stays the same across retries of one message, so servers which take one deliver it once.
+    fn send_call(&self, room_id: &String, text: String, parent_id: Option<String>, client_id: &String) -> Result<Call<Message>, ApiError>;
+    fn update_call(&self, room_id: &String, id: &String, text: String, client_id: &String) -> Result<Call<Message>, ApiError>;
+
+    // Request which receives new messages of the current room; `on_message` is called
+    // from inside curl as each message arrives, or from stream_finished
+    fn stream_request(&self, on_message: Box<FnMut(Message) + Send>) -> Result<Easy, ApiError>;
+
+    // Whether the stream answers once per batch of messages and is meant to be reopened at once,
+    // rather than staying open until the connection drops
+    fn stream_is_long_poll(&self) -> bool {
+        false
+    }
+
+    // Called once a long poll answered in full, for a stream which reads its answer as a whole
+    fn stream_finished(&self) {}
+
+    // Fetches the signed in user with their rooms and groups
+    fn fetch_account(&self) -> Result<(User, Vec<Room>, Vec<Group>), ApiError> {
+        let user = self.user_call()?.perform()?;
+        let rooms = self.rooms_call()?.perform()?;
+
+        let groups = self.groups_call().and_then(|call| call.perform()).unwrap_or_else(|e| {
+            error!("Requesting groups -> {}", e);
+            vec![]
+        });
+
+        Ok((user, rooms, groups))
+    }
+}
+
+// The handler for the account's protocol
+pub fn connect(room_id: &String, token: &String, endpoints: &Endpoints) -> Box<ChatBackend> {
+    match endpoints.protocol {
+        Protocol::Gitter => Box::new(MessageHandler::with_endpoints(room_id, token, endpoints.clone())),
+        Protocol::Matrix => Box::new(MatrixHandler::new(room_id, token, &endpoints.api[..])),
+    }
+}
@@ -0,0 +1,466 @@
+// Command line flags, and the `rooms`, `send`, `tail` and `export` subcommands which use
+// the API without opening a window, so the binary can be used from scripts. Built with
+// the `tui` feature, the `tui` subcommand opens the terminal frontend instead.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, Error, ErrorKind, SubCommand};
+
+use accounts;
+use accounts::Account;
+use api;
+use api::ApiError;
+use chat;
+use chat::{Call, ChatBackend};
+use config;
+use config::Config;
+use export;
+use export::{DateRange, Format, History};
+use matrix;
+use outbox;
+#[cfg(feature = "tui")]
+use tui;
+use logging;
+use {Message, Room};
+
+// Name of the account whose token comes from --token-file; it is never saved
+pub const TOKEN_FILE_ACCOUNT: &'static str = "token-file";
+
+// Wait before `tail` reopens a dropped message stream
+const RECONNECT_SECS: u64 = 5;
+
+// Wait before `export` asks again after HTTP 429; Gitter's rate limit window is a minute
+const RATE_LIMITED_WAIT_SECS: u64 = 60;
+const MAX_RATE_LIMITED_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone)]
+pub enum CliCommand {
+    Rooms,
+    Send { room: String, text: String },
+    Tail { room: String },
+    // `output` is a file, or - for standard output
+    Export { room: String, output: String, format: Option<Format>, range: DateRange },
+    // Signs in to a Matrix homeserver with a password read from standard input
+    MatrixLogin { homeserver: String, user: String },
+    #[cfg(feature = "tui")]
+    Tui,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // A JSON Lines export or cache database opened read-only instead of the accounts
+    pub archive: Option<PathBuf>,
+    pub config_path: Option<PathBuf>,
+    pub token_file: Option<PathBuf>,
+    // Opened on launch instead of the first room in the sidebar
+    pub room: Option<String>,
+    pub api_base: Option<String>,
+    pub stream_base: Option<String>,
+    pub websocket_base: Option<String>,
+    pub verbose: bool,
+    // Runs instead of the GUI
+    pub command: Option<CliCommand>,
+}
+
+impl Options {
+    // Makes the flags which affect the whole app take effect
+    pub fn apply(&self) {
+        logging::init(self.verbose);
+
+        if let Some(ref path) = self.config_path {
+            config::set_path(path.clone());
+        }
+
+        // A self-hosted server usually serves the stream next to the API
+        if let Some(ref api_base) = self.api_base {
+            let stream_base = self.stream_base.clone().unwrap_or(api_base.clone());
+            let websocket_base = self.websocket_base.clone().unwrap_or(String::from(api::DEFAULT_WEBSOCKET_BASE));
+            api::set_override(api::Endpoints::new(&api_base[..], &stream_base[..], &websocket_base[..]));
+        }
+    }
+}
+
+// Exits with usage help on unknown or missing arguments
+pub fn parse() -> Options {
+    let room_arg = Arg::with_name("room")
+        .required(true)
+        .help("Room name such as gitterHQ/sandbox, its URL path or its id");
+
+    let app = App::new("gitter_gtk")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Desktop client for Gitter.im chat. Without a subcommand the window opens.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("PATH")
+            .help("Config file to use instead of $XDG_CONFIG_HOME/gitter_gtk/config.yaml"))
+        .arg(Arg::with_name("archive")
+            .long("archive")
+            .value_name("PATH")
+            .conflicts_with("token-file")
+            .help("Browse a JSON Lines export or a cache database read-only, without signing in"))
+        .arg(Arg::with_name("token-file")
+            .long("token-file")
+            .value_name("PATH")
+            .help("Sign in with the token in this file instead of the saved accounts"))
+        .arg(Arg::with_name("room")
+            .long("room")
+            .value_name("ROOM")
+            .help("Room to open on launch, by name, URL path or id"))
+        .arg(Arg::with_name("api-base")
+            .long("api-base")
+            .value_name("URL")
+            .help("Gitter API to use instead of https://api.gitter.im/v1, e.g. a test server"))
+        .arg(Arg::with_name("stream-base")
+            .long("stream-base")
+            .value_name("URL")
+            .requires("api-base")
+            .help("Message stream to use with --api-base; the API URL when not given"))
+        .arg(Arg::with_name("websocket-base")
+            .long("websocket-base")
+            .value_name("URL")
+            .requires("api-base")
+            .help("Realtime websocket to use with --api-base"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Log every request and other details, also to standard error"))
+        .subcommand(SubCommand::with_name("rooms")
+            .about("Lists your rooms as tab separated id, unread count, mention count and name"))
+        .subcommand(SubCommand::with_name("send")
+            .about("Sends a message to a room")
+            .arg(room_arg.clone())
+            .arg(Arg::with_name("text")
+                .required(true)
+                .help("Message text, or - to read it from standard input")))
+        .subcommand(SubCommand::with_name("tail")
+            .about("Prints the latest messages of a room, then new ones as they arrive")
+            .arg(room_arg.clone()))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes the history of a room to a file as Markdown, HTML or JSON Lines")
+            .arg(room_arg)
+            .arg(Arg::with_name("output")
+                .required(true)
+                .help("File to write, or - for standard output"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["markdown", "html", "jsonl"])
+                .help("Format of the file; taken from its extension when not given, otherwise Markdown"))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("DATE")
+                .validator(validate_date)
+                .help("First day to export, as YYYY-MM-DD in UTC"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("DATE")
+                .validator(validate_date)
+                .help("Last day to export, as YYYY-MM-DD in UTC")))
+        .subcommand(SubCommand::with_name("matrix-login")
+            .about("Adds an account on a Matrix homeserver, e.g. one bridged to Gitter; the password is read from standard input")
+            .arg(Arg::with_name("homeserver")
+                .required(true)
+                .help("Homeserver URL such as https://matrix.example.com"))
+            .arg(Arg::with_name("user")
+                .required(true)
+                .help("User name or full user id such as @alice:example.com")));
+
+    #[cfg(feature = "tui")]
+    let app = app.subcommand(SubCommand::with_name("tui")
+        .about("Opens the chat in the terminal; --room picks the first room"));
+
+    let matches = app.get_matches();
+
+    Options {
+        archive: matches.value_of("archive").map(PathBuf::from),
+        config_path: matches.value_of("config").map(PathBuf::from),
+        token_file: matches.value_of("token-file").map(PathBuf::from),
+        room: matches.value_of("room").map(String::from),
+        api_base: matches.value_of("api-base").map(String::from),
+        stream_base: matches.value_of("stream-base").map(String::from),
+        websocket_base: matches.value_of("websocket-base").map(String::from),
+        verbose: matches.is_present("verbose"),
+        command: command(&matches),
+    }
+}
+
+fn command(matches: &ArgMatches) -> Option<CliCommand> {
+    match matches.subcommand() {
+        ("rooms", _) => Some(CliCommand::Rooms),
+        ("send", Some(args)) => Some(CliCommand::Send {
+            room: String::from(args.value_of("room").unwrap_or_default()),
+            text: String::from(args.value_of("text").unwrap_or_default()),
+        }),
+        ("tail", Some(args)) => Some(CliCommand::Tail {
+            room: String::from(args.value_of("room").unwrap_or_default()),
+        }),
+        ("export", Some(args)) => Some(CliCommand::Export {
+            room: String::from(args.value_of("room").unwrap_or_default()),
+            output: String::from(args.value_of("output").unwrap_or_default()),
+            format: args.value_of("format").and_then(Format::from_name),
+            range: DateRange::new(args.value_of("from"), args.value_of("to")).unwrap_or_else(|e| {
+                Error::with_description(&e[..], ErrorKind::ValueValidation).exit()
+            }),
+        }),
+        ("matrix-login", Some(args)) => Some(CliCommand::MatrixLogin {
+            homeserver: String::from(args.value_of("homeserver").unwrap_or_default()),
+            user: String::from(args.value_of("user").unwrap_or_default()),
+        }),
+        #[cfg(feature = "tui")]
+        ("tui", _) => Some(CliCommand::Tui),
+        _ => None,
+    }
+}
+
+// The account of --token-file; the file holds nothing but the token
+pub fn token_file_account(path: &PathBuf) -> io::Result<Account> {
+    let mut token = String::new();
+    File::open(path)?.read_to_string(&mut token)?;
+
+    let token = String::from(token.trim());
+    if token.len() == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the token file is empty"));
+    }
+
+    Ok(Account {
+        name: String::from(TOKEN_FILE_ACCOUNT),
+        token: token,
+        endpoints: api::Endpoints::default().or_override(),
+    })
+}
+
+// Rooms are found by name ("gitterHQ/sandbox"), by URL path ("/gitterHQ/sandbox"), by Matrix alias
+// ("#gitterHQ_sandbox:gitter.im") or by id; a name differing only in case is accepted when nothing matches exactly
+pub fn find_room<'a>(rooms: &'a Vec<Room>, query: &str) -> Option<&'a Room> {
+    let query = query.trim().trim_left_matches('/');
+    let alias_path = format!("/{}", query);
+
+    rooms.iter()
+        .find(|room| {
+            room.id == query || room.name == query || room.url.trim_left_matches('/') == query ||
+                (query.starts_with('#') && room.url.ends_with(&alias_path[..]))
+        })
+        .or_else(|| rooms.iter().find(|room| room.name.to_lowercase() == query.to_lowercase()))
+}
+
+// Runs a subcommand and returns the exit code; errors go to standard error
+pub fn run(options: &Options, command: &CliCommand, config: &Config) -> i32 {
+    // Signs in rather than using an account
+    if let CliCommand::MatrixLogin { ref homeserver, ref user } = *command {
+        return match matrix_login(homeserver, user) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            },
+        };
+    }
+
+    let account = match options.token_file {
+        Some(ref path) => match token_file_account(path) {
+            Ok(account) => account,
+            Err(e) => {
+                eprintln!("Could not read the token from {} -> {}", path.display(), e);
+                return 1;
+            },
+        },
+        None => match accounts::load(config).into_iter().next() {
+            Some(account) => account,
+            None => {
+                eprintln!("No account is signed in. Sign in with the app first, or pass --token-file.");
+                return 1;
+            },
+        },
+    };
+
+    let handler = || chat::connect(&String::new(), &account.token, &account.endpoints);
+
+    let result = match *command {
+        CliCommand::Rooms => list_rooms(&handler()),
+        CliCommand::Send { ref room, ref text } => send(handler(), room, text),
+        CliCommand::Tail { ref room } => tail(handler(), room),
+        CliCommand::Export { ref room, ref output, format, ref range } => export_room(handler(), room, output, format, range),
+        CliCommand::MatrixLogin { .. } => Ok(()),
+        #[cfg(feature = "tui")]
+        CliCommand::Tui => tui::run(account.clone(), options.room.clone(), config),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn list_rooms(handler: &Box<ChatBackend>) -> Result<(), String> {
+    let rooms: Vec<Room> = perform(handler.rooms_call())?;
+
+    for room in rooms.iter() {
+        println!("{}\t{}\t{}\t{}", room.id, room.unreadItems, room.mentions, room.name);
+    }
+
+    Ok(())
+}
+
+fn send(mut handler: Box<ChatBackend>, room: &String, text: &String) -> Result<(), String> {
+    let room = resolve_room(&mut handler, room)?;
+
+    let text = if text == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map_err(|e| format!("Could not read standard input -> {}", e))?;
+        String::from(text.trim_right())
+    } else {
+        text.clone()
+    };
+
+    if text.len() == 0 {
+        return Err(String::from("Nothing to send, the message is empty"));
+    }
+
+    let sent: Message = perform(handler.send_call(&room.id, text, None, &outbox::new_client_id()))?;
+    println!("{}", sent.id);
+
+    Ok(())
+}
+
+// Prints until interrupted, reconnecting whenever the stream drops; only a rejected token ends it
+fn tail(mut handler: Box<ChatBackend>, room: &String) -> Result<(), String> {
+    resolve_room(&mut handler, room)?;
+
+    // Ids already printed, so messages are not repeated after reconnecting
+    let printed: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    loop {
+        let latest: Vec<Message> = perform(handler.messages_call())?;
+        for message in latest.iter() {
+            print_new_message(&printed, message);
+        }
+
+        loop {
+            let easy = {
+                let printed = printed.clone();
+                handler.stream_request(Box::new(move |message: Message| print_new_message(&printed, &message)))
+            };
+
+            let status = easy.and_then(|mut easy| {
+                easy.perform()?;
+                Ok(easy.response_code()?)
+            });
+
+            match status {
+                // A long poll answers once per batch and is asked again straight away
+                Ok(200...299) if handler.stream_is_long_poll() => continue,
+                Ok(200...299) => eprintln!("Message stream closed by the server, reconnecting"),
+                Ok(401) => return Err(describe(ApiError::Http(401))),
+                Ok(code) => eprintln!("Message stream failed with HTTP {}, reconnecting in {} seconds", code, RECONNECT_SECS),
+                Err(e) => eprintln!("Message stream failed -> {}, reconnecting in {} seconds", e, RECONNECT_SECS),
+            };
+
+            break;
+        }
+
+        thread::sleep(Duration::from_secs(RECONNECT_SECS));
+    }
+}
+
+// Pages back from the newest message, telling on standard error how far it got
+fn export_room(mut handler: Box<ChatBackend>, room: &String, output: &String, format: Option<Format>, range: &DateRange) -> Result<(), String> {
+    let room = resolve_room(&mut handler, room)?;
+    let format = format.or(Format::from_path(Path::new(output))).unwrap_or(Format::Markdown);
+
+    let mut history = History::new(range.clone());
+    let mut rate_limited = 0;
+
+    while !history.is_complete() {
+        let page = handler.history_call(history.before_id()).and_then(|call| call.perform());
+
+        match page {
+            Ok(page) => history.add_page(page),
+            Err(ApiError::Http(429)) if rate_limited + 1 < MAX_RATE_LIMITED_ATTEMPTS => {
+                rate_limited += 1;
+                eprintln!("Rate limited by the server, waiting {} seconds", RATE_LIMITED_WAIT_SECS);
+                thread::sleep(Duration::from_secs(RATE_LIMITED_WAIT_SECS));
+                continue;
+            },
+            Err(e) => return Err(describe(e)),
+        };
+
+        rate_limited = 0;
+        if let Some(reached) = history.reached() {
+            eprintln!("Fetched {} messages, back to {}", history.fetched(), reached);
+        }
+    }
+
+    let messages = history.into_messages();
+
+    let written = if output == "-" {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        export::write(format, &room.name[..], &messages[..], &mut out).and_then(|()| out.flush())
+    } else {
+        export::write_file(Path::new(output), format, &room.name[..], &messages[..])
+    };
+    written.map_err(|e| format!("Could not write {} -> {}", output, e))?;
+
+    eprintln!("Exported {} messages of {}", messages.len(), room.name);
+    Ok(())
+}
+
+fn validate_date(date: String) -> Result<(), String> {
+    export::parse_date(&date[..]).map(|_| ())
+}
+
+fn matrix_login(homeserver: &String, user: &String) -> Result<(), String> {
+    let mut password = String::new();
+    io::stdin().read_to_string(&mut password).map_err(|e| format!("Could not read the password from standard input -> {}", e))?;
+
+    let (user_id, token) = matrix::login(&homeserver[..], &user[..], password.trim_right_matches(|c: char| c == '\n' || c == '\r'))
+        .map_err(|e| match e {
+            ApiError::Http(401) => String::from("The homeserver rejected the user name or password"),
+            e => format!("Could not sign in to {} -> {}", homeserver, e),
+        })?;
+
+    accounts::save_matrix(&user_id[..], &token[..], &homeserver[..]);
+    println!("Signed in as {}", user_id);
+
+    Ok(())
+}
+
+fn print_new_message(printed: &Arc<Mutex<HashSet<String>>>, message: &Message) {
+    if printed.lock().unwrap().insert(message.id.clone()) {
+        println!("[{}] {}: {}", message.sent, message.fromUser.username, message.text);
+    }
+}
+
+// Makes the room the handler's current room
+fn resolve_room(handler: &mut Box<ChatBackend>, query: &String) -> Result<Room, String> {
+    let rooms: Vec<Room> = perform(handler.rooms_call())?;
+
+    match find_room(&rooms, &query[..]) {
+        Some(room) => {
+            handler.set_current_room_id(room.id.clone());
+            Ok(room.clone())
+        },
+        None => Err(format!("You are not in a room called {}; see the rooms subcommand", query)),
+    }
+}
+
+fn perform<T: 'static>(call: Result<Call<T>, ApiError>) -> Result<T, String> {
+    call.and_then(|call| call.perform()).map_err(describe)
+}
+
+fn describe(error: ApiError) -> String {
+    match error {
+        ApiError::Http(401) => String::from("The server rejected the token; sign in again"),
+        e => format!("Request to the server failed -> {}", e),
+    }
+}
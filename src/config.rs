@@ -0,0 +1,571 @@
+// Settings from $XDG_CONFIG_HOME/gitter_gtk/config.yaml, falling back to the legacy
+// $HOME/.gitter_gtk/config.yaml and ./config.yaml. Written back by the Preferences
+// dialog and whenever accounts are added or removed, always to the XDG location; the
+// tokens are then removed from the legacy file it was read from, if any.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use serde_yaml;
+use serde_yaml::Value;
+
+use api::Protocol;
+use logging;
+
+lazy_static! {
+    // Set by --config; replaces both the XDG and the legacy paths
+    static ref PATH_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+    // The file the config was last read from, which may be a legacy one
+    static ref LOADED_FROM: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+// Limits of the poll interval, in seconds
+pub const MIN_POLL_INTERVAL_SECS: u64 = 10;
+pub const MAX_POLL_INTERVAL_SECS: u64 = 3600;
+
+const PROXY_SCHEMES: &'static [&'static str] = &["http", "https", "socks4", "socks4a", "socks5", "socks5h"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    // Whatever the desktop prefers
+    System,
+    Light,
+    Dark,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::System
+    }
+}
+
+// An account signed in on this computer; the token is only here when there is no keyring.
+// The bases point an account at a self-hosted Gitter instead of gitter.im.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccountEntry {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Protocol::is_gitter")]
+    pub protocol: Protocol,
+    // Such as https://gitter.example.com/api/v1, or the homeserver of a Matrix account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    // The API base when unset, as self-hosted servers usually stream next to the API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_base: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_base: Option<String>,
+    // The server rejected the token; it is kept until signing in again replaces it
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub needs_sign_in: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub accounts: Vec<AccountEntry>,
+    // How often rooms and unread counts are refreshed, in seconds
+    pub poll_interval_secs: u64,
+    // Desktop notifications when someone mentions you
+    pub notifications: bool,
+    pub theme: Theme,
+    // Such as "info" or "warn,api=debug"; $GITTER_GTK_LOG and --verbose take precedence
+    pub log_level: String,
+    // Message texts and search queries in the log, which are hidden by default
+    pub log_message_contents: bool,
+    // Pango font description such as "Cantarell 11"; the desktop's font when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub font: Option<String>,
+    // Such as http://proxy.example.com:3128 or socks5h://localhost:1080; $https_proxy and friends when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    // Hosts reached without the proxy, e.g. localhost or .example.com
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+    // PEM certificates to trust besides the system's, such as a company's root CA
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<PathBuf>,
+    // OAuth application used for signing in with the browser
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth_client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth_client_secret: Option<String>,
+    // The single token of config files from before multiple accounts, moved into `accounts` once read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            accounts: vec![],
+            poll_interval_secs: 60,
+            notifications: true,
+            theme: Theme::System,
+            log_level: String::from("info"),
+            log_message_contents: false,
+            font: None,
+            proxy: None,
+            no_proxy: vec![],
+            ca_bundle: None,
+            oauth_client_id: None,
+            oauth_client_secret: None,
+            token: None,
+        }
+    }
+}
+
+impl Config {
+    // Checks what the types alone do not; the reason names the offending setting
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs < MIN_POLL_INTERVAL_SECS || self.poll_interval_secs > MAX_POLL_INTERVAL_SECS {
+            return Err(format!(
+                "poll_interval_secs must be between {} and {} seconds, not {}",
+                MIN_POLL_INTERVAL_SECS,
+                MAX_POLL_INTERVAL_SECS,
+                self.poll_interval_secs
+            ));
+        }
+
+        if let Some(ref font) = self.font {
+            if font.trim().len() == 0 {
+                return Err(String::from("font must not be empty; leave it out to use the desktop's font"));
+            }
+        }
+
+        if let Err(reason) = logging::Filter::parse(&self.log_level[..]) {
+            return Err(format!("log_level: {}", reason));
+        }
+
+        for (index, account) in self.accounts.iter().enumerate() {
+            // Account names are used in file names
+            if account.name.len() == 0 || account.name.contains('/') || account.name.starts_with('.') {
+                return Err(format!("accounts[{}].name \"{}\" is not a valid account name", index, account.name));
+            }
+
+            if self.accounts[..index].iter().any(|other| other.name == account.name) {
+                return Err(format!("accounts[{}].name \"{}\" is listed twice", index, account.name));
+            }
+
+            if account.protocol == Protocol::Matrix && account.api_base.is_none() {
+                return Err(format!("accounts[{}].api_base must be the homeserver of the Matrix account", index));
+            }
+
+            let bases = [
+                ("api_base", &account.api_base, &["http", "https"]),
+                ("stream_base", &account.stream_base, &["http", "https"]),
+                ("websocket_base", &account.websocket_base, &["ws", "wss"]),
+            ];
+            for &(field, base, schemes) in bases.iter() {
+                if let Some(ref base) = *base {
+                    let scheme = base.split("://").next().unwrap_or("");
+                    if !base.contains("://") || !schemes.contains(&scheme) {
+                        return Err(format!("accounts[{}].{} \"{}\" must start with {}://", index, field, base, schemes.join(":// or ")));
+                    }
+                }
+            }
+        }
+
+        if let Some(ref proxy) = self.proxy {
+            let scheme = proxy.split("://").next().unwrap_or("");
+            if !proxy.contains("://") || !PROXY_SCHEMES.contains(&scheme) {
+                return Err(format!("proxy \"{}\" must start with one of {}://", proxy, PROXY_SCHEMES.join("://, ")));
+            }
+        }
+
+        for (index, host) in self.no_proxy.iter().enumerate() {
+            if host.trim().len() == 0 || host.contains(',') {
+                return Err(format!("no_proxy[{}] \"{}\" must be a single host", index, host));
+            }
+        }
+
+        if let Some(ref ca_bundle) = self.ca_bundle {
+            if !ca_bundle.is_file() {
+                return Err(format!("ca_bundle {} is not a file", ca_bundle.display()));
+            }
+        }
+
+        if self.oauth_client_id.is_some() != self.oauth_client_secret.is_some() {
+            return Err(String::from("oauth_client_id and oauth_client_secret must be set together"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, serde_yaml::Error),
+    Invalid(PathBuf, String),
+    // Neither $XDG_CONFIG_HOME nor $HOME is set
+    NoConfigDir,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(f, "could not access {}: {}", path.display(), e),
+            ConfigError::Parse(ref path, ref e) => write!(f, "{} is not a valid config file: {}", path.display(), e),
+            ConfigError::Invalid(ref path, ref reason) => write!(f, "{}: {}", path.display(), reason),
+            ConfigError::NoConfigDir => write!(f, "neither $XDG_CONFIG_HOME nor $HOME is set"),
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+pub fn set_path(path: PathBuf) {
+    *PATH_OVERRIDE.write().unwrap() = Some(path);
+}
+
+// $XDG_CONFIG_HOME/gitter_gtk/config.yaml, where the config is written
+pub fn path() -> Option<PathBuf> {
+    if let Some(ref path) = *PATH_OVERRIDE.read().unwrap() {
+        return Some(path.clone());
+    }
+
+    let config_home = match ::std::env::var("XDG_CONFIG_HOME") {
+        Ok(ref val) if val.len() > 0 => PathBuf::from(val),
+        _ => match ::std::env::var("HOME") {
+            Ok(val) => PathBuf::from(val).join(".config"),
+            Err(_) => return None,
+        },
+    };
+
+    Some(config_home.join("gitter_gtk").join("config.yaml"))
+}
+
+// The directory of config.yaml, which holds the app's other settings files too
+pub fn dir() -> Option<PathBuf> {
+    path().and_then(|path| path.parent().map(|dir| dir.to_path_buf()))
+}
+
+// Read while the XDG file does not exist yet; the next write moves the config to the XDG location
+fn legacy_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    if PATH_OVERRIDE.read().unwrap().is_some() {
+        return paths;
+    }
+
+    if let Ok(home) = ::std::env::var("HOME") {
+        paths.push(PathBuf::from(home).join(".gitter_gtk").join("config.yaml"));
+    }
+    paths.push(PathBuf::from("./config.yaml"));
+
+    paths
+}
+
+// The file the config is read from, if any exists
+pub fn existing_path() -> Option<PathBuf> {
+    path().into_iter().chain(legacy_paths()).find(|path| path.exists())
+}
+
+// A missing file gives the defaults; an unreadable or invalid one is an error, never a panic
+pub fn load() -> Result<Config, ConfigError> {
+    let path = match existing_path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+
+    debug!("Reading config from {}", path.display());
+    *LOADED_FROM.write().unwrap() = Some(path.clone());
+
+    parse(&path, &read(&path)?[..])
+}
+
+// For a file changed while the app runs. An empty or missing file is more likely one being
+// written or replaced than the settings wanted, so it is an error rather than the defaults.
+pub fn reload() -> Result<Config, ConfigError> {
+    let path = match existing_path() {
+        Some(path) => path,
+        None => {
+            let path = path().ok_or(ConfigError::NoConfigDir)?;
+            return Err(ConfigError::Invalid(path, String::from("the file is missing")));
+        },
+    };
+
+    let text = read(&path)?;
+    if text.trim().len() == 0 {
+        return Err(ConfigError::Invalid(path, String::from("the file is empty")));
+    }
+
+    *LOADED_FROM.write().unwrap() = Some(path.clone());
+
+    parse(&path, &text[..])
+}
+
+fn read(path: &PathBuf) -> Result<String, ConfigError> {
+    let mut buffer = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .map_err(|e| ConfigError::Io(path.clone(), e))?;
+
+    Ok(buffer)
+}
+
+pub fn parse(path: &PathBuf, text: &str) -> Result<Config, ConfigError> {
+    // An empty file, or one with only comments, holds no settings
+    let is_empty = text.lines().all(|line| {
+        let line = line.trim();
+        line.len() == 0 || line.starts_with('#')
+    });
+    if is_empty {
+        return Ok(Config::default());
+    }
+
+    let config: Config = serde_yaml::from_str(text).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+    config.validate().map_err(|reason| ConfigError::Invalid(path.clone(), reason))?;
+
+    Ok(config)
+}
+
+// The file may hold tokens, so only the user may read it
+pub fn save(config: &Config) -> Result<(), ConfigError> {
+    let path = path().ok_or(ConfigError::NoConfigDir)?;
+    config.validate().map_err(|reason| ConfigError::Invalid(path.clone(), reason))?;
+
+    let text = serde_yaml::to_string(config).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| ConfigError::Io(parent.to_path_buf(), e))?;
+    }
+
+    replace_file(&path, &(text + "\n")[..], 0o600).map_err(|e| ConfigError::Io(path.clone(), e))?;
+
+    // The tokens read from a legacy file are now in the keyring or the file just written. Only the
+    // file the config came from is touched: ./config.yaml may belong to whatever project the app
+    // was started in.
+    let loaded_from = LOADED_FROM.read().unwrap().clone();
+    if let Some(legacy) = loaded_from.into_iter().find(|legacy| legacy != &path && legacy_paths().contains(legacy)) {
+        match strip_tokens(&legacy) {
+            Ok(true) => info!("Removed the plaintext tokens from {}", legacy.display()),
+            Ok(false) => (),
+            Err(e) => error!("Removing the plaintext tokens from a legacy config -> {}", e),
+        };
+    }
+
+    Ok(())
+}
+
+// Removes `token` and `accounts[].token` from the file, keeping the other settings and its
+// permissions. Returns whether there were any.
+pub fn strip_tokens(path: &PathBuf) -> Result<bool, ConfigError> {
+    let buffer = read(path)?;
+
+    let mut doc: Value = match serde_yaml::from_str(&buffer[..]) {
+        Ok(doc) => doc,
+        // Empty, or only comments
+        Err(_) if parse(path, &buffer[..]).is_ok() => return Ok(false),
+        Err(e) => return Err(ConfigError::Parse(path.clone(), e)),
+    };
+
+    if !remove_tokens(&mut doc) {
+        return Ok(false);
+    }
+
+    let text = serde_yaml::to_string(&doc).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+
+    fs::metadata(path)
+        .and_then(|metadata| replace_file(path, &(text + "\n")[..], metadata.permissions().mode() & 0o777))
+        .map_err(|e| ConfigError::Io(path.clone(), e))?;
+
+    Ok(true)
+}
+
+// Written next to the file and renamed over it, so the watcher never reads it half written
+fn replace_file(path: &PathBuf, text: &str, mode: u32) -> io::Result<()> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(String::from("config.yaml"));
+    let temp = path.with_file_name(format!(".{}.tmp", name));
+
+    // Left over from a crash, possibly with other permissions
+    fs::remove_file(&temp).unwrap_or(());
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut file| file.write_all(text.as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path))
+}
+
+fn remove_tokens(doc: &mut Value) -> bool {
+    let token = Value::String(String::from("token"));
+    let accounts = Value::String(String::from("accounts"));
+    let mut removed = false;
+
+    if let Value::Mapping(ref mut settings) = *doc {
+        removed |= settings.remove(&token).is_some();
+
+        if let Some(&mut Value::Sequence(ref mut entries)) = settings.get_mut(&accounts) {
+            for entry in entries.iter_mut() {
+                if let Value::Mapping(ref mut entry) = *entry {
+                    removed |= entry.remove(&token).is_some();
+                }
+            }
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+    use rand::Rng;
+
+    use super::*;
+
+    fn parse_str(text: &str) -> Result<Config, ConfigError> {
+        parse(&PathBuf::from("config.yaml"), text)
+    }
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        assert_eq!(parse_str("").unwrap(), Config::default());
+        assert_eq!(parse_str("# only a comment\n\n").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn missing_settings_take_defaults() {
+        let config = parse_str("theme: dark\naccounts:\n  - name: work\n").unwrap();
+
+        assert_eq!(config.theme, Theme::Dark);
+        assert_eq!(config.accounts, vec![AccountEntry { name: String::from("work"), ..AccountEntry::default() }]);
+        assert_eq!(config.poll_interval_secs, Config::default().poll_interval_secs);
+        assert!(config.notifications);
+    }
+
+    #[test]
+    fn legacy_token_is_read() {
+        let config = parse_str("token: \"abc\"\n").unwrap();
+
+        assert_eq!(config.token, Some(String::from("abc")));
+        assert!(config.accounts.is_empty());
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_the_setting() {
+        let error = parse_str("pol_interval_secs: 30\n").unwrap_err().to_string();
+        assert!(error.contains("pol_interval_secs"), "{}", error);
+
+        let error = parse_str("theme: blue\n").unwrap_err().to_string();
+        assert!(error.contains("blue"), "{}", error);
+
+        let error = parse_str("poll_interval_secs: 1\n").unwrap_err().to_string();
+        assert!(error.contains("poll_interval_secs must be between 10 and 3600"), "{}", error);
+
+        let error = parse_str("log_level: \"info,api=loud\"\n").unwrap_err().to_string();
+        assert!(error.contains("log_level"), "{}", error);
+
+        let error = parse_str("accounts:\n  - name: work\n  - name: work\n").unwrap_err().to_string();
+        assert!(error.contains("listed twice"), "{}", error);
+
+        let error = parse_str("accounts:\n  - name: team\n    websocket_base: https://gitter.example.com/faye\n").unwrap_err().to_string();
+        assert!(error.contains("accounts[0].websocket_base"), "{}", error);
+
+        let error = parse_str("accounts:\n  - name: \"@tester:example.com\"\n    protocol: matrix\n").unwrap_err().to_string();
+        assert!(error.contains("homeserver"), "{}", error);
+
+        let error = parse_str("proxy: proxy.example.com:3128\n").unwrap_err().to_string();
+        assert!(error.contains("must start with one of http://"), "{}", error);
+
+        let error = parse_str("ca_bundle: /nonexistent/ca.pem\n").unwrap_err().to_string();
+        assert!(error.contains("ca_bundle"), "{}", error);
+
+        let error = parse_str("oauth_client_id: abc\n").unwrap_err().to_string();
+        assert!(error.contains("oauth_client_secret"), "{}", error);
+    }
+
+    #[test]
+    fn written_config_reads_back() {
+        let config = Config {
+            accounts: vec![
+                AccountEntry { name: String::from("work"), token: Some(String::from("secret")), ..AccountEntry::default() },
+                AccountEntry { name: String::from("expired"), needs_sign_in: true, ..AccountEntry::default() },
+                AccountEntry {
+                    name: String::from("team"),
+                    api_base: Some(String::from("https://gitter.example.com/api/v1")),
+                    websocket_base: Some(String::from("wss://gitter.example.com/faye")),
+                    ..AccountEntry::default()
+                },
+                AccountEntry {
+                    name: String::from("@tester:example.com"),
+                    protocol: Protocol::Matrix,
+                    api_base: Some(String::from("https://matrix.example.com")),
+                    ..AccountEntry::default()
+                },
+            ],
+            poll_interval_secs: 120,
+            notifications: false,
+            theme: Theme::Light,
+            font: Some(String::from("Cantarell 11")),
+            proxy: Some(String::from("socks5h://localhost:1080")),
+            no_proxy: vec![String::from("localhost"), String::from(".example.com")],
+            ..Config::default()
+        };
+
+        let text = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(parse_str(&text[..]).unwrap(), config);
+    }
+
+    #[test]
+    fn files_are_replaced_whole_with_their_permissions() {
+        let dir = ::std::env::temp_dir().join(format!("gitter_gtk_config_test_{}", rand::thread_rng().gen::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("work.yml");
+
+        replace_file(&path, "theme: dark\n", 0o600).unwrap();
+        replace_file(&path, "theme: light\n", 0o600).unwrap();
+
+        assert_eq!(read(&path).unwrap(), "theme: light\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // Nothing is left next to it
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tokens_are_stripped_from_a_legacy_file() {
+        let dir = ::std::env::temp_dir().join(format!("gitter_gtk_config_test_{}", rand::thread_rng().gen::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+
+        let legacy = "token: \"legacy\"\ntheme: dark\naccounts:\n  - name: work\n    token: \"secret\"\n    api_base: https://gitter.example.com/api/v1\n";
+        fs::File::create(&path).and_then(|mut file| file.write_all(legacy.as_bytes())).unwrap();
+
+        assert!(strip_tokens(&path).unwrap());
+
+        let mut text = String::new();
+        fs::File::open(&path).and_then(|mut file| file.read_to_string(&mut text)).unwrap();
+        assert!(!text.contains("legacy") && !text.contains("secret"), "{}", text);
+
+        let config = parse(&path, &text[..]).unwrap();
+        assert_eq!(config.token, None);
+        assert_eq!(config.theme, Theme::Dark);
+        assert_eq!(config.accounts, vec![AccountEntry {
+            name: String::from("work"),
+            api_base: Some(String::from("https://gitter.example.com/api/v1")),
+            ..AccountEntry::default()
+        }]);
+
+        // Nothing left to remove, and a file with only comments is left alone
+        assert!(!strip_tokens(&path).unwrap());
+        fs::File::create(&path).and_then(|mut file| file.write_all(b"# nothing here\n")).unwrap();
+        assert!(!strip_tokens(&path).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
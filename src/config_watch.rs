@@ -0,0 +1,109 @@
+// Watches config.yaml with inotify and tells the GTK thread whenever it changed.
+// Editors often save by writing a new file and renaming it over the old one, so
+// the directories are watched rather than the file itself.
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use glib;
+use notify;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use config;
+
+// Editors write in several steps; wait for the last one before reloading
+const DEBOUNCE_MILLIS: u64 = 500;
+
+thread_local!(
+    static HANDLER: RefCell<Option<(mpsc::Receiver<()>, Box<Fn()>)>> = RefCell::new(None)
+);
+
+// Must be called on the GTK thread; `on_change` runs there after every change
+pub fn watch<F>(on_change: F)
+where F: Fn() + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+
+    HANDLER.with(move |cell| {
+        *cell.borrow_mut() = Some((receiver, Box::new(on_change)));
+    });
+
+    // The XDG directory is created, so the file is noticed when it is first written
+    let paths: Vec<PathBuf> = config::path().into_iter().chain(config::existing_path()).collect();
+
+    let mut directories: Vec<PathBuf> = paths.iter()
+        .filter_map(|path| path.parent().map(|parent| parent.to_path_buf()))
+        .collect();
+    directories.dedup();
+
+    // Not always config.yaml, as --config may name any file
+    let mut names: Vec<OsString> = paths.iter()
+        .filter_map(|path| path.file_name().map(|name| name.to_os_string()))
+        .collect();
+    names.dedup();
+
+    thread::spawn(move || {
+        let (event_sender, events) = mpsc::channel();
+
+        let mut watcher = match notify::watcher(event_sender, Duration::from_millis(DEBOUNCE_MILLIS)) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!("Watching config.yaml, changes apply after a restart -> {}", e);
+                return;
+            },
+        };
+
+        for directory in directories.iter() {
+            fs::create_dir_all(directory).unwrap_or(());
+
+            if let Err(e) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+                error!("Watching {} -> {}", directory.display(), e);
+            }
+        }
+
+        for event in events.iter() {
+            let changed = match event {
+                DebouncedEvent::Create(ref path) |
+                DebouncedEvent::Write(ref path) |
+                DebouncedEvent::Chmod(ref path) |
+                DebouncedEvent::Remove(ref path) => is_config(path, &names),
+                DebouncedEvent::Rename(ref from, ref to) => is_config(from, &names) || is_config(to, &names),
+                DebouncedEvent::Error(e, _) => {
+                    error!("Watching config.yaml -> {}", e);
+                    false
+                },
+                _ => false,
+            };
+
+            if changed {
+                if sender.send(()).is_err() {
+                    return;
+                }
+
+                glib::idle_add(dispatch);
+            }
+        }
+    });
+}
+
+fn is_config(path: &PathBuf, names: &Vec<OsString>) -> bool {
+    path.file_name().map(|name| names.iter().any(|config_name| config_name == name)).unwrap_or(false)
+}
+
+// Runs on the GTK thread; several changes waiting at once are handled by one reload
+fn dispatch() -> glib::Continue {
+    HANDLER.with(|cell| {
+        if let Some((ref receiver, ref on_change)) = *cell.borrow() {
+            if receiver.try_iter().count() > 0 {
+                on_change();
+            }
+        }
+    });
+
+    glib::Continue(false)
+}
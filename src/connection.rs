@@ -0,0 +1,184 @@
+// Connection state tracked by the backend core, shown in the headerbar and infobar
+
+use dbus;
+
+use api::ApiError;
+use backend::{Command, CommandSender};
+
+// Consecutive failed requests before the app is considered offline rather than degraded
+const OFFLINE_AFTER_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Online,
+    // Some requests fail or the server is struggling (5xx, rate limited)
+    Degraded,
+    Offline,
+    // The token was rejected; retrying will not help until it changes
+    AuthFailed,
+}
+
+impl ConnectionState {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ConnectionState::Connecting => "Connecting…",
+            ConnectionState::Online => "Online",
+            ConnectionState::Degraded => "Connection problems",
+            ConnectionState::Offline => "Offline",
+            ConnectionState::AuthFailed => "Sign-in failed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionMonitor {
+    state: ConnectionState,
+    consecutive_failures: u32,
+    // Known only when NetworkManager is reachable over D-Bus
+    network_available: Option<bool>,
+}
+
+impl ConnectionMonitor {
+    pub fn new() -> ConnectionMonitor {
+        ConnectionMonitor {
+            state: ConnectionState::Connecting,
+            consecutive_failures: 0,
+            network_available: None,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn report_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.state = ConnectionState::Online;
+    }
+
+    pub fn report_failure(&mut self, error: &ApiError) {
+        match *error {
+            ApiError::Http(401) => {
+                self.state = ConnectionState::AuthFailed;
+                return;
+            },
+            // The server answered, so the network itself is fine
+            ApiError::Http(_) | ApiError::Json(_) => {
+                if self.state != ConnectionState::AuthFailed {
+                    self.state = ConnectionState::Degraded;
+                }
+                return;
+            },
+            ApiError::Network(_) => (),
+        };
+
+        self.consecutive_failures += 1;
+
+        if self.network_available == Some(false) || self.consecutive_failures >= OFFLINE_AFTER_FAILURES {
+            self.state = ConnectionState::Offline;
+        } else if self.state != ConnectionState::AuthFailed {
+            self.state = ConnectionState::Degraded;
+        }
+    }
+
+    pub fn report<T>(&mut self, result: &Result<T, ApiError>) {
+        match *result {
+            Ok(_) => self.report_success(),
+            Err(ref e) => self.report_failure(e),
+        };
+    }
+
+    // Returns true when the network came back, so the caller can reconnect right away
+    pub fn set_network_available(&mut self, available: bool) -> bool {
+        let was_available = self.network_available;
+        self.network_available = Some(available);
+
+        if !available {
+            self.state = ConnectionState::Offline;
+        } else if was_available == Some(false) {
+            self.request_retry();
+            return true;
+        }
+
+        false
+    }
+
+    pub fn request_retry(&mut self) {
+        self.consecutive_failures = 0;
+        self.state = ConnectionState::Connecting;
+    }
+}
+
+// NetworkManager states, see NMState in the NetworkManager D-Bus API
+const NM_STATE_DISCONNECTED: u32 = 20;
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+// Returns false once the backend core has stopped
+fn apply_network_manager_state(commands: &CommandSender, nm_state: u32) -> bool {
+    let available = if nm_state <= NM_STATE_DISCONNECTED {
+        false
+    } else if nm_state >= NM_STATE_CONNECTED_GLOBAL {
+        true
+    } else {
+        return true;
+    };
+
+    commands.unbounded_send(Command::NetworkAvailable(available)).is_ok()
+}
+
+// Follows NetworkManager over the system bus and tells the backend core; does nothing when it is not available.
+// dbus 0.6 only offers blocking calls, so this keeps its own thread.
+pub fn network_manager_thread(commands: CommandSender) {
+    ::std::thread::spawn(move || {
+        let bus = match dbus::Connection::get_private(dbus::BusType::System) {
+            Ok(bus) => bus,
+            Err(e) => {
+                info!("NetworkManager not available, system bus error -> {}", e);
+                return;
+            },
+        };
+
+        let request = dbus::Message::new_method_call(
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.DBus.Properties",
+            "Get"
+        ).unwrap().append2("org.freedesktop.NetworkManager", "State");
+
+        match bus.send_with_reply_and_block(request, 2000) {
+            Ok(reply) => {
+                if let Some(state) = reply.get1::<dbus::arg::Variant<u32>>() {
+                    if !apply_network_manager_state(&commands, state.0) {
+                        return;
+                    }
+                }
+            },
+            Err(_) => {
+                info!("NetworkManager not running, connectivity will not be tracked");
+                return;
+            },
+        };
+
+        let rule = "type='signal',interface='org.freedesktop.NetworkManager',member='StateChanged'";
+        if let Err(e) = bus.add_match(rule) {
+            error!("Watching NetworkManager -> {}", e);
+            return;
+        }
+
+        loop {
+            for message in bus.incoming(1000) {
+                let is_state_change = message.member().map(|member| &*member == "StateChanged").unwrap_or(false);
+
+                if is_state_change {
+                    if let Some(state) = message.get1::<u32>() {
+                        // The backend core stopped, e.g. after signing out
+                        if !apply_network_manager_state(&commands, state) {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,494 @@
+// Writes the history of a room to a file for archiving: Markdown, a standalone HTML
+// page or one JSON message per line. The history is fetched a page at a time going
+// back from the newest message, by the backend core for "Export Room…" and directly
+// by `gitter_gtk export`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+    JsonLines,
+}
+
+// (format, name, label) for the command line and the export dialog
+pub const FORMATS: &'static [(Format, &'static str, &'static str)] = &[
+    (Format::Markdown, "markdown", "Markdown"),
+    (Format::Html, "html", "HTML page"),
+    (Format::JsonLines, "jsonl", "JSON Lines"),
+];
+
+impl Format {
+    // Also accepts the usual file extensions
+    pub fn from_name(name: &str) -> Option<Format> {
+        match &name.to_lowercase()[..] {
+            "markdown" | "md" => Some(Format::Markdown),
+            "html" | "htm" => Some(Format::Html),
+            "jsonl" | "json" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension().and_then(|extension| extension.to_str()).and_then(Format::from_name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Markdown => "markdown",
+            Format::Html => "html",
+            Format::JsonLines => "jsonl",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+// Days as YYYY-MM-DD in UTC, both ends included; None is open ended
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DateRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl DateRange {
+    pub fn new(from: Option<&str>, to: Option<&str>) -> Result<DateRange, String> {
+        let from = match from {
+            Some(from) => Some(parse_date(from)?),
+            None => None,
+        };
+        let to = match to {
+            Some(to) => Some(parse_date(to)?),
+            None => None,
+        };
+
+        if let (&Some(ref from), &Some(ref to)) = (&from, &to) {
+            if from > to {
+                return Err(format!("The range starts on {}, after it ends on {}", from, to));
+            }
+        }
+
+        Ok(DateRange { from: from, to: to })
+    }
+
+    // `sent` is a timestamp such as 2017-11-16T10:00:00.000Z, whose date sorts like the text
+    pub fn contains(&self, sent: &str) -> bool {
+        let date = day(sent);
+
+        self.from.as_ref().map(|from| date >= &from[..]).unwrap_or(true)
+            && self.to.as_ref().map(|to| date <= &to[..]).unwrap_or(true)
+    }
+
+    fn starts_after(&self, sent: &str) -> bool {
+        self.from.as_ref().map(|from| day(sent) < &from[..]).unwrap_or(false)
+    }
+}
+
+// Accepts YYYY-MM-DD, blanks around it ignored
+pub fn parse_date(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let parts: Vec<&str> = text.split('-').collect();
+
+    let number = |index: usize, digits: usize, max: u32| {
+        match parts.get(index) {
+            Some(part) if part.len() == digits && part.chars().all(|c| c.is_digit(10)) => {
+                part.parse::<u32>().map(|value| value >= 1 && value <= max).unwrap_or(false)
+            },
+            _ => false,
+        }
+    };
+    let valid = parts.len() == 3 && number(0, 4, 9999) && number(1, 2, 12) && number(2, 2, 31);
+
+    if valid {
+        Ok(String::from(text))
+    } else {
+        Err(format!("{} is not a date like 2017-11-16", text))
+    }
+}
+
+// What to export and where; sent to the backend core by the export dialog
+#[derive(Debug, Clone)]
+pub struct ExportRequest {
+    pub room_id: String,
+    pub room_name: String,
+    pub path: PathBuf,
+    pub format: Format,
+    pub range: DateRange,
+}
+
+// Reports of an export running in the backend core
+#[derive(Debug, Clone)]
+pub enum ExportEvent {
+    // `reached` is the date of the oldest message fetched so far
+    Progress { fetched: usize, reached: Option<String> },
+    Finished { path: PathBuf, written: usize },
+    Failed(String),
+}
+
+// Pages of history collected newest first, until the start of the room or of the date range
+pub struct History {
+    range: DateRange,
+    pages: Vec<Vec<Message>>,
+    before_id: Option<String>,
+    complete: bool,
+}
+
+impl History {
+    pub fn new(range: DateRange) -> History {
+        History {
+            range: range,
+            pages: vec![],
+            before_id: None,
+            complete: false,
+        }
+    }
+
+    // The next page ends before this message; None asks for the newest messages
+    pub fn before_id(&self) -> Option<&String> {
+        self.before_id.as_ref()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn fetched(&self) -> usize {
+        self.pages.iter().map(|page| page.len()).sum()
+    }
+
+    pub fn reached(&self) -> Option<String> {
+        self.pages.last().and_then(|page| page.first()).map(|message| String::from(day(&message.sent[..])))
+    }
+
+    // `page` is oldest first, like every list of messages the API answers with
+    pub fn add_page(&mut self, page: Vec<Message>) {
+        let oldest = match page.first() {
+            Some(oldest) => oldest.clone(),
+            None => {
+                self.complete = true;
+                return;
+            },
+        };
+
+        // A server which ignores the cursor would answer with the same page forever
+        if self.before_id.as_ref() == Some(&oldest.id) {
+            self.complete = true;
+            return;
+        }
+
+        self.complete = self.range.starts_after(&oldest.sent[..]);
+        self.before_id = Some(oldest.id);
+        self.pages.push(page);
+    }
+
+    // Oldest first, within the range; an edit replaces the text of the message it edits
+    pub fn into_messages(self) -> Vec<Message> {
+        let mut messages: Vec<Message> = vec![];
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let range = self.range;
+
+        for message in self.pages.into_iter().rev().flat_map(|page| page.into_iter()) {
+            if !range.contains(&message.sent[..]) {
+                continue;
+            }
+
+            let position = positions.get(&message.id).cloned();
+            match position {
+                Some(position) => if message.v >= messages[position].v {
+                    messages[position] = message;
+                },
+                None => {
+                    positions.insert(message.id.clone(), messages.len());
+                    messages.push(message);
+                },
+            };
+        }
+
+        messages
+    }
+}
+
+pub fn write_file(path: &Path, format: Format, room_name: &str, messages: &[Message]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write(format, room_name, messages, &mut out)?;
+    out.flush()
+}
+
+pub fn write<W: Write>(format: Format, room_name: &str, messages: &[Message], out: &mut W) -> io::Result<()> {
+    match format {
+        Format::Markdown => write_markdown(room_name, messages, out),
+        Format::Html => write_html(room_name, messages, out),
+        Format::JsonLines => write_json_lines(messages, out),
+    }
+}
+
+// Message texts are Markdown already, so they go in as they are
+fn write_markdown<W: Write>(room_name: &str, messages: &[Message], out: &mut W) -> io::Result<()> {
+    write!(out, "# {}\n\nTimes are in UTC.\n", room_name)?;
+
+    let mut last_day = "";
+    for message in messages.iter() {
+        if day(&message.sent[..]) != last_day {
+            last_day = day(&message.sent[..]);
+            write!(out, "\n## {}\n", last_day)?;
+        }
+
+        write!(
+            out,
+            "\n**{}** (@{}) {}\n\n{}\n",
+            message.fromUser.displayName,
+            message.fromUser.username,
+            time(&message.sent[..]),
+            message.text.trim_right()
+        )?;
+    }
+
+    Ok(())
+}
+
+// The plain text is used, escaped, since the html of a message is whatever the server sent
+fn write_html<W: Write>(room_name: &str, messages: &[Message], out: &mut W) -> io::Result<()> {
+    write!(out, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n", escape_html(room_name))?;
+    write!(out, "<style>\n{}</style>\n</head>\n<body>\n", HTML_STYLE)?;
+    write!(out, "<h1>{}</h1>\n<p class=\"note\">Times are in UTC.</p>\n", escape_html(room_name))?;
+
+    let mut last_day = "";
+    for message in messages.iter() {
+        if day(&message.sent[..]) != last_day {
+            last_day = day(&message.sent[..]);
+            write!(out, "<h2>{}</h2>\n", escape_html(last_day))?;
+        }
+
+        write!(
+            out,
+            "<div class=\"message\" id=\"{}\"><span class=\"from\" title=\"@{}\">{}</span><time datetime=\"{}\">{}</time><div class=\"text\">{}</div></div>\n",
+            escape_html(&message.id[..]),
+            escape_html(&message.fromUser.username[..]),
+            escape_html(&message.fromUser.displayName[..]),
+            escape_html(&message.sent[..]),
+            escape_html(time(&message.sent[..])),
+            escape_html(message.text.trim_right())
+        )?;
+    }
+
+    write!(out, "</body>\n</html>\n")
+}
+
+const HTML_STYLE: &'static str = "body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; }
+h2 { font-size: 1em; border-bottom: 1px solid #ccc; margin-top: 2em; }
+.note, time { color: #777; }
+.message { margin: 0.8em 0; }
+.from { font-weight: bold; margin-right: 0.5em; }
+.text { white-space: pre-wrap; }
+";
+
+// Each message as the API sent it, so the file can be read back by other tools
+fn write_json_lines<W: Write>(messages: &[Message], out: &mut W) -> io::Result<()> {
+    for message in messages.iter() {
+        let line = serde_json::to_string(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write!(out, "{}\n", line)?;
+    }
+
+    Ok(())
+}
+
+fn day(sent: &str) -> &str {
+    sent.get(..10).unwrap_or(sent)
+}
+
+// Hours and minutes of a timestamp such as 2017-11-16T10:00:00.000Z
+fn time(sent: &str) -> &str {
+    sent.get(11..16).unwrap_or("")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fixtures;
+    use tests::fixtures::user;
+
+    // Everything is written by Alice
+    fn message(id: &str, sent: &str, text: &str) -> Message {
+        let mut alice = user("u2", "alice");
+        alice.displayName = String::from("Alice");
+
+        fixtures::message(id, &alice, sent, text)
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| &message.id[..]).collect()
+    }
+
+    fn written(format: Format, messages: &[Message]) -> String {
+        let mut out = vec![];
+        write(format, "org/room", messages, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn formats_are_found_by_name_and_extension() {
+        assert_eq!(Format::from_name("Markdown"), Some(Format::Markdown));
+        assert_eq!(Format::from_name("jsonl"), Some(Format::JsonLines));
+        assert_eq!(Format::from_name("pdf"), None);
+
+        assert_eq!(Format::from_path(Path::new("/tmp/room.html")), Some(Format::Html));
+        assert_eq!(Format::from_path(Path::new("/tmp/room")), None);
+
+        for &(format, name, _) in FORMATS.iter() {
+            assert_eq!(Format::from_name(name), Some(format));
+            assert_eq!(Format::from_name(format.extension()), Some(format));
+        }
+    }
+
+    #[test]
+    fn dates_must_be_valid_and_in_order() {
+        assert_eq!(parse_date(" 2017-11-16 "), Ok(String::from("2017-11-16")));
+        assert!(parse_date("2017-13-01").is_err());
+        assert!(parse_date("16.11.2017").is_err());
+        assert!(parse_date("2017-1-16").is_err());
+
+        assert!(DateRange::new(Some("2017-11-16"), Some("2017-11-15")).is_err());
+
+        let range = DateRange::new(Some("2017-11-15"), Some("2017-11-16")).unwrap();
+        assert!(range.contains("2017-11-15T00:00:00.000Z"));
+        assert!(range.contains("2017-11-16T23:59:59.999Z"));
+        assert!(!range.contains("2017-11-14T23:59:59.999Z"));
+        assert!(!range.contains("2017-11-17T00:00:00.000Z"));
+
+        assert!(DateRange::default().contains("1999-01-01T00:00:00.000Z"));
+    }
+
+    #[test]
+    fn history_pages_back_until_the_room_starts() {
+        let mut history = History::new(DateRange::default());
+        assert_eq!(history.before_id(), None);
+
+        history.add_page(vec![message("c", "2017-11-16T10:00:00.000Z", "C"), message("d", "2017-11-16T11:00:00.000Z", "D")]);
+        assert_eq!(history.before_id(), Some(&String::from("c")));
+        assert!(!history.is_complete());
+
+        history.add_page(vec![message("a", "2017-11-15T10:00:00.000Z", "A"), message("b", "2017-11-15T11:00:00.000Z", "B")]);
+        assert_eq!(history.fetched(), 4);
+        assert_eq!(history.reached(), Some(String::from("2017-11-15")));
+
+        history.add_page(vec![]);
+        assert!(history.is_complete());
+
+        assert_eq!(ids(&history.into_messages()), vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn history_stops_once_past_the_start_of_the_range() {
+        let range = DateRange::new(Some("2017-11-16"), Some("2017-11-16")).unwrap();
+        let mut history = History::new(range);
+
+        history.add_page(vec![message("c", "2017-11-16T10:00:00.000Z", "C"), message("d", "2017-11-17T11:00:00.000Z", "D")]);
+        assert!(!history.is_complete());
+
+        history.add_page(vec![message("a", "2017-11-15T10:00:00.000Z", "A"), message("b", "2017-11-16T09:00:00.000Z", "B")]);
+        assert!(history.is_complete());
+
+        assert_eq!(ids(&history.into_messages()), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn history_stops_when_the_cursor_is_ignored() {
+        let mut history = History::new(DateRange::default());
+        let page = vec![message("a", "2017-11-15T10:00:00.000Z", "A")];
+
+        history.add_page(page.clone());
+        history.add_page(page);
+
+        assert!(history.is_complete());
+        assert_eq!(history.fetched(), 1);
+    }
+
+    #[test]
+    fn edits_replace_the_text_in_place() {
+        let mut edit = message("a", "2017-11-15T10:05:00.000Z", "First, edited");
+        edit.v = 2;
+
+        let mut history = History::new(DateRange::default());
+        history.add_page(vec![message("a", "2017-11-15T10:00:00.000Z", "First"), message("b", "2017-11-15T10:01:00.000Z", "Second"), edit]);
+
+        let messages = history.into_messages();
+        assert_eq!(ids(&messages), vec!["a", "b"]);
+        assert_eq!(messages[0].text, "First, edited");
+    }
+
+    #[test]
+    fn markdown_groups_messages_by_day() {
+        let messages = vec![
+            message("a", "2017-11-15T10:00:00.000Z", "Hello"),
+            message("b", "2017-11-15T10:01:00.000Z", "Two\nlines"),
+            message("c", "2017-11-16T09:30:00.000Z", "Next day"),
+        ];
+
+        assert_eq!(written(Format::Markdown, &messages), "# org/room
+
+Times are in UTC.
+
+## 2017-11-15
+
+**Alice** (@alice) 10:00
+
+Hello
+
+**Alice** (@alice) 10:01
+
+Two
+lines
+
+## 2017-11-16
+
+**Alice** (@alice) 09:30
+
+Next day
+");
+    }
+
+    #[test]
+    fn html_escapes_message_text() {
+        let html = written(Format::Html, &[message("a", "2017-11-15T10:00:00.000Z", "<script>alert('hi')</script> & co")]);
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>org/room</title>"));
+        assert!(html.contains("<h2>2017-11-15</h2>"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt; &amp; co"));
+        assert!(!html.contains("<script>"));
+        assert!(html.ends_with("</html>\n"));
+    }
+
+    #[test]
+    fn json_lines_read_back_as_messages() {
+        let messages = vec![message("a", "2017-11-15T10:00:00.000Z", "Hello"), message("b", "2017-11-15T10:01:00.000Z", "Two\nlines")];
+        let lines = written(Format::JsonLines, &messages);
+
+        let read: Vec<Message> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(ids(&read), vec!["a", "b"]);
+        assert_eq!(read[1].text, "Two\nlines");
+    }
+}
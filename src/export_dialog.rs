@@ -0,0 +1,185 @@
+// "Export Room…": asks where to save the history of the open room, in which format and
+// for which days, then shows how far the backend core got with a button to cancel.
+
+use std::path::PathBuf;
+
+use gtk;
+use gtk::prelude::*;
+
+use export::{DateRange, ExportEvent, ExportRequest, Format, FORMATS};
+use Room;
+
+// None when the user cancelled
+pub fn choose_export(parent: &gtk::Window, room: &Room) -> Option<ExportRequest> {
+    let dialog = gtk::FileChooserDialog::new(Some("Export Room"), Some(parent), gtk::FileChooserAction::Save);
+    dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+    dialog.add_button("Export", gtk::ResponseType::Accept.into());
+    dialog.set_default_response(gtk::ResponseType::Accept.into());
+    dialog.set_do_overwrite_confirmation(true);
+
+    // Room names such as org/repo are not file names
+    let base_name = room.name.replace('/', "-");
+    dialog.set_current_name(format!("{}.{}", base_name, Format::Markdown.extension()));
+
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(8);
+    grid.set_column_spacing(12);
+
+    let format = gtk::ComboBoxText::new();
+    for &(_, name, label) in FORMATS.iter() {
+        format.append(Some(name), label);
+    }
+    format.set_active_id(Some(Format::Markdown.name()));
+
+    // The file name follows the format, keeping what the user typed before the extension
+    {
+        let dialog = dialog.clone();
+        format.connect_changed(move |this| {
+            let format = match this.get_active_id().and_then(|id| Format::from_name(&id[..])) {
+                Some(format) => format,
+                None => return,
+            };
+
+            let name = dialog.get_current_name().unwrap_or_default();
+            let stem = PathBuf::from(&name[..]).file_stem().and_then(|stem| stem.to_str()).map(String::from).unwrap_or(name);
+            dialog.set_current_name(format!("{}.{}", stem, format.extension()));
+        });
+    }
+
+    let from = gtk::Entry::new();
+    from.set_placeholder_text("YYYY-MM-DD");
+    from.set_width_chars(12);
+    let to = gtk::Entry::new();
+    to.set_placeholder_text("YYYY-MM-DD");
+    to.set_width_chars(12);
+
+    let error = gtk::Label::new(None);
+    error.set_halign(gtk::Align::Start);
+
+    let rows: Vec<(&str, gtk::Widget)> = vec![
+        ("Format", format.clone().upcast()),
+        ("From (empty for the start)", from.clone().upcast()),
+        ("To (empty for today)", to.clone().upcast()),
+    ];
+
+    for (i, (title, widget)) in rows.into_iter().enumerate() {
+        let label = gtk::Label::new(Some(title));
+        label.set_halign(gtk::Align::End);
+        widget.set_halign(gtk::Align::Start);
+
+        grid.attach(&label, 0, i as i32, 1, 1);
+        grid.attach(&widget, 1, i as i32, 1, 1);
+    }
+    grid.attach(&error, 0, 3, 2, 1);
+
+    grid.show_all();
+    dialog.set_extra_widget(&grid);
+
+    let mut request = None;
+
+    // Stays open while the dates are invalid, saying why
+    while dialog.run() == gtk::ResponseType::Accept.into() {
+        let path = match dialog.get_filename() {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let range = DateRange::new(entry_text(&from).as_ref().map(|from| &from[..]), entry_text(&to).as_ref().map(|to| &to[..]));
+
+        match range {
+            Ok(range) => {
+                request = Some(ExportRequest {
+                    room_id: room.id.clone(),
+                    room_name: room.name.clone(),
+                    path: path,
+                    format: format.get_active_id().and_then(|id| Format::from_name(&id[..])).unwrap_or(Format::Markdown),
+                    range: range,
+                });
+                break;
+            },
+            Err(e) => error.set_text(&e[..]),
+        };
+    }
+
+    dialog.destroy();
+    request
+}
+
+fn entry_text(entry: &gtk::Entry) -> Option<String> {
+    entry.get_text().map(|text| String::from(text.trim())).and_then(|text| if text.len() == 0 { None } else { Some(text) })
+}
+
+// Stays open while the export runs, without blocking the window; closing it cancels the export
+pub struct ExportProgress {
+    dialog: gtk::Dialog,
+    label: gtk::Label,
+    bar: gtk::ProgressBar,
+    cancel_button: gtk::Widget,
+}
+
+impl ExportProgress {
+    // `on_close` is called once the dialog is closed, whether the export finished or not
+    pub fn show<F>(parent: &gtk::Window, room_name: &str, on_close: F) -> ExportProgress
+    where F: Fn() + 'static
+    {
+        let dialog = gtk::Dialog::new();
+        dialog.set_title(&format!("Exporting {}", room_name)[..]);
+        dialog.set_transient_for(Some(parent));
+        dialog.set_default_size(360, -1);
+        let cancel_button = dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 10);
+        content.set_border_width(15);
+
+        let label = gtk::Label::new("Loading the newest messages…");
+        label.set_halign(gtk::Align::Start);
+        label.set_line_wrap(true);
+        let bar = gtk::ProgressBar::new();
+
+        content.add(&label);
+        content.add(&bar);
+        dialog.get_content_area().add(&content);
+
+        dialog.connect_response(move |this, _| {
+            this.destroy();
+            on_close();
+        });
+        dialog.show_all();
+
+        ExportProgress {
+            dialog: dialog,
+            label: label,
+            bar: bar,
+            cancel_button: cancel_button,
+        }
+    }
+
+    // How many messages the history goes back is not known, so the bar only shows that something happens
+    pub fn update(&self, event: &ExportEvent) {
+        match *event {
+            ExportEvent::Progress { fetched, ref reached } => {
+                let text = match *reached {
+                    Some(ref reached) => format!("Loaded {} messages, back to {}…", fetched, reached),
+                    None => format!("Loaded {} messages…", fetched),
+                };
+                self.label.set_text(&text[..]);
+                self.bar.pulse();
+            },
+            ExportEvent::Finished { ref path, written } => {
+                self.label.set_text(&format!("Saved {} messages to {}", written, path.display())[..]);
+                self.bar.set_fraction(1.0);
+                self.finish();
+            },
+            ExportEvent::Failed(ref error) => {
+                self.label.set_text(&format!("The export failed. {}", error)[..]);
+                self.finish();
+            },
+        };
+    }
+
+    // Nothing is left to cancel
+    fn finish(&self) {
+        self.cancel_button.hide();
+        self.dialog.add_button("Close", gtk::ResponseType::Close.into());
+    }
+}
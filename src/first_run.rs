@@ -0,0 +1,259 @@
+// Sign-in assistant, shown on first run while no account is configured and when adding an account.
+// Signs in through the browser or takes a personal access token, and hands the token over once the user applies.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use gtk;
+use gtk::prelude::*;
+
+use api::ApiError;
+use auth;
+use auth::{AuthError, OAuthClient, SignIn};
+use User;
+
+// How often the assistant checks whether the sign-in thread has finished
+const POLL_MILLIS: u32 = 100;
+
+const INTRO_TEXT: &'static str = "GtkGitter is a desktop client for Gitter.im chat.\n\n\
+    Sign in with your Gitter account to see your rooms and messages.";
+
+// `on_signed_in` gets the verified token and its user when the user applies.
+// Without a parent window nothing else is open, so cancelling quits the app.
+pub fn show_assistant<F>(client: Option<OAuthClient>, parent: Option<&gtk::Window>, on_signed_in: F)
+where F: Fn(String, User) + 'static
+{
+    let assistant = gtk::Assistant::new();
+    assistant.set_title("Welcome to GtkGitter");
+
+    let first_run = parent.is_none();
+    if let Some(parent) = parent {
+        assistant.set_title("Add Account");
+        assistant.set_transient_for(Some(parent));
+        assistant.set_modal(true);
+    }
+    assistant.set_default_size(520, 360);
+    assistant.set_position(gtk::WindowPosition::Center);
+
+    // The token and user once sign-in succeeded
+    let signed_in: Rc<RefCell<Option<(String, User)>>> = Rc::new(RefCell::new(None));
+    // Tells a running browser sign-in to stop waiting
+    let cancelled = Arc::new(Mutex::new(false));
+
+    // Welcome page
+    let intro = gtk::Label::new(Some(INTRO_TEXT));
+    intro.set_line_wrap(true);
+    assistant.append_page(&intro);
+    assistant.set_page_type(&intro, gtk::AssistantPageType::Intro);
+    assistant.set_page_title(&intro, "Welcome");
+    assistant.set_page_complete(&intro, true);
+
+    // Sign-in page
+    let page = gtk::Box::new(gtk::Orientation::Vertical, 12);
+    page.set_border_width(12);
+
+    let browser_button = gtk::Button::new_with_label("Sign in with Gitter");
+    let spinner = gtk::Spinner::new();
+    let status = gtk::Label::new(None);
+    status.set_line_wrap(true);
+    status.set_selectable(true);
+
+    let token_expander = gtk::Expander::new(Some("Use a personal access token instead"));
+    let token_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    let token_entry = gtk::Entry::new();
+    token_entry.set_visibility(false);
+    token_entry.set_placeholder_text("Token from developer.gitter.im");
+    let token_button = gtk::Button::new_with_label("Use Token");
+    token_box.pack_start(&token_entry, true, true, 0);
+    token_box.pack_start(&token_button, false, false, 0);
+    token_expander.add(&token_box);
+
+    page.pack_start(&browser_button, false, false, 0);
+    page.pack_start(&spinner, false, false, 0);
+    page.pack_start(&status, false, false, 0);
+    page.pack_end(&token_expander, false, false, 0);
+
+    // Without OAuth credentials only a personal token can be used
+    if client.is_none() {
+        browser_button.set_sensitive(false);
+        status.set_text("Browser sign-in is not configured for this build. Paste a personal access token below.");
+        token_expander.set_expanded(true);
+    }
+
+    assistant.append_page(&page);
+    assistant.set_page_type(&page, gtk::AssistantPageType::Content);
+    assistant.set_page_title(&page, "Sign In");
+
+    // Confirmation page
+    let confirm = gtk::Label::new(None);
+    confirm.set_line_wrap(true);
+    assistant.append_page(&confirm);
+    assistant.set_page_type(&confirm, gtk::AssistantPageType::Confirm);
+    assistant.set_page_title(&confirm, "Done");
+    assistant.set_page_complete(&confirm, true);
+
+    // Both ways of signing in end here, on the GTK thread
+    let finish: Rc<Fn(Result<(String, User), AuthError>)> = {
+        let assistant = assistant.clone();
+        let page = page.clone();
+        let confirm = confirm.clone();
+        let browser_button = browser_button.clone();
+        let token_button = token_button.clone();
+        let spinner = spinner.clone();
+        let status = status.clone();
+        let signed_in = signed_in.clone();
+        let has_client = client.is_some();
+
+        Rc::new(move |result| {
+            spinner.stop();
+            browser_button.set_sensitive(has_client);
+            token_button.set_sensitive(true);
+
+            match result {
+                Ok((token, user)) => {
+                    status.set_text(&format!("Signed in as @{}", user.username)[..]);
+                    confirm.set_text(&format!(
+                        "You are signed in as {} (@{}).\n\nApply to open your rooms.",
+                        user.displayName,
+                        user.username
+                    )[..]);
+                    *signed_in.borrow_mut() = Some((token, user));
+                    assistant.set_page_complete(&page, true);
+                    assistant.next_page();
+                },
+                Err(AuthError::Api(ApiError::Http(401))) => {
+                    status.set_text("Gitter did not accept these credentials. Please try again.");
+                },
+                Err(e) => {
+                    error!("Signing in -> {}", e);
+                    status.set_text(&format!("Signing in failed: {}", e)[..]);
+                },
+            };
+        })
+    };
+
+    // Browser sign-in runs on its own thread; the result is picked up on the GTK thread
+    {
+        let finish = finish.clone();
+        let spinner = spinner.clone();
+        let status = status.clone();
+        let token_button = token_button.clone();
+        let cancelled = cancelled.clone();
+
+        browser_button.connect_clicked(move |button| {
+            let client = match client {
+                Some(ref client) => client.clone(),
+                None => return,
+            };
+
+            let sign_in = match SignIn::start(client) {
+                Ok(sign_in) => sign_in,
+                Err(e) => {
+                    finish(Err(e));
+                    return;
+                },
+            };
+
+            let url = sign_in.authorize_url();
+            if let Err(e) = gtk::show_uri(None, &url[..], 0) {
+                error!("Opening browser -> {}", e);
+            }
+
+            button.set_sensitive(false);
+            token_button.set_sensitive(false);
+            spinner.start();
+            status.set_text(&format!("Waiting for you to sign in with your browser. If it did not open, visit:\n{}", url)[..]);
+
+            *cancelled.lock().unwrap() = false;
+            let (sender, receiver) = mpsc::channel();
+            {
+                let cancelled = cancelled.clone();
+                thread::spawn(move || {
+                    sender.send(sign_in.finish(cancelled)).unwrap_or(());
+                });
+            }
+
+            let finish = finish.clone();
+            gtk::timeout_add(POLL_MILLIS, move || {
+                match receiver.try_recv() {
+                    Ok(result) => {
+                        finish(result);
+                        gtk::Continue(false)
+                    },
+                    Err(mpsc::TryRecvError::Empty) => gtk::Continue(true),
+                    Err(mpsc::TryRecvError::Disconnected) => gtk::Continue(false),
+                }
+            });
+        });
+    }
+
+    // A pasted token is checked against the API before it is accepted
+    {
+        let finish = finish.clone();
+        let spinner = spinner.clone();
+        let browser_button = browser_button.clone();
+        let token_entry = token_entry.clone();
+
+        token_button.connect_clicked(move |button| {
+            let token = String::from(token_entry.get_text().unwrap_or_default().trim());
+            if token.len() == 0 {
+                return;
+            }
+
+            button.set_sensitive(false);
+            browser_button.set_sensitive(false);
+            spinner.start();
+
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let result = auth::verify_token(&token).map(|user| (token, user));
+                sender.send(result).unwrap_or(());
+            });
+
+            let finish = finish.clone();
+            gtk::timeout_add(POLL_MILLIS, move || {
+                match receiver.try_recv() {
+                    Ok(result) => {
+                        finish(result);
+                        gtk::Continue(false)
+                    },
+                    Err(mpsc::TryRecvError::Empty) => gtk::Continue(true),
+                    Err(mpsc::TryRecvError::Disconnected) => gtk::Continue(false),
+                }
+            });
+        });
+    }
+
+    {
+        let token_button = token_button.clone();
+        token_entry.connect_activate(move |_| token_button.clicked());
+    }
+
+    // Apply is only reachable from the confirmation page, after signing in
+    {
+        let signed_in = signed_in.clone();
+        assistant.connect_apply(move |_| {
+            if let Some((token, user)) = signed_in.borrow_mut().take() {
+                on_signed_in(token, user);
+            }
+        });
+    }
+
+    assistant.connect_cancel(move |this| {
+        *cancelled.lock().unwrap() = true;
+        this.destroy();
+
+        if first_run {
+            gtk::main_quit();
+        }
+    });
+
+    assistant.connect_close(move |this| {
+        this.destroy();
+    });
+
+    assistant.show_all();
+}
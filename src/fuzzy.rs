@@ -0,0 +1,96 @@
+// Fuzzy matching used to filter and rank room names
+
+// Scores how well `pattern` matches `text` as a case-insensitive subsequence.
+// Returns None when some character of the pattern cannot be found in order.
+// Consecutive characters and characters at the start of a word score higher.
+pub fn score(pattern: &str, text: &str) -> Option<i64> {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().filter(|c| !c.is_whitespace()).collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    if pattern.len() == 0 {
+        return Some(0);
+    }
+
+    // best[i]: score of the best alignment of the pattern so far whose last match is at i;
+    // every alignment is tried, so a later run like "gtk" in "gitter_gtk" is found
+    let mut best: Vec<Option<i64>> = vec![None; text.len()];
+
+    for (j, p) in pattern.iter().enumerate() {
+        let mut next: Vec<Option<i64>> = vec![None; text.len()];
+
+        for i in 0..text.len() {
+            if text[i] != *p {
+                continue;
+            }
+
+            let previous = if j == 0 {
+                Some(0)
+            } else {
+                (0..i).filter_map(|last| best[last].map(|score| {
+                    // Reward runs of matching characters
+                    if last + 1 == i {
+                        score + 5
+                    } else {
+                        score - (i - last) as i64 / 4
+                    }
+                })).max()
+            };
+
+            // Reward matches at the start of a word or path segment
+            let word_start = if i == 0 || !text[i - 1].is_alphanumeric() { 8 } else { 0 };
+
+            next[i] = previous.map(|score| score + 1 + word_start);
+        }
+
+        best = next;
+    }
+
+    let mut score = match best.into_iter().filter_map(|score| score).max() {
+        Some(score) => score,
+        None => return None,
+    };
+
+    // Prefer shorter names when everything else is equal
+    score -= text.len() as i64 / 10;
+
+    Some(score)
+}
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    score(pattern, text).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_case_insensitive_subsequences() {
+        assert!(matches("gtk", "seanr707/Gitter_GTK"));
+        assert!(matches("GH sand", "gitterHQ/sandbox"));
+        assert!(!matches("ktg", "gitter_gtk"));
+        assert!(!matches("gitterx", "gitter"));
+        assert_eq!(score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn runs_of_characters_score_higher() {
+        assert!(score("abc", "abcxx").unwrap() > score("abc", "axbxc").unwrap());
+    }
+
+    #[test]
+    fn contiguous_runs_win_over_the_first_scattered_match() {
+        assert!(score("gtk", "gitter_gtk").unwrap() > score("gtk", "gitter_t_k").unwrap());
+    }
+
+    #[test]
+    fn word_starts_score_higher() {
+        assert!(score("s", "org/sandbox").unwrap() > score("s", "org/absox").unwrap());
+        assert!(score("b", "rust-lang/book").unwrap() > score("b", "rust-lang/abook").unwrap());
+    }
+
+    #[test]
+    fn shorter_names_win_ties() {
+        assert!(score("rust", "rust").unwrap() > score("rust", "rust-lang/rust-very-long-room-name").unwrap());
+    }
+}
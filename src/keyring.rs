@@ -0,0 +1,210 @@
+// Keeps the access token in the freedesktop Secret Service (GNOME Keyring, KWallet)
+// instead of in plaintext in config.yaml. dbus 0.6 only offers blocking calls,
+// which is fine for the few lookups made while starting and signing in or out.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use dbus;
+use dbus::arg::{Iter, RefArg, Variant};
+use dbus::Path;
+
+const SERVICE: &'static str = "org.freedesktop.secrets";
+const SERVICE_PATH: &'static str = "/org/freedesktop/secrets";
+const DEFAULT_COLLECTION_PATH: &'static str = "/org/freedesktop/secrets/aliases/default";
+
+const SERVICE_INTERFACE: &'static str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &'static str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &'static str = "org.freedesktop.Secret.Item";
+const PROMPT_INTERFACE: &'static str = "org.freedesktop.Secret.Prompt";
+
+// Shown for the item in Seahorse and the like
+const LABEL: &'static str = "GtkGitter access token";
+
+// Attributes which find the token of an account among the user's secrets
+const APPLICATION_ATTRIBUTE: &'static str = "application";
+const APPLICATION: &'static str = "gitter_gtk";
+const ACCOUNT_ATTRIBUTE: &'static str = "account";
+
+const CALL_TIMEOUT_MILLIS: i32 = 5000;
+
+// The user may need a while to type their keyring password
+const PROMPT_TIMEOUT_SECS: u64 = 120;
+
+// A "/" path means no prompt is needed
+const NO_PROMPT: &'static str = "/";
+
+// Why the keyring could not be used
+#[derive(Debug)]
+pub enum KeyringError {
+    Dbus(dbus::Error),
+    // The service answered with something other than the Secret Service API describes
+    Reply(String),
+    // The user dismissed the unlock prompt
+    Dismissed,
+}
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyringError::Dbus(ref e) => write!(f, "D-Bus error: {}", e.message().unwrap_or("unknown")),
+            KeyringError::Reply(ref e) => write!(f, "unexpected reply from the Secret Service: {}", e),
+            KeyringError::Dismissed => write!(f, "the keyring was not unlocked"),
+        }
+    }
+}
+
+impl From<dbus::Error> for KeyringError {
+    fn from(e: dbus::Error) -> KeyringError {
+        KeyringError::Dbus(e)
+    }
+}
+
+impl From<dbus::arg::TypeMismatchError> for KeyringError {
+    fn from(e: dbus::arg::TypeMismatchError) -> KeyringError {
+        KeyringError::Reply(format!("{:?}", e))
+    }
+}
+
+// A connection to the Secret Service with a plain (unencrypted) session, which is
+// acceptable because the session bus is only reachable by the user
+pub struct Keyring {
+    bus: dbus::Connection,
+    session: Path<'static>,
+}
+
+impl Keyring {
+    // Fails when there is no session bus or nothing provides the Secret Service
+    pub fn connect() -> Result<Keyring, KeyringError> {
+        Keyring::with_connection(dbus::Connection::get_private(dbus::BusType::Session)?)
+    }
+
+    pub fn with_connection(bus: dbus::Connection) -> Result<Keyring, KeyringError> {
+        let request = method_call(SERVICE_PATH, SERVICE_INTERFACE, "OpenSession")?
+            .append2("plain", Variant(""));
+        let reply = bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let (_, session): (Variant<Iter>, Path) = reply.read2()?;
+        let session = session.into_static();
+
+        Ok(Keyring {
+            bus: bus,
+            session: session,
+        })
+    }
+
+    pub fn load_token(&self, account: &str) -> Result<Option<String>, KeyringError> {
+        let item = match self.find_item(account)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+
+        let request = method_call(&item, ITEM_INTERFACE, "GetSecret")?.append1(self.session.clone());
+        let reply = self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let (_, _, value, _): (Path, Vec<u8>, Vec<u8>, String) = reply.read1()?;
+
+        match String::from_utf8(value) {
+            Ok(token) => Ok(Some(token)),
+            Err(e) => Err(KeyringError::Reply(format!("token is not UTF-8: {}", e))),
+        }
+    }
+
+    // Replaces the token stored for the account before, if any
+    pub fn save_token(&self, account: &str, token: &str) -> Result<(), KeyringError> {
+        let label = format!("{} ({})", LABEL, account);
+
+        let mut properties: HashMap<&str, Variant<Box<RefArg>>> = HashMap::new();
+        properties.insert("org.freedesktop.Secret.Item.Label", Variant(Box::new(label)));
+        properties.insert("org.freedesktop.Secret.Item.Attributes", Variant(Box::new(attributes(account))));
+
+        let secret = (self.session.clone(), Vec::<u8>::new(), token.as_bytes().to_vec(), "text/plain; charset=utf8");
+
+        let request = method_call(DEFAULT_COLLECTION_PATH, COLLECTION_INTERFACE, "CreateItem")?
+            .append3(properties, secret, true);
+        let reply = self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let (_, prompt): (Path, Path) = reply.read2()?;
+
+        // A locked collection asks to be unlocked before the item is created
+        self.complete_prompt(prompt)
+    }
+
+    pub fn delete_token(&self, account: &str) -> Result<(), KeyringError> {
+        let item = match self.find_item(account)? {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+
+        let request = method_call(&item, ITEM_INTERFACE, "Delete")?;
+        let reply = self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let prompt: Path = reply.read1()?;
+
+        self.complete_prompt(prompt)
+    }
+
+    // The item of the account, unlocked if it was locked
+    fn find_item(&self, account: &str) -> Result<Option<Path<'static>>, KeyringError> {
+        let request = method_call(SERVICE_PATH, SERVICE_INTERFACE, "SearchItems")?.append1(attributes(account));
+        let reply = self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let (unlocked, locked): (Vec<Path>, Vec<Path>) = reply.read2()?;
+
+        if let Some(item) = unlocked.into_iter().next() {
+            return Ok(Some(item.into_static()));
+        }
+
+        let item = match locked.into_iter().next() {
+            Some(item) => item.into_static(),
+            None => return Ok(None),
+        };
+
+        let request = method_call(SERVICE_PATH, SERVICE_INTERFACE, "Unlock")?.append1(vec![item.clone()]);
+        let reply = self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+        let (_, prompt): (Vec<Path>, Path) = reply.read2()?;
+        self.complete_prompt(prompt)?;
+
+        Ok(Some(item))
+    }
+
+    // Shows the service's prompt, e.g. for the keyring password, and waits for the user
+    fn complete_prompt(&self, prompt: Path) -> Result<(), KeyringError> {
+        if &*prompt == NO_PROMPT {
+            return Ok(());
+        }
+
+        let rule = format!("type='signal',interface='{}',member='Completed',path='{}'", PROMPT_INTERFACE, &*prompt);
+        self.bus.add_match(&rule[..])?;
+
+        // The window id is only a hint for placing the dialog
+        let request = method_call(&prompt, PROMPT_INTERFACE, "Prompt")?.append1("");
+        self.bus.send_with_reply_and_block(request, CALL_TIMEOUT_MILLIS)?;
+
+        let deadline = Instant::now() + Duration::from_secs(PROMPT_TIMEOUT_SECS);
+
+        while Instant::now() < deadline {
+            for message in self.bus.incoming(1000) {
+                let is_completed = message.member().map(|member| &*member == "Completed").unwrap_or(false)
+                    && message.path().map(|path| &*path == &*prompt).unwrap_or(false);
+
+                if is_completed {
+                    self.bus.remove_match(&rule[..]).unwrap_or(());
+
+                    let dismissed: bool = message.read1()?;
+                    return if dismissed { Err(KeyringError::Dismissed) } else { Ok(()) };
+                }
+            }
+        }
+
+        self.bus.remove_match(&rule[..]).unwrap_or(());
+        Err(KeyringError::Dismissed)
+    }
+}
+
+fn attributes(account: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    attributes.insert(String::from(APPLICATION_ATTRIBUTE), String::from(APPLICATION));
+    attributes.insert(String::from(ACCOUNT_ATTRIBUTE), String::from(account));
+    attributes
+}
+
+fn method_call(path: &str, interface: &str, method: &str) -> Result<dbus::Message, KeyringError> {
+    dbus::Message::new_method_call(SERVICE, path, interface, method).map_err(KeyringError::Reply)
+}
@@ -0,0 +1,360 @@
+// Leveled logging through the `log` crate. Records are written to
+// $XDG_STATE_HOME/gitter_gtk/gitter_gtk.log, which is rotated once it grows past
+// MAX_FILE_BYTES, and warnings and errors are echoed to standard error.
+//
+// The level comes from log_level in config.yaml, --verbose or $GITTER_GTK_LOG, in
+// increasing priority, e.g. "info,api=debug". Tokens are never logged; message
+// contents and search queries only with log_message_contents, see `private`.
+
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use config::Config;
+
+pub const ENV_VAR: &'static str = "GITTER_GTK_LOG";
+
+const FILE_NAME: &'static str = "gitter_gtk.log";
+
+// The log is moved to gitter_gtk.log.1 past this size, keeping ROTATED_FILES old logs
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+const ROTATED_FILES: u32 = 3;
+
+// Short targets in a filter such as "api" mean this crate's modules
+const CRATE_NAME: &'static str = "gitter_gtk";
+
+// Set by log_message_contents in config.yaml
+static PRIVATE: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    static ref STATE: Mutex<LogState> = Mutex::new(LogState {
+        filter: Filter::default(),
+        console: LevelFilter::Warn,
+        verbose: false,
+        file: None,
+        written: 0,
+    });
+}
+
+static LOGGER: Logger = Logger;
+
+struct Logger;
+
+struct LogState {
+    filter: Filter,
+    // Records up to this level are echoed to standard error as well
+    console: LevelFilter,
+    // --verbose, which wins over the level in config.yaml
+    verbose: bool,
+    file: Option<(PathBuf, File)>,
+    // Size of the current file, to know when to rotate it
+    written: u64,
+}
+
+// Which records are logged: a default level plus levels for modules and their children
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            default: LevelFilter::Info,
+            targets: vec![],
+        }
+    }
+}
+
+impl Filter {
+    // Comma separated levels, each for everything ("debug") or for a module ("api=debug")
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+
+        for directive in spec.split(',').map(|directive| directive.trim()).filter(|directive| directive.len() > 0) {
+            let mut parts = directive.splitn(2, '=');
+            let first = parts.next().unwrap_or("");
+
+            match parts.next() {
+                Some(level) => filter.targets.push((String::from(first.trim()), parse_level(level)?)),
+                None => filter.default = parse_level(first)?,
+            };
+        }
+
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        // The most specific module wins
+        self.targets.iter()
+            .filter(|&&(ref module, _)| target_matches(module, target))
+            .max_by_key(|&&(ref module, _)| module.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|&(_, level)| level).fold(self.default, |a, b| if b > a { b } else { a })
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level.trim())
+        .map_err(|_| format!("\"{}\" is not a log level, use off, error, warn, info, debug or trace", level.trim()))
+}
+
+fn target_matches(module: &str, target: &str) -> bool {
+    let full = format!("{}::{}", CRATE_NAME, module);
+
+    [module, &full[..]].iter().any(|module| {
+        target == *module || (target.starts_with(*module) && target[module.len()..].starts_with("::"))
+    })
+}
+
+// Starts logging with the default level, before config.yaml is read
+pub fn init(verbose: bool) {
+    {
+        let mut state = STATE.lock().unwrap();
+        state.verbose = verbose;
+        state.file = open_file();
+        state.written = state.file.as_ref().and_then(|&(_, ref file)| file.metadata().ok()).map(|m| m.len()).unwrap_or(0);
+    }
+
+    if let Err(e) = log::set_logger(&LOGGER) {
+        eprintln!("Could not start logging -> {}", e);
+        return;
+    }
+
+    apply_filter(None);
+}
+
+// Applies the logging settings of config.yaml, at startup and whenever the file changes
+pub fn configure(config: &Config) {
+    PRIVATE.store(config.log_message_contents, Ordering::Relaxed);
+    apply_filter(Some(&config.log_level[..]));
+}
+
+// The terminal UI draws on the terminal, so nothing may be echoed there while it runs
+pub fn set_console(enabled: bool) {
+    STATE.lock().unwrap().console = if enabled { LevelFilter::Warn } else { LevelFilter::Off };
+}
+
+fn apply_filter(config_level: Option<&str>) {
+    let mut state = STATE.lock().unwrap();
+
+    let filter = match ::std::env::var(ENV_VAR) {
+        Ok(ref spec) if spec.len() > 0 => Filter::parse(&spec[..]).unwrap_or_else(|e| {
+            eprintln!("Ignoring ${} -> {}", ENV_VAR, e);
+            Filter::default()
+        }),
+        _ if state.verbose => Filter { default: LevelFilter::Debug, targets: vec![] },
+        // Already checked when config.yaml was read
+        _ => config_level.and_then(|spec| Filter::parse(spec).ok()).unwrap_or_default(),
+    };
+
+    // Whatever --verbose shows is printed too, as before there was a log file
+    if state.verbose && state.console != LevelFilter::Off {
+        state.console = LevelFilter::Trace;
+    }
+
+    log::set_max_level(filter.max_level());
+    state.filter = filter;
+}
+
+// $XDG_STATE_HOME/gitter_gtk/gitter_gtk.log
+pub fn path() -> Option<PathBuf> {
+    let state_home = match ::std::env::var("XDG_STATE_HOME") {
+        Ok(ref val) if val.len() > 0 => PathBuf::from(val),
+        _ => match ::std::env::var("HOME") {
+            Ok(val) => PathBuf::from(val).join(".local").join("state"),
+            Err(_) => return None,
+        },
+    };
+
+    Some(state_home.join("gitter_gtk").join(FILE_NAME))
+}
+
+fn open_file() -> Option<(PathBuf, File)> {
+    let path = path()?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap_or(());
+    }
+
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => Some((path, file)),
+        Err(e) => {
+            eprintln!("Could not open the log file {} -> {}", path.display(), e);
+            None
+        },
+    }
+}
+
+// gitter_gtk.log.2 becomes gitter_gtk.log.3 and so on; the oldest is overwritten
+fn rotate(path: &PathBuf) {
+    let numbered = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+
+    for n in (1..ROTATED_FILES).rev() {
+        fs::rename(numbered(n), numbered(n + 1)).unwrap_or(());
+    }
+
+    fs::rename(path, numbered(1)).unwrap_or(());
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= STATE.lock().unwrap().filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        let mut state = STATE.lock().unwrap();
+
+        if record.level() > state.filter.level(record.target()) {
+            return;
+        }
+
+        let line = format!("{} {:<5} {}: {}\n", timestamp(SystemTime::now()), record.level(), record.target(), record.args());
+
+        if record.level() <= state.console {
+            eprint!("{}", line);
+        }
+
+        if state.written + line.len() as u64 > MAX_FILE_BYTES {
+            if let Some((path, file)) = state.file.take() {
+                drop(file);
+                rotate(&path);
+                state.file = open_file();
+                state.written = 0;
+            }
+        }
+
+        let written = match state.file {
+            Some((_, ref mut file)) => file.write_all(line.as_bytes()).is_ok(),
+            None => false,
+        };
+
+        if written {
+            state.written += line.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, ref mut file)) = STATE.lock().unwrap().file {
+            file.flush().unwrap_or(());
+        }
+    }
+}
+
+// Shows what users wrote, such as message texts and search queries, only when they asked for it
+pub struct Private<T>(pub T);
+
+pub fn private<T: fmt::Display>(value: T) -> Private<T> {
+    Private(value)
+}
+
+impl<T: fmt::Display> fmt::Display for Private<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if PRIVATE.load(Ordering::Relaxed) {
+            self.0.fmt(f)
+        } else {
+            write!(f, "<hidden>")
+        }
+    }
+}
+
+// A request URL without its query, which can hold a search
+pub fn url(url: &str) -> String {
+    match url.find('?') {
+        Some(start) => format!("{}?{}", &url[..start], private(&url[start + 1..])),
+        None => String::from(url),
+    }
+}
+
+// UTC in RFC 3339 with milliseconds, e.g. 2018-01-07T14:05:09.120Z
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_nanos() / 1000000
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + (if month <= 2 { 1 } else { 0 });
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn filter_picks_the_most_specific_module() {
+        let filter = Filter::parse("warn, api=debug, gitter_gtk::api::stream=trace").unwrap();
+
+        assert_eq!(filter.level("gitter_gtk::backend"), LevelFilter::Warn);
+        assert_eq!(filter.level("gitter_gtk::api"), LevelFilter::Debug);
+        assert_eq!(filter.level("gitter_gtk::api::stream"), LevelFilter::Trace);
+        // A module is not the parent of one whose name merely starts the same
+        assert_eq!(filter.level("gitter_gtk::apis"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn filter_rejects_unknown_levels() {
+        assert_eq!(Filter::parse("").unwrap(), Filter::default());
+
+        let error = Filter::parse("info,api=loud").unwrap_err();
+        assert!(error.contains("loud"), "{}", error);
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+
+        let leap_day = UNIX_EPOCH + Duration::from_millis(951_782_400_250);
+        assert_eq!(timestamp(leap_day), "2000-02-29T00:00:00.250Z");
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_515_333_909);
+        assert_eq!(timestamp(time), "2018-01-07T14:05:09.000Z");
+    }
+
+    #[test]
+    fn private_text_is_hidden_by_default() {
+        assert_eq!(private("hello").to_string(), "<hidden>");
+        assert_eq!(url("https://api.gitter.im/v1/rooms/1/chatMessages?q=secret&limit=50"),
+            "https://api.gitter.im/v1/rooms/1/chatMessages?<hidden>");
+        assert_eq!(url("https://api.gitter.im/v1/rooms"), "https://api.gitter.im/v1/rooms");
+    }
+}
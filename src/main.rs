@@ -1,30 +1,100 @@
 #![feature(use_extern_macros)]
 #![feature(underscore_lifetimes)]
 #![feature(drain_filter)]
+#![feature(slice_patterns)]
+extern crate gdk;
 extern crate gtk;
 
+extern crate clap;
 extern crate curl;
+extern crate dbus;
 
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
+extern crate serde_yaml;
 #[macro_use]
 extern crate serde_derive;
 
 extern crate regex;
+extern crate rusqlite;
 extern crate yaml_rust;
 
+extern crate notify;
 extern crate notify_rust;
+extern crate rand;
 
-use std::fs::File;
-use std::io::Read;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+extern crate futures;
+extern crate glib;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tui")]
+extern crate libc;
+#[cfg(feature = "tui")]
+extern crate termion;
+extern crate tokio_core;
+extern crate tokio_curl;
 
-use gtk::prelude::*;
+use std::fs;
+use std::path::PathBuf;
 
-use curl::easy::{Easy, List};
+use gtk::prelude::*;
 
-use yaml_rust::YamlLoader;
+mod accounts;
+mod api;
+mod archive;
+mod auth;
+mod backend;
+mod chat;
+mod cli;
+mod config;
+mod config_watch;
+mod connection;
+mod export;
+mod export_dialog;
+mod first_run;
+mod fuzzy;
+mod keyring;
+mod logging;
+mod matrix;
+mod network;
+mod outbox;
+mod preferences;
+mod scheduler;
+mod search;
+mod shortcuts;
+mod sidebar;
+mod state;
+mod store;
+mod switcher;
+#[cfg(feature = "tui")]
+mod tui;
+mod ui_channel;
+
+#[cfg(test)]
+mod tests;
+
+use accounts::Account;
+use api::{ApiError, Endpoints};
+use archive::Archive;
+use auth::OAuthClient;
+use backend::{BackendConfig, Command, CommandSender, Event};
+use cli::Options;
+use config::{Config, ConfigError};
+use connection::ConnectionState;
+use export_dialog::ExportProgress;
+use keyring::Keyring;
+use outbox::{DeliveryState, OutboxItem};
+use search::{MessageCache, SearchResult};
+use sidebar::{Section, SidebarState};
+use state::{Action, AppState, Change, ComposerMode, PendingMessage};
+use store::{SqliteStore, Store};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Url {
@@ -52,7 +122,7 @@ struct User {
 
 // Room resource with fields from gitter.im
 #[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Room {
     id: String,
     name: String,
@@ -60,11 +130,25 @@ struct Room {
     url: String,
     oneToOne: bool,
     mentions: u32,
-    // favourite: u32,
+    #[serde(default)]
+    unreadItems: u32,
+    #[serde(default)]
+    favourite: Option<u32>,
+    #[serde(default)]
+    groupId: Option<String>,
     githubType: String,
     lurk: bool
 }
 
+// Group (community) resource with fields from gitter.im
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Group {
+    id: String,
+    name: String,
+    uri: String,
+}
+
 // Message with fields from gitter.im
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -81,65 +165,11 @@ struct Message {
     v: i32,
 }
 
-// Sends and receives data from Gitter.im API
-#[derive(Clone)]
-struct MessageHandler {
-    current_room_id: String,
-    token: String,
-}
-
-impl MessageHandler {
-    fn new(room_id: &String, token: &String) -> MessageHandler {
-        MessageHandler {
-            current_room_id: room_id.clone(),
-            token: token.clone(),
-        }
-    }
-
-    /** May be used later to allow changing Yaml file during session
-    *
-    fn set_token(&mut self, token: String) {
-        self.token = token;
-    }
-    */
-
-    fn set_current_room_id(&mut self, id: String) {
-        self.current_room_id = id;
-    }
-
-    fn load_messages(&self) -> Vec<Message> {
-        let url = format!("https://api.gitter.im/v1/rooms/{}/chatMessages?limit=15", &self.current_room_id);
-        get_url::<Message>(&url, &self.token)
-    }
-
-    fn send_message(&self, message: String) {
-        if message.trim().len() == 0 {
-            ()
-        }
-
-        let url = format!("https://api.gitter.im/v1/rooms/{}/chatMessages", &self.current_room_id);
-
-        let mut easy = Easy::new();
-
-        let json = String::from("{\"text\": \"") + &message[..] + "\"}";
-        let mut json = json.as_bytes();
-
-        easy.url(&url).unwrap();
-        easy.post(true).unwrap();
-        easy.post_field_size(json.len() as u64).unwrap();
-
-        let mut list = List::new();
-        list.append("Content-Type: application/json").unwrap();
-        list.append("Accept: application/json").unwrap();
-        list.append(&(format!("Authorization: Bearer {}", &self.token))).unwrap();
-        easy.http_headers(list).unwrap();
-
-        let mut transfer = easy.transfer();
-        transfer.read_function(|buf| {
-            Ok(json.read(buf).unwrap_or(0))
-        }).unwrap();
-        transfer.perform().unwrap();
-    }
+// Messages queued by the GUI for delivery by the backend core
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum OutgoingMessage {
+    New { text: String, parent_id: Option<String> },
+    Edit { id: String, text: String },
 }
 
 // Stores and parses messages
@@ -196,26 +226,89 @@ impl MessageStore {
     }
 }
 
+// Number of cached messages shown when opening a room
+const CACHED_MESSAGES_SHOWN: usize = 50;
+
+// How long reading the tokens may take before a window says what the app is waiting for
+const KEYRING_LOADING_DELAY_MILLIS: u32 = 300;
+
+// A room row in the sidebar, kept so rows can be filtered and selected later
+#[derive(Clone)]
+struct SidebarRow {
+    row: gtk::ListBoxRow,
+    label: gtk::Label,
+    room_id: String,
+    room_name: String,
+    section_key: String,
+}
+
+// A message shown in the chat, kept so it can be searched, edited and replied to
+#[derive(Clone)]
+struct MessageRow {
+    message: Message,
+    widget: gtk::EventBox,
+    label: gtk::Label,
+}
+
+// The windows of all signed in accounts; only one is shown at a time
+type AccountWindows = Rc<RefCell<Vec<MainWindow>>>;
+
 #[derive(Clone)]
 struct MainWindow {
+    account: String,
+    account_switcher: gtk::ComboBoxText,
+    account_windows: AccountWindows,
+    add_account_item: gtk::MenuItem,
     builder: gtk::Builder,
+    // Set while a page of an archive is shown, so the scrolling this causes loads no other page
+    archive_paging: Rc<Cell<bool>>,
+    // None in archive mode, which has no backend core
+    commands: Option<CommandSender>,
+    composer_status: gtk::Label,
+    connection_indicator: gtk::Label,
+    connection_infobar: gtk::InfoBar,
+    connection_message: gtk::Label,
+    connection_retry_button: gtk::Button,
+    // The dialog of the running export, if any
+    export_progress: Rc<RefCell<Option<ExportProgress>>>,
+    export_room_item: gtk::MenuItem,
+    go_to_date_item: gtk::MenuItem,
     headerbar: gtk::HeaderBar,
+    message_cache: Rc<RefCell<MessageCache>>,
+    message_rows: Rc<RefCell<Vec<MessageRow>>>,
+    message_search_bar: gtk::SearchBar,
+    message_search_entry: gtk::SearchEntry,
+    pending_widgets: Rc<RefCell<Vec<gtk::Box>>>,
+    preferences_item: gtk::MenuItem,
+    room_rows: Rc<RefCell<Vec<SidebarRow>>>,
     scroll_window: gtk::ScrolledWindow,
     scrollable_box: gtk::Box,
+    search_all_rooms: gtk::CheckButton,
+    search_result_data: Rc<RefCell<Vec<SearchResult>>>,
+    search_results: gtk::ListBox,
+    search_results_revealer: gtk::Revealer,
     send_text_button: gtk::Button,
     sidebar: gtk::ListBox,
     sidebar_button: gtk::Button,
+    sidebar_filter: gtk::SearchEntry,
     sidebar_revealer: gtk::Revealer,
+    sidebar_state: Rc<RefCell<SidebarState>>,
+    sign_out_item: gtk::MenuItem,
+    state: Rc<RefCell<AppState>>,
+    store: Option<Rc<SqliteStore>>,
     text_box: gtk::Entry,
-    user: User,
+    // The token the backend core signs in with
+    token: Rc<RefCell<String>>,
+    // Set while the account switcher is refilled, so its changed signal is ignored
+    updating_account_switcher: Rc<Cell<bool>>,
     window: gtk::Window,
     viewport: gtk::Viewport,
 }
 
 impl MainWindow {
-    fn new(user: &User) -> MainWindow {
+    fn new(state: AppState, commands: Option<CommandSender>, store: Option<SqliteStore>, account: Account, account_windows: AccountWindows) -> MainWindow {
         if gtk::init().is_err() {
-            println!("Failed to initialize GTK.");
+            error!("Failed to initialize GTK.");
         }
 
         // First we get the file content.
@@ -225,28 +318,76 @@ impl MainWindow {
         let builder = gtk::Builder::new_from_string(glade_src);
         let window: gtk::Window = builder.get_object("window1").unwrap();
 
+        let account_switcher: gtk::ComboBoxText = builder.get_object("account_switcher").unwrap();
+        let add_account_item: gtk::MenuItem = builder.get_object("add_account_item").unwrap();
         let button: gtk::Button = builder.get_object("sendTextButton").unwrap();
         let entry: gtk::Entry = builder.get_object("textInputBox").unwrap();
         let headerbar: gtk::HeaderBar = builder.get_object("headerbar").unwrap();
+        let composer_status: gtk::Label = builder.get_object("composer_status").unwrap();
+        let connection_indicator: gtk::Label = builder.get_object("connection_indicator").unwrap();
+        let connection_infobar: gtk::InfoBar = builder.get_object("connection_infobar").unwrap();
+        let connection_message: gtk::Label = builder.get_object("connection_message").unwrap();
+        let connection_retry_button: gtk::Button = builder.get_object("connection_retry_button").unwrap();
+        let export_room_item: gtk::MenuItem = builder.get_object("export_room_item").unwrap();
+        let go_to_date_item: gtk::MenuItem = builder.get_object("go_to_date_item").unwrap();
+        let message_search_bar: gtk::SearchBar = builder.get_object("message_search_bar").unwrap();
+        let message_search_entry: gtk::SearchEntry = builder.get_object("message_search_entry").unwrap();
         let scroll_window: gtk::ScrolledWindow = builder.get_object("scroll_window").unwrap();
         let scrollable_box: gtk::Box = builder.get_object("scrollable_box").unwrap();
+        let search_all_rooms: gtk::CheckButton = builder.get_object("search_all_rooms").unwrap();
+        let search_results: gtk::ListBox = builder.get_object("search_results").unwrap();
+        let search_results_revealer: gtk::Revealer = builder.get_object("search_results_revealer").unwrap();
         let sidebar: gtk::ListBox = builder.get_object("sidebar").unwrap();
         let sidebar_button: gtk::Button = builder.get_object("sidebar_button").unwrap();
+        let sidebar_filter: gtk::SearchEntry = builder.get_object("sidebar_filter").unwrap();
         let sidebar_revealer: gtk::Revealer = builder.get_object("sidebar_revealer").unwrap();
+        let preferences_item: gtk::MenuItem = builder.get_object("preferences_item").unwrap();
+        let sign_out_item: gtk::MenuItem = builder.get_object("sign_out_item").unwrap();
         let viewport: gtk::Viewport = builder.get_object("viewport").unwrap();
 
         MainWindow {
+            account: account.name,
+            account_switcher: account_switcher,
+            account_windows: account_windows,
+            add_account_item: add_account_item,
+            archive_paging: Rc::new(Cell::new(false)),
             builder: builder,
+            commands: commands,
+            composer_status: composer_status,
+            connection_indicator: connection_indicator,
+            connection_infobar: connection_infobar,
+            connection_message: connection_message,
+            connection_retry_button: connection_retry_button,
+            export_progress: Rc::new(RefCell::new(None)),
+            export_room_item: export_room_item,
+            go_to_date_item: go_to_date_item,
             window: window,
             headerbar: headerbar,
+            message_cache: Rc::new(RefCell::new(MessageCache::new())),
+            message_rows: Rc::new(RefCell::new(vec![])),
+            message_search_bar: message_search_bar,
+            message_search_entry: message_search_entry,
+            pending_widgets: Rc::new(RefCell::new(vec![])),
+            preferences_item: preferences_item,
+            room_rows: Rc::new(RefCell::new(vec![])),
             send_text_button: button,
             scroll_window: scroll_window,
             scrollable_box: scrollable_box,
+            search_all_rooms: search_all_rooms,
+            search_result_data: Rc::new(RefCell::new(vec![])),
+            search_results: search_results,
+            search_results_revealer: search_results_revealer,
             sidebar: sidebar,
             sidebar_button: sidebar_button,
+            sidebar_filter: sidebar_filter,
             sidebar_revealer: sidebar_revealer,
+            sidebar_state: Rc::new(RefCell::new(SidebarState::load())),
+            sign_out_item: sign_out_item,
+            state: Rc::new(RefCell::new(state)),
+            store: store.map(Rc::new),
             text_box: entry,
-            user: user.clone(),
+            token: Rc::new(RefCell::new(account.token)),
+            updating_account_switcher: Rc::new(Cell::new(false)),
             viewport: viewport,
         }
     }
@@ -267,7 +408,7 @@ impl MainWindow {
         // println!("{:?}", html);
         // let text = format!("<span foreground='orange'><b>@{}</b></span>: {}", message.fromUser.username, message.text);
 
-        let text = format!("@{}: {}", message.fromUser.username, message.text);
+        let text = message_text(message);
 
         // let label = gtk::Label::new(None);
         // label.set_markup(&text);
@@ -288,400 +429,1772 @@ impl MainWindow {
         label
     }
 
-    fn add_messages(&mut self, store: &MessageStore, notification_sender: mpsc::Sender<String>) {
-
-        for message in store.messages.iter() {
-            let label = self.create_message_label(message);
-
-            self.scrollable_box.add(&label);
-            label.show();
+    // Turns events from the backend core into actions; search results are not part of the state
+    fn handle_event(&self, event: Event) {
+        match event {
+            Event::MessagesAdded { room_id, messages } => self.dispatch(Action::MessagesAdded {
+                room_id: room_id,
+                messages: messages,
+            }),
+            Event::RoomsUpdated { rooms, groups } => self.dispatch(Action::RoomsUpdated {
+                rooms: rooms,
+                groups: groups,
+            }),
+            Event::SearchResults(results) => self.show_search_results(results),
+            Event::ContextLoaded { room_id, message_id, messages } => self.dispatch(Action::ContextLoaded {
+                room_id: room_id,
+                message_id: message_id,
+                messages: messages,
+            }),
+            Event::Outbox(event) => self.dispatch(Action::Outbox(event)),
+            Event::ConnectionChanged(state) => self.dispatch(Action::ConnectionChanged(state)),
+            Event::Export(event) => {
+                if let Some(ref progress) = *self.export_progress.borrow() {
+                    progress.update(&event);
+                }
+            },
+        };
+    }
 
-            // Check to send notification to notify thread if message mentions current user
-            if message.mentions.len() > 0 && &message.mentions[0].screenName == &self.user.username {
-                let notification_body = format!("Message from user {}!", message.fromUser.username);
-                notification_sender.send(notification_body).unwrap();
+    fn send_command(&self, command: Command) {
+        if let Some(ref commands) = self.commands {
+            if let Err(e) = commands.unbounded_send(command) {
+                error!("Backend core stopped -> {}", e);
             }
         }
     }
 
-    fn add_rooms(&mut self, rooms: &Vec<Room>, send_id: &mpsc::Sender<String>) {
-        for room in rooms.iter() {
-            let row = gtk::ListBoxRow::new();
+    fn is_archive(&self) -> bool {
+        self.commands.is_none()
+    }
 
-            let gtk_box = gtk::EventBox::new();
+    // Asks where to save the history of the open room, then has the backend core fetch and write it
+    fn export_room(&self) {
+        let room = match self.state.borrow().current_room() {
+            Some(room) => room.clone(),
+            None => return,
+        };
 
-            let label = gtk::Label::new(Some(&room.name[..]));
+        // One export at a time
+        if self.export_progress.borrow().is_some() {
+            return;
+        }
 
-            label.set_justify(gtk::Justification::Fill);
-            label.set_halign(gtk::Align::Start);
+        let request = match export_dialog::choose_export(&self.window, &room) {
+            Some(request) => request,
+            None => return,
+        };
 
-            let self_clone = self.clone();
-            // let row_clone = row.clone();
-            let room_clone = room.clone();
-            let sender = send_id.clone();
-
-            row.connect_button_press_event(move |_this, button| {
-                if button.get_button() == 1 {
-                    let id: String = room_clone.id.clone();
-                    sender.send(id.clone()).unwrap();
-
-                    // Use this instead of function to make compiler happy about &mut self
-                    for label in self_clone.scrollable_box.get_children().iter_mut() {
-                        &label.destroy();
-                    }
+        // Closing the dialog early stops the export; once it is done this does nothing
+        let self_clone = self.clone();
+        let progress = ExportProgress::show(&self.window, &room.name[..], move || {
+            self_clone.send_command(Command::CancelExport);
+            *self_clone.export_progress.borrow_mut() = None;
+        });
 
-                    // Hide sidebar after choosing new room
-                    self_clone.sidebar_revealer.set_reveal_child(false);
-                }
+        *self.export_progress.borrow_mut() = Some(progress);
+        self.send_command(Command::Export(request));
+    }
 
-                gtk::Inhibit(false)
-            });
+    // Forgets the token and everything cached for the account, then shows another account or asks to sign in again
+    fn sign_out(&self) {
+        let dialog = gtk::MessageDialog::new(
+            Some(&self.window),
+            gtk::DIALOG_MODAL,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            "Sign out of Gitter?\n\nCached messages and messages waiting to be sent are removed from this computer."
+        );
+        dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+        dialog.add_button("Sign Out", gtk::ResponseType::Accept.into());
+
+        let response = dialog.run();
+        dialog.destroy();
+
+        if response != gtk::ResponseType::Accept.into() {
+            return;
+        }
 
-            /* Meant to allow user to click "enter" key to activate
-            let self_clone = self.clone();
-            let room_clone = room.clone();
-            let sender = send_id.clone();
-
-            row.connect_activate(move |_this| {
-                let sender = send_id.clone();
-                // println!("{:?}", button);
-                println!("Room id is {}", room_clone.id);
-                let id: String = room_clone.id.clone();
-                sender.send(id.clone());
-                self_clone.empty_scrollable_box();
-            });
-            */
+        accounts::remove(&self.account[..]);
+        remove_cache(&accounts::cache_path(&self.account[..]));
 
+        let next = self.account_windows.borrow().iter().find(|window| window.account != self.account).cloned();
+        if let Some(ref next) = next {
+            next.show_account();
+        }
 
-            gtk_box.add(&label);
+        self.close_account();
 
-            row.add(&gtk_box);
+        match next {
+            Some(next) => next.refresh_account_switchers(),
+            None => show_sign_in(self.account_windows.clone()),
+        };
+    }
 
-            self.sidebar.add(&row);
-        }
+    // Signs in again with a token changed in config.yaml
+    fn set_token(&self, token: String) {
+        *self.token.borrow_mut() = token.clone();
+        self.send_command(Command::SetToken(token));
     }
 
-    // Currently a delay here; does not scroll until next thread loop after message received
-    fn scroll_to_bottom(&self) {
-        let bottom_of_page = self.scroll_window.get_vadjustment().unwrap().get_upper();
-        let adjustment = self.scroll_window.get_vadjustment().unwrap();
+    // Stops the account's backend core and removes its window
+    fn close_account(&self) {
+        self.send_command(Command::Shutdown);
+        self.account_windows.borrow_mut().retain(|window| window.account != self.account);
+        self.window.destroy();
+    }
 
-        adjustment.set_value(bottom_of_page);
+    fn add_account(&self) {
+        let windows = self.account_windows.clone();
 
-        self.scroll_window.set_vadjustment(&adjustment);
+        first_run::show_assistant(OAuthClient::from_config(&read_config().unwrap_or_default()), Some(&self.window), move |token, user| {
+            sign_in(windows.clone(), token, user);
+        });
     }
 
-    fn show_all(&self) {
-        self.window.show_all();
+    // Shows this account's window in place of the one on screen
+    fn show_account(&self) {
+        let shown = self.account_windows.borrow().iter()
+            .find(|window| window.account != self.account && window.window.get_visible())
+            .cloned();
+
+        if let Some(shown) = shown {
+            let (x, y) = shown.window.get_position();
+            let (width, height) = shown.window.get_size();
+            self.window.move_(x, y);
+            self.window.resize(width, height);
+            shown.window.hide();
+        }
+
+        self.window.show();
+        self.window.present();
     }
 
-    fn start(&mut self, message_sender: mpsc::Sender<String>) {
-        // Set username in subtitle
-        {
-            let subtitle = format!("@{}", self.user.username);
-            self.headerbar.set_subtitle(&subtitle[..]);
-        }
+    // Every window's switcher lists all signed in accounts, and is hidden while there is only one
+    fn refresh_account_switchers(&self) {
+        let windows = self.account_windows.borrow().clone();
 
-        // Window events
-        {
-            self.window.show_all();
-            self.window.connect_delete_event(|_, _| {
-                gtk::main_quit();
-                Inhibit(false)
-            });
+        for window in windows.iter() {
+            window.updating_account_switcher.set(true);
+            window.account_switcher.remove_all();
+
+            for account in windows.iter() {
+                let label = format!("@{}", account.state.borrow().user.username);
+                window.account_switcher.append(Some(&account.account[..]), &label[..]);
+            }
+
+            window.account_switcher.set_active_id(Some(&window.account[..]));
+            window.account_switcher.set_visible(windows.len() > 1);
+            window.updating_account_switcher.set(false);
         }
+    }
 
-        // Send Button click event
-        {
-            let self_clone = self.clone();
-            let self_clone2 = self.clone();
-            let clone_message_sender = message_sender.clone();
-            // let button_clone = self.send_text_button.clone();
-            self_clone.send_text_button.connect_clicked(move |_| {
-                let text = self_clone2.text_box.get_text().unwrap();
-                println!("{:?}", text);
-                clone_message_sender.send(text).unwrap();
-
-                self_clone2.text_box.set_text("");
-            });
+    // Applies an action to the state and renders whatever it changed
+    fn dispatch(&self, action: Action) {
+        let changes = self.state.borrow_mut().reduce(action);
+
+        for change in changes.into_iter() {
+            self.render(change);
         }
+    }
 
-        // Enter key send message button event
-        {
-            let self_clone = self.clone();
-            let self_clone2 = self.clone();
-            let clone_message_sender = message_sender.clone();
-            // let button_clone = self.send_text_button.clone();
-            self_clone.text_box.connect_key_press_event(move |_this, key| {
-                let enter_key = 65293;
+    fn render(&self, change: Change) {
+        match change {
+            Change::RoomsChanged => self.render_rooms(),
+            Change::RoomCountsChanged(room_id) => self.render_room_counts(&room_id),
+            Change::CurrentRoomChanged => self.render_current_room(),
+            Change::MessagesReset => self.render_messages(),
+            Change::MessagesAppended(ids) => self.append_messages(&ids),
+            Change::MessageEdited(id) => self.update_message_label(&id),
+            Change::MessageFilterChanged => self.filter_messages(),
+            Change::ScrollTo(id) => self.scroll_to_message(&id),
+            Change::ComposerChanged { clear_text } => self.render_composer(clear_text),
+            Change::PendingChanged => self.refresh_pending_rows(),
+            Change::ConnectionChanged => {
+                let state = self.state.borrow().connection;
+                self.show_connection_state(state);
+            },
+        };
+    }
 
-                if enter_key == key.get_keyval() {
-                    let text = self_clone2.text_box.get_text().unwrap();
-                    clone_message_sender.send(text).unwrap();
+    fn append_messages(&self, ids: &Vec<String>) {
+        let (room_id, messages) = {
+            let state = self.state.borrow();
+            let messages: Vec<Message> = ids.iter().filter_map(|id| state.message(id).cloned()).collect();
+            (state.current_room_id.clone(), messages)
+        };
 
-                    self_clone2.text_box.set_text("");
+        if let Some(room_id) = room_id {
+            self.message_cache.borrow_mut().add_messages(&room_id, &messages);
+        }
 
-                    gtk::Inhibit(true);
-                }
+        for message in messages.iter() {
+            self.add_message_row(message);
+        }
 
-                gtk::Inhibit(false)
-            });
+        self.show_all();
+
+        // An archive only appends the page below, which is read from the top
+        if !self.is_archive() {
+            self.scroll_to_bottom();
         }
+    }
 
-        // Sidebar reveal button event
+    fn add_message_row(&self, message: &Message) {
+        let label = self.create_message_label(message);
+
+        let event_box = gtk::EventBox::new();
+        event_box.add(&label);
+
+        // Double click replies to the message
         {
             let self_clone = self.clone();
-            let self_clone2 = self.clone();
-            self_clone.sidebar_button.connect_clicked(move |_this| {
-                let sidebar_revealer = &self_clone2.sidebar_revealer;
-                if sidebar_revealer.get_child_revealed() {
-                    sidebar_revealer.set_reveal_child(false);
-                } else {
-                    sidebar_revealer.set_reveal_child(true);
+            let message_id = message.id.clone();
+            event_box.connect_button_press_event(move |_this, button| {
+                if button.get_event_type() == gdk::EventType::DoubleButtonPress {
+                    self_clone.dispatch(Action::ReplyTo(message_id.clone()));
                 }
+
+                gtk::Inhibit(false)
             });
         }
-    }
-}
 
-// Takes url and token to get data from Gitter API
-fn get_url<T>(url: &String, token: &String) -> Vec<T>
-where T: serde::de::DeserializeOwned + Clone
-{
-    let mut easy = Easy::new();
+        self.scrollable_box.add(&event_box);
 
-    easy.url(&url).unwrap();
+        // Visibility of messages is managed by the in-room search, not window.show_all()
+        event_box.show_all();
+        event_box.set_no_show_all(true);
 
-    let mut list = List::new();
+        if !self.state.borrow().is_visible(message) {
+            event_box.hide();
+        }
 
-    list.append("Accept: application/json").unwrap();
+        self.message_rows.borrow_mut().push(MessageRow {
+            message: message.clone(),
+            widget: event_box,
+            label: label,
+        });
+    }
 
-    list.append(&(format!("Authorization: Bearer {}", token))).unwrap();
+    // History from the cache, shown straight away; the backend only adds what is new.
+    // An archive shows the same page and loads the others as it is scrolled.
+    fn cached_messages(&self, room_id: &String) -> Vec<Message> {
+        match self.store {
+            Some(ref store) => store.load_messages(room_id, CACHED_MESSAGES_SHOWN).unwrap_or_else(|e| {
+                error!("Reading cached messages -> {}", e);
+                vec![]
+            }),
+            None => vec![],
+        }
+    }
 
-    easy.http_headers(list).unwrap();
+    fn render_messages(&self) {
+        self.clear_messages();
 
-    let mut raw_data: Vec<u8> = vec![];
-    {
-        let mut transfer = easy.transfer();
-        transfer.write_function(|new_data| {
-            &raw_data.extend(new_data.iter());
+        let messages = self.state.borrow().messages.clone();
 
-            Ok(new_data.len())
-        }).unwrap();
+        for message in messages.iter() {
+            self.add_message_row(message);
+        }
 
-        transfer.perform().unwrap();
-    };
+        self.scroll_to_bottom();
+    }
 
-    let json_data: Vec<T> = match serde_json::from_slice(&raw_data[..]) {
-        Ok(data) => data,
-        Err(e) => {
-            println!("ERROR Reading Json for {} -> {}", &url, e);
-            let empty: Vec<T> = vec![];
-            empty
-        },
-    };
+    fn clear_messages(&self) {
+        for label in self.scrollable_box.get_children().iter_mut() {
+            &label.destroy();
+        }
 
-    json_data
-}
+        self.message_rows.borrow_mut().clear();
+        self.pending_widgets.borrow_mut().clear();
+    }
 
-// Reads config file found in $HOME/.gitter_gtk/config.yaml or cwd
-fn read_config() -> yaml_rust::Yaml {
-    let config_path = match std::env::var("HOME") {
-        Ok(val) => val + "/.gitter_gtk/config.yaml",
-        Err(e) => {
-            println!("Error: {}\nVariable \"$HOME\", not found. Looking for config.yaml in './'", e);
-            String::from("./config.yaml")
-        },
-    };
+    fn render_composer(&self, clear_text: bool) {
+        let mode = self.state.borrow().composer.clone();
 
-    let error_msg = format!("ERROR: File not found at {}!", &config_path);
-    let mut buffer = String::new();
+        if clear_text {
+            self.text_box.set_text("");
+        }
 
-    // Read config file
-    {
-        match File::open(config_path) {
-            Ok(mut _f) => {
-                println!("Reading from .gitter_gtk");
-                _f.read_to_string(&mut buffer).unwrap();
+        match mode {
+            ComposerMode::Normal => {
+                self.composer_status.hide();
             },
-            Err(_) => match File::open(String::from("./config.yaml")) {
-                Ok(mut _f) => {
-                    println!("Reading from current dir");
-                    _f.read_to_string(&mut buffer).unwrap();
-                },
-                Err(_) => println!("{}", error_msg),
+            ComposerMode::Editing(ref message) => {
+                self.composer_status.set_text("Editing message (Escape to cancel)");
+                self.composer_status.show();
+                self.text_box.set_text(&message.text[..]);
+                self.text_box.set_position(-1);
+            },
+            ComposerMode::Replying(ref message) => {
+                let status = format!("Replying to @{} (Escape to cancel)", message.fromUser.username);
+                self.composer_status.set_text(&status[..]);
+                self.composer_status.show();
             },
         };
+
+        if !mode.is_normal() {
+            self.text_box.grab_focus();
+        }
     }
 
-    let base_config = YamlLoader::load_from_str(&buffer[..]).unwrap();
-    let config = &base_config[0];
+    // Queues the composer text as a new message, reply or edit depending on the composer mode
+    fn submit_composer(&self) {
+        let text = self.text_box.get_text().unwrap_or(String::new());
 
-    config.clone()
-}
+        let queued = {
+            let state = self.state.borrow();
+            match (state.current_room_id.clone(), state.outgoing_message(&text[..])) {
+                (Some(room_id), Some(outgoing)) => OutboxItem::new(&room_id, outgoing),
+                _ => return,
+            }
+        };
+
+        // Shown as "sending…" straight away; the outbox keeps it until the server has it
+        self.send_command(Command::Send(queued.clone()));
+        self.dispatch(Action::Queued(queued));
+    }
 
-fn notification_thread(receiver: mpsc::Receiver<String>) {
-    std::thread::spawn(move || {
-        loop {
-            let body = match receiver.recv() {
-                Ok(msg) => msg,
-                Err(_) => String::from("")
+    // Rebuilds the rows for undelivered messages of the current room, below the chat history
+    fn refresh_pending_rows(&self) {
+        for widget in self.pending_widgets.borrow_mut().drain(..) {
+            widget.destroy();
+        }
+
+        let (username, pending_messages) = {
+            let state = self.state.borrow();
+            let pending_messages: Vec<PendingMessage> = state.current_pending().into_iter().cloned().collect();
+            (state.user.username.clone(), pending_messages)
+        };
+
+        for pending in pending_messages.iter() {
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 10);
+
+            let text = match pending.item.message {
+                OutgoingMessage::New { ref text, .. } => format!("@{}: {}", username, text),
+                OutgoingMessage::Edit { ref text, .. } => format!("@{} (edit): {}", username, text),
             };
 
-            notify_rust::Notification::new()
-                .summary("New message")
-                .body(&body[..])
-                .icon("email")
-                .timeout(5000)
-                .show().unwrap()
-                .wait_for_action({|action|
-                    match action {
-                        "default" => {println!("you clicked \"default\"")},
-                        "clicked" => {println!("that was correct")},
-                        // here "__closed" is a hardcoded keyword
-                        "__closed" => {println!("the notification was closed")},
-                        _ => ()
-                    }
+            let label = gtk::Label::new(Some(&text[..]));
+            label.set_line_wrap(true);
+            label.set_halign(gtk::Align::Start);
+            label.set_sensitive(false);
+            row.add(&label);
+
+            let status = match (&pending.item.state, &pending.server_id) {
+                (&DeliveryState::Failed, _) => String::from("failed"),
+                (_, &Some(_)) => String::from("sent"),
+                _ if pending.item.attempts > 0 => format!("sending… (retry {})", pending.item.attempts),
+                _ => String::from("sending…"),
+            };
+
+            let status_label = gtk::Label::new(None);
+            status_label.set_markup(&format!("<small><i>{}</i></small>", status)[..]);
+            row.add(&status_label);
+
+            if pending.item.state == DeliveryState::Failed {
+                let retry_button = gtk::Button::new_with_label("Retry");
+                let discard_button = gtk::Button::new_with_label("Discard");
+
+                let self_clone = self.clone();
+                let client_id = pending.item.client_id.clone();
+                retry_button.connect_clicked(move |_this| {
+                    self_clone.retry_pending(&client_id);
                 });
 
-            // Prevent multiple notifications filling up too quickly
-            let five_secs = std::time::Duration::from_millis(5000);
-            std::thread::sleep(five_secs);
-        }
-    });
-}
+                let self_clone = self.clone();
+                let client_id = pending.item.client_id.clone();
+                discard_button.connect_clicked(move |_this| {
+                    self_clone.discard_pending(&client_id);
+                });
 
-fn message_thread(message_fetcher: Arc<Mutex<MessageHandler>>, mut message_store: MessageStore, message_sender: mpsc::Sender<MessageStore>) {
-    std::thread::spawn(move || {
-        loop {
-            // Update message store
-            // Scope locks message_fetcher and then unlocks after setting messages
-            {
-                let message_fetcher = message_fetcher.lock().unwrap();
-                let new_messages = message_fetcher.load_messages();
-                &message_store.set_messages(new_messages);
+                row.add(&retry_button);
+                row.add(&discard_button);
             }
 
-            // Send data to GTK thread
-            {
-                let data = (&message_store).clone();
-                message_sender.send(data).unwrap();
-            }
+            self.scrollable_box.add(&row);
+            row.show_all();
 
-            // Sleep for 5s before checking server again
-            {
-                let five_secs = std::time::Duration::from_millis(5000);
-                std::thread::sleep(five_secs);
+            self.pending_widgets.borrow_mut().push(row);
+        }
+    }
+
+    fn retry_pending(&self, client_id: &String) {
+        self.send_command(Command::RetryMessage(client_id.clone()));
+        self.dispatch(Action::RetryPending(client_id.clone()));
+    }
+
+    fn discard_pending(&self, client_id: &String) {
+        self.send_command(Command::DiscardMessage(client_id.clone()));
+        self.dispatch(Action::DiscardPending(client_id.clone()));
+    }
+
+    // Shows the edited text right away instead of waiting for the server
+    fn update_message_label(&self, id: &String) {
+        let message = match self.state.borrow().message(id) {
+            Some(message) => message.clone(),
+            None => return,
+        };
+
+        for row in self.message_rows.borrow_mut().iter_mut() {
+            if &row.message.id == id {
+                row.message = message.clone();
+                row.label.set_text(&message_text(&row.message)[..]);
             }
         }
-    });
-}
+    }
 
-fn room_thread(message_fetcher: Arc<Mutex<MessageHandler>>, room_id_receiver: mpsc::Receiver<String>) {
-    std::thread::spawn(move || {
-        loop {
-            match room_id_receiver.recv() {
-                Ok(id) => {
-                    let mut message_fetcher = message_fetcher.lock().unwrap();
-                    println!("Setting room id to {}", id);
-                    message_fetcher.set_current_room_id(id);
-                },
-                Err(e) => println!("ERROR Room Id Receiver -> {}", e),
+    fn show_connection_state(&self, state: ConnectionState) {
+        let colour = match state {
+            ConnectionState::Online => "#2ecc71",
+            ConnectionState::Connecting => "#95a5a6",
+            ConnectionState::Degraded => "#f39c12",
+            ConnectionState::Offline | ConnectionState::AuthFailed => "#e74c3c",
+        };
+
+        let indicator = format!("<span foreground=\"{}\">●</span> {}", colour, state.description());
+        self.connection_indicator.set_markup(&indicator[..]);
+
+        let message = match state {
+            ConnectionState::Online | ConnectionState::Connecting => None,
+            ConnectionState::Degraded => Some((gtk::MessageType::Warning, "Having trouble reaching Gitter, retrying…")),
+            ConnectionState::Offline => Some((gtk::MessageType::Warning, "You are offline. Messages will be sent when the connection returns.")),
+            ConnectionState::AuthFailed => Some((gtk::MessageType::Error, "Gitter rejected your sign-in. Sign out and sign in again.")),
+        };
+
+        match message {
+            Some((message_type, text)) => {
+                self.connection_infobar.set_message_type(message_type);
+                self.connection_message.set_text(text);
+                self.connection_infobar.show();
+            },
+            None => self.connection_infobar.hide(),
+        };
+    }
+
+    fn current_room_id(&self) -> Option<String> {
+        self.state.borrow().current_room_id.clone()
+    }
+
+    // Enter in the search bar asks the server for the current room, or searches the cache for all rooms
+    fn run_message_search(&self) {
+        let query = self.message_search_entry.get_text().unwrap_or(String::new());
+
+        if query.trim().len() == 0 {
+            self.search_results_revealer.set_reveal_child(false);
+            return;
+        }
+
+        if self.search_all_rooms.get_active() {
+            let results = match self.store {
+                Some(ref store) => store.search_messages(None, &query[..], 100).unwrap_or_else(|e| {
+                    error!("Searching cache -> {}", e);
+                    vec![]
+                }),
+                None => self.message_cache.borrow().search(&query[..]),
             };
+            self.show_search_results(results);
+            return;
         }
-    });
-}
 
-fn outgoing_message_thread(message_fetcher: Arc<Mutex<MessageHandler>>, message_receiver: mpsc::Receiver<String>) {
-    std::thread::spawn(move || {
-        loop {
-            match message_receiver.recv() {
-                Ok(msg) => {
-                    let message_fetcher = message_fetcher.lock().unwrap();
-                    message_fetcher.send_message(msg);
-                },
-                Err(e) => println!("ERROR Outgoing Message Receiver -> {}", e),
+        let room_id = match self.current_room_id() {
+            Some(id) => id,
+            None => return,
+        };
+
+        // An archive has nobody to ask, but all of its messages are in the store
+        if self.is_archive() {
+            let results = match self.store {
+                Some(ref store) => store.search_messages(Some(&room_id), &query[..], 100).unwrap_or_else(|e| {
+                    error!("Searching archive -> {}", e);
+                    vec![]
+                }),
+                None => vec![],
             };
+            self.show_search_results(results);
+            return;
         }
-    });
-}
 
-fn main() {
-    let config = read_config();
+        self.send_command(Command::Search {
+            room_id: room_id,
+            query: query,
+        });
+    }
 
-    let (tx, rx) = mpsc::channel();
-    let (tx_room_id, rx_room_id) = mpsc::channel();
-    let (tx_notification, rx_notification) = mpsc::channel();
-    let (tx_send_message, rx_send_message) = mpsc::channel();
+    fn show_search_results(&self, results: Vec<SearchResult>) {
+        for row in self.search_results.get_children().iter() {
+            row.destroy();
+        }
 
-    let token = String::from(config["token"].as_str().unwrap());
+        if results.len() == 0 {
+            let label = gtk::Label::new(Some("No messages found"));
+            self.search_results.add(&label);
+        }
 
-    let message_store = MessageStore::new();
+        for &(ref room_id, ref message) in results.iter() {
+            let room_name = self.state.borrow().rooms.iter()
+                .find(|room| &room.id == room_id)
+                .map(|room| room.name.clone())
+                .unwrap_or(String::new());
 
-    let user: &User = &get_url::<User>(
-        &String::from("https://api.gitter.im/v1/user"),
-        &String::from(config["token"].as_str().unwrap())
-    )[0];
+            let text = format!("{}  {}", room_name, message_text(message));
 
-    let mut rooms = get_url::<Room>(
-        &String::from("https://api.gitter.im/v1/rooms"),
-        &token
-    );
+            let label = gtk::Label::new(Some(&text[..]));
+            label.set_halign(gtk::Align::Start);
+            label.set_line_wrap(true);
+            label.set_margin_top(3);
+            label.set_margin_bottom(3);
 
-    // Alphabetize first, then split into repos and private chats
-    {
-        rooms.sort_unstable_by(|a, b| {
-            a.name.cmp(&b.name)
-        });
+            self.search_results.add(&label);
+        }
 
-        let conversations = rooms.drain_filter(|x| x.oneToOne).collect::<Vec<_>>();
+        *self.search_result_data.borrow_mut() = results;
 
-        rooms.extend(conversations);
+        self.search_results.show_all();
+        self.search_results_revealer.set_reveal_child(true);
     }
 
-    // Remove mutability from vector
-    let rooms = rooms;
+    // Opens the room of a search result and loads the history around the message
+    fn jump_to_message(&self, room_id: &String, message: &Message) {
+        if self.current_room_id().as_ref() != Some(room_id) {
+            self.switch_room(room_id);
+        }
 
-    let message_fetcher: Arc<Mutex<MessageHandler>> = Arc::new(Mutex::new(MessageHandler::new(
-        &rooms[0].id,
-        &token
-    )));
+        self.message_search_bar.set_search_mode(false);
+        self.search_results_revealer.set_reveal_child(false);
 
-    let mut window = MainWindow::new(user);
-    {
-        window.add_rooms(&rooms, &tx_room_id);
-        window.start(tx_send_message);
-    }
+        if self.is_archive() {
+            self.show_archive_page(room_id, &message.sent[..], &message.id);
+            return;
+        }
 
-    // Start our threads to handle logic and keep GUI thread free
-    {
-        message_thread(message_fetcher.clone(), message_store, tx.clone());
+        self.send_command(Command::LoadAround {
+            room_id: room_id.clone(),
+            message_id: message.id.clone(),
+        });
+    }
 
-        room_thread(message_fetcher.clone(), rx_room_id);
+    // An archive reads its history from the store rather than a server: half a page either side of `sent`
+    fn show_archive_page(&self, room_id: &String, sent: &str, message_id: &String) {
+        let store = match self.store {
+            Some(ref store) => store.clone(),
+            None => return,
+        };
 
-        outgoing_message_thread(message_fetcher.clone(), rx_send_message);
+        let half = CACHED_MESSAGES_SHOWN / 2;
+        let page = store.load_messages_before(room_id, sent, half).and_then(|mut messages| {
+            messages.extend(store.load_messages_from(room_id, sent, half)?);
+            Ok(messages)
+        });
 
-        notification_thread(rx_notification);
+        match page {
+            Ok(messages) => self.show_archive_change(Action::ContextLoaded {
+                room_id: room_id.clone(),
+                message_id: message_id.clone(),
+                messages: messages,
+            }),
+            Err(e) => error!("Reading archived messages -> {}", e),
+        };
     }
 
-    // let mut window_clone = window.clone();
-    gtk::timeout_add(5000, move || {
-        use mpsc::TryRecvError;
+    // Loads the next page of an archive once its history is scrolled to the top or the bottom
+    fn page_archive(&self, adjustment: &gtk::Adjustment) {
+        if self.archive_paging.get() {
+            return;
+        }
+
+        let (room_id, first, last) = {
+            let state = self.state.borrow();
+            let room_id = match state.current_room_id {
+                Some(ref room_id) => room_id.clone(),
+                None => return,
+            };
+            let first = state.messages.first().cloned();
+            let last = state.messages.last().cloned();
+            (room_id, first, last)
+        };
 
-        match rx.try_recv() {
-            Ok(store) => {
-                window.add_messages(&store, tx_notification.clone());
+        let store = match self.store {
+            Some(ref store) => store.clone(),
+            None => return,
+        };
 
-                window.show_all();
-                window.scroll_to_bottom();
+        let at_top = adjustment.get_value() <= adjustment.get_lower();
+        let at_bottom = adjustment.get_value() + adjustment.get_page_size() >= adjustment.get_upper();
 
-                gtk::Continue(true)
+        let action = match (first, last) {
+            (Some(ref first), _) if at_top => {
+                store.load_messages_before(&room_id, &first.sent[..], CACHED_MESSAGES_SHOWN).map(|messages| {
+                    Action::OlderMessagesLoaded { room_id: room_id.clone(), messages: messages }
+                })
             },
-            Err(TryRecvError::Disconnected) => gtk::Continue(false),
-            Err(TryRecvError::Empty) => gtk::Continue(true),
-        }
+            // Starts at the last message shown, which is left out as it is already there
+            (_, Some(ref last)) if at_bottom => {
+                store.load_messages_from(&room_id, &last.sent[..], CACHED_MESSAGES_SHOWN + 1).map(|messages| {
+                    Action::MessagesAdded { room_id: room_id.clone(), messages: messages }
+                })
+            },
+            _ => return,
+        };
 
-    });
+        match action {
+            Ok(action) => self.show_archive_change(action),
+            Err(e) => error!("Reading archived messages -> {}", e),
+        };
+    }
 
-    gtk::main();
+    // Rebuilding the rows scrolls the window about until the message scrolled to is in view
+    fn show_archive_change(&self, action: Action) {
+        self.archive_paging.set(true);
+        self.dispatch(action);
+
+        let paging = self.archive_paging.clone();
+        gtk::timeout_add(200, move || {
+            paging.set(false);
+            gtk::Continue(false)
+        });
+    }
+
+    // Waits for the rows to be allocated, then scrolls the message into view
+    fn scroll_to_message(&self, message_id: &String) {
+        let widget = match self.message_rows.borrow().iter().find(|row| &row.message.id == message_id) {
+            Some(row) => row.widget.clone(),
+            None => return,
+        };
+
+        let self_clone = self.clone();
+        gtk::timeout_add(50, move || {
+            if let Some((_, y)) = widget.translate_coordinates(&self_clone.scrollable_box, 0, 0) {
+                let adjustment = self_clone.scroll_window.get_vadjustment().unwrap();
+                adjustment.set_value(y as f64);
+            }
+
+            gtk::Continue(false)
+        });
+    }
+
+    // Shows the first message of a day, or the first one after it, with the page around it
+    fn go_to_date(&self) {
+        let dialog = gtk::Dialog::new();
+        dialog.set_title("Go to Date");
+        dialog.set_transient_for(Some(&self.window));
+        dialog.set_modal(true);
+        dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+        dialog.add_button("Go", gtk::ResponseType::Accept.into());
+        dialog.set_default_response(gtk::ResponseType::Accept.into());
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 10);
+        content.set_border_width(15);
+
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text("YYYY-MM-DD");
+        entry.set_activates_default(true);
+        let error = gtk::Label::new(None);
+        error.set_halign(gtk::Align::Start);
+
+        content.add(&entry);
+        content.add(&error);
+        dialog.get_content_area().add(&content);
+        dialog.show_all();
+
+        // Stays open while there is nowhere to go, saying why
+        while dialog.run() == gtk::ResponseType::Accept.into() {
+            let text = entry.get_text().unwrap_or(String::new());
+            let date = match export::parse_date(&text[..]) {
+                Ok(date) => date,
+                Err(e) => {
+                    error.set_text(&e[..]);
+                    continue;
+                },
+            };
+
+            let room_id = match self.current_room_id() {
+                Some(room_id) => room_id,
+                None => break,
+            };
+
+            let found = match self.store {
+                Some(ref store) => store.load_messages_from(&room_id, &date[..], 1).unwrap_or_else(|e| {
+                    error!("Reading archived messages -> {}", e);
+                    vec![]
+                }),
+                None => vec![],
+            };
+
+            match found.first() {
+                Some(message) => {
+                    self.show_archive_page(&room_id, &message.sent[..], &message.id);
+                    break;
+                },
+                None => error.set_text(&format!("No messages on or after {}", date)[..]),
+            };
+        }
+
+        dialog.destroy();
+    }
+
+    fn open_message_search(&self) {
+        self.message_search_bar.set_search_mode(true);
+        self.message_search_entry.grab_focus();
+    }
+
+    fn filter_messages(&self) {
+        let state = self.state.borrow();
+
+        for row in self.message_rows.borrow().iter() {
+            if state.is_visible(&row.message) {
+                row.widget.show();
+            } else {
+                row.widget.hide();
+            }
+        }
+    }
+
+    fn scroll_page(&self, pages: f64) {
+        let adjustment = self.scroll_window.get_vadjustment().unwrap();
+
+        let value = adjustment.get_value() + pages * adjustment.get_page_size();
+        adjustment.set_value(value);
+    }
+
+    // Moves to the room above or below the current one in the sidebar, optionally skipping read rooms
+    fn select_adjacent_room(&self, forward: bool, unread_only: bool) {
+        let target = self.state.borrow().adjacent_room(forward, unread_only);
+
+        if let Some(room_id) = target {
+            self.switch_room(&room_id);
+        }
+    }
+
+    // Creates a clickable header row which collapses or expands the rows of its section
+    fn add_section_header(&self, section: &Section, rows: &Vec<gtk::ListBoxRow>) {
+        let row = gtk::ListBoxRow::new();
+
+        let collapsed = self.sidebar_state.borrow().is_collapsed(&section.key[..]);
+
+        let button = gtk::Button::new_with_label(&section_title(section, collapsed)[..]);
+        button.set_relief(gtk::ReliefStyle::None);
+        button.set_halign(gtk::Align::Start);
+
+        let sidebar_state = self.sidebar_state.clone();
+        let section_clone = section.clone();
+        let rows = rows.clone();
+
+        button.connect_clicked(move |this| {
+            let collapsed = !sidebar_state.borrow().is_collapsed(&section_clone.key[..]);
+            sidebar_state.borrow_mut().set_collapsed(&section_clone.key[..], collapsed);
+
+            this.set_label(&section_title(&section_clone, collapsed)[..]);
+
+            for row in rows.iter() {
+                if collapsed {
+                    row.hide();
+                } else {
+                    row.show();
+                }
+            }
+        });
+
+        row.add(&button);
+
+        self.sidebar.add(&row);
+    }
+
+    // Rebuilds the sidebar, e.g. when the room list refreshes after starting from cache
+    fn render_rooms(&self) {
+        for row in self.sidebar.get_children().iter() {
+            row.destroy();
+        }
+
+        self.room_rows.borrow_mut().clear();
+
+        let sections = self.state.borrow().sections.clone();
+        self.add_rooms(&sections);
+
+        self.render_current_room();
+
+        let query = self.sidebar_filter.get_text().unwrap_or(String::new());
+        self.filter_sidebar(&query[..]);
+    }
+
+    fn add_rooms(&self, sections: &Vec<Section>) {
+        for section in sections.iter() {
+            let mut section_rows: Vec<gtk::ListBoxRow> = vec![];
+
+            for room in section.rooms.iter() {
+                let row = gtk::ListBoxRow::new();
+
+                let gtk_box = gtk::EventBox::new();
+
+                let label = gtk::Label::new(Some(&room_label(room)[..]));
+
+                label.set_justify(gtk::Justification::Fill);
+                label.set_halign(gtk::Align::Start);
+                label.set_margin_left(15);
+
+                let self_clone = self.clone();
+                // let row_clone = row.clone();
+                let room_clone = room.clone();
+
+                row.connect_button_press_event(move |_this, button| {
+                    if button.get_button() == 1 {
+                        self_clone.switch_room(&room_clone.id);
+                    }
+
+                    gtk::Inhibit(false)
+                });
+
+                // Allows keyboard users to press "enter" on a focused row
+                let self_clone = self.clone();
+                let room_clone = room.clone();
+
+                row.connect_activate(move |_this| {
+                    self_clone.switch_room(&room_clone.id);
+                });
+
+                gtk_box.add(&label);
+
+                row.add(&gtk_box);
+
+                // Visibility of room rows is managed by their section header, not window.show_all()
+                row.show_all();
+                row.set_no_show_all(true);
+
+                if self.sidebar_state.borrow().is_collapsed(&section.key[..]) {
+                    row.hide();
+                }
+
+                self.room_rows.borrow_mut().push(SidebarRow {
+                    row: row.clone(),
+                    label: label.clone(),
+                    room_id: room.id.clone(),
+                    room_name: room.name.clone(),
+                    section_key: section.key.clone(),
+                });
+
+                section_rows.push(row);
+            }
+
+            self.add_section_header(section, &section_rows);
+
+            for row in section_rows.iter() {
+                self.sidebar.add(row);
+            }
+        }
+    }
+
+    // Relabels one sidebar row after its unread count changed
+    fn render_room_counts(&self, room_id: &String) {
+        let state = self.state.borrow();
+
+        if let Some(room) = state.rooms.iter().find(|room| &room.id == room_id) {
+            for sidebar_row in self.room_rows.borrow().iter().filter(|row| &row.room_id == room_id) {
+                sidebar_row.label.set_text(&room_label(room)[..]);
+            }
+        }
+    }
+
+    // Highlights the current room in the sidebar and headerbar
+    fn render_current_room(&self) {
+        let state = self.state.borrow();
+
+        if let Some(room) = state.current_room() {
+            self.headerbar.set_title(&room.name[..]);
+        }
+
+        if let Some(ref room_id) = state.current_room_id {
+            if let Some(sidebar_row) = self.room_rows.borrow().iter().find(|row| &row.room_id == room_id) {
+                self.sidebar.select_row(Some(&sidebar_row.row));
+            }
+        }
+    }
+
+    // Opens a room given on the command line by name, URL path or id
+    fn open_room_named(&self, query: &str) {
+        let room_id = cli::find_room(&self.state.borrow().rooms, query).map(|room| room.id.clone());
+
+        match room_id {
+            Some(room_id) => self.switch_room(&room_id),
+            None => error!("@{} is not in a room called {}", self.state.borrow().user.username, query),
+        };
+    }
+
+    // Shows the room with its cached history; the backend core is told separately
+    fn open_room(&self, room_id: &String) {
+        let cached = self.cached_messages(room_id);
+
+        self.dispatch(Action::SwitchRoom {
+            room_id: room_id.clone(),
+            cached: cached,
+        });
+    }
+
+    fn switch_room(&self, room_id: &String) {
+        self.send_command(Command::SwitchRoom(room_id.clone()));
+
+        self.message_search_bar.set_search_mode(false);
+        self.search_results_revealer.set_reveal_child(false);
+
+        self.open_room(room_id);
+
+        // Hide sidebar after choosing new room
+        self.sidebar_revealer.set_reveal_child(false);
+    }
+
+    // Shows only rooms whose name fuzzy matches the query, or restores the collapsed sections
+    fn filter_sidebar(&self, query: &str) {
+        let sidebar_state = self.sidebar_state.borrow();
+
+        for sidebar_row in self.room_rows.borrow().iter() {
+            let visible = if query.trim().len() == 0 {
+                !sidebar_state.is_collapsed(&sidebar_row.section_key[..])
+            } else {
+                fuzzy::matches(query, &sidebar_row.room_name[..])
+            };
+
+            if visible {
+                sidebar_row.row.show();
+            } else {
+                sidebar_row.row.hide();
+            }
+        }
+    }
+
+    // Popup listing the best matching rooms; Enter switches to the selected one
+    fn open_quick_switcher(&self) {
+        let popup = gtk::Window::new(gtk::WindowType::Toplevel);
+        popup.set_transient_for(Some(&self.window));
+        popup.set_modal(true);
+        popup.set_decorated(false);
+        popup.set_position(gtk::WindowPosition::CenterOnParent);
+        popup.set_default_size(350, -1);
+
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 5);
+        container.set_border_width(10);
+
+        let entry = gtk::SearchEntry::new();
+        entry.set_placeholder_text("Switch to room...");
+
+        let results = gtk::ListBox::new();
+        let ranked: Rc<RefCell<Vec<Room>>> = Rc::new(RefCell::new(vec![]));
+
+        container.add(&entry);
+        container.add(&results);
+        popup.add(&container);
+
+        {
+            let state = self.state.borrow();
+            fill_switcher_results(&results, &ranked, &state.rooms, &state.recent_rooms, "");
+        }
+
+        {
+            let self_clone = self.clone();
+            let results = results.clone();
+            let ranked = ranked.clone();
+            entry.connect_changed(move |this| {
+                let query = this.get_text().unwrap_or(String::new());
+                let state = self_clone.state.borrow();
+                fill_switcher_results(&results, &ranked, &state.rooms, &state.recent_rooms, &query[..]);
+            });
+        }
+
+        {
+            let self_clone = self.clone();
+            let popup_clone = popup.clone();
+            entry.connect_key_press_event(move |_this, event| {
+                use gdk::enums::key;
+
+                let selected = results.get_selected_row().map(|row| row.get_index()).unwrap_or(0);
+
+                match event.get_keyval() {
+                    key::Escape => {
+                        popup_clone.destroy();
+                        gtk::Inhibit(true)
+                    },
+                    key::Down | key::Up => {
+                        let next = if event.get_keyval() == key::Down { selected + 1 } else { selected - 1 };
+                        if let Some(row) = results.get_row_at_index(next) {
+                            results.select_row(Some(&row));
+                        }
+                        gtk::Inhibit(true)
+                    },
+                    key::Return | key::KP_Enter => {
+                        let room_id = ranked.borrow().get(selected as usize).map(|room| room.id.clone());
+                        if let Some(room_id) = room_id {
+                            self_clone.switch_room(&room_id);
+                        }
+                        popup_clone.destroy();
+                        gtk::Inhibit(true)
+                    },
+                    _ => gtk::Inhibit(false),
+                }
+            });
+        }
+
+        popup.show_all();
+    }
+
+    // Currently a delay here; does not scroll until next thread loop after message received
+    fn scroll_to_bottom(&self) {
+        let bottom_of_page = self.scroll_window.get_vadjustment().unwrap().get_upper();
+        let adjustment = self.scroll_window.get_vadjustment().unwrap();
+
+        adjustment.set_value(bottom_of_page);
+
+        self.scroll_window.set_vadjustment(&adjustment);
+    }
+
+    fn show_all(&self) {
+        self.window.show_all();
+    }
+
+    fn start(&self) {
+        // Open the first room shown in the sidebar; the backend core starts with the same room
+        {
+            self.render_rooms();
+
+            let first_room_id = self.state.borrow().rooms.get(0).map(|room| room.id.clone());
+            if let Some(room_id) = first_room_id {
+                self.open_room(&room_id);
+            }
+        }
+
+        // Set username in subtitle, or the file of an archive
+        {
+            let subtitle = if self.is_archive() {
+                format!("{} (read-only)", self.account)
+            } else {
+                format!("@{}", self.state.borrow().user.username)
+            };
+            self.headerbar.set_subtitle(&subtitle[..]);
+        }
+
+        // Window events
+        {
+            self.window.show_all();
+            self.window.connect_delete_event(|_, _| {
+                gtk::main_quit();
+                Inhibit(false)
+            });
+        }
+
+        // An archive has nothing to send, sign in to or fetch, but can be browsed by date
+        if self.is_archive() {
+            if let Some(composer) = self.text_box.get_parent() {
+                composer.set_no_show_all(true);
+                composer.hide();
+            }
+            self.connection_indicator.set_no_show_all(true);
+            self.connection_indicator.hide();
+
+            self.add_account_item.hide();
+            self.export_room_item.hide();
+            self.sign_out_item.hide();
+            self.go_to_date_item.show();
+
+            let self_clone = self.clone();
+            self.scroll_window.get_vadjustment().unwrap().connect_value_changed(move |adjustment| {
+                self_clone.page_archive(adjustment);
+            });
+        }
+
+        // Send Button click event
+        {
+            let self_clone = self.clone();
+            self.send_text_button.connect_clicked(move |_| {
+                self_clone.submit_composer();
+            });
+        }
+
+        // Composer keys: Enter sends, Escape cancels edit/reply, Up edits the last own message
+        {
+            let self_clone = self.clone();
+            self.text_box.connect_key_press_event(move |this, event| {
+                use gdk::enums::key;
+
+                match event.get_keyval() {
+                    key::Return | key::KP_Enter => {
+                        self_clone.submit_composer();
+                        gtk::Inhibit(true)
+                    },
+                    key::Escape if !self_clone.state.borrow().composer.is_normal() => {
+                        self_clone.dispatch(Action::CancelComposer);
+                        gtk::Inhibit(true)
+                    },
+                    key::Up if this.get_text().unwrap_or_default().len() == 0 => {
+                        self_clone.dispatch(Action::EditLastOwnMessage);
+                        gtk::Inhibit(true)
+                    },
+                    _ => gtk::Inhibit(false),
+                }
+            });
+        }
+
+        // Window wide shortcuts, listed in shortcuts::SHORTCUTS
+        {
+            let self_clone = self.clone();
+            self.window.connect_key_press_event(move |this, event| {
+                use gdk::enums::key;
+
+                let keyval = event.get_keyval();
+                let state = event.get_state();
+                let ctrl = state.contains(gdk::CONTROL_MASK);
+                let alt = state.contains(gdk::MOD1_MASK);
+                let shift = state.contains(gdk::SHIFT_MASK);
+
+                // Entries and text views, like the composer, use the page keys themselves
+                let editing = this.get_focus()
+                    .map_or(false, |widget| widget.is::<gtk::Editable>() || widget.is::<gtk::TextView>());
+
+                match keyval {
+                    key::k if ctrl => self_clone.open_quick_switcher(),
+                    key::f if ctrl => self_clone.open_message_search(),
+                    key::Up | key::Down if alt => self_clone.select_adjacent_room(keyval == key::Down, shift),
+                    key::Page_Up if !editing => self_clone.scroll_page(-1.0),
+                    key::Page_Down if !editing => self_clone.scroll_page(1.0),
+                    key::question | key::slash if ctrl => shortcuts::show_shortcuts_window(&self_clone.window),
+                    key::F1 => shortcuts::show_shortcuts_window(&self_clone.window),
+                    _ => return gtk::Inhibit(false),
+                };
+
+                gtk::Inhibit(true)
+            });
+        }
+
+        // In-room search hides messages which do not match
+        {
+            self.message_search_bar.connect_entry(&self.message_search_entry);
+
+            let self_clone = self.clone();
+            self.message_search_entry.connect_changed(move |this| {
+                let query = this.get_text().unwrap_or(String::new());
+                self_clone.dispatch(Action::FilterMessages(query));
+            });
+
+            let self_clone = self.clone();
+            self.message_search_entry.connect_activate(move |_this| {
+                self_clone.run_message_search();
+            });
+
+            let self_clone = self.clone();
+            self.search_all_rooms.connect_toggled(move |_this| {
+                self_clone.run_message_search();
+            });
+
+            let self_clone = self.clone();
+            self.search_results.connect_row_activated(move |_this, row| {
+                let result = self_clone.search_result_data.borrow().get(row.get_index() as usize).cloned();
+
+                if let Some((room_id, message)) = result {
+                    self_clone.jump_to_message(&room_id, &message);
+                }
+            });
+        }
+
+        // Sidebar filter events; Enter opens the best match
+        {
+            let self_clone = self.clone();
+            self.sidebar_filter.connect_changed(move |this| {
+                let query = this.get_text().unwrap_or(String::new());
+                self_clone.filter_sidebar(&query[..]);
+            });
+
+            let self_clone = self.clone();
+            self.sidebar_filter.connect_activate(move |this| {
+                let query = this.get_text().unwrap_or(String::new());
+                let best = {
+                    let state = self_clone.state.borrow();
+                    switcher::rank_rooms(&query[..], &state.rooms, &state.recent_rooms).into_iter().next()
+                };
+
+                if let Some(room) = best {
+                    this.set_text("");
+                    self_clone.switch_room(&room.id);
+                }
+            });
+        }
+
+        // Retry now skips every wait in the backend core
+        {
+            let self_clone = self.clone();
+            self.connection_retry_button.connect_clicked(move |_this| {
+                self_clone.send_command(Command::RetryNow);
+            });
+        }
+
+        // Header bar menu and account switcher
+        {
+            let self_clone = self.clone();
+            self.sign_out_item.connect_activate(move |_this| {
+                self_clone.sign_out();
+            });
+
+            let self_clone = self.clone();
+            self.add_account_item.connect_activate(move |_this| {
+                self_clone.add_account();
+            });
+
+            let self_clone = self.clone();
+            self.export_room_item.connect_activate(move |_this| {
+                self_clone.export_room();
+            });
+
+            let self_clone = self.clone();
+            self.go_to_date_item.connect_activate(move |_this| {
+                self_clone.go_to_date();
+            });
+
+            let self_clone = self.clone();
+            self.preferences_item.connect_activate(move |_this| {
+                let windows = self_clone.account_windows.clone();
+                preferences::show_preferences(&self_clone.window, move |config| apply_settings(&windows, config));
+            });
+
+            let self_clone = self.clone();
+            self.account_switcher.connect_changed(move |this| {
+                if self_clone.updating_account_switcher.get() {
+                    return;
+                }
+
+                let account = match this.get_active_id() {
+                    Some(account) => account,
+                    None => return,
+                };
+
+                let other = self_clone.account_windows.borrow().iter().find(|window| window.account == account).cloned();
+
+                if let Some(other) = other {
+                    if other.account != self_clone.account {
+                        // This switcher keeps showing its own account for when the window returns
+                        self_clone.updating_account_switcher.set(true);
+                        this.set_active_id(Some(&self_clone.account[..]));
+                        self_clone.updating_account_switcher.set(false);
+
+                        other.show_account();
+                    }
+                }
+            });
+        }
+
+        // Sidebar reveal button event
+        {
+            let self_clone = self.clone();
+            let self_clone2 = self.clone();
+            self_clone.sidebar_button.connect_clicked(move |_this| {
+                let sidebar_revealer = &self_clone2.sidebar_revealer;
+                if sidebar_revealer.get_child_revealed() {
+                    sidebar_revealer.set_reveal_child(false);
+                } else {
+                    sidebar_revealer.set_reveal_child(true);
+                }
+            });
+        }
+    }
+}
+
+// Text shown for a message in the chat
+fn message_text(message: &Message) -> String {
+    format!("@{}: {}", message.fromUser.username, message.text)
+}
+
+// Header text for a sidebar section, with an arrow showing whether it is collapsed
+fn section_title(section: &Section, collapsed: bool) -> String {
+    let arrow = if collapsed { "▸" } else { "▾" };
+    format!("{} {} ({})", arrow, section.title, section.rooms.len())
+}
+
+// Room name with its unread count, for the sidebar and the quick switcher
+fn room_label(room: &Room) -> String {
+    if room.unreadItems > 0 {
+        format!("{} ({})", room.name, room.unreadItems)
+    } else {
+        room.name.clone()
+    }
+}
+
+// Replaces the quick switcher rows with the rooms ranked for the query
+fn fill_switcher_results(list: &gtk::ListBox, ranked: &Rc<RefCell<Vec<Room>>>, rooms: &Vec<Room>, recent_rooms: &Vec<String>, query: &str) {
+    for row in list.get_children().iter() {
+        row.destroy();
+    }
+
+    let new_ranked = switcher::rank_rooms(query, rooms, recent_rooms);
+
+    for room in new_ranked.iter() {
+        let label = gtk::Label::new(Some(&room_label(room)[..]));
+        label.set_halign(gtk::Align::Start);
+
+        list.add(&label);
+    }
+
+    list.show_all();
+
+    if let Some(row) = list.get_row_at_index(0) {
+        list.select_row(Some(&row));
+    }
+
+    *ranked.borrow_mut() = new_ranked;
+}
+
+// Reads config.yaml and moves plaintext tokens, also those of earlier versions, into the keyring
+fn read_config() -> Result<Config, ConfigError> {
+    config::load().map(migrate_tokens)
+}
+
+fn migrate_tokens(mut config: Config) -> Config {
+    if accounts::migrate_tokens(&mut config, Keyring::connect) {
+        if let Err(e) = config::save(&config) {
+            error!("Saving config.yaml -> {}", e);
+        }
+    }
+
+    config
+}
+
+// Applies saved settings to the look of the app and to the backend core of every account
+fn apply_settings(windows: &AccountWindows, config: &Config) {
+    logging::configure(config);
+    network::configure(config);
+    preferences::apply_appearance(config);
+
+    for window in windows.borrow().iter() {
+        window.send_command(Command::UpdateSettings {
+            poll_interval_secs: config.poll_interval_secs,
+            notifications: config.notifications,
+        });
+    }
+}
+
+fn save_account(cache: &Option<SqliteStore>, user: &User, rooms: &Vec<Room>, groups: &Vec<Group>) {
+    if let Some(ref cache) = *cache {
+        let result = cache.save_current_user(user)
+            .and_then(|_| cache.save_rooms(rooms))
+            .and_then(|_| cache.save_groups(groups));
+
+        if let Err(e) = result {
+            error!("Caching account -> {}", e);
+        }
+    }
+}
+
+// The GUI and the backend core each open their own connection to the cache; the app keeps working without one
+fn open_cache(cache_path: &Option<PathBuf>) -> Option<SqliteStore> {
+    match *cache_path {
+        Some(ref path) => match SqliteStore::open(path) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                error!("Opening cache at {:?} -> {}", path, e);
+                None
+            },
+        },
+        None => None,
+    }
+}
+
+// Removes the cache of a signed out account, along with SQLite's write-ahead log
+fn remove_cache(cache_path: &Option<PathBuf>) {
+    if let Some(ref path) = *cache_path {
+        for suffix in &["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            fs::remove_file(&file).unwrap_or(());
+        }
+    }
+}
+
+fn main() {
+    let options = cli::parse();
+    options.apply();
+
+    // Subcommands run without a window
+    if let Some(ref command) = options.command {
+        let config = read_config().unwrap_or_else(|e| {
+            eprintln!("Could not read the config, using the defaults -> {}", e);
+            Config::default()
+        });
+        logging::configure(&config);
+        network::configure(&config);
+
+        std::process::exit(cli::run(&options, command, &config));
+    }
+
+    if gtk::init().is_err() {
+        error!("Failed to initialize GTK.");
+        return;
+    }
+
+    // A broken config.yaml is reported and left alone; the app runs with the defaults
+    let config = match read_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Reading config.yaml, using the defaults -> {}", e);
+            preferences::show_config_error(None, &e);
+            Config::default()
+        },
+    };
+    logging::configure(&config);
+    network::configure(&config);
+    preferences::apply_appearance(&config);
+
+    // --archive opens a file instead of any account, and needs neither a token nor the network
+    if let Some(ref path) = options.archive {
+        match archive::open(path) {
+            Ok(archive) => start_archive(archive, &options),
+            Err(e) => {
+                error!("Opening the archive {} -> {}", path.display(), e);
+                std::process::exit(1);
+            },
+        };
+
+        gtk::main();
+        return;
+    }
+
+    // --token-file replaces the saved accounts for this session
+    let token_file_account = match options.token_file {
+        Some(ref path) => match cli::token_file_account(path) {
+            Ok(account) => Some(account),
+            Err(e) => {
+                error!("Reading the token from {} -> {}", path.display(), e);
+                std::process::exit(1);
+            },
+        },
+        None => None,
+    };
+    let windows: AccountWindows = Rc::new(RefCell::new(vec![]));
+
+    watch_config(windows.clone(), config.clone(), options.token_file.is_none());
+
+    match token_file_account {
+        Some(account) => start_accounts(vec![account], windows, config, options),
+        None => {
+            let loading = keyring_loading_window();
+            let loaded = Rc::new(Cell::new(false));
+
+            // Only shown when the keyring takes a while, e.g. to ask for its password
+            {
+                let loading = loading.clone();
+                let loaded = loaded.clone();
+                gtk::timeout_add(KEYRING_LOADING_DELAY_MILLIS, move || {
+                    if !loaded.get() {
+                        loading.show_all();
+                    }
+
+                    gtk::Continue(false)
+                });
+            }
+
+            load_accounts(config.clone(), move |accounts| {
+                loaded.set(true);
+                loading.destroy();
+                start_accounts(accounts, windows, config, options);
+            });
+        },
+    };
+
+    gtk::main();
+}
+
+// Opens a window for each account, or asks to sign in when there is none
+fn start_accounts(accounts: Vec<Account>, windows: AccountWindows, config: Config, options: Options) {
+    // Nothing to sign in with yet, so ask first
+    if accounts.len() == 0 {
+        show_sign_in(windows);
+        return;
+    }
+
+    // Started from the main loop, so it can be quit when no account could be loaded
+    gtk::idle_add(move || {
+        let mut rejected = false;
+
+        for account in accounts.iter() {
+            if let Err(ApiError::Http(401)) = start_account(account.clone(), windows.clone(), &config) {
+                rejected = true;
+            }
+        }
+
+        open_launch_room(&windows, &options);
+
+        if windows.borrow().is_empty() {
+            // A token from --token-file is not replaced by signing in
+            if rejected && options.token_file.is_none() {
+                show_sign_in(windows.clone());
+            } else {
+                gtk::main_quit();
+            }
+        }
+
+        gtk::Continue(false)
+    });
+}
+
+// Reads the accounts' tokens on another thread, as the keyring may wait for the user to unlock
+// it; `loaded` then runs on the GTK thread
+fn load_accounts<F>(config: Config, loaded: F) where F: FnOnce(Vec<Account>) + 'static {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        sender.send(accounts::load(&config)).unwrap_or(());
+    });
+
+    let mut loaded = Some(loaded);
+    gtk::timeout_add(50, move || {
+        let accounts = match receiver.try_recv() {
+            Ok(accounts) => accounts,
+            Err(mpsc::TryRecvError::Empty) => return gtk::Continue(true),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                error!("Reading the tokens of the accounts stopped without an answer");
+                vec![]
+            },
+        };
+
+        if let Some(loaded) = loaded.take() {
+            loaded(accounts);
+        }
+
+        gtk::Continue(false)
+    });
+}
+
+// Says what the app is waiting for while the keyring is unlocked; closing it quits
+fn keyring_loading_window() -> gtk::Window {
+    let window = gtk::Window::new(gtk::WindowType::Toplevel);
+    window.set_title("GtkGitter");
+    window.set_position(gtk::WindowPosition::Center);
+
+    let page = gtk::Box::new(gtk::Orientation::Vertical, 12);
+    page.set_border_width(24);
+
+    let spinner = gtk::Spinner::new();
+    spinner.start();
+
+    page.pack_start(&spinner, false, false, 0);
+    page.pack_start(&gtk::Label::new(Some("Reading your accounts from the keyring…")), false, false, 0);
+    window.add(&page);
+
+    window.connect_delete_event(|_, _| {
+        gtk::main_quit();
+        gtk::Inhibit(false)
+    });
+
+    window
+}
+
+// Applies edits to config.yaml while the app runs. An edit which does not load is
+// rejected with an error, and the last good settings stay in use.
+fn watch_config(windows: AccountWindows, config: Config, manage_accounts: bool) {
+    let last_good = RefCell::new(config);
+    let error_dialog: RefCell<Option<gtk::MessageDialog>> = RefCell::new(None);
+
+    config_watch::watch(move || {
+        if let Some(dialog) = error_dialog.borrow_mut().take() {
+            dialog.destroy();
+        }
+
+        // An empty or missing file is rejected too, so the last good settings stay
+        let config = match config::reload().map(migrate_tokens) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Rejected changes to config.yaml -> {}", e);
+                let parent = windows.borrow().iter().find(|window| window.window.get_visible()).map(|window| window.window.clone());
+                *error_dialog.borrow_mut() = Some(preferences::show_rejected_config(parent.as_ref(), &e));
+                return;
+            },
+        };
+
+        // Also reached when the app wrote the file itself
+        if *last_good.borrow() == config {
+            return;
+        }
+
+        apply_settings(&windows, &config);
+        if manage_accounts {
+            update_accounts(&windows, &config);
+        }
+        *last_good.borrow_mut() = config;
+    });
+}
+
+// Opens the room given with --room in the window on screen
+fn open_launch_room(windows: &AccountWindows, options: &Options) {
+    let room = match options.room {
+        Some(ref room) => room,
+        None => return,
+    };
+
+    let shown = windows.borrow().iter().find(|window| window.window.get_visible()).cloned();
+    if let Some(window) = shown {
+        window.open_room_named(&room[..]);
+    }
+}
+
+// Signs accounts whose token changed in again, and opens accounts added to config.yaml
+fn update_accounts(windows: &AccountWindows, config: &Config) {
+    let windows = windows.clone();
+    let new_config = config.clone();
+
+    load_accounts(config.clone(), move |accounts| {
+        for account in accounts {
+            let open = windows.borrow().iter().find(|window| window.account == account.name).cloned();
+
+            match open {
+                Some(window) => {
+                    if *window.token.borrow() != account.token {
+                        info!("The token of {} changed, signing in again", account.name);
+                        window.set_token(account.token);
+                    }
+                },
+                None => {
+                    start_account(account, windows.clone(), &new_config).ok();
+                },
+            };
+        }
+    });
+}
+
+// Shows the sign-in assistant while no account is open; cancelling it quits
+fn show_sign_in(windows: AccountWindows) {
+    first_run::show_assistant(OAuthClient::from_config(&read_config().unwrap_or_default()), None, move |token, user| {
+        sign_in(windows.clone(), token, user);
+    });
+}
+
+// Remembers a newly signed in account and shows it; signing in again to an open account replaces its token
+fn sign_in(windows: AccountWindows, token: String, user: User) {
+    let open = windows.borrow().iter().find(|window| window.state.borrow().user.id == user.id).cloned();
+
+    let name = match open {
+        Some(ref window) => window.account.clone(),
+        None => user.username.clone(),
+    };
+    accounts::save(&name[..], &token[..]);
+
+    if let Some(window) = open {
+        window.close_account();
+    }
+
+    let config = read_config().unwrap_or_default();
+    let account = Account {
+        endpoints: accounts::endpoints(&config, &name[..]),
+        name: name,
+        token: token,
+    };
+
+    match start_account(account, windows.clone(), &config) {
+        Ok(window) => window.show_account(),
+        Err(_) if windows.borrow().is_empty() => show_sign_in(windows.clone()),
+        Err(_) => (),
+    };
+}
+
+// A token from --token-file may belong to anyone, so nothing is cached for it
+fn account_cache_path(account: &Account) -> Option<PathBuf> {
+    if account.name == cli::TOKEN_FILE_ACCOUNT {
+        None
+    } else {
+        accounts::cache_path(&account.name[..])
+    }
+}
+
+// The user, rooms and groups of an account, and whether they came from the cache.
+// Starts instantly from cache and refreshes in the background; only the first run waits on the network.
+fn load_account(account: &Account, cache: &Option<SqliteStore>) -> Result<(User, Vec<Room>, Vec<Group>, bool), ApiError> {
+    let (cached_user, cached_rooms, cached_groups) = match *cache {
+        Some(ref cache) => (
+            cache.load_current_user().unwrap_or(None),
+            cache.load_rooms().unwrap_or(vec![]),
+            cache.load_groups().unwrap_or(vec![]),
+        ),
+        None => (None, vec![], vec![]),
+    };
+
+    if let Some(user) = cached_user {
+        if cached_rooms.len() > 0 {
+            return Ok((user, cached_rooms, cached_groups, true));
+        }
+    }
+
+    let (user, rooms, groups) = chat::connect(&String::new(), &account.token, &account.endpoints).fetch_account()?;
+    save_account(cache, &user, &rooms, &groups);
+
+    Ok((user, rooms, groups, false))
+}
+
+// Opens the window of an archive; it has no backend core, its store stands in for the server
+fn start_archive(archive: Archive, options: &Options) {
+    let app_state = AppState::new(&archive.user, &archive.rooms, &vec![]);
+
+    let account = Account {
+        name: archive.name,
+        token: String::new(),
+        endpoints: Endpoints::default(),
+    };
+
+    let windows: AccountWindows = Rc::new(RefCell::new(vec![]));
+    let window = MainWindow::new(app_state, None, Some(archive.store), account, windows.clone());
+    window.start();
+
+    windows.borrow_mut().push(window);
+    open_launch_room(&windows, options);
+}
+
+// Opens the window of a signed in account, hidden when another account is already shown
+fn start_account(account: Account, windows: AccountWindows, config: &Config) -> Result<MainWindow, ApiError> {
+    let token = account.token.clone();
+    let cache_path = account_cache_path(&account);
+    let cache = open_cache(&cache_path);
+
+    let (user, rooms, groups, started_from_cache) = match load_account(&account, &cache) {
+        Ok(loaded) => loaded,
+        Err(ApiError::Http(401)) => {
+            error!("Gitter rejected the saved token of {}, it has to sign in again", account.name);
+            if account.name != cli::TOKEN_FILE_ACCOUNT {
+                accounts::mark_needs_sign_in(&account.name[..]);
+            }
+            return Err(ApiError::Http(401));
+        },
+        Err(e) => {
+            error!("Could not load the account {} from Gitter and nothing is cached yet -> {}", account.name, e);
+            return Err(e);
+        },
+    };
+
+    // Splits rooms into Favourites, communities and private chats, each alphabetized
+    let app_state = AppState::new(&user, &rooms, &groups);
+
+    // The first room shown in the sidebar is opened first
+    let first_room_id = app_state.rooms.get(0).map(|room| room.id.clone()).unwrap_or(String::new());
+
+    // All network work happens in the backend core; its events reach the GUI as soon as they are sent
+    let (events, event_receiver) = ui_channel::channel();
+    let commands = backend::start(BackendConfig {
+        token: token,
+        endpoints: account.endpoints.clone(),
+        user: user,
+        room_id: first_room_id,
+        cache_path: cache_path,
+        refresh_account: started_from_cache,
+        poll_interval_secs: config.poll_interval_secs,
+        notifications: config.notifications,
+    }, events);
+
+    let shown = windows.borrow().iter().any(|window| window.window.get_visible());
+
+    let window = MainWindow::new(app_state, Some(commands.clone()), cache, account, windows.clone());
+    window.start();
+    if shown {
+        window.window.hide();
+    }
+
+    windows.borrow_mut().push(window.clone());
+    window.refresh_account_switchers();
+
+    connection::network_manager_thread(commands);
+
+    {
+        let window = window.clone();
+        event_receiver.attach(move |event| window.handle_event(event));
+    }
+
+    Ok(window)
 }
@@ -0,0 +1,646 @@
+// The Matrix client-server API, so an account on a homeserver, e.g. one bridged to
+// Gitter with rooms like #org_repo:gitter.im, works in the same window as a Gitter
+// account. Rooms come from /sync, and new messages from a /sync long poll filtered
+// to the open room.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use curl::easy::{Easy, List};
+use serde_json;
+use serde_json::Value;
+
+use api;
+use api::{ApiError, Request};
+use chat::{Call, ChatBackend};
+use logging;
+use {Group, Mention, Message, Room, User};
+
+const CLIENT_API: &'static str = "/_matrix/client/v3";
+
+// How long the homeserver holds the stream's /sync open when nothing happens;
+// below the request timeout of api::new_request_handle
+const LONG_POLL_MILLIS: u32 = 20000;
+
+// Messages in the first answer of the stream, which has nothing to continue from
+const INITIAL_TIMELINE_LIMIT: u32 = 15;
+
+// Events asked for per page of history; /context splits them between both sides of the event
+const HISTORY_PAGE_SIZE: u32 = 100;
+
+#[derive(Deserialize, Debug)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginResponse {
+    user_id: String,
+    access_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: SyncRooms,
+    #[serde(default)]
+    account_data: Events,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct SyncRooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    leave: HashMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct JoinedRoom {
+    #[serde(default)]
+    state: Events,
+    #[serde(default)]
+    timeline: Events,
+    #[serde(default)]
+    account_data: Events,
+    #[serde(default)]
+    unread_notifications: UnreadNotifications,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct UnreadNotifications {
+    #[serde(default)]
+    highlight_count: u32,
+    #[serde(default)]
+    notification_count: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Events {
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    event_id: String,
+    #[serde(default)]
+    sender: String,
+    #[serde(default)]
+    origin_server_ts: u64,
+    #[serde(default)]
+    content: Value,
+}
+
+// Newest first, as asked for with dir=b
+#[derive(Deserialize, Debug)]
+struct RoomMessages {
+    chunk: Vec<Event>,
+}
+
+// events_before is newest first, events_after oldest first
+#[derive(Deserialize, Debug)]
+struct Context {
+    #[serde(default)]
+    events_before: Vec<Event>,
+    event: Event,
+    #[serde(default)]
+    events_after: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    search_categories: SearchCategories,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchCategories {
+    room_events: SearchResults,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResults {
+    #[serde(default)]
+    results: Vec<SearchResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResult {
+    result: Event,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventId {
+    event_id: String,
+}
+
+// What the sidebar shows of one room, from the events seen so far
+#[derive(Debug, Default)]
+struct RoomState {
+    name: Option<String>,
+    alias: Option<String>,
+    topic: Option<String>,
+    favourite: Option<u32>,
+    highlight_count: u32,
+    notification_count: u32,
+}
+
+// The rooms as of the last /sync of rooms_call, which continues from a next_batch of its own;
+// later answers only hold the rooms and state which changed since
+#[derive(Debug, Default)]
+struct RoomList {
+    next_batch: Option<String>,
+    rooms: BTreeMap<String, RoomState>,
+    direct: Vec<String>,
+}
+
+impl RoomList {
+    fn update(&mut self, sync: &SyncResponse) {
+        self.next_batch = Some(sync.next_batch.clone());
+
+        // m.direct lists the rooms shared with each user as a direct chat
+        if let Some(direct) = sync.account_data.events.iter().filter(|event| event.kind == "m.direct").last() {
+            self.direct = direct.content.as_object().iter()
+                .flat_map(|users| users.values())
+                .filter_map(|room_ids| room_ids.as_array())
+                .flat_map(|room_ids| room_ids.iter())
+                .filter_map(|room_id| room_id.as_str().map(String::from))
+                .collect();
+        }
+
+        for id in sync.rooms.leave.keys() {
+            self.rooms.remove(id);
+        }
+
+        for (id, room) in sync.rooms.join.iter() {
+            let known = self.rooms.entry(id.clone()).or_insert_with(RoomState::default);
+
+            // The latest state event of a type wins
+            for event in room.state.events.iter().chain(room.timeline.events.iter()) {
+                let value = |key: &str| event.content[key].as_str().map(String::from);
+
+                match &event.kind[..] {
+                    "m.room.name" => known.name = value("name"),
+                    "m.room.canonical_alias" => known.alias = value("alias"),
+                    "m.room.topic" => known.topic = value("topic"),
+                    _ => (),
+                };
+            }
+
+            // Tag orders run from 0 to 1; the sidebar sorts favourites by the number
+            if let Some(tags) = room.account_data.events.iter().filter(|event| event.kind == "m.tag").last() {
+                known.favourite = tags.content["tags"].get("m.favourite")
+                    .map(|tag| (tag["order"].as_f64().unwrap_or(0.0) * 1000.0) as u32);
+            }
+
+            known.highlight_count = room.unread_notifications.highlight_count;
+            known.notification_count = room.unread_notifications.notification_count;
+        }
+    }
+
+    fn rooms(&self) -> Vec<Room> {
+        self.rooms.iter().map(|(id, room)| {
+            let name = room.name.clone().or(room.alias.clone()).unwrap_or(id.clone());
+
+            Room {
+                id: id.clone(),
+                name: name,
+                topic: room.topic.clone().unwrap_or(String::new()),
+                // The server, like a Gitter community: "/example.com/#rust:example.com"
+                url: format!("/{}/{}", id.splitn(2, ':').nth(1).unwrap_or(""), room.alias.clone().unwrap_or(id.clone())),
+                oneToOne: self.direct.contains(id),
+                mentions: room.highlight_count,
+                unreadItems: room.notification_count,
+                favourite: room.favourite,
+                groupId: None,
+                githubType: String::from("MATRIX"),
+                lurk: false,
+            }
+        }).collect()
+    }
+}
+
+// The answer of the stream's /sync in flight, read once it is complete
+struct StreamAnswer {
+    buffer: Vec<u8>,
+    on_message: Box<FnMut(Message) + Send>,
+}
+
+// Builds the requests for one account on a homeserver; current_room_id is the room being read
+pub struct MatrixHandler {
+    current_room_id: String,
+    token: String,
+    // Such as https://matrix.example.com
+    homeserver: String,
+    // Where the stream's next /sync continues from; None until it answered once
+    since: Arc<Mutex<Option<String>>>,
+    stream_answer: Arc<Mutex<Option<StreamAnswer>>>,
+    room_list: Arc<Mutex<RoomList>>,
+}
+
+impl MatrixHandler {
+    pub fn new(room_id: &String, token: &String, homeserver: &str) -> MatrixHandler {
+        MatrixHandler {
+            current_room_id: room_id.clone(),
+            token: token.clone(),
+            homeserver: String::from(homeserver.trim_right_matches('/')),
+            since: Arc::new(Mutex::new(None)),
+            stream_answer: Arc::new(Mutex::new(None)),
+            room_list: Arc::new(Mutex::new(RoomList::default())),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}{}", self.homeserver, CLIENT_API, path)
+    }
+
+    fn room_url(&self, room_id: &String, path: &str) -> String {
+        self.url(&format!("/rooms/{}{}", api::url_encode(room_id), path))
+    }
+
+    // The client id of the outbox is the transaction id, so a retried send arrives only once
+    // An edit answers with the id of the message it replaces, like Gitter does, so it is confirmed
+    // when the edited message comes back
+    fn send_event(&self, room_id: &String, content: Value, text: String, client_id: &String, replaces: Option<&String>) -> Result<Call<Message>, ApiError> {
+        let url = self.room_url(room_id, &format!("/send/m.room.message/{}", api::url_encode(client_id)));
+        let request = Request::json("PUT", &url, &self.token, serde_json::to_string(&content)?)?;
+        let replaces = replaces.cloned();
+
+        Ok(Call::new(request, move |sent: EventId| Ok(match replaces {
+            Some(ref original) => Message { v: 2, ..sent_message(original.clone(), &text) },
+            None => sent_message(sent.event_id, &text),
+        })))
+    }
+}
+
+impl ChatBackend for MatrixHandler {
+    fn current_room_id(&self) -> &String {
+        &self.current_room_id
+    }
+
+    // The stream of another room starts over
+    fn set_current_room_id(&mut self, id: String) {
+        self.current_room_id = id;
+        self.since = Arc::new(Mutex::new(None));
+        self.stream_answer = Arc::new(Mutex::new(None));
+    }
+
+    fn set_token(&mut self, token: String) {
+        self.token = token;
+    }
+
+    fn for_room(&self, room_id: &String) -> Box<ChatBackend> {
+        Box::new(MatrixHandler::new(room_id, &self.token, &self.homeserver[..]))
+    }
+
+    fn user_call(&self) -> Result<Call<User>, ApiError> {
+        let request = Request::get(&self.url("/account/whoami"), &self.token)?;
+        Ok(Call::new(request, |who: WhoAmI| Ok(user(&who.user_id))))
+    }
+
+    // Runs every poll, so the /sync only asks for the state shown in the sidebar: no member
+    // lists, no presence and one timeline event per room; and after the first, only for changes
+    fn rooms_call(&self) -> Result<Call<Vec<Room>>, ApiError> {
+        let filter = json!({
+            "room": {
+                "state": {
+                    "lazy_load_members": true,
+                    "types": ["m.room.name", "m.room.canonical_alias", "m.room.topic"]
+                },
+                "timeline": { "limit": 1 }
+            },
+            "presence": { "types": [] }
+        });
+        let since = self.room_list.lock().unwrap().next_batch.clone();
+
+        let url = match since {
+            Some(ref since) => self.url(&format!(
+                "/sync?filter={}&since={}",
+                api::url_encode(&filter.to_string()),
+                api::url_encode(since)
+            )),
+            None => self.url(&format!("/sync?filter={}", api::url_encode(&filter.to_string()))),
+        };
+
+        let room_list = self.room_list.clone();
+
+        Ok(Call::new(Request::get(&url, &self.token)?, move |sync: SyncResponse| {
+            let mut room_list = room_list.lock().unwrap();
+            room_list.update(&sync);
+
+            let rooms = room_list.rooms();
+            Ok(rooms)
+        }))
+    }
+
+    // Matrix has no communities like Gitter's, so rooms are grouped by their server
+    fn groups_call(&self) -> Result<Call<Vec<Group>>, ApiError> {
+        Ok(Call::Ready(vec![]))
+    }
+
+    fn messages_call(&self) -> Result<Call<Vec<Message>>, ApiError> {
+        let url = self.room_url(&self.current_room_id, "/messages?dir=b&limit=15");
+
+        Ok(Call::new(Request::get(&url, &self.token)?, |messages: RoomMessages| {
+            Ok(to_messages(messages.chunk.iter().rev()))
+        }))
+    }
+
+    fn search_call(&self, query: &String) -> Result<Call<Vec<Message>>, ApiError> {
+        let body = json!({
+            "search_categories": {
+                "room_events": {
+                    "search_term": query,
+                    "filter": { "rooms": [&self.current_room_id], "limit": 50 },
+                    "order_by": "recent"
+                }
+            }
+        });
+        let request = Request::json("POST", &self.url("/search"), &self.token, body.to_string())?;
+
+        // Answered newest first
+        Ok(Call::new(request, |search: SearchResponse| {
+            Ok(to_messages(search.search_categories.room_events.results.iter().rev().map(|result| &result.result)))
+        }))
+    }
+
+    // Pages are found by event id, like Gitter's beforeId, rather than with pagination tokens
+    fn history_call(&self, before_id: Option<&String>) -> Result<Call<Vec<Message>>, ApiError> {
+        let before_id = match before_id {
+            Some(before_id) => before_id,
+            None => {
+                let url = self.room_url(&self.current_room_id, &format!("/messages?dir=b&limit={}", HISTORY_PAGE_SIZE));
+
+                return Ok(Call::new(Request::get(&url, &self.token)?, |messages: RoomMessages| {
+                    Ok(to_messages(messages.chunk.iter().rev()))
+                }));
+            },
+        };
+
+        let url = self.room_url(
+            &self.current_room_id,
+            &format!("/context/{}?limit={}", api::url_encode(before_id), HISTORY_PAGE_SIZE)
+        );
+
+        Ok(Call::new(Request::get(&url, &self.token)?, |context: Context| {
+            Ok(to_messages(context.events_before.iter().rev()))
+        }))
+    }
+
+    fn around_call(&self, message_id: &String) -> Result<Call<Vec<Message>>, ApiError> {
+        let url = self.room_url(&self.current_room_id, &format!("/context/{}?limit=30", api::url_encode(message_id)));
+
+        Ok(Call::new(Request::get(&url, &self.token)?, |context: Context| {
+            let events = context.events_before.iter().rev()
+                .chain(Some(&context.event))
+                .chain(context.events_after.iter());
+
+            Ok(to_messages(events))
+        }))
+    }
+
+    // A read receipt for the newest message covers everything before it
+    fn mark_read_call(&self, _user_id: &String, message_ids: Vec<String>) -> Result<Call<()>, ApiError> {
+        let last = match message_ids.last() {
+            Some(last) => last.clone(),
+            None => return Ok(Call::Ready(())),
+        };
+
+        let url = self.room_url(&self.current_room_id, &format!("/receipt/m.read/{}", api::url_encode(&last)));
+        let request = Request::json("POST", &url, &self.token, String::from("{}"))?;
+
+        Ok(Call::new(request, |_: Value| Ok(())))
+    }
+
+    fn send_call(&self, room_id: &String, text: String, parent_id: Option<String>, client_id: &String) -> Result<Call<Message>, ApiError> {
+        let mut content = json!({ "msgtype": "m.text", "body": &text });
+
+        if let Some(parent_id) = parent_id {
+            content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": parent_id } });
+        }
+
+        self.send_event(room_id, content, text, client_id, None)
+    }
+
+    // An edit is a new event replacing the original
+    fn update_call(&self, room_id: &String, id: &String, text: String, client_id: &String) -> Result<Call<Message>, ApiError> {
+        let content = json!({
+            "msgtype": "m.text",
+            "body": format!("* {}", text),
+            "m.new_content": { "msgtype": "m.text", "body": &text },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": id }
+        });
+
+        self.send_event(room_id, content, text, client_id, Some(id))
+    }
+
+    // A /sync of the open room, answered as soon as something happens there
+    fn stream_request(&self, on_message: Box<FnMut(Message) + Send>) -> Result<Easy, ApiError> {
+        let since = self.since.lock().unwrap().clone();
+
+        let filter = json!({
+            "room": {
+                "rooms": [&self.current_room_id],
+                "timeline": { "limit": INITIAL_TIMELINE_LIMIT },
+                "state": { "types": [] },
+                "account_data": { "types": [] }
+            },
+            "account_data": { "types": [] },
+            "presence": { "types": [] }
+        });
+
+        // The first /sync returns the latest messages at once
+        let url = match since {
+            Some(ref since) => self.url(&format!(
+                "/sync?filter={}&timeout={}&since={}",
+                api::url_encode(&filter.to_string()),
+                LONG_POLL_MILLIS,
+                api::url_encode(since)
+            )),
+            None => self.url(&format!("/sync?filter={}&timeout=0", api::url_encode(&filter.to_string()))),
+        };
+        debug!("Polling {}", logging::url(&url));
+
+        let mut easy = api::new_request_handle()?;
+        easy.url(&url)?;
+
+        let mut list = List::new();
+        list.append("Accept: application/json")?;
+        list.append(&(format!("Authorization: Bearer {}", &self.token)))?;
+        easy.http_headers(list)?;
+
+        *self.stream_answer.lock().unwrap() = Some(StreamAnswer { buffer: vec![], on_message: on_message });
+        let stream_answer = self.stream_answer.clone();
+
+        // The answer is one JSON object, read by stream_finished once it is complete
+        easy.write_function(move |new_data| {
+            if let Some(ref mut answer) = *stream_answer.lock().unwrap() {
+                answer.buffer.extend(new_data.iter());
+            }
+
+            Ok(new_data.len())
+        })?;
+
+        Ok(easy)
+    }
+
+    fn stream_is_long_poll(&self) -> bool {
+        true
+    }
+
+    fn stream_finished(&self) {
+        let answer = self.stream_answer.lock().unwrap().take();
+
+        let mut answer = match answer {
+            Some(answer) => answer,
+            None => return,
+        };
+
+        let sync = match serde_json::from_slice::<SyncResponse>(&answer.buffer[..]) {
+            Ok(sync) => sync,
+            Err(e) => {
+                error!("Reading synced messages -> {}", e);
+                return;
+            },
+        };
+
+        *self.since.lock().unwrap() = Some(sync.next_batch.clone());
+
+        if let Some(room) = sync.rooms.join.get(&self.current_room_id) {
+            for message in to_messages(room.timeline.events.iter()) {
+                (&mut *answer.on_message)(message);
+            }
+        }
+    }
+}
+
+// Signs in with a password, returning the user id and an access token
+pub fn login(homeserver: &str, user: &str, password: &str) -> Result<(String, String), ApiError> {
+    let url = format!("{}{}/login", homeserver.trim_right_matches('/'), CLIENT_API);
+    let body = json!({
+        "type": "m.login.password",
+        "identifier": { "type": "m.id.user", "user": user },
+        "password": password,
+        "initial_device_display_name": "GtkGitter"
+    });
+
+    let mut easy = api::new_request_handle()?;
+    easy.url(&url)?;
+    easy.post(true)?;
+    easy.post_fields_copy(body.to_string().as_bytes())?;
+
+    let mut list = List::new();
+    list.append("Content-Type: application/json")?;
+    list.append("Accept: application/json")?;
+    easy.http_headers(list)?;
+
+    let mut response = vec![];
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            response.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+
+    // A wrong password is answered with HTTP 403
+    let login: LoginResponse = match api::parse_response(&mut easy, &response[..]) {
+        Err(ApiError::Http(403)) => return Err(ApiError::Http(401)),
+        result => result?,
+    };
+
+    Ok((login.user_id, login.access_token))
+}
+
+// "@alice:example.com" is shown as alice
+fn localpart(user_id: &str) -> String {
+    String::from(user_id.trim_left_matches('@').split(':').next().unwrap_or(user_id))
+}
+
+fn user(user_id: &str) -> User {
+    User {
+        id: String::from(user_id),
+        username: localpart(user_id),
+        displayName: localpart(user_id),
+        url: format!("/{}", user_id),
+        avatarUrlSmall: String::new(),
+        avatarUrlMedium: String::new(),
+    }
+}
+
+fn time_of(origin_server_ts: u64) -> String {
+    logging::timestamp(UNIX_EPOCH + Duration::from_millis(origin_server_ts))
+}
+
+fn content_str(content: &Value, key: &str) -> String {
+    String::from(content[key].as_str().unwrap_or(""))
+}
+
+// Text messages of a timeline; an edit becomes the edited message with its new text
+fn to_messages<'a, I>(events: I) -> Vec<Message> where I: Iterator<Item = &'a Event> {
+    events.filter_map(to_message).collect()
+}
+
+fn to_message(event: &Event) -> Option<Message> {
+    if event.kind != "m.room.message" || !event.content["body"].is_string() {
+        return None;
+    }
+
+    let replaces = &event.content["m.relates_to"];
+    let (id, content, version) = match (replaces["rel_type"].as_str(), replaces["event_id"].as_str()) {
+        (Some("m.replace"), Some(original)) => (String::from(original), &event.content["m.new_content"], 2),
+        _ => (event.event_id.clone(), &event.content, 1),
+    };
+
+    let text = content_str(content, "body");
+    let html = match content["format"].as_str() {
+        Some("org.matrix.custom.html") => content_str(content, "formatted_body"),
+        _ => text.clone(),
+    };
+
+    let mentions = event.content["m.mentions"]["user_ids"].as_array()
+        .map(|user_ids| user_ids.iter()
+            .filter_map(|user_id| user_id.as_str())
+            .map(|user_id| Mention { screenName: localpart(user_id) })
+            .collect())
+        .unwrap_or(vec![]);
+
+    Some(Message {
+        id: id,
+        text: text,
+        html: html,
+        sent: time_of(event.origin_server_ts),
+        fromUser: user(&event.sender[..]),
+        // Matrix keeps track with read receipts instead
+        unread: false,
+        readBy: 0,
+        urls: vec![],
+        mentions: mentions,
+        v: version,
+    })
+}
+
+// Only the id of a sent message matters, until the stream echoes it back
+fn sent_message(event_id: String, text: &String) -> Message {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    Message {
+        id: event_id,
+        text: text.clone(),
+        html: text.clone(),
+        sent: logging::timestamp(UNIX_EPOCH + now),
+        fromUser: user(""),
+        unread: false,
+        readBy: 0,
+        urls: vec![],
+        mentions: vec![],
+        v: 1,
+    }
+}
+
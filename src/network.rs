@@ -0,0 +1,116 @@
+// Proxy and certificate settings from config.yaml, applied to every curl handle:
+// API requests, message streams and signing in. Without a proxy in config.yaml curl
+// reads http_proxy, https_proxy, all_proxy and no_proxy from the environment itself.
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use curl;
+use curl::easy::Easy;
+
+use config::Config;
+
+// Where distributions keep the trusted certificates, which an extra bundle is added to
+const SYSTEM_BUNDLES: &'static [&'static str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub proxy: Option<String>,
+    // Comma separated hosts reached without the proxy
+    pub no_proxy: Option<String>,
+    // Certificates to trust instead of curl's default
+    pub ca_file: Option<PathBuf>,
+}
+
+// Applies the settings of config.yaml to the requests made from now on
+pub fn configure(config: &Config) {
+    let ca_file = config.ca_bundle.as_ref().map(|extra| {
+        combined_bundle(extra).unwrap_or_else(|e| {
+            warn!("Could not add the system certificates to {}, trusting only its certificates -> {}", extra.display(), e);
+            extra.clone()
+        })
+    });
+
+    let settings = Settings {
+        proxy: config.proxy.clone(),
+        no_proxy: if config.no_proxy.is_empty() { None } else { Some(config.no_proxy.join(",")) },
+        ca_file: ca_file,
+    };
+
+    if let Some(ref proxy) = settings.proxy {
+        debug!("Connecting through the proxy {}", proxy);
+    }
+
+    *SETTINGS.write().unwrap() = settings;
+}
+
+pub fn apply(easy: &mut Easy) -> Result<(), curl::Error> {
+    apply_settings(easy, &SETTINGS.read().unwrap())
+}
+
+pub fn apply_settings(easy: &mut Easy, settings: &Settings) -> Result<(), curl::Error> {
+    if let Some(ref proxy) = settings.proxy {
+        easy.proxy(&proxy[..])?;
+    }
+
+    if let Some(ref no_proxy) = settings.no_proxy {
+        easy.noproxy(&no_proxy[..])?;
+    }
+
+    if let Some(ref ca_file) = settings.ca_file {
+        easy.cainfo(ca_file)?;
+    }
+
+    Ok(())
+}
+
+// curl takes a single bundle, so the extra certificates and the system's are written
+// together to $XDG_CACHE_HOME/gitter_gtk/ca-bundle.pem
+fn combined_bundle(extra: &PathBuf) -> io::Result<PathBuf> {
+    let mut combined = String::new();
+
+    match SYSTEM_BUNDLES.iter().map(PathBuf::from).find(|path| path.is_file()) {
+        Some(system) => {
+            fs::File::open(&system)?.read_to_string(&mut combined)?;
+            combined.push('\n');
+        },
+        None => warn!("No system certificates found, trusting only those in {}", extra.display()),
+    };
+
+    fs::File::open(extra)?.read_to_string(&mut combined)?;
+
+    let path = cache_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither $XDG_CACHE_HOME nor $HOME is set"))?
+        .join("ca-bundle.pem");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::File::create(&path)?.write_all(combined.as_bytes())?;
+
+    Ok(path)
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let cache_home = match ::std::env::var("XDG_CACHE_HOME") {
+        Ok(ref val) if val.len() > 0 => PathBuf::from(val),
+        _ => match ::std::env::var("HOME") {
+            Ok(val) => PathBuf::from(val).join(".cache"),
+            Err(_) => return None,
+        },
+    };
+
+    Some(cache_home.join("gitter_gtk"))
+}
@@ -0,0 +1,135 @@
+// Messages waiting to be delivered, retried with backoff until sent or discarded
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use OutgoingMessage;
+
+// Attempts before a message is marked failed and needs the user to retry it
+pub const MAX_ATTEMPTS: u32 = 5;
+
+// Longest wait between two attempts, in seconds
+const MAX_BACKOFF_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DeliveryState {
+    Sending,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxItem {
+    // Generated locally so the GUI can follow the message until the server echoes it back
+    pub client_id: String,
+    pub room_id: String,
+    pub message: OutgoingMessage,
+    pub attempts: u32,
+    // Unix time in seconds of the next attempt
+    pub next_attempt: u64,
+    pub state: DeliveryState,
+}
+
+impl OutboxItem {
+    pub fn new(room_id: &String, message: OutgoingMessage) -> OutboxItem {
+        OutboxItem {
+            client_id: new_client_id(),
+            room_id: room_id.clone(),
+            message: message,
+            attempts: 0,
+            next_attempt: 0,
+            state: DeliveryState::Sending,
+        }
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        self.state == DeliveryState::Sending && self.next_attempt <= now
+    }
+
+    // Schedules the next attempt, or gives up after MAX_ATTEMPTS
+    pub fn record_failure(&mut self, now: u64) {
+        self.attempts += 1;
+
+        if self.attempts >= MAX_ATTEMPTS {
+            self.state = DeliveryState::Failed;
+        } else {
+            self.next_attempt = now + backoff_secs(self.attempts);
+        }
+    }
+
+    // Manual retry from the GUI starts a fresh round of attempts
+    pub fn retry(&mut self) {
+        self.attempts = 0;
+        self.next_attempt = 0;
+        self.state = DeliveryState::Sending;
+    }
+}
+
+// Progress reports from the backend core to the GUI, by client id
+#[derive(Debug, Clone)]
+pub enum OutboxEvent {
+    // Loaded from the cache at start-up
+    Queued(OutboxItem),
+    Sent { client_id: String, server_id: String },
+    Retrying { client_id: String, attempts: u32 },
+    Failed { client_id: String },
+}
+
+// 2, 4, 8... seconds, capped at MAX_BACKOFF_SECS
+pub fn backoff_secs(attempts: u32) -> u64 {
+    if attempts >= 6 {
+        return MAX_BACKOFF_SECS;
+    }
+
+    (1u64 << attempts).min(MAX_BACKOFF_SECS)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Unique on this computer; also the Matrix transaction id
+pub fn new_client_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(::std::time::Duration::from_secs(0));
+    format!("local-{}-{:09}", now.as_secs(), now.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(message: OutgoingMessage) -> OutboxItem {
+        OutboxItem::new(&String::from("a"), message)
+    }
+
+    fn new_message(text: &str) -> OutgoingMessage {
+        OutgoingMessage::New { text: String::from(text), parent_id: None }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let waits: Vec<u64> = (0..8).map(backoff_secs).collect();
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(backoff_secs(100), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn failures_schedule_the_next_attempt_until_giving_up() {
+        let mut item = item(new_message("hello"));
+        assert!(item.is_due(0));
+
+        item.record_failure(1000);
+        assert_eq!(item.attempts, 1);
+        assert_eq!(item.next_attempt, 1002);
+        assert!(!item.is_due(1001));
+        assert!(item.is_due(1002));
+
+        for _ in 1..MAX_ATTEMPTS {
+            item.record_failure(2000);
+        }
+        assert_eq!(item.state, DeliveryState::Failed);
+        assert!(!item.is_due(u64::max_value()));
+
+        item.retry();
+        assert_eq!(item.attempts, 0);
+        assert!(item.is_due(0));
+    }
+}
@@ -0,0 +1,203 @@
+// Preferences dialog, writing its settings back to config.yaml, and applying
+// the theme and font settings to GTK.
+
+use std::cell::RefCell;
+
+use glib::prelude::*;
+use gtk;
+use gtk::prelude::*;
+
+use config;
+use config::{Config, ConfigError, Theme, MAX_POLL_INTERVAL_SECS, MIN_POLL_INTERVAL_SECS};
+
+const DARK_THEME_PROPERTY: &'static str = "gtk-application-prefer-dark-theme";
+const FONT_PROPERTY: &'static str = "gtk-font-name";
+
+// (id, label) of each theme in the dialog
+const THEMES: &'static [(&'static str, &'static str)] = &[
+    ("system", "Same as the desktop"),
+    ("light", "Light"),
+    ("dark", "Dark"),
+];
+
+// The desktop's own settings, to go back to when the custom ones are cleared
+thread_local!(static DESKTOP_APPEARANCE: RefCell<Option<(bool, Option<String>)>> = RefCell::new(None));
+
+// `on_saved` gets the config once it is written, to apply it to the open accounts
+pub fn show_preferences<F>(parent: &gtk::Window, on_saved: F)
+where F: Fn(&Config)
+{
+    // Settings the dialog does not show are kept as they are in the file
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Reading config.yaml -> {}", e);
+            show_config_error(Some(parent), &e);
+            return;
+        },
+    };
+
+    let dialog = gtk::Dialog::new();
+    dialog.set_title("Preferences");
+    dialog.set_transient_for(Some(parent));
+    dialog.set_modal(true);
+    dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+    dialog.add_button("Save", gtk::ResponseType::Accept.into());
+    dialog.set_default_response(gtk::ResponseType::Accept.into());
+
+    let grid = gtk::Grid::new();
+    grid.set_border_width(15);
+    grid.set_row_spacing(8);
+    grid.set_column_spacing(20);
+
+    let poll_interval = gtk::SpinButton::new_with_range(MIN_POLL_INTERVAL_SECS as f64, MAX_POLL_INTERVAL_SECS as f64, 10.0);
+    poll_interval.set_value(config.poll_interval_secs as f64);
+
+    let notifications = gtk::CheckButton::new_with_label("Notify me when I am mentioned");
+    notifications.set_active(config.notifications);
+
+    let theme = gtk::ComboBoxText::new();
+    for &(id, label) in THEMES.iter() {
+        theme.append(Some(id), label);
+    }
+    theme.set_active_id(Some(theme_id(config.theme)));
+
+    let custom_font = gtk::CheckButton::new_with_label("Use a custom font");
+    custom_font.set_active(config.font.is_some());
+    let font = gtk::FontButton::new();
+    if let Some(ref name) = config.font {
+        font.set_font(&name[..]);
+    }
+    font.set_sensitive(config.font.is_some());
+    {
+        let font = font.clone();
+        custom_font.connect_toggled(move |this| font.set_sensitive(this.get_active()));
+    }
+
+    let error = gtk::Label::new(None);
+    error.set_line_wrap(true);
+
+    let rows: Vec<(&str, gtk::Widget)> = vec![
+        ("Refresh rooms every (seconds)", poll_interval.clone().upcast()),
+        ("Notifications", notifications.clone().upcast()),
+        ("Theme", theme.clone().upcast()),
+        ("Font", custom_font.clone().upcast()),
+        ("", font.clone().upcast()),
+    ];
+
+    for (i, (title, widget)) in rows.into_iter().enumerate() {
+        let label = gtk::Label::new(Some(title));
+        label.set_halign(gtk::Align::End);
+        widget.set_halign(gtk::Align::Start);
+
+        grid.attach(&label, 0, i as i32, 1, 1);
+        grid.attach(&widget, 1, i as i32, 1, 1);
+    }
+    grid.attach(&error, 0, 5, 2, 1);
+
+    dialog.get_content_area().add(&grid);
+    dialog.show_all();
+
+    // Stays open while the settings are invalid, saying why
+    while dialog.run() == gtk::ResponseType::Accept.into() {
+        let mut changed = config.clone();
+        changed.poll_interval_secs = poll_interval.get_value_as_int() as u64;
+        changed.notifications = notifications.get_active();
+        changed.theme = theme_from_id(&theme.get_active_id().unwrap_or_default()[..]);
+        changed.font = if custom_font.get_active() { font.get_font() } else { None };
+
+        match config::save(&changed) {
+            Ok(()) => {
+                on_saved(&changed);
+                break;
+            },
+            Err(e) => {
+                error!("Saving preferences -> {}", e);
+                error.set_text(&format!("Could not save: {}", e)[..]);
+            },
+        };
+    }
+
+    dialog.destroy();
+}
+
+// Told at startup, and when the dialog cannot open, so the user knows to fix the file
+pub fn show_config_error(parent: Option<&gtk::Window>, error: &ConfigError) {
+    let text = format!("Your settings could not be read, so the defaults are used.\n\n{}", error);
+
+    let dialog = gtk::MessageDialog::new(
+        parent,
+        gtk::DIALOG_MODAL,
+        gtk::MessageType::Error,
+        gtk::ButtonsType::Ok,
+        &text[..]
+    );
+
+    dialog.run();
+    dialog.destroy();
+}
+
+// Shown when an edit to config.yaml is rejected, without blocking the app; the caller destroys it once the file is fixed
+pub fn show_rejected_config(parent: Option<&gtk::Window>, error: &ConfigError) -> gtk::MessageDialog {
+    let text = format!("Your changes to the settings were not applied, the previous settings stay in use.\n\n{}", error);
+
+    let dialog = gtk::MessageDialog::new(
+        parent,
+        gtk::DialogFlags::empty(),
+        gtk::MessageType::Error,
+        gtk::ButtonsType::Close,
+        &text[..]
+    );
+
+    dialog.connect_response(|this, _| this.destroy());
+    dialog.show_all();
+
+    dialog
+}
+
+// Applies the theme and font to every window of the app
+pub fn apply_appearance(config: &Config) {
+    let settings = match gtk::Settings::get_default() {
+        Some(settings) => settings,
+        None => return,
+    };
+
+    let (desktop_dark, desktop_font) = DESKTOP_APPEARANCE.with(|appearance| {
+        appearance.borrow_mut().get_or_insert_with(|| {
+            let dark = settings.get_property(DARK_THEME_PROPERTY).ok().and_then(|value| value.get::<bool>());
+            let font = settings.get_property(FONT_PROPERTY).ok().and_then(|value| value.get::<String>());
+            (dark.unwrap_or(false), font)
+        }).clone()
+    });
+
+    let dark = match config.theme {
+        Theme::System => desktop_dark,
+        Theme::Light => false,
+        Theme::Dark => true,
+    };
+    if let Err(e) = settings.set_property(DARK_THEME_PROPERTY, &dark.to_value()) {
+        error!("Setting theme -> {}", e);
+    }
+
+    if let Some(font) = config.font.clone().or(desktop_font) {
+        if let Err(e) = settings.set_property(FONT_PROPERTY, &font.to_value()) {
+            error!("Setting font -> {}", e);
+        }
+    }
+}
+
+fn theme_id(theme: Theme) -> &'static str {
+    match theme {
+        Theme::System => "system",
+        Theme::Light => "light",
+        Theme::Dark => "dark",
+    }
+}
+
+fn theme_from_id(id: &str) -> Theme {
+    match id {
+        "light" => Theme::Light,
+        "dark" => Theme::Dark,
+        _ => Theme::System,
+    }
+}
@@ -0,0 +1,298 @@
+// Decides when the backend core performs each request. Gitter allows a limited
+// number of requests per window and says how many are left in X-RateLimit-*
+// headers; the user's own actions get what is left first, background refreshes
+// wait when it runs low, and everything pauses after an HTTP 429.
+
+use std::cmp;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Requests performed at once
+const MAX_IN_FLIGHT: usize = 4;
+
+// Backoff after an HTTP 429 without Retry-After, doubled for every 429 in a row
+const BACKOFF_BASE_MILLIS: u64 = 2000;
+const BACKOFF_MAX_MILLIS: u64 = 120000;
+
+// Most important first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // Sending and editing messages
+    User,
+    // What the user is looking at: the open room, search results
+    Interactive,
+    // Marking messages as read, refreshing the account after a reconnect
+    Refresh,
+    // The periodic refresh of rooms and unread counts
+    Background,
+}
+
+impl Priority {
+    // Requests left in the window which only more important requests may use
+    fn reserve(&self, limit: u32) -> u32 {
+        match *self {
+            Priority::User => 0,
+            Priority::Interactive => 1,
+            Priority::Refresh => limit / 10,
+            Priority::Background => limit / 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    // When `remaining` goes back to `limit`
+    pub reset: Instant,
+}
+
+struct Queued<T> {
+    priority: Priority,
+    // Requests of the same priority go in the order they came
+    sequence: u64,
+    item: T,
+}
+
+pub struct Scheduler<T> {
+    queue: Vec<Queued<T>>,
+    next_sequence: u64,
+    in_flight: usize,
+    rate_limit: Option<RateLimit>,
+    backoff_until: Option<Instant>,
+    // HTTP 429s in a row
+    rate_limited: u32,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Scheduler<T> {
+        Scheduler {
+            queue: vec![],
+            next_sequence: 0,
+            in_flight: 0,
+            rate_limit: None,
+            backoff_until: None,
+            rate_limited: 0,
+        }
+    }
+
+    pub fn push(&mut self, priority: Priority, item: T) {
+        self.queue.push(Queued {
+            priority: priority,
+            sequence: self.next_sequence,
+            item: item,
+        });
+        self.next_sequence += 1;
+    }
+
+    // The next request to perform now, if any may go; call `finished` once it is done
+    pub fn next(&mut self, now: Instant) -> Option<T> {
+        if self.in_flight >= MAX_IN_FLIGHT || self.backing_off(now) {
+            return None;
+        }
+
+        let index = self.queue.iter()
+            .enumerate()
+            .min_by_key(|&(_, queued)| (queued.priority, queued.sequence))
+            .map(|(index, _)| index)?;
+
+        if let Some(rate_limit) = self.current_limit(now) {
+            // Requests in flight have not been counted by the server yet
+            let left = rate_limit.remaining.saturating_sub(self.in_flight as u32);
+            if left <= self.queue[index].priority.reserve(rate_limit.limit) {
+                return None;
+            }
+        }
+
+        self.in_flight += 1;
+        Some(self.queue.remove(index).item)
+    }
+
+    // Records the outcome of a request `next` gave out; `status` is None when no response arrived
+    pub fn finished(&mut self, now: Instant, status: Option<u32>, rate_limit: Option<RateLimit>, retry_after: Option<Duration>) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+
+        if rate_limit.is_some() {
+            self.rate_limit = rate_limit;
+        }
+
+        if status == Some(429) {
+            let backoff = retry_after.unwrap_or_else(|| {
+                let exponent = cmp::min(self.rate_limited, 16);
+                Duration::from_millis(cmp::min(BACKOFF_BASE_MILLIS << exponent, BACKOFF_MAX_MILLIS))
+            });
+
+            self.rate_limited += 1;
+            self.backoff_until = Some(now + backoff);
+        } else if status.is_some() {
+            self.rate_limited = 0;
+        }
+    }
+
+    // When a waiting request may go, if the queue is held back by the rate limit
+    pub fn wake_at(&self, now: Instant) -> Option<Instant> {
+        if self.queue.is_empty() || self.in_flight >= MAX_IN_FLIGHT {
+            return None;
+        }
+
+        match self.backoff_until {
+            Some(until) if until > now => return Some(until),
+            _ => (),
+        };
+
+        self.current_limit(now).map(|rate_limit| rate_limit.reset)
+    }
+
+    fn backing_off(&self, now: Instant) -> bool {
+        self.backoff_until.map(|until| until > now).unwrap_or(false)
+    }
+
+    // The last rate limit seen, unless its window is over
+    fn current_limit(&self, now: Instant) -> Option<RateLimit> {
+        match self.rate_limit {
+            Some(rate_limit) if rate_limit.reset > now => Some(rate_limit),
+            _ => None,
+        }
+    }
+}
+
+// Reads X-RateLimit-Limit, X-RateLimit-Remaining and X-RateLimit-Reset. The reset is
+// a Unix time in seconds or milliseconds, or seconds from now when it is small.
+pub fn parse_rate_limit(limit: Option<String>, remaining: Option<String>, reset: Option<String>, now: Instant) -> Option<RateLimit> {
+    let limit = limit?.trim().parse::<u32>().ok()?;
+    let remaining = remaining?.trim().parse::<u32>().ok()?;
+    let reset = reset?.trim().parse::<u64>().ok()?;
+
+    let unix_millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000).unwrap_or(0);
+
+    let millis_from_now = if reset > 100000000000 {
+        reset.saturating_sub(unix_millis)
+    } else if reset > 1000000000 {
+        (reset * 1000).saturating_sub(unix_millis)
+    } else {
+        reset * 1000
+    };
+
+    Some(RateLimit {
+        limit: limit,
+        remaining: remaining,
+        reset: now + Duration::from_millis(millis_from_now),
+    })
+}
+
+// Retry-After in seconds; the HTTP date form is not used by Gitter
+pub fn parse_retry_after(value: Option<String>) -> Option<Duration> {
+    value?.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limit: u32, remaining: u32, now: Instant) -> Option<RateLimit> {
+        Some(RateLimit {
+            limit: limit,
+            remaining: remaining,
+            reset: now + Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn user_requests_go_first() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+
+        scheduler.push(Priority::Background, "poll");
+        scheduler.push(Priority::Interactive, "messages");
+        scheduler.push(Priority::User, "send");
+        scheduler.push(Priority::Interactive, "search");
+
+        let order: Vec<&str> = (0..4).filter_map(|_| scheduler.next(now)).collect();
+        assert_eq!(order, vec!["send", "messages", "search", "poll"]);
+    }
+
+    #[test]
+    fn only_a_few_requests_run_at_once() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+
+        for i in 0..6 {
+            scheduler.push(Priority::Interactive, i);
+        }
+
+        assert_eq!((0..6).filter_map(|_| scheduler.next(now)).count(), MAX_IN_FLIGHT);
+
+        scheduler.finished(now, Some(200), None, None);
+        assert_eq!(scheduler.next(now), Some(4));
+    }
+
+    #[test]
+    fn background_requests_leave_room_for_the_user() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+
+        scheduler.push(Priority::Refresh, "first");
+        scheduler.next(now);
+        scheduler.finished(now, Some(200), limit(100, 20, now), None);
+
+        scheduler.push(Priority::Background, "poll");
+        assert_eq!(scheduler.next(now), None);
+        assert_eq!(scheduler.wake_at(now), Some(now + Duration::from_secs(60)));
+
+        // A more important request overtakes the waiting one
+        scheduler.push(Priority::User, "send");
+        assert_eq!(scheduler.next(now), Some("send"));
+
+        // Once the window is over the limit is forgotten
+        assert_eq!(scheduler.next(now + Duration::from_secs(61)), Some("poll"));
+    }
+
+    #[test]
+    fn rate_limited_requests_back_off() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+
+        scheduler.push(Priority::User, "send");
+        scheduler.next(now);
+        scheduler.finished(now, Some(429), None, None);
+
+        scheduler.push(Priority::User, "send");
+        assert_eq!(scheduler.next(now), None);
+        assert_eq!(scheduler.wake_at(now), Some(now + Duration::from_millis(BACKOFF_BASE_MILLIS)));
+
+        // The wait doubles while the server keeps refusing
+        let later = now + Duration::from_millis(BACKOFF_BASE_MILLIS);
+        assert_eq!(scheduler.next(later), Some("send"));
+        scheduler.finished(later, Some(429), None, None);
+        assert_eq!(scheduler.wake_at(later), None);
+        scheduler.push(Priority::User, "send");
+        assert_eq!(scheduler.wake_at(later), Some(later + Duration::from_millis(BACKOFF_BASE_MILLIS * 2)));
+
+        // Retry-After is used when given
+        let after = later + Duration::from_millis(BACKOFF_BASE_MILLIS * 2);
+        assert_eq!(scheduler.next(after), Some("send"));
+        scheduler.finished(after, Some(429), None, Some(Duration::from_secs(7)));
+        scheduler.push(Priority::User, "send");
+        assert_eq!(scheduler.wake_at(after), Some(after + Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn rate_limit_headers_are_read() {
+        let now = Instant::now();
+        let header = |value: &str| Some(String::from(value));
+
+        let rate_limit = parse_rate_limit(header("100"), header("42"), header("30"), now).unwrap();
+        assert_eq!(rate_limit.limit, 100);
+        assert_eq!(rate_limit.remaining, 42);
+        assert_eq!(rate_limit.reset, now + Duration::from_secs(30));
+
+        // A Unix time in the past means the window is already over
+        let rate_limit = parse_rate_limit(header("100"), header("0"), header("1500000000000"), now).unwrap();
+        assert_eq!(rate_limit.reset, now);
+
+        assert_eq!(parse_rate_limit(None, header("42"), header("30"), now), None);
+        assert_eq!(parse_rate_limit(header("many"), header("42"), header("30"), now), None);
+
+        assert_eq!(parse_retry_after(header(" 12 ")), Some(Duration::from_secs(12)));
+        assert_eq!(parse_retry_after(header("Wed, 21 Oct 2015 07:28:00 GMT")), None);
+    }
+}
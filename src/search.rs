@@ -0,0 +1,131 @@
+// Searching messages already received, across every room
+
+use std::collections::HashMap;
+
+use Message;
+
+// Number of messages kept per room for searching across rooms
+pub const MAX_CACHED_PER_ROOM: usize = 500;
+
+// Results are (room id, message) pairs so a result can be opened in its room
+pub type SearchResult = (String, Message);
+
+// Messages received this session, keyed by room id
+#[derive(Debug, Clone)]
+pub struct MessageCache {
+    rooms: HashMap<String, Vec<Message>>,
+}
+
+impl MessageCache {
+    pub fn new() -> MessageCache {
+        MessageCache {
+            rooms: HashMap::new(),
+        }
+    }
+
+    pub fn add_messages(&mut self, room_id: &String, messages: &Vec<Message>) {
+        let cached = self.rooms.entry(room_id.clone()).or_insert(vec![]);
+
+        for message in messages.iter() {
+            if !cached.iter().any(|m| m.id == message.id) {
+                cached.push(message.clone());
+            }
+        }
+
+        if cached.len() > MAX_CACHED_PER_ROOM {
+            let overflow = cached.len() - MAX_CACHED_PER_ROOM;
+            cached.drain(..overflow);
+        }
+    }
+
+    // Case-insensitive search of text and author in every room, newest first
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        let query = query.trim().to_lowercase();
+
+        if query.len() == 0 {
+            return vec![];
+        }
+
+        let mut results: Vec<SearchResult> = vec![];
+
+        for (room_id, messages) in self.rooms.iter() {
+            for message in messages.iter() {
+                if message.text.to_lowercase().contains(&query[..])
+                    || message.fromUser.username.to_lowercase().contains(&query[..]) {
+                    results.push((room_id.clone(), message.clone()));
+                }
+            }
+        }
+
+        // Gitter timestamps are ISO 8601, so they sort correctly as strings
+        results.sort_by(|a, b| b.1.sent.cmp(&a.1.sent));
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fixtures::{message, user};
+
+    fn ids(results: &Vec<SearchResult>) -> Vec<(&str, &str)> {
+        results.iter().map(|&(ref room_id, ref found)| (&room_id[..], &found.id[..])).collect()
+    }
+
+    #[test]
+    fn search_finds_text_and_authors_in_every_room_newest_first() {
+        let alice = user("u1", "alice");
+        let bob = user("u2", "bob");
+        let mut cache = MessageCache::new();
+
+        cache.add_messages(&String::from("a"), &vec![
+            message("m1", &alice, "2017-11-16T10:00:00.000Z", "Release notes"),
+            message("m2", &bob, "2017-11-16T12:00:00.000Z", "lunch?"),
+        ]);
+        cache.add_messages(&String::from("b"), &vec![
+            message("m3", &bob, "2017-11-16T11:00:00.000Z", "the RELEASE is out"),
+        ]);
+
+        assert_eq!(ids(&cache.search("release")), vec![("b", "m3"), ("a", "m1")]);
+        assert_eq!(ids(&cache.search(" Bob ")), vec![("a", "m2"), ("b", "m3")]);
+        assert!(cache.search("nothing like it").is_empty());
+        assert!(cache.search("  ").is_empty());
+    }
+
+    #[test]
+    fn messages_already_cached_are_not_added_again() {
+        let alice = user("u1", "alice");
+        let mut cache = MessageCache::new();
+        let room_id = String::from("a");
+
+        cache.add_messages(&room_id, &vec![message("m1", &alice, "2017-11-16T10:00:00.000Z", "hello")]);
+        cache.add_messages(&room_id, &vec![
+            message("m1", &alice, "2017-11-16T10:00:00.000Z", "hello"),
+            message("m2", &alice, "2017-11-16T10:01:00.000Z", "hello again"),
+        ]);
+
+        assert_eq!(ids(&cache.search("hello")), vec![("a", "m2"), ("a", "m1")]);
+    }
+
+    #[test]
+    fn oldest_messages_of_a_room_are_dropped_past_the_limit() {
+        let alice = user("u1", "alice");
+        let mut cache = MessageCache::new();
+        let room_id = String::from("a");
+
+        let messages: Vec<Message> = (0..MAX_CACHED_PER_ROOM + 10)
+            .map(|i| message(&format!("m{}", i)[..], &alice, &format!("2017-11-16T10:00:00.{:03}Z", i)[..], "hello"))
+            .collect();
+        cache.add_messages(&room_id, &messages);
+
+        // Another room keeps its own messages
+        cache.add_messages(&String::from("b"), &vec![message("other", &alice, "2017-11-15T10:00:00.000Z", "hello")]);
+
+        let cached = &cache.rooms[&room_id];
+        assert_eq!(cached.len(), MAX_CACHED_PER_ROOM);
+        assert_eq!(cached[0].id, "m10");
+        assert_eq!(cached[MAX_CACHED_PER_ROOM - 1].id, format!("m{}", MAX_CACHED_PER_ROOM + 9));
+        assert_eq!(cache.rooms[&String::from("b")].len(), 1);
+    }
+}
@@ -0,0 +1,45 @@
+// Keyboard shortcut reference shown with F1 or Ctrl+?
+
+use gtk;
+use gtk::prelude::*;
+
+// (keys, description) pairs in the order they are listed in the help window
+pub const SHORTCUTS: &'static [(&'static str, &'static str)] = &[
+    ("Ctrl+K", "Quick switcher"),
+    ("Alt+Up / Alt+Down", "Previous / next room"),
+    ("Alt+Shift+Up / Alt+Shift+Down", "Previous / next unread room"),
+    ("Ctrl+F", "Search in room"),
+    ("PageUp / PageDown", "Scroll message history"),
+    ("Enter", "Send message"),
+    ("Up (empty message box)", "Edit your last message"),
+    ("Double click message", "Reply to message"),
+    ("Escape", "Cancel edit or reply"),
+    ("F1 / Ctrl+?", "Show keyboard shortcuts"),
+];
+
+pub fn show_shortcuts_window(parent: &gtk::Window) {
+    let window = gtk::Window::new(gtk::WindowType::Toplevel);
+    window.set_title("Keyboard Shortcuts");
+    window.set_transient_for(Some(parent));
+    window.set_position(gtk::WindowPosition::CenterOnParent);
+
+    let grid = gtk::Grid::new();
+    grid.set_border_width(15);
+    grid.set_row_spacing(8);
+    grid.set_column_spacing(20);
+
+    for (i, &(keys, description)) in SHORTCUTS.iter().enumerate() {
+        let keys_label = gtk::Label::new(None);
+        keys_label.set_markup(&format!("<b>{}</b>", keys)[..]);
+        keys_label.set_halign(gtk::Align::End);
+
+        let description_label = gtk::Label::new(Some(description));
+        description_label.set_halign(gtk::Align::Start);
+
+        grid.attach(&keys_label, 0, i as i32, 1, 1);
+        grid.attach(&description_label, 1, i as i32, 1, 1);
+    }
+
+    window.add(&grid);
+    window.show_all();
+}
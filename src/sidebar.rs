@@ -0,0 +1,290 @@
+// Groups rooms into the collapsible sections shown in the sidebar
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+use config;
+use {Group, Room};
+
+pub const FAVOURITES_KEY: &'static str = "favourites";
+pub const DIRECT_MESSAGES_KEY: &'static str = "direct_messages";
+
+// One collapsible block of rooms in the sidebar
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub key: String,
+    pub title: String,
+    pub rooms: Vec<Room>,
+}
+
+impl Section {
+    fn new(key: &str, title: &str) -> Section {
+        Section {
+            key: String::from(key),
+            title: String::from(title),
+            rooms: vec![],
+        }
+    }
+}
+
+// Finds the community name for a room, using /v1/groups first and the url prefix second
+fn community_name(room: &Room, groups: &Vec<Group>) -> String {
+    if let Some(ref group_id) = room.groupId {
+        if let Some(group) = groups.iter().find(|g| &g.id == group_id) {
+            return group.name.clone();
+        }
+    }
+
+    // Room urls look like "/org/repo", so the first segment is the community
+    match room.url.trim_left_matches('/').split('/').next() {
+        Some(prefix) if prefix.len() > 0 => String::from(prefix),
+        _ => room.name.clone(),
+    }
+}
+
+// Splits rooms into Favourites, one section per community, then Direct Messages
+pub fn group_rooms(rooms: &Vec<Room>, groups: &Vec<Group>) -> Vec<Section> {
+    let mut favourites = Section::new(FAVOURITES_KEY, "Favourites");
+    let mut direct_messages = Section::new(DIRECT_MESSAGES_KEY, "Direct Messages");
+    let mut communities: Vec<Section> = vec![];
+
+    for room in rooms.iter() {
+        if room.favourite.is_some() {
+            favourites.rooms.push(room.clone());
+        } else if room.oneToOne {
+            direct_messages.rooms.push(room.clone());
+        } else {
+            let name = community_name(room, groups);
+            let key = format!("community:{}", name.to_lowercase());
+
+            match communities.iter().position(|s| s.key == key) {
+                Some(index) => communities[index].rooms.push(room.clone()),
+                None => {
+                    let mut section = Section::new(&key[..], &name[..]);
+                    section.rooms.push(room.clone());
+                    communities.push(section);
+                },
+            };
+        }
+    }
+
+    // Favourites keep the order chosen on Gitter, everything else is alphabetized
+    favourites.rooms.sort_by_key(|room| room.favourite.unwrap_or(0));
+    direct_messages.rooms.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    communities.sort_unstable_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
+    for section in communities.iter_mut() {
+        section.rooms.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    let mut sections = vec![];
+
+    if favourites.rooms.len() > 0 {
+        sections.push(favourites);
+    }
+
+    sections.extend(communities);
+
+    if direct_messages.rooms.len() > 0 {
+        sections.push(direct_messages);
+    }
+
+    sections
+}
+
+// Remembers which sections are collapsed between sessions
+#[derive(Debug, Clone)]
+pub struct SidebarState {
+    path: Option<PathBuf>,
+    collapsed: HashSet<String>,
+}
+
+impl SidebarState {
+    // Reads state from sidebar.yaml next to config.yaml, or from the legacy
+    // $HOME/.gitter_gtk/sidebar.yaml until the first save; everything starts expanded if missing
+    pub fn load() -> SidebarState {
+        let path = config::dir().map(|dir| dir.join("sidebar.yaml"));
+
+        let legacy_path = ::std::env::var("HOME").ok()
+            .map(|home| PathBuf::from(home).join(".gitter_gtk").join("sidebar.yaml"));
+
+        let read_path = path.iter().chain(legacy_path.iter()).find(|path| path.exists()).cloned();
+
+        let mut collapsed = HashSet::new();
+
+        if let Some(ref read_path) = read_path {
+            let mut buffer = String::new();
+
+            if let Ok(mut f) = File::open(read_path) {
+                f.read_to_string(&mut buffer).unwrap_or(0);
+            }
+
+            if let Ok(docs) = YamlLoader::load_from_str(&buffer[..]) {
+                if let Some(doc) = docs.get(0) {
+                    if let Some(keys) = doc["collapsed"].as_vec() {
+                        for key in keys.iter().filter_map(|k| k.as_str()) {
+                            collapsed.insert(String::from(key));
+                        }
+                    }
+                }
+            }
+        }
+
+        SidebarState {
+            path: path,
+            collapsed: collapsed,
+        }
+    }
+
+    pub fn is_collapsed(&self, key: &str) -> bool {
+        self.collapsed.contains(key)
+    }
+
+    pub fn set_collapsed(&mut self, key: &str, collapsed: bool) {
+        if collapsed {
+            self.collapsed.insert(String::from(key));
+        } else {
+            self.collapsed.remove(key);
+        }
+
+        self.save();
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let mut keys: Vec<&String> = self.collapsed.iter().collect();
+        keys.sort();
+
+        let mut hash = ::yaml_rust::yaml::Hash::new();
+        hash.insert(
+            Yaml::String(String::from("collapsed")),
+            Yaml::Array(keys.iter().map(|k| Yaml::String((*k).clone())).collect())
+        );
+
+        let mut out = String::new();
+        {
+            let mut emitter = YamlEmitter::new(&mut out);
+            if let Err(e) = emitter.dump(&Yaml::Hash(hash)) {
+                error!("Writing sidebar state -> {:?}", e);
+                return;
+            }
+        }
+
+        let result = match path.parent() {
+            Some(dir) => fs::create_dir_all(dir),
+            None => Ok(()),
+        }.and_then(|_| File::create(path)).and_then(|mut f| f.write_all(out.as_bytes()));
+
+        if let Err(e) = result {
+            error!("Writing sidebar state to {} -> {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fixtures::room;
+
+    fn favourite(mut room: Room, position: u32) -> Room {
+        room.favourite = Some(position);
+        room
+    }
+
+    fn direct(mut room: Room) -> Room {
+        room.oneToOne = true;
+        room
+    }
+
+    fn in_group(mut room: Room, group_id: &str) -> Room {
+        room.groupId = Some(String::from(group_id));
+        room
+    }
+
+    fn group(id: &str, name: &str) -> Group {
+        Group {
+            id: String::from(id),
+            name: String::from(name),
+            uri: String::from(name),
+        }
+    }
+
+    fn titles(sections: &Vec<Section>) -> Vec<String> {
+        sections.iter().map(|section| section.title.clone()).collect()
+    }
+
+    fn names(section: &Section) -> Vec<String> {
+        section.rooms.iter().map(|room| room.name.clone()).collect()
+    }
+
+    #[test]
+    fn favourites_come_first_in_the_order_chosen_on_gitter() {
+        let rooms = vec![
+            room("a", "org/alpha"),
+            favourite(room("z", "org/zeta"), 1),
+            favourite(room("b", "org/beta"), 2),
+        ];
+
+        let sections = group_rooms(&rooms, &vec![]);
+
+        assert_eq!(sections[0].key, FAVOURITES_KEY);
+        assert_eq!(names(&sections[0]), vec!["org/zeta", "org/beta"]);
+        assert_eq!(titles(&sections), vec!["Favourites", "org"]);
+        assert_eq!(names(&sections[1]), vec!["org/alpha"]);
+    }
+
+    #[test]
+    fn rooms_are_grouped_by_community_from_groups_or_url() {
+        let rooms = vec![
+            room("c", "rust-lang/rust"),
+            in_group(room("a", "gitterHQ/sandbox"), "g1"),
+            room("b", "Rust-Lang/cargo"),
+            // The group name wins over the url of the room
+            in_group(room("d", "other/gitter"), "g1"),
+            // An unknown group falls back to the url
+            in_group(room("e", "zeta/repo"), "missing"),
+        ];
+
+        let sections = group_rooms(&rooms, &vec![group("g1", "Gitter")]);
+
+        assert_eq!(titles(&sections), vec!["Gitter", "rust-lang", "zeta"]);
+        assert_eq!(sections[0].key, "community:gitter");
+        assert_eq!(names(&sections[0]), vec!["gitterHQ/sandbox", "other/gitter"]);
+        // Communities differing only in case are one section, titled after the first room seen
+        assert_eq!(names(&sections[1]), vec!["Rust-Lang/cargo", "rust-lang/rust"]);
+    }
+
+    #[test]
+    fn direct_messages_come_last_unless_favourite() {
+        let rooms = vec![
+            direct(room("u2", "zoe")),
+            room("a", "org/alpha"),
+            direct(room("u1", "adam")),
+            favourite(direct(room("u3", "bea")), 1),
+        ];
+
+        let sections = group_rooms(&rooms, &vec![]);
+
+        assert_eq!(titles(&sections), vec!["Favourites", "org", "Direct Messages"]);
+        assert_eq!(names(&sections[0]), vec!["bea"]);
+        assert_eq!(sections[2].key, DIRECT_MESSAGES_KEY);
+        assert_eq!(names(&sections[2]), vec!["adam", "zoe"]);
+    }
+
+    #[test]
+    fn empty_sections_are_left_out() {
+        assert!(group_rooms(&vec![], &vec![]).is_empty());
+
+        let sections = group_rooms(&vec![room("a", "org/alpha")], &vec![]);
+        assert_eq!(titles(&sections), vec!["org"]);
+    }
+}
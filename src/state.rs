@@ -0,0 +1,996 @@
+// UI-agnostic application state. MainWindow turns GTK signals and backend events into
+// Actions; `reduce` applies them and returns the Changes the window has to render.
+
+use std::collections::HashMap;
+
+use connection::ConnectionState;
+use outbox::{DeliveryState, OutboxEvent, OutboxItem};
+use sidebar;
+use sidebar::Section;
+use {Group, Message, OutgoingMessage, Room, User};
+
+// Number of rooms remembered for ranking in the quick switcher
+pub const MAX_RECENT_ROOMS: usize = 20;
+
+// What the message box will do with its text when sent
+#[derive(Clone, Debug)]
+pub enum ComposerMode {
+    Normal,
+    Editing(Message),
+    Replying(Message),
+}
+
+impl ComposerMode {
+    pub fn is_normal(&self) -> bool {
+        match *self {
+            ComposerMode::Normal => true,
+            _ => false,
+        }
+    }
+}
+
+// A message in the outbox, shown below the chat until the server echoes it back
+#[derive(Clone, Debug)]
+pub struct PendingMessage {
+    pub item: OutboxItem,
+    pub server_id: Option<String>,
+}
+
+// Everything that can change the state, from the user or from the backend core
+#[derive(Clone, Debug)]
+pub enum Action {
+    RoomsUpdated { rooms: Vec<Room>, groups: Vec<Group> },
+    // `cached` is the history read from the cache, shown until the backend catches up
+    SwitchRoom { room_id: String, cached: Vec<Message> },
+    MessagesAdded { room_id: String, messages: Vec<Message> },
+    // History around a message, replacing the visible messages
+    ContextLoaded { room_id: String, message_id: String, messages: Vec<Message> },
+    // A page of history from before the first visible message, e.g. when an archive is scrolled up
+    OlderMessagesLoaded { room_id: String, messages: Vec<Message> },
+    FilterMessages(String),
+    EditLastOwnMessage,
+    ReplyTo(String),
+    CancelComposer,
+    // A message the user sent, already handed to the backend core
+    Queued(OutboxItem),
+    RetryPending(String),
+    DiscardPending(String),
+    Outbox(OutboxEvent),
+    ConnectionChanged(ConnectionState),
+}
+
+// What the window has to render after an action, in order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    RoomsChanged,
+    // Unread or mention counts of one room, shown in its sidebar row
+    RoomCountsChanged(String),
+    CurrentRoomChanged,
+    // Every visible message has to be rebuilt
+    MessagesReset,
+    // Ids of messages added to the bottom of the chat
+    MessagesAppended(Vec<String>),
+    MessageEdited(String),
+    MessageFilterChanged,
+    ScrollTo(String),
+    ComposerChanged { clear_text: bool },
+    PendingChanged,
+    ConnectionChanged,
+}
+
+pub struct AppState {
+    pub user: User,
+    // Rooms grouped for the sidebar; `rooms` is the same list flattened in sidebar order
+    pub sections: Vec<Section>,
+    pub rooms: Vec<Room>,
+    pub current_room_id: Option<String>,
+    // Most recently opened first
+    pub recent_rooms: Vec<String>,
+    // Messages of the current room, oldest first
+    pub messages: Vec<Message>,
+    pub message_filter: String,
+    pub composer: ComposerMode,
+    pub pending: Vec<PendingMessage>,
+    pub connection: ConnectionState,
+    // Unread and mention counts of each room as the server last reported them
+    reported_counts: HashMap<String, (u32, u32)>,
+}
+
+impl AppState {
+    pub fn new(user: &User, rooms: &Vec<Room>, groups: &Vec<Group>) -> AppState {
+        let mut state = AppState {
+            user: user.clone(),
+            sections: vec![],
+            rooms: vec![],
+            current_room_id: None,
+            recent_rooms: vec![],
+            messages: vec![],
+            message_filter: String::new(),
+            composer: ComposerMode::Normal,
+            pending: vec![],
+            connection: ConnectionState::Connecting,
+            reported_counts: HashMap::new(),
+        };
+
+        state.set_rooms(rooms, groups);
+
+        state
+    }
+
+    pub fn current_room(&self) -> Option<&Room> {
+        match self.current_room_id {
+            Some(ref id) => self.rooms.iter().find(|room| &room.id == id),
+            None => None,
+        }
+    }
+
+    pub fn message(&self, id: &String) -> Option<&Message> {
+        self.messages.iter().find(|message| &message.id == id)
+    }
+
+    // Whether the message passes the in-room search
+    pub fn is_visible(&self, message: &Message) -> bool {
+        message_matches(message, &self.message_filter[..])
+    }
+
+    // Undelivered messages of the current room
+    pub fn current_pending(&self) -> Vec<&PendingMessage> {
+        self.pending.iter()
+            .filter(|pending| Some(&pending.item.room_id) == self.current_room_id.as_ref())
+            .collect()
+    }
+
+    // What sending `text` from the composer would queue, if anything
+    pub fn outgoing_message(&self, text: &str) -> Option<OutgoingMessage> {
+        if text.trim().len() == 0 || self.current_room_id.is_none() {
+            return None;
+        }
+
+        let text = String::from(text);
+
+        Some(match self.composer {
+            ComposerMode::Normal => OutgoingMessage::New { text: text, parent_id: None },
+            ComposerMode::Replying(ref message) => OutgoingMessage::New { text: text, parent_id: Some(message.id.clone()) },
+            ComposerMode::Editing(ref message) => OutgoingMessage::Edit { id: message.id.clone(), text: text },
+        })
+    }
+
+    // The room above or below the current one in the sidebar, wrapping around, optionally skipping read rooms
+    pub fn adjacent_room(&self, forward: bool, unread_only: bool) -> Option<String> {
+        let current_index = match self.current_room_id {
+            Some(ref id) => self.rooms.iter().position(|room| &room.id == id).unwrap_or(0),
+            None => 0,
+        };
+
+        let count = self.rooms.len();
+
+        for step in 1..count {
+            let index = if forward {
+                (current_index + step) % count
+            } else {
+                (current_index + count - step) % count
+            };
+
+            let room = &self.rooms[index];
+            if !unread_only || room.unreadItems > 0 || room.mentions > 0 {
+                return Some(room.id.clone());
+            }
+        }
+
+        None
+    }
+
+    pub fn reduce(&mut self, action: Action) -> Vec<Change> {
+        match action {
+            Action::RoomsUpdated { rooms, groups } => self.set_rooms(&rooms, &groups),
+            Action::SwitchRoom { room_id, cached } => self.switch_room(room_id, cached),
+            Action::MessagesAdded { room_id, messages } => self.add_messages(&room_id, messages),
+            Action::ContextLoaded { room_id, message_id, messages } => {
+                if self.current_room_id.as_ref() != Some(&room_id) {
+                    return vec![];
+                }
+
+                self.messages = messages;
+                self.confirm_echoed();
+
+                vec![Change::MessagesReset, Change::PendingChanged, Change::ScrollTo(message_id)]
+            },
+            Action::OlderMessagesLoaded { room_id, messages } => {
+                if self.current_room_id.as_ref() != Some(&room_id) {
+                    return vec![];
+                }
+
+                let mut older: Vec<Message> = messages.into_iter()
+                    .filter(|message| self.message(&message.id).is_none())
+                    .collect();
+
+                // The message which was at the top stays in view
+                let first_id = match self.messages.first() {
+                    Some(first) if older.len() > 0 => first.id.clone(),
+                    _ => return vec![],
+                };
+
+                older.extend(self.messages.drain(..));
+                self.messages = older;
+                self.confirm_echoed();
+
+                vec![Change::MessagesReset, Change::PendingChanged, Change::ScrollTo(first_id)]
+            },
+            Action::FilterMessages(query) => {
+                self.message_filter = query;
+                vec![Change::MessageFilterChanged]
+            },
+            Action::EditLastOwnMessage => {
+                let last_own_message = self.messages.iter().rev()
+                    .find(|message| message.fromUser.id == self.user.id)
+                    .cloned();
+
+                match last_own_message {
+                    Some(message) => self.set_composer(ComposerMode::Editing(message)),
+                    None => vec![],
+                }
+            },
+            Action::ReplyTo(message_id) => {
+                let message = self.message(&message_id).cloned();
+
+                match message {
+                    Some(message) => self.set_composer(ComposerMode::Replying(message)),
+                    None => vec![],
+                }
+            },
+            Action::CancelComposer => self.set_composer(ComposerMode::Normal),
+            Action::Queued(item) => self.queue(item),
+            Action::RetryPending(client_id) => {
+                if let Some(pending) = self.pending.iter_mut().find(|p| p.item.client_id == client_id) {
+                    pending.item.retry();
+                }
+
+                vec![Change::PendingChanged]
+            },
+            Action::DiscardPending(client_id) => {
+                self.pending.retain(|p| p.item.client_id != client_id);
+                vec![Change::PendingChanged]
+            },
+            Action::Outbox(event) => {
+                self.handle_outbox_event(event);
+                vec![Change::PendingChanged]
+            },
+            Action::ConnectionChanged(state) => {
+                self.connection = state;
+                vec![Change::ConnectionChanged]
+            },
+        }
+    }
+
+    // Rebuilds the sidebar only if its rooms or sections changed, otherwise just the counts
+    // that differ; counts tracked here since are kept until the server reports new ones
+    fn set_rooms(&mut self, rooms: &Vec<Room>, groups: &Vec<Group>) -> Vec<Change> {
+        let mut rooms = rooms.clone();
+
+        for room in rooms.iter_mut() {
+            let reported = (room.unreadItems, room.mentions);
+
+            if self.reported_counts.get(&room.id) == Some(&reported) {
+                if let Some(known) = self.rooms.iter().find(|known| known.id == room.id) {
+                    room.unreadItems = known.unreadItems;
+                    room.mentions = known.mentions;
+                }
+            }
+
+            self.reported_counts.insert(room.id.clone(), reported);
+        }
+
+        let mut sections = sidebar::group_rooms(&rooms, groups);
+
+        // The current room is being read, whatever the server counted
+        if let Some(ref id) = self.current_room_id {
+            for section in sections.iter_mut() {
+                for room in section.rooms.iter_mut().filter(|room| &room.id == id) {
+                    room.unreadItems = 0;
+                    room.mentions = 0;
+                }
+            }
+        }
+
+        let same_layout = sections.len() == self.sections.len() &&
+            sections.iter().zip(self.sections.iter()).all(|(new, old)| same_section_layout(new, old));
+
+        let recounted: Vec<String> = sections.iter()
+            .flat_map(|section| section.rooms.iter())
+            .filter(|room| match self.rooms.iter().find(|known| known.id == room.id) {
+                Some(known) => (known.unreadItems, known.mentions) != (room.unreadItems, room.mentions),
+                None => true,
+            })
+            .map(|room| room.id.clone())
+            .collect();
+
+        self.sections = sections;
+        self.rooms = self.sections.iter()
+            .flat_map(|section| section.rooms.iter().cloned())
+            .collect();
+
+        if same_layout {
+            recounted.into_iter().map(Change::RoomCountsChanged).collect()
+        } else {
+            vec![Change::RoomsChanged]
+        }
+    }
+
+    fn switch_room(&mut self, room_id: String, cached: Vec<Message>) -> Vec<Change> {
+        self.recent_rooms.retain(|id| id != &room_id);
+        self.recent_rooms.insert(0, room_id.clone());
+        self.recent_rooms.truncate(MAX_RECENT_ROOMS);
+
+        self.update_room(&room_id, |room| {
+            room.unreadItems = 0;
+            room.mentions = 0;
+        });
+
+        self.current_room_id = Some(room_id.clone());
+        self.messages = cached;
+        self.message_filter = String::new();
+        self.confirm_echoed();
+
+        let mut changes = vec![
+            Change::CurrentRoomChanged,
+            Change::RoomCountsChanged(room_id),
+            Change::MessagesReset,
+            Change::PendingChanged,
+        ];
+        changes.extend(self.set_composer(ComposerMode::Normal));
+
+        changes
+    }
+
+    fn add_messages(&mut self, room_id: &String, messages: Vec<Message>) -> Vec<Change> {
+        let mut new_messages = vec![];
+        let mut edits = vec![];
+
+        for message in messages.into_iter() {
+            let shown = self.message(&message.id).map(|shown| (shown.v, shown.text.clone()));
+
+            match shown {
+                None => new_messages.push(message),
+                // An edit keeps the id of the original; Gitter counts up `v`, Matrix only changes the text
+                Some((v, ref text)) if message.v > v || (message.v == v && &message.text != text) => edits.push(message),
+                // Already shown from the cache or received earlier
+                Some(_) => (),
+            };
+        }
+
+        if new_messages.len() == 0 && edits.len() == 0 {
+            return vec![];
+        }
+
+        if self.current_room_id.as_ref() != Some(room_id) {
+            // Edits are not new to anyone
+            if new_messages.len() == 0 {
+                return vec![];
+            }
+
+            let username = self.user.username.clone();
+            let unread = new_messages.len() as u32;
+            let mentions = new_messages.iter()
+                .filter(|message| message.mentions.iter().any(|mention| mention.screenName == username))
+                .count() as u32;
+
+            self.update_room(room_id, |room| {
+                room.unreadItems += unread;
+                room.mentions += mentions;
+            });
+
+            return vec![Change::RoomCountsChanged(room_id.clone())];
+        }
+
+        let ids: Vec<String> = new_messages.iter().map(|message| message.id.clone()).collect();
+        let edited_ids: Vec<String> = edits.iter().map(|message| message.id.clone()).collect();
+
+        for edit in edits.into_iter() {
+            if let Some(message) = self.messages.iter_mut().find(|message| message.id == edit.id) {
+                message.text = edit.text;
+                message.html = edit.html;
+                message.v = edit.v;
+            }
+        }
+
+        self.messages.extend(new_messages);
+        self.confirm_echoed();
+
+        let mut changes = vec![];
+        if ids.len() > 0 {
+            changes.push(Change::MessagesAppended(ids));
+        }
+        changes.extend(edited_ids.into_iter().map(Change::MessageEdited));
+
+        // Undelivered messages stay below the newest history
+        changes.push(Change::PendingChanged);
+        changes
+    }
+
+    // The sidebar sections hold their own copies of the rooms, so both are kept in step
+    fn update_room<F>(&mut self, room_id: &String, mut update: F) where F: FnMut(&mut Room) {
+        for room in self.rooms.iter_mut().filter(|room| &room.id == room_id) {
+            update(room);
+        }
+
+        for section in self.sections.iter_mut() {
+            for room in section.rooms.iter_mut().filter(|room| &room.id == room_id) {
+                update(room);
+            }
+        }
+    }
+
+    // The server echoed back some of our queued messages, so they are delivered. An edit is
+    // confirmed on the id of the message it changed. Also run when shown messages are replaced,
+    // as the echo may be among cached or loaded history rather than arrive as new.
+    fn confirm_echoed(&mut self) {
+        let shown: Vec<String> = self.messages.iter().map(|message| message.id.clone()).collect();
+
+        self.pending.retain(|pending| match pending.server_id {
+            Some(ref server_id) => !shown.contains(server_id),
+            None => true,
+        });
+    }
+
+    fn set_composer(&mut self, mode: ComposerMode) -> Vec<Change> {
+        if mode.is_normal() && self.composer.is_normal() {
+            return vec![];
+        }
+
+        // Leaving an edit throws away the edited text; replies keep what was typed
+        let clear_text = match (&self.composer, &mode) {
+            (&ComposerMode::Editing(_), &ComposerMode::Normal) => true,
+            _ => false,
+        };
+
+        self.composer = mode;
+
+        vec![Change::ComposerChanged { clear_text: clear_text }]
+    }
+
+    fn queue(&mut self, item: OutboxItem) -> Vec<Change> {
+        let mut changes = vec![];
+
+        // Shows the edited text right away instead of waiting for the server
+        if let OutgoingMessage::Edit { ref id, ref text } = item.message {
+            if let Some(message) = self.messages.iter_mut().find(|message| &message.id == id) {
+                message.text = text.clone();
+                changes.push(Change::MessageEdited(id.clone()));
+            }
+        }
+
+        self.pending.push(PendingMessage {
+            item: item,
+            server_id: None,
+        });
+        changes.push(Change::PendingChanged);
+
+        self.composer = ComposerMode::Normal;
+        changes.push(Change::ComposerChanged { clear_text: true });
+
+        changes
+    }
+
+    fn handle_outbox_event(&mut self, event: OutboxEvent) {
+        match event {
+            OutboxEvent::Queued(item) => {
+                self.pending.push(PendingMessage {
+                    item: item,
+                    server_id: None,
+                });
+            },
+            OutboxEvent::Sent { client_id, server_id } => {
+                // Confirmed right away if the echo already arrived, otherwise by MessagesAdded
+                let echoed = self.message(&server_id).is_some();
+
+                if echoed {
+                    self.pending.retain(|pending| pending.item.client_id != client_id);
+                } else if let Some(pending) = self.pending.iter_mut().find(|p| p.item.client_id == client_id) {
+                    pending.server_id = Some(server_id);
+                }
+            },
+            OutboxEvent::Retrying { client_id, attempts } => {
+                if let Some(pending) = self.pending.iter_mut().find(|p| p.item.client_id == client_id) {
+                    pending.item.attempts = attempts;
+                }
+            },
+            OutboxEvent::Failed { client_id } => {
+                if let Some(pending) = self.pending.iter_mut().find(|p| p.item.client_id == client_id) {
+                    pending.item.state = DeliveryState::Failed;
+                }
+            },
+        };
+    }
+}
+
+// Case-insensitive match of the in-room search query against text and author
+pub fn message_matches(message: &Message, query: &str) -> bool {
+    let query = query.trim().to_lowercase();
+
+    query.len() == 0
+        || message.text.to_lowercase().contains(&query[..])
+        || message.fromUser.username.to_lowercase().contains(&query[..])
+}
+
+// Whether two sections show the same rooms under the same title, whatever their counts
+fn same_section_layout(new: &Section, old: &Section) -> bool {
+    new.key == old.key && new.title == old.title && new.rooms.len() == old.rooms.len() &&
+        new.rooms.iter().zip(old.rooms.iter()).all(|(new, old)| new.id == old.id && new.name == old.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fixtures;
+    use tests::fixtures::{room, user};
+    use Mention;
+
+    fn me() -> User {
+        user("u1", "me")
+    }
+
+    // The reducer does not look at when messages were sent
+    fn message(id: &str, from: &User, text: &str) -> Message {
+        fixtures::message(id, from, "2017-11-01T10:00:00.000Z", text)
+    }
+
+    fn mention(mut message: Message, username: &str) -> Message {
+        message.mentions.push(Mention { screenName: String::from(username) });
+        message
+    }
+
+    // Rooms a/alpha, b/beta and c/gamma, sorted into one community section
+    fn state_with_rooms() -> AppState {
+        AppState::new(&me(), &vec![room("c", "org/gamma"), room("a", "org/alpha"), room("b", "org/beta")], &vec![])
+    }
+
+    fn state_in_room(room_id: &str) -> AppState {
+        let mut state = state_with_rooms();
+        state.reduce(Action::SwitchRoom { room_id: String::from(room_id), cached: vec![] });
+        state
+    }
+
+    fn new_message(room_id: &str, text: &str) -> OutboxItem {
+        OutboxItem::new(&String::from(room_id), OutgoingMessage::New { text: String::from(text), parent_id: None })
+    }
+
+    fn ids(messages: &Vec<Message>) -> Vec<String> {
+        messages.iter().map(|message| message.id.clone()).collect()
+    }
+
+    #[test]
+    fn new_state_flattens_sections_in_sidebar_order() {
+        let state = state_with_rooms();
+
+        let names: Vec<String> = state.rooms.iter().map(|room| room.name.clone()).collect();
+        assert_eq!(names, vec!["org/alpha", "org/beta", "org/gamma"]);
+        assert_eq!(state.current_room_id, None);
+        assert_eq!(state.connection, ConnectionState::Connecting);
+    }
+
+    #[test]
+    fn switch_room_shows_cached_messages_and_clears_unread() {
+        let mut state = state_with_rooms();
+        state.rooms[1].unreadItems = 4;
+        state.rooms[1].mentions = 1;
+
+        let cached = vec![message("m1", &me(), "hello")];
+        let changes = state.reduce(Action::SwitchRoom { room_id: String::from("b"), cached: cached });
+
+        assert_eq!(changes, vec![
+            Change::CurrentRoomChanged,
+            Change::RoomCountsChanged(String::from("b")),
+            Change::MessagesReset,
+            Change::PendingChanged,
+        ]);
+        assert_eq!(state.current_room().unwrap().name, "org/beta");
+        assert_eq!(state.current_room().unwrap().unreadItems, 0);
+        assert_eq!(state.current_room().unwrap().mentions, 0);
+        assert_eq!(ids(&state.messages), vec!["m1"]);
+    }
+
+    #[test]
+    fn switch_room_confirms_echoes_among_cached_messages() {
+        let mut state = state_in_room("a");
+
+        let item = new_message("b", "hi");
+        let client_id = item.client_id.clone();
+        state.reduce(Action::Queued(item));
+        state.reduce(Action::Outbox(OutboxEvent::Sent { client_id: client_id, server_id: String::from("m1") }));
+        assert_eq!(state.pending.len(), 1);
+
+        state.reduce(Action::SwitchRoom { room_id: String::from("b"), cached: vec![message("m1", &me(), "hi")] });
+
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn switch_room_keeps_recent_rooms_unique_and_bounded() {
+        let mut state = state_with_rooms();
+
+        for id in vec!["a", "b", "a", "c"] {
+            state.reduce(Action::SwitchRoom { room_id: String::from(id), cached: vec![] });
+        }
+
+        assert_eq!(state.recent_rooms, vec!["c", "a", "b"]);
+
+        for i in 0..(MAX_RECENT_ROOMS + 5) {
+            state.reduce(Action::SwitchRoom { room_id: format!("room{}", i), cached: vec![] });
+        }
+
+        assert_eq!(state.recent_rooms.len(), MAX_RECENT_ROOMS);
+    }
+
+    #[test]
+    fn switch_room_resets_filter_and_cancels_edit() {
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &me(), "mine")] });
+        state.reduce(Action::FilterMessages(String::from("mine")));
+        state.reduce(Action::EditLastOwnMessage);
+
+        let changes = state.reduce(Action::SwitchRoom { room_id: String::from("b"), cached: vec![] });
+
+        assert!(changes.contains(&Change::ComposerChanged { clear_text: true }));
+        assert!(state.composer.is_normal());
+        assert_eq!(state.message_filter, "");
+        assert!(state.messages.is_empty());
+    }
+
+    #[test]
+    fn messages_added_skips_messages_already_shown() {
+        let other = user("u2", "other");
+        let mut state = state_with_rooms();
+        state.reduce(Action::SwitchRoom { room_id: String::from("a"), cached: vec![message("m1", &other, "one")] });
+
+        let changes = state.reduce(Action::MessagesAdded {
+            room_id: String::from("a"),
+            messages: vec![message("m1", &other, "one"), message("m2", &other, "two")],
+        });
+
+        assert_eq!(changes, vec![Change::MessagesAppended(vec![String::from("m2")]), Change::PendingChanged]);
+        assert_eq!(ids(&state.messages), vec!["m1", "m2"]);
+
+        let changes = state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m2", &other, "two")] });
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn edits_of_shown_messages_change_their_text() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &other, "typo")] });
+
+        // Matrix keeps the version and only changes the text
+        let changes = state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &other, "fixed")] });
+        assert_eq!(changes, vec![Change::MessageEdited(String::from("m1")), Change::PendingChanged]);
+        assert_eq!(state.messages[0].text, "fixed");
+
+        // Gitter counts the version up; an older version is not applied
+        let mut gitter_edit = message("m1", &other, "fixed again");
+        gitter_edit.v = 2;
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![gitter_edit] });
+        assert_eq!((&state.messages[0].text[..], state.messages[0].v), ("fixed again", 2));
+
+        let changes = state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &other, "typo")] });
+        assert!(changes.is_empty());
+        assert_eq!(ids(&state.messages), vec!["m1"]);
+    }
+
+    #[test]
+    fn own_edit_is_confirmed_on_the_original_id() {
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &me(), "typo")] });
+
+        let item = OutboxItem::new(&String::from("a"), OutgoingMessage::Edit { id: String::from("m1"), text: String::from("fixed") });
+        let client_id = item.client_id.clone();
+        state.reduce(Action::Queued(item));
+        assert_eq!(state.messages[0].text, "fixed");
+
+        // The message it changed is shown, so the edit is delivered once the server answers
+        state.reduce(Action::Outbox(OutboxEvent::Sent { client_id: client_id, server_id: String::from("m1") }));
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn messages_for_other_rooms_count_as_unread() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+
+        let changes = state.reduce(Action::MessagesAdded {
+            room_id: String::from("b"),
+            messages: vec![message("m1", &other, "hi"), mention(message("m2", &other, "@me hi"), "me")],
+        });
+
+        assert_eq!(changes, vec![Change::RoomCountsChanged(String::from("b"))]);
+        assert!(state.messages.is_empty());
+
+        let beta = state.rooms.iter().find(|room| room.id == "b").unwrap();
+        assert_eq!(beta.unreadItems, 2);
+        assert_eq!(beta.mentions, 1);
+
+        // The sidebar is drawn from the sections, which must agree
+        let beta = state.sections.iter().flat_map(|section| section.rooms.iter()).find(|room| room.id == "b").unwrap();
+        assert_eq!(beta.unreadItems, 2);
+        assert_eq!(beta.mentions, 1);
+
+        state.reduce(Action::SwitchRoom { room_id: String::from("b"), cached: vec![] });
+
+        let beta = state.sections.iter().flat_map(|section| section.rooms.iter()).find(|room| room.id == "b").unwrap();
+        assert_eq!(beta.unreadItems, 0);
+        assert_eq!(beta.mentions, 0);
+    }
+
+    #[test]
+    fn rooms_updated_keeps_current_room_read() {
+        let mut state = state_in_room("a");
+
+        let mut alpha = room("a", "org/alpha");
+        alpha.unreadItems = 7;
+        let mut delta = room("d", "org/delta");
+        delta.unreadItems = 2;
+
+        let changes = state.reduce(Action::RoomsUpdated { rooms: vec![alpha, delta], groups: vec![] });
+
+        assert_eq!(changes, vec![Change::RoomsChanged]);
+        assert_eq!(state.rooms.len(), 2);
+        assert_eq!(state.current_room().unwrap().unreadItems, 0);
+        assert_eq!(state.rooms[1].unreadItems, 2);
+    }
+
+    #[test]
+    fn rooms_updated_with_the_same_rooms_only_recounts() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+        let rooms = vec![room("c", "org/gamma"), room("a", "org/alpha"), room("b", "org/beta")];
+
+        let changes = state.reduce(Action::RoomsUpdated { rooms: rooms.clone(), groups: vec![] });
+        assert!(changes.is_empty());
+
+        // Counted here while the server still reports the old count
+        state.reduce(Action::MessagesAdded { room_id: String::from("b"), messages: vec![message("m1", &other, "hi")] });
+
+        let changes = state.reduce(Action::RoomsUpdated { rooms: rooms.clone(), groups: vec![] });
+        assert!(changes.is_empty());
+        assert_eq!(state.rooms.iter().find(|room| room.id == "b").unwrap().unreadItems, 1);
+
+        // A new count from the server replaces it
+        let mut recounted = rooms.clone();
+        recounted[2].unreadItems = 5;
+
+        let changes = state.reduce(Action::RoomsUpdated { rooms: recounted, groups: vec![] });
+        assert_eq!(changes, vec![Change::RoomCountsChanged(String::from("b"))]);
+        assert_eq!(state.rooms.iter().find(|room| room.id == "b").unwrap().unreadItems, 5);
+    }
+
+    #[test]
+    fn context_loaded_replaces_messages_of_current_room_only() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m9", &other, "latest")] });
+
+        let changes = state.reduce(Action::ContextLoaded {
+            room_id: String::from("b"),
+            message_id: String::from("m2"),
+            messages: vec![message("m2", &other, "old")],
+        });
+        assert!(changes.is_empty());
+        assert_eq!(ids(&state.messages), vec!["m9"]);
+
+        let changes = state.reduce(Action::ContextLoaded {
+            room_id: String::from("a"),
+            message_id: String::from("m2"),
+            messages: vec![message("m1", &other, "older"), message("m2", &other, "old")],
+        });
+        assert_eq!(changes, vec![Change::MessagesReset, Change::PendingChanged, Change::ScrollTo(String::from("m2"))]);
+        assert_eq!(ids(&state.messages), vec!["m1", "m2"]);
+    }
+
+    #[test]
+    fn older_messages_are_put_before_the_first_shown() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m3", &other, "third")] });
+
+        let changes = state.reduce(Action::OlderMessagesLoaded {
+            room_id: String::from("a"),
+            messages: vec![message("m1", &other, "first"), message("m2", &other, "second"), message("m3", &other, "third")],
+        });
+        assert_eq!(changes, vec![Change::MessagesReset, Change::PendingChanged, Change::ScrollTo(String::from("m3"))]);
+        assert_eq!(ids(&state.messages), vec!["m1", "m2", "m3"]);
+
+        // Nothing older left, or another room
+        assert!(state.reduce(Action::OlderMessagesLoaded { room_id: String::from("a"), messages: vec![] }).is_empty());
+        assert!(state.reduce(Action::OlderMessagesLoaded {
+            room_id: String::from("b"),
+            messages: vec![message("m0", &other, "elsewhere")],
+        }).is_empty());
+        assert_eq!(ids(&state.messages), vec!["m1", "m2", "m3"]);
+    }
+
+    #[test]
+    fn filter_matches_text_and_author_case_insensitively() {
+        let other = user("u2", "Other");
+        let mut state = state_in_room("a");
+        let hello = message("m1", &other, "Hello World");
+
+        assert!(state.is_visible(&hello));
+
+        assert_eq!(state.reduce(Action::FilterMessages(String::from("  world "))), vec![Change::MessageFilterChanged]);
+        assert!(state.is_visible(&hello));
+
+        state.reduce(Action::FilterMessages(String::from("other")));
+        assert!(state.is_visible(&hello));
+
+        state.reduce(Action::FilterMessages(String::from("goodbye")));
+        assert!(!state.is_visible(&hello));
+    }
+
+    #[test]
+    fn edit_last_own_message_skips_other_users() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+
+        assert!(state.reduce(Action::EditLastOwnMessage).is_empty());
+
+        state.reduce(Action::MessagesAdded {
+            room_id: String::from("a"),
+            messages: vec![message("m1", &me(), "first"), message("m2", &me(), "second"), message("m3", &other, "theirs")],
+        });
+
+        let changes = state.reduce(Action::EditLastOwnMessage);
+
+        assert_eq!(changes, vec![Change::ComposerChanged { clear_text: false }]);
+        match state.composer {
+            ComposerMode::Editing(ref message) => assert_eq!(message.id, "m2"),
+            ref mode => panic!("expected editing, got {:?}", mode),
+        };
+    }
+
+    #[test]
+    fn reply_and_cancel() {
+        let other = user("u2", "other");
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &other, "question?")] });
+
+        assert!(state.reduce(Action::ReplyTo(String::from("missing"))).is_empty());
+
+        state.reduce(Action::ReplyTo(String::from("m1")));
+        match state.outgoing_message("answer") {
+            Some(OutgoingMessage::New { ref parent_id, .. }) => assert_eq!(parent_id, &Some(String::from("m1"))),
+            other => panic!("expected a reply, got {:?}", other),
+        };
+
+        // Cancelling a reply keeps the typed text
+        assert_eq!(state.reduce(Action::CancelComposer), vec![Change::ComposerChanged { clear_text: false }]);
+        assert!(state.composer.is_normal());
+        assert!(state.reduce(Action::CancelComposer).is_empty());
+    }
+
+    #[test]
+    fn outgoing_message_needs_text_and_a_room() {
+        let mut state = state_with_rooms();
+        assert!(state.outgoing_message("hi").is_none());
+
+        state.reduce(Action::SwitchRoom { room_id: String::from("a"), cached: vec![] });
+        assert!(state.outgoing_message("   ").is_none());
+
+        match state.outgoing_message("hi") {
+            Some(OutgoingMessage::New { ref text, ref parent_id }) => {
+                assert_eq!(text, "hi");
+                assert_eq!(parent_id, &None);
+            },
+            other => panic!("expected a new message, got {:?}", other),
+        };
+    }
+
+    #[test]
+    fn queued_edit_updates_message_and_resets_composer() {
+        let mut state = state_in_room("a");
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("m1", &me(), "typo")] });
+        state.reduce(Action::EditLastOwnMessage);
+
+        let outgoing = state.outgoing_message("fixed").unwrap();
+        let item = OutboxItem::new(&String::from("a"), outgoing);
+        let changes = state.reduce(Action::Queued(item));
+
+        assert_eq!(changes, vec![
+            Change::MessageEdited(String::from("m1")),
+            Change::PendingChanged,
+            Change::ComposerChanged { clear_text: true },
+        ]);
+        assert_eq!(state.messages[0].text, "fixed");
+        assert!(state.composer.is_normal());
+        assert_eq!(state.pending.len(), 1);
+    }
+
+    #[test]
+    fn pending_messages_are_shown_in_their_own_room() {
+        let mut state = state_in_room("a");
+        state.reduce(Action::Queued(new_message("a", "here")));
+        state.reduce(Action::Queued(new_message("b", "there")));
+
+        assert_eq!(state.current_pending().len(), 1);
+
+        state.reduce(Action::SwitchRoom { room_id: String::from("b"), cached: vec![] });
+        let pending = state.current_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].item.room_id, "b");
+    }
+
+    #[test]
+    fn sent_message_is_confirmed_when_echoed() {
+        let mut state = state_in_room("a");
+        let item = new_message("a", "hello");
+        let client_id = item.client_id.clone();
+        state.reduce(Action::Queued(item));
+
+        state.reduce(Action::Outbox(OutboxEvent::Sent { client_id: client_id.clone(), server_id: String::from("s1") }));
+        assert_eq!(state.pending[0].server_id, Some(String::from("s1")));
+
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("s1", &me(), "hello")] });
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn sent_message_already_echoed_is_confirmed_right_away() {
+        let mut state = state_in_room("a");
+        let item = new_message("a", "hello");
+        let client_id = item.client_id.clone();
+        state.reduce(Action::Queued(item));
+        state.reduce(Action::MessagesAdded { room_id: String::from("a"), messages: vec![message("s1", &me(), "hello")] });
+
+        state.reduce(Action::Outbox(OutboxEvent::Sent { client_id: client_id, server_id: String::from("s1") }));
+
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn failed_message_can_be_retried_or_discarded() {
+        let mut state = state_in_room("a");
+        let item = new_message("a", "hello");
+        let client_id = item.client_id.clone();
+        state.reduce(Action::Queued(item));
+
+        state.reduce(Action::Outbox(OutboxEvent::Retrying { client_id: client_id.clone(), attempts: 2 }));
+        assert_eq!(state.pending[0].item.attempts, 2);
+
+        state.reduce(Action::Outbox(OutboxEvent::Failed { client_id: client_id.clone() }));
+        assert_eq!(state.pending[0].item.state, DeliveryState::Failed);
+
+        assert_eq!(state.reduce(Action::RetryPending(client_id.clone())), vec![Change::PendingChanged]);
+        assert_eq!(state.pending[0].item.state, DeliveryState::Sending);
+        assert_eq!(state.pending[0].item.attempts, 0);
+
+        state.reduce(Action::DiscardPending(client_id));
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn queued_from_previous_session_is_pending() {
+        let mut state = state_in_room("a");
+
+        state.reduce(Action::Outbox(OutboxEvent::Queued(new_message("a", "left over"))));
+
+        assert_eq!(state.current_pending().len(), 1);
+    }
+
+    #[test]
+    fn adjacent_room_wraps_and_skips_read_rooms() {
+        let mut state = state_in_room("a");
+
+        assert_eq!(state.adjacent_room(true, false), Some(String::from("b")));
+        assert_eq!(state.adjacent_room(false, false), Some(String::from("c")));
+        assert_eq!(state.adjacent_room(true, true), None);
+
+        state.rooms[2].mentions = 1;
+        assert_eq!(state.adjacent_room(true, true), Some(String::from("c")));
+    }
+
+    #[test]
+    fn connection_changes_are_rendered() {
+        let mut state = state_with_rooms();
+
+        assert_eq!(state.reduce(Action::ConnectionChanged(ConnectionState::Offline)), vec![Change::ConnectionChanged]);
+        assert_eq!(state.connection, ConnectionState::Offline);
+    }
+}
@@ -0,0 +1,577 @@
+// On-disk cache of rooms, users and messages so the app starts from cache and works offline
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use rusqlite;
+use rusqlite::{Connection, OpenFlags};
+use serde_json;
+
+use outbox::OutboxItem;
+use search::SearchResult;
+use {Group, Message, Room, User};
+
+// Each entry upgrades the schema by one version; never edit an entry once released
+const MIGRATIONS: &'static [&'static str] = &[
+    // 1: initial schema
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        is_current INTEGER NOT NULL DEFAULT 0,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE rooms (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE groups (
+        id TEXT PRIMARY KEY,
+        json TEXT NOT NULL
+    );
+    CREATE TABLE messages (
+        id TEXT PRIMARY KEY,
+        room_id TEXT NOT NULL,
+        sent TEXT NOT NULL,
+        from_user_id TEXT NOT NULL,
+        text TEXT NOT NULL,
+        json TEXT NOT NULL
+    );
+    CREATE INDEX messages_room_sent ON messages (room_id, sent);",
+    // 2: messages waiting to be sent
+    "CREATE TABLE outbox (
+        client_id TEXT PRIMARY KEY,
+        json TEXT NOT NULL
+    );",
+];
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    // The schema version of a database opened read-only, which cannot be migrated
+    Version(i64),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Io(ref e) => write!(f, "IO error: {}", e),
+            StoreError::Json(ref e) => write!(f, "JSON error: {}", e),
+            StoreError::Sqlite(ref e) => write!(f, "SQLite error: {}", e),
+            StoreError::Version(0) => write!(f, "not a cache database"),
+            StoreError::Version(version) => write!(f, "cache database of a newer version (schema {})", version),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> StoreError {
+        StoreError::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> StoreError {
+        StoreError::Sqlite(e)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+// Everything the app needs from the cache; kept free of GTK so it can be used from any thread
+pub trait Store {
+    fn save_current_user(&self, user: &User) -> StoreResult<()>;
+    fn load_current_user(&self) -> StoreResult<Option<User>>;
+
+    // Replaces the cached room list with the rooms currently joined
+    fn save_rooms(&self, rooms: &Vec<Room>) -> StoreResult<()>;
+    fn load_rooms(&self) -> StoreResult<Vec<Room>>;
+
+    fn save_groups(&self, groups: &Vec<Group>) -> StoreResult<()>;
+    fn load_groups(&self) -> StoreResult<Vec<Group>>;
+
+    // Inserts new messages and updates edited ones
+    fn save_messages(&self, room_id: &String, messages: &Vec<Message>) -> StoreResult<()>;
+
+    // Newest `limit` messages of a room, oldest first
+    fn load_messages(&self, room_id: &String, limit: usize) -> StoreResult<Vec<Message>>;
+    // Pages of history: the `limit` newest messages sent before `sent`, or the `limit` oldest
+    // sent at or after it, oldest first. `sent` may be just a date, e.g. 2017-11-16
+    fn load_messages_before(&self, room_id: &String, sent: &str, limit: usize) -> StoreResult<Vec<Message>>;
+    fn load_messages_from(&self, room_id: &String, sent: &str, limit: usize) -> StoreResult<Vec<Message>>;
+
+    // Case-insensitive search of one cached room, or of every one, newest first
+    fn search_messages(&self, room_id: Option<&String>, query: &str, limit: usize) -> StoreResult<Vec<SearchResult>>;
+
+    // Inserts or updates a message waiting to be sent
+    fn save_outbox_item(&self, item: &OutboxItem) -> StoreResult<()>;
+    fn load_outbox(&self) -> StoreResult<Vec<OutboxItem>>;
+    fn remove_outbox_item(&self, client_id: &String) -> StoreResult<()>;
+}
+
+// $XDG_DATA_HOME/gitter_gtk
+fn data_dir() -> Option<PathBuf> {
+    let data_home = match ::std::env::var("XDG_DATA_HOME") {
+        Ok(ref val) if val.len() > 0 => PathBuf::from(val),
+        _ => match ::std::env::var("HOME") {
+            Ok(val) => PathBuf::from(val).join(".local/share"),
+            Err(_) => return None,
+        },
+    };
+
+    Some(data_home.join("gitter_gtk"))
+}
+
+// Cache database location, $XDG_DATA_HOME/gitter_gtk/cache.db
+pub fn default_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("cache.db"))
+}
+
+// Cache database of one account, $XDG_DATA_HOME/gitter_gtk/accounts/<name>.db
+pub fn account_path(account: &str) -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("accounts").join(format!("{}.db", account)))
+}
+
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &PathBuf) -> StoreResult<SqliteStore> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(path)?;
+
+        // Several threads keep their own connection to the same file
+        connection.execute_batch("PRAGMA journal_mode = WAL;")?;
+        connection.busy_timeout(::std::time::Duration::from_millis(2000))?;
+
+        SqliteStore::from_connection(connection)
+    }
+
+    pub fn open_in_memory() -> StoreResult<SqliteStore> {
+        SqliteStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    // Reads a cache, e.g. a copy of another account's, without migrating or otherwise writing to it.
+    // Older schemas hold everything that is read; a newer one may not be understood.
+    pub fn open_read_only(path: &PathBuf) -> StoreResult<SqliteStore> {
+        let store = SqliteStore {
+            connection: Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+        };
+
+        let version = store.version()?;
+        if version < 1 || version > MIGRATIONS.len() as i64 {
+            return Err(StoreError::Version(version));
+        }
+
+        Ok(store)
+    }
+
+    fn from_connection(connection: Connection) -> StoreResult<SqliteStore> {
+        let store = SqliteStore {
+            connection: connection,
+        };
+
+        store.migrate()?;
+
+        Ok(store)
+    }
+
+    // Applies every migration newer than the version recorded in the database
+    fn migrate(&self) -> StoreResult<()> {
+        let version = self.version()?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let sql = format!("BEGIN;\n{}\nPRAGMA user_version = {};\nCOMMIT;", migration, i + 1);
+            self.connection.execute_batch(&sql[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn version(&self) -> StoreResult<i64> {
+        Ok(self.connection.query_row("PRAGMA user_version", &[], |row| row.get(0))?)
+    }
+
+    fn in_transaction<F>(&self, f: F) -> StoreResult<()>
+    where F: FnOnce(&Connection) -> StoreResult<()>
+    {
+        self.connection.execute_batch("BEGIN")?;
+
+        match f(&self.connection) {
+            Ok(()) => {
+                self.connection.execute_batch("COMMIT")?;
+                Ok(())
+            },
+            Err(e) => {
+                self.connection.execute_batch("ROLLBACK").unwrap_or(());
+                Err(e)
+            },
+        }
+    }
+}
+
+impl Store for SqliteStore {
+    fn save_current_user(&self, user: &User) -> StoreResult<()> {
+        let json = serde_json::to_string(user)?;
+
+        self.in_transaction(|conn| {
+            conn.execute("UPDATE users SET is_current = 0", &[])?;
+            conn.execute(
+                "INSERT OR REPLACE INTO users (id, is_current, json) VALUES (?1, 1, ?2)",
+                &[&user.id, &json]
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_current_user(&self) -> StoreResult<Option<User>> {
+        let mut statement = self.connection.prepare("SELECT json FROM users WHERE is_current = 1 LIMIT 1")?;
+        let mut rows = statement.query(&[])?;
+
+        match rows.next() {
+            Some(row) => {
+                let json: String = row?.get(0);
+                Ok(Some(serde_json::from_str(&json[..])?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn save_rooms(&self, rooms: &Vec<Room>) -> StoreResult<()> {
+        self.in_transaction(|conn| {
+            conn.execute("DELETE FROM rooms", &[])?;
+
+            for room in rooms.iter() {
+                let json = serde_json::to_string(room)?;
+                conn.execute(
+                    "INSERT INTO rooms (id, name, json) VALUES (?1, ?2, ?3)",
+                    &[&room.id, &room.name, &json]
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn load_rooms(&self) -> StoreResult<Vec<Room>> {
+        load_json_column(&self.connection, "SELECT json FROM rooms ORDER BY name", &[])
+    }
+
+    fn save_groups(&self, groups: &Vec<Group>) -> StoreResult<()> {
+        self.in_transaction(|conn| {
+            conn.execute("DELETE FROM groups", &[])?;
+
+            for group in groups.iter() {
+                let json = serde_json::to_string(group)?;
+                conn.execute("INSERT INTO groups (id, json) VALUES (?1, ?2)", &[&group.id, &json])?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn load_groups(&self) -> StoreResult<Vec<Group>> {
+        load_json_column(&self.connection, "SELECT json FROM groups", &[])
+    }
+
+    fn save_messages(&self, room_id: &String, messages: &Vec<Message>) -> StoreResult<()> {
+        self.in_transaction(|conn| {
+            for message in messages.iter() {
+                let json = serde_json::to_string(message)?;
+                conn.execute(
+                    "INSERT OR REPLACE INTO messages (id, room_id, sent, from_user_id, text, json)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    &[&message.id, room_id, &message.sent, &message.fromUser.id, &message.text, &json]
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn load_messages(&self, room_id: &String, limit: usize) -> StoreResult<Vec<Message>> {
+        let limit = limit as i64;
+        let mut messages: Vec<Message> = load_json_column(
+            &self.connection,
+            "SELECT json FROM messages WHERE room_id = ?1 ORDER BY sent DESC LIMIT ?2",
+            &[room_id, &limit]
+        )?;
+
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    fn load_messages_before(&self, room_id: &String, sent: &str, limit: usize) -> StoreResult<Vec<Message>> {
+        let limit = limit as i64;
+        let mut messages: Vec<Message> = load_json_column(
+            &self.connection,
+            "SELECT json FROM messages WHERE room_id = ?1 AND sent < ?2 ORDER BY sent DESC LIMIT ?3",
+            &[room_id, &sent, &limit]
+        )?;
+
+        messages.reverse();
+
+        Ok(messages)
+    }
+
+    fn load_messages_from(&self, room_id: &String, sent: &str, limit: usize) -> StoreResult<Vec<Message>> {
+        let limit = limit as i64;
+        load_json_column(
+            &self.connection,
+            "SELECT json FROM messages WHERE room_id = ?1 AND sent >= ?2 ORDER BY sent LIMIT ?3",
+            &[room_id, &sent, &limit]
+        )
+    }
+
+    fn search_messages(&self, room_id: Option<&String>, query: &str, limit: usize) -> StoreResult<Vec<SearchResult>> {
+        let pattern = format!("%{}%", escape_like(query.trim()));
+        let limit = limit as i64;
+
+        let mut statement = self.connection.prepare(
+            "SELECT room_id, json FROM messages
+             WHERE (?3 IS NULL OR room_id = ?3) AND text LIKE ?1 ESCAPE '\\'
+             ORDER BY sent DESC LIMIT ?2"
+        )?;
+
+        let rows = statement.query_map(&[&pattern, &limit, &room_id], |row| {
+            let room_id: String = row.get(0);
+            let json: String = row.get(1);
+            (room_id, json)
+        })?;
+
+        let mut results = vec![];
+        for row in rows {
+            let (room_id, json) = row?;
+            results.push((room_id, serde_json::from_str(&json[..])?));
+        }
+
+        Ok(results)
+    }
+
+    fn save_outbox_item(&self, item: &OutboxItem) -> StoreResult<()> {
+        let json = serde_json::to_string(item)?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO outbox (client_id, json) VALUES (?1, ?2)",
+            &[&item.client_id, &json]
+        )?;
+        Ok(())
+    }
+
+    fn load_outbox(&self) -> StoreResult<Vec<OutboxItem>> {
+        load_json_column(&self.connection, "SELECT json FROM outbox ORDER BY client_id", &[])
+    }
+
+    fn remove_outbox_item(&self, client_id: &String) -> StoreResult<()> {
+        self.connection.execute("DELETE FROM outbox WHERE client_id = ?1", &[client_id])?;
+        Ok(())
+    }
+}
+
+// Searched text is matched literally, so % and _ in a query are not wildcards
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if c == '\\' || c == '%' || c == '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn load_json_column<T>(connection: &Connection, sql: &str, params: &[&rusqlite::types::ToSql]) -> StoreResult<Vec<T>>
+where T: ::serde::de::DeserializeOwned
+{
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map(params, |row| row.get::<i32, String>(0))?;
+
+    let mut values = vec![];
+    for json in rows {
+        values.push(serde_json::from_str(&json?[..])?);
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+    use rand::Rng;
+
+    use super::*;
+    use tests::fixtures;
+    use tests::fixtures::user;
+
+    fn message(id: &str, sent: &str, text: &str) -> Message {
+        fixtures::message(id, &user("u1", "me"), sent, text)
+    }
+
+    fn ids(messages: &Vec<Message>) -> Vec<String> {
+        messages.iter().map(|message| message.id.clone()).collect()
+    }
+
+    fn store_with_messages() -> SqliteStore {
+        let store = SqliteStore::open_in_memory().unwrap();
+
+        // Saved out of order, as pages of history and the stream arrive
+        store.save_messages(&String::from("a"), &vec![
+            message("m3", "2017-11-16T12:00:00.000Z", "third"),
+            message("m1", "2017-11-16T10:00:00.000Z", "first"),
+        ]).unwrap();
+        store.save_messages(&String::from("a"), &vec![message("m2", "2017-11-16T11:00:00.000Z", "second")]).unwrap();
+        store.save_messages(&String::from("b"), &vec![message("m4", "2017-11-16T13:00:00.000Z", "elsewhere")]).unwrap();
+
+        store
+    }
+
+    #[test]
+    fn migrations_bring_the_schema_to_the_latest_version() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert_eq!(store.version().unwrap(), MIGRATIONS.len() as i64);
+
+        // Running them again changes nothing
+        store.migrate().unwrap();
+        assert_eq!(store.version().unwrap(), MIGRATIONS.len() as i64);
+        assert!(store.load_outbox().unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_messages_gives_the_newest_oldest_first() {
+        let store = store_with_messages();
+
+        assert_eq!(ids(&store.load_messages(&String::from("a"), 10).unwrap()), vec!["m1", "m2", "m3"]);
+        assert_eq!(ids(&store.load_messages(&String::from("a"), 2).unwrap()), vec!["m2", "m3"]);
+        assert_eq!(ids(&store.load_messages(&String::from("b"), 10).unwrap()), vec!["m4"]);
+        assert!(store.load_messages(&String::from("c"), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_pages_either_side_of_a_time() {
+        let store = store_with_messages();
+        let room_id = String::from("a");
+
+        assert_eq!(ids(&store.load_messages_before(&room_id, "2017-11-16T12:00:00.000Z", 10).unwrap()), vec!["m1", "m2"]);
+        assert_eq!(ids(&store.load_messages_before(&room_id, "2017-11-16T12:00:00.000Z", 1).unwrap()), vec!["m2"]);
+        assert!(store.load_messages_before(&room_id, "2017-11-16T10:00:00.000Z", 10).unwrap().is_empty());
+
+        assert_eq!(ids(&store.load_messages_from(&room_id, "2017-11-16T11:00:00.000Z", 10).unwrap()), vec!["m2", "m3"]);
+        assert_eq!(ids(&store.load_messages_from(&room_id, "2017-11-16", 2).unwrap()), vec!["m1", "m2"]);
+        assert!(store.load_messages_from(&room_id, "2017-11-17", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn saving_an_edit_replaces_the_message() {
+        let store = store_with_messages();
+
+        let mut edited = message("m2", "2017-11-16T11:00:00.000Z", "second, edited");
+        edited.v = 2;
+        store.save_messages(&String::from("a"), &vec![edited]).unwrap();
+
+        let messages = store.load_messages(&String::from("a"), 10).unwrap();
+        assert_eq!(ids(&messages), vec!["m1", "m2", "m3"]);
+        assert_eq!(messages[1].text, "second, edited");
+        assert_eq!(messages[1].v, 2);
+    }
+
+    #[test]
+    fn search_finds_messages_in_every_room_newest_first() {
+        let store = store_with_messages();
+
+        let results = store.search_messages(None, " E ", 10).unwrap();
+        let found: Vec<(&str, &str)> = results.iter().map(|&(ref room_id, ref message)| (&room_id[..], &message.id[..])).collect();
+        assert_eq!(found, vec![("b", "m4"), ("a", "m2")]);
+
+        assert_eq!(store.search_messages(None, "i", 1).unwrap().len(), 1);
+        assert!(store.search_messages(None, "missing", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_of_one_room_limits_within_the_room() {
+        let store = store_with_messages();
+
+        let found = |room_id: &str, query: &str, limit: usize| -> Vec<String> {
+            store.search_messages(Some(&String::from(room_id)), query, limit).unwrap().into_iter().map(|(_, message)| message.id).collect()
+        };
+
+        assert_eq!(found("a", "e", 10), vec!["m2"]);
+        assert_eq!(found("a", "i", 1), vec!["m3"]);
+        assert_eq!(found("b", "e", 10), vec!["m4"]);
+        assert!(found("c", "e", 10).is_empty());
+    }
+
+    #[test]
+    fn search_matches_wildcard_characters_literally() {
+        let store = store_with_messages();
+        store.save_messages(&String::from("a"), &vec![
+            message("m5", "2017-11-17T10:00:00.000Z", "100% done"),
+            message("m6", "2017-11-17T11:00:00.000Z", "snake_case"),
+        ]).unwrap();
+
+        let found = |query: &str| -> Vec<String> {
+            store.search_messages(None, query, 10).unwrap().into_iter().map(|(_, message)| message.id).collect()
+        };
+
+        assert_eq!(found("%"), vec!["m5"]);
+        assert_eq!(found("_"), vec!["m6"]);
+        assert!(found("\\").is_empty());
+    }
+
+    #[test]
+    fn current_user_is_the_last_saved() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert!(store.load_current_user().unwrap().is_none());
+
+        store.save_current_user(&user("u1", "me")).unwrap();
+        store.save_current_user(&user("u2", "other")).unwrap();
+        assert_eq!(store.load_current_user().unwrap().unwrap().username, "other");
+    }
+
+    #[test]
+    fn caches_of_older_versions_open_read_only() {
+        let dir = ::std::env::temp_dir().join(format!("gitter_gtk_store_test_{}", rand::thread_rng().gen::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.db");
+
+        let version = |version: usize| {
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch(&format!("PRAGMA user_version = {};", version)[..]).unwrap();
+        };
+
+        // Only the first migration, from before the outbox
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch(&format!("{}\nPRAGMA user_version = 1;", MIGRATIONS[0])[..]).unwrap();
+        }
+        assert!(SqliteStore::open_read_only(&path).unwrap().load_rooms().unwrap().is_empty());
+
+        version(MIGRATIONS.len());
+        assert!(SqliteStore::open_read_only(&path).is_ok());
+
+        version(MIGRATIONS.len() + 1);
+        match SqliteStore::open_read_only(&path) {
+            Err(StoreError::Version(version)) => assert_eq!(version, MIGRATIONS.len() as i64 + 1),
+            other => panic!("expected a version error, got {:?}", other.map(|_| ())),
+        };
+
+        version(0);
+        assert!(SqliteStore::open_read_only(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
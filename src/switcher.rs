@@ -0,0 +1,103 @@
+// Ranks rooms for the Ctrl+K quick switcher
+
+use fuzzy;
+use Room;
+
+// Number of rooms shown in the quick switcher at once
+pub const MAX_RESULTS: usize = 10;
+
+// Combines the fuzzy score with unread state and how recently the room was used
+fn rank(query: &str, room: &Room, recent_rooms: &Vec<String>) -> Option<i64> {
+    let mut score = match fuzzy::score(query, &room.name[..]) {
+        Some(score) => score,
+        None => return None,
+    };
+
+    if room.mentions > 0 {
+        score += 30;
+    } else if room.unreadItems > 0 {
+        score += 20;
+    }
+
+    if let Some(index) = recent_rooms.iter().position(|id| id == &room.id) {
+        score += (MAX_RESULTS as i64 - index as i64).max(0) * 3;
+    }
+
+    Some(score)
+}
+
+// Returns the best matching rooms for the query, best first
+pub fn rank_rooms(query: &str, rooms: &Vec<Room>, recent_rooms: &Vec<String>) -> Vec<Room> {
+    let mut ranked: Vec<(i64, &Room)> = rooms.iter()
+        .filter_map(|room| rank(query, room, recent_rooms).map(|score| (score, room)))
+        .collect();
+
+    // Stable sort keeps the sidebar order for equal scores
+    ranked.sort_by(|a, b| b.0.cmp(&a.0));
+
+    ranked.into_iter()
+        .take(MAX_RESULTS)
+        .map(|(_, room)| room.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::fixtures::room;
+
+    fn unread(mut room: Room, unread_items: u32, mentions: u32) -> Room {
+        room.unreadItems = unread_items;
+        room.mentions = mentions;
+        room
+    }
+
+    fn ids(rooms: &Vec<Room>) -> Vec<String> {
+        rooms.iter().map(|room| room.id.clone()).collect()
+    }
+
+    #[test]
+    fn leaves_out_rooms_which_do_not_match() {
+        let rooms = vec![room("a", "org/alpha"), room("b", "org/beta")];
+
+        assert_eq!(ids(&rank_rooms("bet", &rooms, &vec![])), vec!["b"]);
+        assert!(rank_rooms("zzz", &rooms, &vec![]).is_empty());
+    }
+
+    #[test]
+    fn mentions_rank_above_unread_above_read() {
+        let rooms = vec![
+            room("a", "org/alpha"),
+            unread(room("b", "org/beta"), 3, 0),
+            unread(room("c", "org/gamma"), 1, 1),
+        ];
+
+        assert_eq!(ids(&rank_rooms("", &rooms, &vec![])), vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn recent_rooms_rank_higher_the_more_recent() {
+        let rooms = vec![room("a", "org/alpha"), room("b", "org/beta"), room("c", "org/gamma")];
+        let recent = vec![String::from("c"), String::from("b")];
+
+        assert_eq!(ids(&rank_rooms("", &rooms, &recent)), vec!["c", "b", "a"]);
+
+        // The most recent room outranks an unread one, but the second most recent does not outrank a mention
+        let rooms = vec![unread(room("a", "org/alpha"), 5, 0), room("c", "org/gamma")];
+        assert_eq!(ids(&rank_rooms("", &rooms, &vec![String::from("c")])), vec!["c", "a"]);
+
+        let rooms = vec![room("c", "org/gamma"), unread(room("a", "org/alpha"), 5, 1)];
+        assert_eq!(ids(&rank_rooms("", &rooms, &vec![String::from("x"), String::from("c")])), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn equal_scores_keep_the_sidebar_order_and_results_are_bounded() {
+        let rooms: Vec<Room> = (0..(MAX_RESULTS + 5)).map(|i| room(&format!("r{}", i)[..], "org/room")).collect();
+
+        let ranked = rank_rooms("room", &rooms, &vec![]);
+
+        assert_eq!(ranked.len(), MAX_RESULTS);
+        assert_eq!(ranked[0].id, "r0");
+        assert_eq!(ranked[MAX_RESULTS - 1].id, format!("r{}", MAX_RESULTS - 1));
+    }
+}
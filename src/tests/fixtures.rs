@@ -0,0 +1,45 @@
+// Rooms, users and messages for the unit tests of the modules which handle them
+
+use {Message, Room, User};
+
+pub fn user(id: &str, username: &str) -> User {
+    User {
+        id: String::from(id),
+        username: String::from(username),
+        displayName: String::from(username),
+        url: format!("/{}", username),
+        avatarUrlSmall: String::new(),
+        avatarUrlMedium: String::new(),
+    }
+}
+
+pub fn room(id: &str, name: &str) -> Room {
+    Room {
+        id: String::from(id),
+        name: String::from(name),
+        topic: String::new(),
+        url: format!("/{}", name),
+        oneToOne: false,
+        mentions: 0,
+        unreadItems: 0,
+        favourite: None,
+        groupId: None,
+        githubType: String::from("REPO"),
+        lurk: false,
+    }
+}
+
+pub fn message(id: &str, from: &User, sent: &str, text: &str) -> Message {
+    Message {
+        id: String::from(id),
+        text: String::from(text),
+        html: String::from(text),
+        sent: String::from(sent),
+        fromUser: from.clone(),
+        unread: false,
+        readBy: 0,
+        urls: vec![],
+        mentions: vec![],
+        v: 1,
+    }
+}
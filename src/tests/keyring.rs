@@ -0,0 +1,184 @@
+// Storing the token through the Secret Service, against the stub on a private bus
+
+use accounts;
+use config::{AccountEntry, Config};
+use keyring::{Keyring, KeyringError};
+use tests::secret_service_stub::{PrivateBus, SecretServiceStub};
+
+fn keyring(bus: &PrivateBus) -> Keyring {
+    Keyring::with_connection(bus.connect()).unwrap()
+}
+
+#[test]
+fn token_round_trips() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+    let keyring = keyring(&bus);
+
+    assert_eq!(keyring.load_token("tester").unwrap(), None);
+
+    keyring.save_token("tester", "secret-token").unwrap();
+    assert_eq!(keyring.load_token("tester").unwrap(), Some(String::from("secret-token")));
+
+    let items = stub.items();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].label, "GtkGitter access token (tester)");
+    assert_eq!(items[0].attributes.get("application"), Some(&String::from("gitter_gtk")));
+    assert_eq!(items[0].attributes.get("account"), Some(&String::from("tester")));
+    assert_eq!(items[0].secret, b"secret-token".to_vec());
+}
+
+#[test]
+fn saving_replaces_previous_token() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+    let keyring = keyring(&bus);
+
+    keyring.save_token("tester", "first").unwrap();
+    keyring.save_token("tester", "second").unwrap();
+
+    assert_eq!(stub.items().len(), 1);
+    assert_eq!(keyring.load_token("tester").unwrap(), Some(String::from("second")));
+}
+
+#[test]
+fn accounts_keep_their_own_tokens() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+    let keyring = keyring(&bus);
+
+    keyring.save_token("work", "work-token").unwrap();
+    keyring.save_token("personal", "personal-token").unwrap();
+
+    assert_eq!(stub.items().len(), 2);
+    assert_eq!(keyring.load_token("work").unwrap(), Some(String::from("work-token")));
+    assert_eq!(keyring.load_token("personal").unwrap(), Some(String::from("personal-token")));
+
+    keyring.delete_token("work").unwrap();
+    assert_eq!(keyring.load_token("work").unwrap(), None);
+    assert_eq!(keyring.load_token("personal").unwrap(), Some(String::from("personal-token")));
+}
+
+#[test]
+fn delete_removes_token() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+    let keyring = keyring(&bus);
+
+    keyring.save_token("tester", "secret-token").unwrap();
+    keyring.delete_token("tester").unwrap();
+
+    assert!(stub.items().is_empty());
+    assert_eq!(keyring.load_token("tester").unwrap(), None);
+
+    // Signing out twice is fine
+    keyring.delete_token("tester").unwrap();
+}
+
+#[test]
+fn locked_token_is_unlocked_before_reading() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+    let keyring = keyring(&bus);
+
+    keyring.save_token("tester", "secret-token").unwrap();
+    stub.lock_all();
+
+    assert_eq!(keyring.load_token("tester").unwrap(), Some(String::from("secret-token")));
+    assert!(stub.items().iter().all(|item| !item.locked));
+}
+
+#[test]
+fn missing_service_is_an_error() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+
+    // Nothing owns org.freedesktop.secrets, so callers fall back to config.yaml
+    assert!(Keyring::with_connection(bus.connect()).is_err());
+}
+
+fn entry(name: &str, token: Option<&str>) -> AccountEntry {
+    AccountEntry {
+        name: String::from(name),
+        token: token.map(String::from),
+        api_base: Some(String::from("https://gitter.example.com/api/v1")),
+        ..AccountEntry::default()
+    }
+}
+
+#[test]
+fn plaintext_tokens_move_into_the_keyring() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let stub = SecretServiceStub::start(&bus);
+
+    let mut config = Config::default();
+    config.accounts = vec![entry("work", Some("work-token")), entry("personal", None)];
+
+    assert!(accounts::migrate_tokens(&mut config, || Keyring::with_connection(bus.connect())));
+
+    assert_eq!(config.accounts, vec![entry("work", None), entry("personal", None)]);
+    assert_eq!(stub.items().len(), 1);
+    assert_eq!(keyring(&bus).load_token("work").unwrap(), Some(String::from("work-token")));
+}
+
+#[test]
+fn legacy_token_becomes_the_default_account() {
+    let bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let _stub = SecretServiceStub::start(&bus);
+
+    let mut config = Config::default();
+    config.token = Some(String::from("legacy-token"));
+
+    assert!(accounts::migrate_tokens(&mut config, || Keyring::with_connection(bus.connect())));
+
+    assert_eq!(config.token, None);
+    assert_eq!(config.accounts.len(), 1);
+    assert_eq!(config.accounts[0].name, accounts::LEGACY_ACCOUNT);
+    assert_eq!(config.accounts[0].token, None);
+    assert_eq!(keyring(&bus).load_token(accounts::LEGACY_ACCOUNT).unwrap(), Some(String::from("legacy-token")));
+}
+
+#[test]
+fn tokens_stay_in_the_config_without_a_keyring() {
+    let mut config = Config::default();
+    config.token = Some(String::from("legacy-token"));
+
+    let changed = accounts::migrate_tokens(&mut config, || Err(KeyringError::Reply(String::from("no keyring"))));
+
+    // The legacy token still becomes an account, so the config is written back
+    assert!(changed);
+    assert_eq!(config.token, None);
+    assert_eq!(config.accounts[0].token, Some(String::from("legacy-token")));
+}
+
+#[test]
+fn nothing_to_migrate_does_not_connect() {
+    let mut config = Config::default();
+    config.accounts = vec![entry("work", None)];
+
+    assert!(!accounts::migrate_tokens(&mut config, || panic!("connected without a token to move")));
+    assert_eq!(config.accounts, vec![entry("work", None)]);
+}
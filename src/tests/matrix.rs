@@ -0,0 +1,231 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use api::{ApiError, Endpoints};
+use chat;
+use chat::ChatBackend;
+use matrix;
+use serde_json;
+use serde_json::Value;
+use tests::mock_homeserver::{MockHomeserver, DIRECT_ROOM, FRIEND, MATRIX_TOKEN, MATRIX_USER, PASSWORD, RUST_ROOM};
+use Message;
+
+#[test]
+fn login_gives_a_token() {
+    let server = MockHomeserver::start();
+
+    let (user_id, token) = matrix::login(&server.homeserver()[..], "tester", PASSWORD).unwrap();
+    assert_eq!(user_id, MATRIX_USER);
+    assert_eq!(token, MATRIX_TOKEN);
+
+    match matrix::login(&server.homeserver()[..], "tester", "wrong") {
+        Err(ApiError::Http(401)) => (),
+        other => panic!("expected HTTP 401, got {:?}", other),
+    };
+}
+
+#[test]
+fn fetch_account_reads_rooms_from_sync() {
+    let server = MockHomeserver::start();
+    let handler = chat::connect(&String::new(), &String::from(MATRIX_TOKEN), &Endpoints::matrix(&server.homeserver()[..]));
+
+    let (user, rooms, groups) = handler.fetch_account().unwrap();
+
+    assert_eq!(user.id, MATRIX_USER);
+    assert_eq!(user.username, "tester");
+    assert_eq!(rooms.len(), 2);
+    assert!(groups.is_empty());
+
+    let rust = rooms.iter().find(|room| room.id == RUST_ROOM).unwrap();
+    assert_eq!(rust.name, "Rust");
+    assert_eq!(rust.topic, "All things Rust");
+    assert_eq!(rust.url, "/localhost/#rust:localhost");
+    assert_eq!(rust.favourite, Some(500));
+    assert_eq!(rust.unreadItems, 2);
+    assert_eq!(rust.mentions, 1);
+    assert!(!rust.oneToOne);
+
+    let direct = rooms.iter().find(|room| room.id == DIRECT_ROOM).unwrap();
+    assert_eq!(direct.name, "Friend");
+    assert!(direct.oneToOne);
+    assert_eq!(direct.favourite, None);
+
+    // Polled often, so only the state shown in the sidebar is asked for
+    let sync = server.requests().into_iter().find(|request| request.path == "/_matrix/client/v3/sync").unwrap();
+    let filter: Value = serde_json::from_str(&sync.query["filter"][..]).unwrap();
+    assert_eq!(filter["room"]["state"]["lazy_load_members"], json!(true));
+    assert_eq!(filter["room"]["state"]["types"], json!(["m.room.name", "m.room.canonical_alias", "m.room.topic"]));
+}
+
+#[test]
+fn room_list_sync_continues_from_its_own_batch() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+
+    handler.rooms_call().unwrap().perform().unwrap();
+    assert!(!server.last_request().query.contains_key("since"));
+
+    // Later answers leave out the state which did not change, so it is kept from before
+    server.post_from_friend(RUST_ROOM, "Anyone here?");
+    let rooms = handler.rooms_call().unwrap().perform().unwrap();
+    assert!(server.last_request().query.contains_key("since"));
+
+    assert_eq!(rooms.len(), 2);
+    let rust = rooms.iter().find(|room| room.id == RUST_ROOM).unwrap();
+    assert_eq!(rust.name, "Rust");
+    assert_eq!(rust.topic, "All things Rust");
+    assert!(rooms.iter().find(|room| room.id == DIRECT_ROOM).unwrap().oneToOne);
+}
+
+#[test]
+fn wrong_token_is_rejected() {
+    let server = MockHomeserver::start();
+
+    match server.handler_with_token("", "wrong-token").fetch_account() {
+        Err(ApiError::Http(401)) => (),
+        other => panic!("expected HTTP 401, got {:?}", other.map(|(user, _, _)| user)),
+    };
+}
+
+#[test]
+fn messages_are_oldest_first() {
+    let server = MockHomeserver::start();
+
+    let messages = server.handler(RUST_ROOM).messages_call().unwrap().perform().unwrap();
+
+    let texts: Vec<&str> = messages.iter().map(|message| &message.text[..]).collect();
+    assert_eq!(texts, vec!["Hello from Matrix", "Hi there", "tester: borrow checker question"]);
+    assert_eq!(messages[0].fromUser.username, "friend");
+    assert_eq!(messages[0].fromUser.id, FRIEND);
+    assert_eq!(messages[0].sent, "2017-11-16T10:00:01.000Z");
+
+    // Mentions of the user become Gitter style mentions, which notifications look at
+    assert_eq!(messages[2].mentions.len(), 1);
+    assert_eq!(messages[2].mentions[0].screenName, "tester");
+
+    assert_eq!(server.last_request().path, "/_matrix/client/v3/rooms/%21rust%3Alocalhost/messages");
+}
+
+#[test]
+fn sent_and_edited_messages_arrive() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let sent = handler.send_call(&String::from(RUST_ROOM), String::from("Sent over Matrix"), None, &String::from("local-1")).unwrap().perform().unwrap();
+    assert_eq!(server.last_request().method, "PUT");
+    assert_eq!(server.last_request().path, "/_matrix/client/v3/rooms/%21rust%3Alocalhost/send/m.room.message/local-1");
+
+    let edited = handler.update_call(&String::from(RUST_ROOM), &sent.id, String::from("Edited over Matrix"), &String::from("local-2")).unwrap().perform().unwrap();
+    // Answered with the id of the original, which the edited message comes back with
+    assert_eq!(edited.id, sent.id);
+
+    // The edit replaces the text of the original message
+    let messages = handler.messages_call().unwrap().perform().unwrap();
+    let latest: Vec<(&str, &str, i32)> = messages.iter().rev().take(2)
+        .map(|message| (&message.id[..], &message.text[..], message.v))
+        .collect();
+
+    assert_eq!(latest, vec![(&sent.id[..], "Edited over Matrix", 2), (&sent.id[..], "Sent over Matrix", 1)]);
+}
+
+#[test]
+fn retried_send_arrives_once() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+    let send = || handler.send_call(&String::from(RUST_ROOM), String::from("Retried"), None, &String::from("local-1")).unwrap().perform().unwrap();
+
+    // The first answer was lost, so the outbox sends the same message again
+    let first = send();
+    let again = send();
+    assert_eq!(again.id, first.id);
+
+    let messages = handler.messages_call().unwrap().perform().unwrap();
+    assert_eq!(messages.iter().filter(|message| message.text == "Retried").count(), 1);
+}
+
+#[test]
+fn stream_long_polls_for_new_messages() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+    let received: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+
+    assert!(handler.stream_is_long_poll());
+
+    // The first poll answers at once with the latest messages
+    let poll = || {
+        let received = received.clone();
+        let mut easy = handler.stream_request(Box::new(move |message: Message| {
+            received.lock().unwrap().push(message.text);
+        })).unwrap();
+        easy.perform().unwrap();
+        assert_eq!(easy.response_code().unwrap(), 200);
+        handler.stream_finished();
+    };
+
+    poll();
+    assert_eq!(received.lock().unwrap().len(), 3);
+    assert!(!server.last_request().query.contains_key("since"));
+
+    // The next one continues from there and waits for something new
+    received.lock().unwrap().clear();
+    let poster = {
+        let server = server.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            server.post_from_friend(RUST_ROOM, "New on Matrix");
+            server.post_from_friend(DIRECT_ROOM, "Elsewhere");
+        })
+    };
+
+    poll();
+    poster.join().unwrap();
+
+    assert_eq!(*received.lock().unwrap(), vec![String::from("New on Matrix")]);
+    assert_eq!(server.last_request().query.get("since").map(|since| &since[..]), Some("s4"));
+}
+
+#[test]
+fn search_and_context_read_matrix_events() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let results = handler.search_call(&String::from("borrow")).unwrap().perform().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, "$3");
+
+    let context = handler.around_call(&String::from("$2")).unwrap().perform().unwrap();
+    let ids: Vec<&str> = context.iter().map(|message| &message.id[..]).collect();
+    assert_eq!(ids, vec!["$1", "$2", "$3"]);
+}
+
+#[test]
+fn history_pages_back_by_event_id() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let newest = handler.history_call(None).unwrap().perform().unwrap();
+    assert_eq!(newest.len(), 3);
+
+    let before: Vec<String> = handler.history_call(Some(&String::from("$2"))).unwrap().perform().unwrap()
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(before, vec!["$1"]);
+
+    assert!(handler.history_call(Some(&String::from("$1"))).unwrap().perform().unwrap().is_empty());
+}
+
+#[test]
+fn read_receipt_marks_the_newest_message() {
+    let server = MockHomeserver::start();
+    let handler = server.handler(RUST_ROOM);
+
+    handler.mark_read_call(&String::from(MATRIX_USER), vec![String::from("$1"), String::from("$3")]).unwrap().perform().unwrap();
+    assert_eq!(server.receipts(), vec![(String::from(RUST_ROOM), String::from("$3"))]);
+
+    // Nothing to mark asks nothing
+    let requests = server.requests().len();
+    handler.mark_read_call(&String::from(MATRIX_USER), vec![]).unwrap().perform().unwrap();
+    assert_eq!(server.requests().len(), requests);
+}
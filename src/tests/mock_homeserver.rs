@@ -0,0 +1,360 @@
+// A fake Matrix homeserver on a local port with two rooms, answering the parts of the
+// client-server API the app uses. Like MockServer, each test starts its own.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+use serde_json::Value;
+
+use matrix::MatrixHandler;
+use tests::mock_server::{percent_decode, read_request, RecordedRequest};
+
+pub const MATRIX_TOKEN: &'static str = "matrix-token";
+pub const MATRIX_USER: &'static str = "@tester:localhost";
+pub const PASSWORD: &'static str = "correct horse";
+
+pub const FRIEND: &'static str = "@friend:localhost";
+pub const RUST_ROOM: &'static str = "!rust:localhost";
+pub const DIRECT_ROOM: &'static str = "!direct:localhost";
+
+// Longest a /sync waits for new events, whatever timeout it asked for
+const MAX_SYNC_WAIT_MILLIS: u64 = 2000;
+
+struct TimelineEvent {
+    // Position in the whole server's history, which since tokens count
+    position: u64,
+    room_id: String,
+    event: Value,
+}
+
+struct HomeserverState {
+    // Oldest first
+    timeline: Vec<TimelineEvent>,
+    // Transaction id to event id, so a repeated send is stored once
+    transactions: HashMap<String, String>,
+    // (room id, event id) of every read receipt
+    receipts: Vec<(String, String)>,
+    requests: Vec<RecordedRequest>,
+}
+
+impl HomeserverState {
+    fn new() -> HomeserverState {
+        let mut state = HomeserverState {
+            timeline: vec![],
+            transactions: HashMap::new(),
+            receipts: vec![],
+            requests: vec![],
+        };
+
+        state.add_message(RUST_ROOM, FRIEND, json!({ "msgtype": "m.text", "body": "Hello from Matrix" }));
+        state.add_message(RUST_ROOM, MATRIX_USER, json!({ "msgtype": "m.text", "body": "Hi there" }));
+        state.add_message(RUST_ROOM, FRIEND, json!({
+            "msgtype": "m.text",
+            "body": "tester: borrow checker question",
+            "m.mentions": { "user_ids": [MATRIX_USER] }
+        }));
+        state.add_message(DIRECT_ROOM, FRIEND, json!({ "msgtype": "m.text", "body": "Psst" }));
+
+        state
+    }
+
+    fn position(&self) -> u64 {
+        self.timeline.len() as u64
+    }
+
+    fn add_message(&mut self, room_id: &str, sender: &str, content: Value) -> String {
+        let position = self.position() + 1;
+        let event_id = format!("${}", position);
+
+        self.timeline.push(TimelineEvent {
+            position: position,
+            room_id: String::from(room_id),
+            event: json!({
+                "type": "m.room.message",
+                "event_id": event_id,
+                "sender": sender,
+                "origin_server_ts": 1510826400000u64 + position * 1000,
+                "content": content
+            }),
+        });
+
+        event_id
+    }
+
+    fn room_events(&self, room_id: &str) -> Vec<Value> {
+        self.timeline.iter()
+            .filter(|event| event.room_id == room_id)
+            .map(|event| event.event.clone())
+            .collect()
+    }
+
+    fn route(&mut self, request: &RecordedRequest) -> (u32, Value) {
+        let segments: Vec<String> = request.path.trim_matches('/').split('/').map(percent_decode).collect();
+        let segments: Vec<&str> = segments.iter().map(|segment| &segment[..]).collect();
+
+        let body: Value = serde_json::from_str(&request.body[..]).unwrap_or(Value::Null);
+
+        match (&request.method[..], &segments[..]) {
+            ("GET", &["_matrix", "client", "v3", "account", "whoami"]) => (200, json!({ "user_id": MATRIX_USER })),
+            ("GET", &["_matrix", "client", "v3", "rooms", room_id, "messages"]) => {
+                let limit = query_number(request, "limit", 10);
+                let mut events = self.room_events(room_id);
+                events.reverse();
+                events.truncate(limit);
+
+                (200, json!({ "chunk": events, "start": "t0", "end": "t1" }))
+            },
+            ("GET", &["_matrix", "client", "v3", "rooms", room_id, "context", event_id]) => {
+                let events = self.room_events(room_id);
+                let half = query_number(request, "limit", 10) / 2;
+
+                match events.iter().position(|event| event["event_id"] == json!(event_id)) {
+                    Some(index) => {
+                        let mut before = events[index.saturating_sub(half)..index].to_vec();
+                        before.reverse();
+                        let after = events[index + 1..cmp::min(index + 1 + half, events.len())].to_vec();
+
+                        (200, json!({ "events_before": before, "event": events[index].clone(), "events_after": after }))
+                    },
+                    None => (404, json!({ "errcode": "M_NOT_FOUND" })),
+                }
+            },
+            ("POST", &["_matrix", "client", "v3", "search"]) => {
+                let criteria = &body["search_categories"]["room_events"];
+                let term = criteria["search_term"].as_str().unwrap_or("").to_lowercase();
+                let rooms: Vec<Value> = criteria["filter"]["rooms"].as_array().cloned().unwrap_or(vec![]);
+
+                let mut results: Vec<Value> = self.timeline.iter()
+                    .filter(|event| rooms.is_empty() || rooms.contains(&json!(event.room_id)))
+                    .filter(|event| event.event["content"]["body"].as_str().unwrap_or("").to_lowercase().contains(&term[..]))
+                    .map(|event| json!({ "rank": 1.0, "result": event.event.clone() }))
+                    .collect();
+                results.reverse();
+
+                (200, json!({ "search_categories": { "room_events": { "count": results.len(), "results": results } } }))
+            },
+            ("PUT", &["_matrix", "client", "v3", "rooms", room_id, "send", "m.room.message", transaction_id]) => {
+                if let Some(event_id) = self.transactions.get(transaction_id) {
+                    return (200, json!({ "event_id": event_id }));
+                }
+
+                let event_id = self.add_message(room_id, MATRIX_USER, body);
+                self.transactions.insert(String::from(transaction_id), event_id.clone());
+
+                (200, json!({ "event_id": event_id }))
+            },
+            ("POST", &["_matrix", "client", "v3", "rooms", room_id, "receipt", "m.read", event_id]) => {
+                self.receipts.push((String::from(room_id), String::from(event_id)));
+                (200, json!({}))
+            },
+            _ => (404, json!({ "errcode": "M_UNRECOGNIZED" })),
+        }
+    }
+
+    // Rooms of the filter, or all, with the events after `since`
+    fn sync(&self, filter: &Value, since: u64) -> Value {
+        let room_filter: Option<Vec<Value>> = filter["room"]["rooms"].as_array().cloned();
+        let limit = filter["room"]["timeline"]["limit"].as_u64().unwrap_or(10) as usize;
+        let mut joined = json!({});
+
+        for &(room_id, name) in [(RUST_ROOM, "Rust"), (DIRECT_ROOM, "Friend")].iter() {
+            if let Some(ref rooms) = room_filter {
+                if !rooms.contains(&json!(room_id)) {
+                    continue;
+                }
+            }
+
+            let mut events: Vec<Value> = self.timeline.iter()
+                .filter(|event| event.room_id == room_id && event.position > since)
+                .map(|event| event.event.clone())
+                .collect();
+            let start = events.len().saturating_sub(limit);
+            let events = events.split_off(start);
+
+            // Only the first sync gets the state; later ones have nothing new
+            let mut state = vec![];
+            if since == 0 {
+                state.push(json!({ "type": "m.room.name", "state_key": "", "content": { "name": name } }));
+                if room_id == RUST_ROOM {
+                    state.push(json!({ "type": "m.room.canonical_alias", "state_key": "", "content": { "alias": "#rust:localhost" } }));
+                    state.push(json!({ "type": "m.room.topic", "state_key": "", "content": { "topic": "All things Rust" } }));
+                }
+            }
+
+            let account_data = if room_id == RUST_ROOM {
+                json!([{ "type": "m.tag", "content": { "tags": { "m.favourite": { "order": 0.5 } } } }])
+            } else {
+                json!([])
+            };
+
+            let unread = if room_id == RUST_ROOM {
+                json!({ "highlight_count": 1, "notification_count": 2 })
+            } else {
+                json!({ "highlight_count": 0, "notification_count": 1 })
+            };
+
+            joined[room_id] = json!({
+                "state": { "events": state },
+                "timeline": { "events": events, "limited": false },
+                "account_data": { "events": account_data },
+                "unread_notifications": unread
+            });
+        }
+
+        // The friend's direct chat
+        let mut direct = json!({});
+        direct[FRIEND] = json!([DIRECT_ROOM]);
+
+        json!({
+            "next_batch": format!("s{}", self.position()),
+            "rooms": { "join": joined },
+            "account_data": { "events": [{ "type": "m.direct", "content": direct }] }
+        })
+    }
+}
+
+// Clones share the same server
+#[derive(Clone)]
+pub struct MockHomeserver {
+    address: String,
+    state: Arc<Mutex<HomeserverState>>,
+}
+
+impl MockHomeserver {
+    pub fn start() -> MockHomeserver {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(HomeserverState::new()));
+
+        {
+            let state = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if let Ok(stream) = stream {
+                        let state = state.clone();
+                        thread::spawn(move || handle_connection(stream, state));
+                    }
+                }
+            });
+        }
+
+        MockHomeserver {
+            address: address,
+            state: state,
+        }
+    }
+
+    pub fn homeserver(&self) -> String {
+        self.address.clone()
+    }
+
+    pub fn handler(&self, room_id: &str) -> MatrixHandler {
+        self.handler_with_token(room_id, MATRIX_TOKEN)
+    }
+
+    pub fn handler_with_token(&self, room_id: &str, token: &str) -> MatrixHandler {
+        MatrixHandler::new(&String::from(room_id), &String::from(token), &self.address[..])
+    }
+
+    // Someone else posts in a room, waking up a waiting /sync
+    pub fn post_from_friend(&self, room_id: &str, text: &str) -> String {
+        self.state.lock().unwrap().add_message(room_id, FRIEND, json!({ "msgtype": "m.text", "body": text }))
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn last_request(&self) -> RecordedRequest {
+        self.requests().pop().expect("no request was made")
+    }
+
+    pub fn receipts(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().receipts.clone()
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<HomeserverState>>) {
+    let (request, mut writer) = match read_request(stream) {
+        Some(read) => read,
+        None => return,
+    };
+
+    state.lock().unwrap().requests.push(request.clone());
+
+    let authorized = request.headers.get("authorization").map(|auth| auth == &format!("Bearer {}", MATRIX_TOKEN)).unwrap_or(false);
+    let path = request.path.clone();
+
+    let (status, body) = if request.method == "POST" && path == "/_matrix/client/v3/login" {
+        login(&request)
+    } else if !authorized {
+        (401, json!({ "errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token" }))
+    } else if request.method == "GET" && path == "/_matrix/client/v3/sync" {
+        sync(&request, &state)
+    } else {
+        state.lock().unwrap().route(&request)
+    };
+
+    respond(&mut writer, status, &body);
+}
+
+fn login(request: &RecordedRequest) -> (u32, Value) {
+    let body: Value = serde_json::from_str(&request.body[..]).unwrap_or(Value::Null);
+    let user = body["identifier"]["user"].as_str().unwrap_or("");
+
+    if (user == "tester" || user == MATRIX_USER) && body["password"] == json!(PASSWORD) {
+        (200, json!({ "user_id": MATRIX_USER, "access_token": MATRIX_TOKEN, "device_id": "MOCKDEVICE" }))
+    } else {
+        (403, json!({ "errcode": "M_FORBIDDEN", "error": "Invalid username or password" }))
+    }
+}
+
+// Waits, without holding the lock, until something happened after `since` or the timeout passed
+fn sync(request: &RecordedRequest, state: &Arc<Mutex<HomeserverState>>) -> (u32, Value) {
+    let filter: Value = request.query.get("filter").and_then(|filter| serde_json::from_str(&filter[..]).ok()).unwrap_or(json!({}));
+    let since = request.query.get("since")
+        .and_then(|since| since.trim_left_matches('s').parse::<u64>().ok())
+        .unwrap_or(0);
+    let wait = cmp::min(query_number(request, "timeout", 0) as u64, MAX_SYNC_WAIT_MILLIS);
+
+    let started = Instant::now();
+    loop {
+        {
+            let state = state.lock().unwrap();
+            let response = state.sync(&filter, since);
+
+            let has_events = response["rooms"]["join"].as_object()
+                .map(|rooms| rooms.values().any(|room| room["timeline"]["events"].as_array().map(|events| events.len() > 0).unwrap_or(false)))
+                .unwrap_or(false);
+
+            if since == 0 || has_events || started.elapsed() >= Duration::from_millis(wait) {
+                return (200, response);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn query_number(request: &RecordedRequest, name: &str, default: usize) -> usize {
+    request.query.get(name).and_then(|value| value.parse::<usize>().ok()).unwrap_or(default)
+}
+
+fn respond(writer: &mut TcpStream, status: u32, body: &Value) {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        if status == 200 { "OK" } else { "Error" },
+        body.len()
+    );
+
+    writer.write_all(head.as_bytes()).unwrap_or(());
+    writer.write_all(body.as_bytes()).unwrap_or(());
+}
@@ -0,0 +1,529 @@
+// A fake Gitter API on a local port, answering from the JSON in fixtures/.
+// Each test starts its own server so tests can run in parallel.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+use serde_json::Value;
+
+use api;
+use api::{Endpoints, MessageHandler};
+
+pub const TOKEN: &'static str = "mock-token";
+
+// Ids used in the fixtures
+pub const USER_ID: &'static str = "5a0c1e1bd73408ce4f800001";
+pub const RUST_ROOM: &'static str = "room-rust";
+pub const GTK_ROOM: &'static str = "room-gtk";
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    // Names in lower case
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+    status: u32,
+    body: String,
+    etag: Option<String>,
+}
+
+impl Response {
+    fn json(status: u32, value: &Value) -> Response {
+        Response {
+            status: status,
+            body: value.to_string(),
+            etag: None,
+        }
+    }
+
+    // Successful GETs carry an ETag, and are answered with HTTP 304 when the client has that version
+    fn conditional(self, if_none_match: Option<&String>) -> Response {
+        if self.status != 200 {
+            return self;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.body.hash(&mut hasher);
+        let etag = format!("\"{:x}\"", hasher.finish());
+
+        if if_none_match == Some(&etag) {
+            Response {
+                status: 304,
+                body: String::new(),
+                etag: Some(etag),
+            }
+        } else {
+            Response {
+                etag: Some(etag),
+                ..self
+            }
+        }
+    }
+
+    fn error(status: u32, message: &str) -> Response {
+        Response::json(status, &json!({ "error": message }))
+    }
+}
+
+struct MockState {
+    users: Vec<Value>,
+    rooms: Vec<Value>,
+    groups: Vec<Value>,
+    // Oldest first, like the real API
+    messages: HashMap<String, Vec<Value>>,
+    next_id: u32,
+    // Served in order instead of whatever the next requests would have returned
+    failures: Vec<Response>,
+    requests: Vec<RecordedRequest>,
+}
+
+impl MockState {
+    fn from_fixtures() -> MockState {
+        let messages: HashMap<String, Vec<Value>> = serde_json::from_str(include_str!("fixtures/messages.json")).unwrap();
+
+        MockState {
+            users: serde_json::from_str(include_str!("fixtures/users.json")).unwrap(),
+            rooms: serde_json::from_str(include_str!("fixtures/rooms.json")).unwrap(),
+            groups: serde_json::from_str(include_str!("fixtures/groups.json")).unwrap(),
+            messages: messages,
+            next_id: 1,
+            failures: vec![],
+            requests: vec![],
+        }
+    }
+
+    fn new_message(&mut self, room_id: &str, from: usize, text: &str, parent_id: Option<&str>) -> Value {
+        let id = format!("new-{:04}", self.next_id);
+        self.next_id += 1;
+
+        let mut message = json!({
+            "id": id,
+            "text": text,
+            "html": text,
+            "sent": format!("2017-11-16T10:00:00.{:03}Z", self.next_id),
+            "fromUser": self.users[from].clone(),
+            // Our own messages are never unread for us
+            "unread": from != 0,
+            "readBy": 0,
+            "urls": [],
+            "mentions": [],
+            "v": 1
+        });
+
+        if let Some(parent_id) = parent_id {
+            message["parentId"] = json!(parent_id);
+        }
+
+        self.messages.entry(String::from(room_id)).or_insert(vec![]).push(message.clone());
+
+        message
+    }
+
+    fn unread_ids(&self, room_id: &str) -> Vec<String> {
+        self.messages.get(room_id).map(|messages| {
+            messages.iter()
+                .filter(|message| message["unread"] == json!(true))
+                .map(|message| String::from(message["id"].as_str().unwrap()))
+                .collect()
+        }).unwrap_or(vec![])
+    }
+
+    fn route(&mut self, request: &RecordedRequest) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+        match (&request.method[..], &segments[..]) {
+            ("GET", &["v1", "user"]) => Response::json(200, &json!([self.users[0].clone()])),
+            ("GET", &["v1", "rooms"]) => {
+                let rooms: Vec<Value> = self.rooms.iter().map(|room| {
+                    let mut room = room.clone();
+                    let unread = self.unread_ids(room["id"].as_str().unwrap()).len();
+                    room["unreadItems"] = json!(unread);
+                    room
+                }).collect();
+
+                Response::json(200, &json!(rooms))
+            },
+            ("GET", &["v1", "groups"]) => Response::json(200, &json!(self.groups.clone())),
+            ("GET", &["v1", "rooms", room_id, "chatMessages"]) => self.list_messages(room_id, &request.query),
+            ("POST", &["v1", "rooms", room_id, "chatMessages"]) => {
+                if !self.messages.contains_key(room_id) {
+                    return Response::error(404, "Room not found");
+                }
+
+                let body: Value = match serde_json::from_str(&request.body[..]) {
+                    Ok(body) => body,
+                    Err(_) => return Response::error(400, "Invalid JSON"),
+                };
+
+                match body["text"].as_str() {
+                    Some(text) => {
+                        let message = self.new_message(room_id, 0, text, body["parentId"].as_str());
+                        Response::json(200, &message)
+                    },
+                    None => Response::error(400, "Text is required"),
+                }
+            },
+            ("PUT", &["v1", "rooms", room_id, "chatMessages", message_id]) => {
+                let body: Value = match serde_json::from_str(&request.body[..]) {
+                    Ok(body) => body,
+                    Err(_) => return Response::error(400, "Invalid JSON"),
+                };
+
+                let message = self.messages.get_mut(room_id)
+                    .and_then(|messages| messages.iter_mut().find(|message| message["id"] == json!(message_id)));
+
+                match message {
+                    Some(message) => {
+                        let v = message["v"].as_u64().unwrap_or(1) + 1;
+                        message["text"] = body["text"].clone();
+                        message["html"] = body["text"].clone();
+                        message["v"] = json!(v);
+                        Response::json(200, message)
+                    },
+                    None => Response::error(404, "Message not found"),
+                }
+            },
+            ("GET", &["v1", "user", _, "rooms", room_id, "unreadItems"]) => {
+                Response::json(200, &json!({ "chat": self.unread_ids(room_id), "mention": [] }))
+            },
+            ("POST", &["v1", "user", _, "rooms", room_id, "unreadItems"]) => {
+                let body: Value = serde_json::from_str(&request.body[..]).unwrap_or(Value::Null);
+                let read: Vec<Value> = body["chat"].as_array().cloned().unwrap_or(vec![]);
+
+                if let Some(messages) = self.messages.get_mut(room_id) {
+                    for message in messages.iter_mut().filter(|message| read.contains(&message["id"])) {
+                        message["unread"] = json!(false);
+                    }
+                }
+
+                Response::json(200, &json!({ "success": true }))
+            },
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    // Supports the parameters the app uses: limit, q, aroundId and beforeId
+    fn list_messages(&self, room_id: &str, query: &HashMap<String, String>) -> Response {
+        let messages = match self.messages.get(room_id) {
+            Some(messages) => messages,
+            None => return Response::error(404, "Room not found"),
+        };
+
+        // Like Gitter, at most 100 at a time
+        let limit = query.get("limit").and_then(|limit| limit.parse::<usize>().ok()).unwrap_or(50).min(100);
+
+        let mut matching: Vec<Value> = match query.get("q") {
+            Some(q) => messages.iter()
+                .filter(|message| message["text"].as_str().unwrap_or("").to_lowercase().contains(&q.to_lowercase()[..]))
+                .cloned()
+                .collect(),
+            None => messages.clone(),
+        };
+
+        let end = match (query.get("aroundId"), query.get("beforeId")) {
+            (Some(id), _) => match matching.iter().position(|message| message["id"] == json!(id)) {
+                Some(index) => (index + limit / 2 + 1).min(matching.len()),
+                None => return Response::error(404, "Message not found"),
+            },
+            (None, Some(id)) => match matching.iter().position(|message| message["id"] == json!(id)) {
+                Some(index) => index,
+                None => return Response::error(404, "Message not found"),
+            },
+            (None, None) => matching.len(),
+        };
+
+        matching.truncate(end);
+        let start = matching.len().saturating_sub(limit);
+
+        Response::json(200, &json!(matching[start..].to_vec()))
+    }
+}
+
+pub struct MockServer {
+    address: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::from_fixtures()));
+
+        {
+            let state = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if let Ok(stream) = stream {
+                        let state = state.clone();
+                        thread::spawn(move || handle_connection(stream, state));
+                    }
+                }
+            });
+        }
+
+        MockServer {
+            address: address,
+            state: state,
+        }
+    }
+
+    // http://127.0.0.1:port, also usable as an HTTP proxy
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    pub fn api_base(&self) -> String {
+        format!("{}/v1", self.address)
+    }
+
+    pub fn stream_base(&self) -> String {
+        format!("{}/stream/v1", self.address)
+    }
+
+    // What an account in config.yaml pointing at this server gets
+    pub fn endpoints(&self) -> Endpoints {
+        Endpoints::new(&self.api_base()[..], &self.stream_base()[..], api::DEFAULT_WEBSOCKET_BASE)
+    }
+
+    pub fn handler(&self, room_id: &str) -> MessageHandler {
+        self.handler_with_token(room_id, TOKEN)
+    }
+
+    pub fn handler_with_token(&self, room_id: &str, token: &str) -> MessageHandler {
+        MessageHandler::with_endpoints(&String::from(room_id), &String::from(token), self.endpoints())
+    }
+
+    // The next request gets this response, whatever it asked for
+    pub fn fail_next(&self, status: u32, body: &str) {
+        self.state.lock().unwrap().failures.push(Response {
+            status: status,
+            body: String::from(body),
+            etag: None,
+        });
+    }
+
+    // Someone else posts in a room
+    pub fn post_from_other_user(&self, room_id: &str, text: &str) -> String {
+        let message = self.state.lock().unwrap().new_message(room_id, 1, text, None);
+        String::from(message["id"].as_str().unwrap())
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn last_request(&self) -> RecordedRequest {
+        self.requests().pop().expect("no request was made")
+    }
+
+    pub fn unread_ids(&self, room_id: &str) -> Vec<String> {
+        self.state.lock().unwrap().unread_ids(room_id)
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let (request, mut writer) = match read_request(stream) {
+        Some(read) => read,
+        None => return,
+    };
+    let headers = request.headers.clone();
+
+    let authorized = headers.get("authorization").map(|auth| auth == &format!("Bearer {}", TOKEN)).unwrap_or(false);
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+
+        if state.failures.len() > 0 {
+            Some(state.failures.remove(0))
+        } else if !authorized {
+            Some(Response::error(401, "Unauthorized"))
+        } else if request.path.starts_with("/stream/") {
+            None
+        } else if request.method == "GET" {
+            Some(state.route(&request).conditional(headers.get("if-none-match")))
+        } else {
+            Some(state.route(&request))
+        }
+    };
+
+    match response {
+        Some(response) => write_response(&mut writer, &response),
+        None => stream_messages(&mut writer, &request.path, &state),
+    };
+}
+
+// Reads one HTTP request, answering "Expect: 100-continue"; the stream is handed back for the response
+pub fn read_request(stream: TcpStream) -> Option<(RecordedRequest, TcpStream)> {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return None;
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = String::from(parts.next().unwrap_or(""));
+    let target = String::from(parts.next().unwrap_or(""));
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().len() == 0 {
+            break;
+        }
+
+        if let Some(colon) = line.find(':') {
+            headers.insert(line[..colon].trim().to_lowercase(), String::from(line[colon + 1..].trim()));
+        }
+    }
+
+    if headers.get("expect").map(|expect| expect == "100-continue").unwrap_or(false) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap_or(());
+    }
+
+    let length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap_or(());
+
+    // Asked as a proxy the target is the whole URL; the server answers for any host
+    let target = if target.starts_with("http://") {
+        let path_start = target[7..].find('/').map(|index| index + 7).unwrap_or(target.len());
+        String::from(&target[path_start..])
+    } else {
+        target
+    };
+
+    let (path, query) = match target.find('?') {
+        Some(index) => (String::from(&target[..index]), parse_query(&target[index + 1..])),
+        None => (target.clone(), HashMap::new()),
+    };
+
+    let request = RecordedRequest {
+        method: method,
+        path: path,
+        query: query,
+        headers: headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+
+    Some((request, writer))
+}
+
+fn write_response(writer: &mut TcpStream, response: &Response) {
+    let mut extra = response.etag.as_ref().map(|etag| format!("ETag: {}\r\n", etag)).unwrap_or(String::new());
+
+    // Rate limit headers like Gitter's, always with plenty left
+    extra.push_str("X-RateLimit-Limit: 100\r\nX-RateLimit-Remaining: 99\r\nX-RateLimit-Reset: 60\r\n");
+    if response.status == 429 {
+        extra.push_str("Retry-After: 1\r\n");
+    }
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len(),
+        extra
+    );
+
+    writer.write_all(head.as_bytes()).unwrap_or(());
+    writer.write_all(response.body.as_bytes()).unwrap_or(());
+}
+
+// Sends every message of the room as newline separated JSON, then hangs up.
+// Lines are split across writes and mixed with heartbeats, like the real stream.
+fn stream_messages(writer: &mut TcpStream, path: &String, state: &Arc<Mutex<MockState>>) {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let messages = match &segments[..] {
+        &["stream", "v1", "rooms", room_id, "chatMessages"] => state.lock().unwrap().messages.get(room_id).cloned(),
+        _ => None,
+    };
+
+    let messages = match messages {
+        Some(messages) => messages,
+        None => return write_response(writer, &Response::error(404, "Not found")),
+    };
+
+    writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n").unwrap_or(());
+
+    for message in messages.iter() {
+        let line = format!("{}\n", message);
+        let (first, second) = line.split_at(line.len() / 2);
+
+        writer.write_all(b" \n").unwrap_or(());
+        writer.write_all(first.as_bytes()).unwrap_or(());
+        writer.flush().unwrap_or(());
+        thread::sleep(Duration::from_millis(10));
+        writer.write_all(second.as_bytes()).unwrap_or(());
+    }
+
+    writer.flush().unwrap_or(());
+}
+
+fn reason(status: u32) -> &'static str {
+    match status {
+        200 => "OK",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|pair| pair.len() > 0)
+        .map(|pair| match pair.find('=') {
+            Some(index) => (percent_decode(&pair[..index]), percent_decode(&pair[index + 1..])),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+                match u8::from_str_radix(&hex[..], 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    },
+                    Err(_) => decoded.push(b'%'),
+                }
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        };
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,11 @@
+// Integration tests running the network layer against in-process mocks of the Gitter API
+// and a Matrix homeserver, and the keyring against a stub Secret Service on a private
+// D-Bus daemon; and the rooms, users and messages the unit tests of other modules share
+
+pub mod fixtures;
+mod keyring;
+mod matrix;
+mod mock_homeserver;
+mod mock_server;
+mod network;
+mod secret_service_stub;
@@ -0,0 +1,472 @@
+// The request builders of api.rs performed against the mock server
+
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+use serde_json::Value;
+
+use curl::easy::Easy;
+
+use accounts;
+use api::{ApiError, Endpoints, MessageHandler};
+use chat::ChatBackend;
+use config::{AccountEntry, Config};
+use connection::{ConnectionMonitor, ConnectionState};
+use export::{DateRange, History};
+use network;
+use tests::mock_server::{MockServer, GTK_ROOM, RUST_ROOM, TOKEN, USER_ID};
+use {Message, MessageStore};
+
+fn fetch_messages(handler: &MessageHandler) -> Vec<Message> {
+    handler.messages_request().unwrap().perform().unwrap()
+}
+
+// What `gitter_gtk export` does, without the waits after HTTP 429
+fn fetch_history(handler: &MessageHandler, range: DateRange) -> Vec<Message> {
+    let mut history = History::new(range);
+
+    while !history.is_complete() {
+        let page = handler.history_call(history.before_id()).unwrap().perform().unwrap();
+        history.add_page(page);
+    }
+
+    history.into_messages()
+}
+
+#[test]
+fn fetch_account_loads_user_rooms_and_groups() {
+    let server = MockServer::start();
+
+    let (user, rooms, groups) = server.handler("").fetch_account().unwrap();
+
+    assert_eq!(user.id, USER_ID);
+    assert_eq!(user.username, "tester");
+    assert_eq!(rooms.len(), 3);
+    assert_eq!(groups.len(), 1);
+
+    let rust = rooms.iter().find(|room| room.id == RUST_ROOM).unwrap();
+    assert_eq!(rust.unreadItems, 2);
+    assert_eq!(rust.groupId, Some(String::from("group-rust")));
+}
+
+#[test]
+fn account_uses_the_server_in_its_config() {
+    let server = MockServer::start();
+    let config = Config {
+        accounts: vec![AccountEntry {
+            name: String::from("team"),
+            api_base: Some(format!("{}/", server.api_base())),
+            ..AccountEntry::default()
+        }],
+        ..Config::default()
+    };
+
+    // The stream is looked for next to a self-hosted API
+    let endpoints = accounts::endpoints(&config, "team");
+    assert_eq!(endpoints.api, server.api_base());
+    assert_eq!(endpoints.stream, server.api_base());
+
+    let (user, _, _) = MessageHandler::with_endpoints(&String::new(), &String::from(TOKEN), endpoints).fetch_account().unwrap();
+    assert_eq!(user.id, USER_ID);
+    assert_eq!(server.last_request().path, "/v1/groups");
+
+    // Other accounts stay on gitter.im
+    assert_eq!(accounts::endpoints(&config, "personal"), Endpoints::default());
+}
+
+#[test]
+fn fetch_account_rejects_wrong_token() {
+    let server = MockServer::start();
+
+    match server.handler_with_token("", "wrong-token").fetch_account() {
+        Err(ApiError::Http(401)) => (),
+        other => panic!("expected HTTP 401, got {:?}", other.map(|(user, _, _)| user)),
+    };
+
+    // Gitter answers an unknown token with an empty user list instead
+    server.fail_next(200, "[]");
+    match server.handler("").fetch_account() {
+        Err(ApiError::Http(401)) => (),
+        other => panic!("expected HTTP 401, got {:?}", other.map(|(user, _, _)| user)),
+    };
+}
+
+#[test]
+fn send_keeps_special_characters() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+    let text = String::from("Grüße 👋 \"quoted\" back\\slash & <b>html</b> 100% done\nsecond line");
+
+    let sent: Message = handler.send_request(&String::from(RUST_ROOM), text.clone(), None).unwrap().perform().unwrap();
+    assert_eq!(sent.text, text);
+    assert_eq!(sent.fromUser.id, USER_ID);
+
+    let request = server.last_request();
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, format!("/v1/rooms/{}/chatMessages", RUST_ROOM));
+
+    let body: Value = serde_json::from_str(&request.body).unwrap();
+    assert_eq!(body, json!({ "text": text }));
+
+    let messages = fetch_messages(&handler);
+    assert_eq!(messages.last().unwrap().id, sent.id);
+    assert_eq!(messages.last().unwrap().text, text);
+}
+
+#[test]
+fn reply_sends_parent_id() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    handler
+        .send_request(&String::from(RUST_ROOM), String::from("Sure"), Some(String::from("rust-03")))
+        .unwrap()
+        .perform::<Message>()
+        .unwrap();
+
+    let body: Value = serde_json::from_str(&server.last_request().body).unwrap();
+    assert_eq!(body, json!({ "text": "Sure", "parentId": "rust-03" }));
+}
+
+#[test]
+fn edit_updates_text_and_version() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let edited: Message = handler
+        .update_request(&String::from(RUST_ROOM), &String::from("rust-02"), String::from("Edited"))
+        .unwrap()
+        .perform()
+        .unwrap();
+
+    assert_eq!(edited.id, "rust-02");
+    assert_eq!(edited.text, "Edited");
+    assert_eq!(edited.v, 2);
+    assert_eq!(server.last_request().method, "PUT");
+
+    let messages = fetch_messages(&handler);
+    assert_eq!(messages.iter().find(|message| message.id == "rust-02").unwrap().text, "Edited");
+}
+
+#[test]
+fn messages_are_paged_to_the_latest() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let ids: Vec<String> = (0..20)
+        .map(|i| server.post_from_other_user(RUST_ROOM, &format!("Message {}", i)))
+        .collect();
+
+    let messages = fetch_messages(&handler);
+    let received: Vec<String> = messages.into_iter().map(|message| message.id).collect();
+
+    assert_eq!(received, ids[5..].to_vec());
+    assert_eq!(server.last_request().query.get("limit"), Some(&String::from("15")));
+}
+
+#[test]
+fn around_loads_both_sides_of_a_message() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    for i in 0..40 {
+        server.post_from_other_user(RUST_ROOM, &format!("Message {}", i));
+    }
+
+    let messages: Vec<Message> = handler.around_request(&String::from("rust-03")).unwrap().perform().unwrap();
+    let position = messages.iter().position(|message| message.id == "rust-03").unwrap();
+
+    assert_eq!(messages[0].id, "rust-01");
+    assert!(position < messages.len() - 1);
+    assert!(messages.len() <= 30);
+}
+
+#[test]
+fn history_pages_back_to_the_first_message() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let ids: Vec<String> = (0..250)
+        .map(|i| server.post_from_other_user(RUST_ROOM, &format!("Message {}", i)))
+        .collect();
+
+    let messages = fetch_history(&handler, DateRange::default());
+    let received: Vec<String> = messages.into_iter().map(|message| message.id).collect();
+
+    assert_eq!(received.len(), 254);
+    assert_eq!(received[..4].to_vec(), vec!["rust-01", "rust-02", "rust-03", "rust-04"]);
+    assert_eq!(received[4..].to_vec(), ids);
+
+    // Three pages of at most 100, then an empty one where the room starts
+    let pages = server.requests();
+    assert_eq!(pages.len(), 4);
+    assert_eq!(pages[0].query.get("beforeId"), None);
+    assert_eq!(pages[1].query.get("beforeId"), Some(&ids[150]));
+    assert_eq!(pages[3].query.get("beforeId"), Some(&String::from("rust-01")));
+}
+
+#[test]
+fn history_stops_at_the_start_of_the_date_range() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    // Posted on 2017-11-16, after the fixtures of the day before
+    for i in 0..150 {
+        server.post_from_other_user(RUST_ROOM, &format!("Message {}", i));
+    }
+
+    let messages = fetch_history(&handler, DateRange::new(Some("2017-11-16"), None).unwrap());
+
+    assert_eq!(messages.len(), 150);
+    assert!(messages.iter().all(|message| message.sent.starts_with("2017-11-16")));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn search_encodes_query() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let id = server.post_from_other_user(RUST_ROOM, "Anyone here using C++ & Co?");
+
+    let results: Vec<Message> = handler.search_request(&String::from("c++ & co")).unwrap().perform().unwrap();
+
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, id);
+    assert_eq!(server.last_request().query.get("q"), Some(&String::from("c++ & co")));
+}
+
+#[test]
+fn polling_only_reports_new_messages() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+    let mut store = MessageStore::new();
+
+    let first = store.transform_messages(fetch_messages(&handler));
+    assert_eq!(first.len(), 4);
+    store.set_messages(first);
+
+    let id = server.post_from_other_user(RUST_ROOM, "Something new");
+    let polled = fetch_messages(&handler);
+    let second = store.transform_messages(polled.clone());
+    assert_eq!(second.len(), 1);
+    assert_eq!(second[0].id, id);
+    store.set_messages(polled);
+
+    let third = store.transform_messages(fetch_messages(&handler));
+    assert!(third.is_empty());
+}
+
+#[test]
+fn unchanged_responses_are_not_sent_again() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let first = fetch_messages(&handler);
+    assert_eq!(server.last_request().headers.get("if-none-match"), None);
+
+    // The server answers HTTP 304 and the cached body is used
+    let second = fetch_messages(&handler);
+    assert!(server.last_request().headers.contains_key("if-none-match"));
+    let ids = |messages: &Vec<Message>| messages.iter().map(|message| message.id.clone()).collect::<Vec<String>>();
+    assert_eq!(ids(&second), ids(&first));
+
+    let id = server.post_from_other_user(RUST_ROOM, "Something new");
+    let third = fetch_messages(&handler);
+    assert_eq!(third.last().unwrap().id, id);
+}
+
+#[test]
+fn requests_accept_gzip() {
+    let server = MockServer::start();
+
+    server.handler("").fetch_account().unwrap();
+
+    for request in server.requests() {
+        let encodings = request.headers.get("accept-encoding").cloned().unwrap_or(String::new());
+        assert!(encodings.contains("gzip"), "{} {} accepts {:?}", request.method, request.path, encodings);
+    }
+}
+
+#[test]
+fn rate_limited_request_can_be_sent_again() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    let (mut easy, response) = handler.send_request(&String::from(RUST_ROOM), String::from("Once more"), None).unwrap().into_parts();
+
+    server.fail_next(429, "{\"error\":\"Too Many Requests\"}");
+    easy.perform().unwrap();
+    assert_eq!(easy.response_code().unwrap(), 429);
+    assert_eq!(response.header("retry-after"), Some(String::from("1")));
+    assert_eq!(response.header("x-ratelimit-remaining"), Some(String::from("99")));
+
+    // The scheduler performs the same handle again once the wait is over
+    response.clear();
+    easy.perform().unwrap();
+    let sent: Message = response.parse(&mut easy).unwrap();
+
+    assert_eq!(sent.text, "Once more");
+    assert_eq!(response.header("retry-after"), None);
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].body, requests[1].body);
+}
+
+#[test]
+fn requests_go_through_the_configured_proxy() {
+    let proxy = MockServer::start();
+    let settings = network::Settings {
+        proxy: Some(proxy.address()),
+        ..network::Settings::default()
+    };
+
+    // The host does not exist, so only the proxy can answer
+    let mut easy = Easy::new();
+    network::apply_settings(&mut easy, &settings).unwrap();
+    easy.url("http://api.gitter.invalid/v1/user").unwrap();
+    easy.perform().unwrap();
+
+    // The mock answers 401 as the request carries no token
+    assert_eq!(easy.response_code().unwrap(), 401);
+    assert_eq!(proxy.last_request().path, "/v1/user");
+
+    // Hosts on the no-proxy list are reached directly
+    let server = MockServer::start();
+    let settings = network::Settings {
+        proxy: Some(proxy.address()),
+        no_proxy: Some(String::from("127.0.0.1")),
+        ..network::Settings::default()
+    };
+
+    let mut easy = Easy::new();
+    network::apply_settings(&mut easy, &settings).unwrap();
+    easy.url(&format!("{}/user", server.api_base())).unwrap();
+    easy.perform().unwrap();
+
+    assert_eq!(server.requests().len(), 1);
+    assert_eq!(proxy.requests().len(), 1);
+}
+
+#[test]
+fn mark_read_clears_unread_items() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+
+    assert_eq!(server.unread_ids(RUST_ROOM), vec![String::from("rust-03"), String::from("rust-04")]);
+
+    handler
+        .mark_read_request(&String::from(USER_ID), vec![String::from("rust-03"), String::from("rust-04")])
+        .unwrap()
+        .perform::<Value>()
+        .unwrap();
+
+    assert!(server.unread_ids(RUST_ROOM).is_empty());
+    assert_eq!(server.last_request().path, format!("/v1/user/{}/rooms/{}/unreadItems", USER_ID, RUST_ROOM));
+    assert_eq!(server.unread_ids(GTK_ROOM), Vec::<String>::new());
+}
+
+#[test]
+fn server_errors_map_to_connection_states() {
+    let server = MockServer::start();
+    let handler = server.handler(RUST_ROOM);
+    let mut monitor = ConnectionMonitor::new();
+
+    server.fail_next(500, "{\"error\":\"Internal Server Error\"}");
+    let result = handler.messages_request().unwrap().perform::<Vec<Message>>();
+    match result {
+        Err(ApiError::Http(500)) => (),
+        ref other => panic!("expected HTTP 500, got {:?}", other),
+    };
+    monitor.report(&result);
+    assert_eq!(monitor.state(), ConnectionState::Degraded);
+
+    let result = handler.messages_request().unwrap().perform::<Vec<Message>>();
+    monitor.report(&result);
+    assert_eq!(monitor.state(), ConnectionState::Online);
+
+    server.fail_next(429, "{\"error\":\"Too Many Requests\"}");
+    let result = handler.messages_request().unwrap().perform::<Vec<Message>>();
+    match result {
+        Err(ApiError::Http(429)) => (),
+        ref other => panic!("expected HTTP 429, got {:?}", other),
+    };
+    monitor.report(&result);
+    assert_eq!(monitor.state(), ConnectionState::Degraded);
+
+    server.fail_next(200, "{not json");
+    let result = handler.messages_request().unwrap().perform::<Vec<Message>>();
+    match result {
+        Err(ApiError::Json(_)) => (),
+        ref other => panic!("expected invalid JSON, got {:?}", other),
+    };
+    monitor.report(&result);
+    assert_eq!(monitor.state(), ConnectionState::Degraded);
+
+    let result = server.handler_with_token(RUST_ROOM, "wrong-token").messages_request().unwrap().perform::<Vec<Message>>();
+    match result {
+        Err(ApiError::Http(401)) => (),
+        ref other => panic!("expected HTTP 401, got {:?}", other),
+    };
+    monitor.report(&result);
+    assert_eq!(monitor.state(), ConnectionState::AuthFailed);
+}
+
+#[test]
+fn unreachable_server_goes_offline() {
+    // Nothing listens on the discard port of localhost
+    let handler = MessageHandler::with_endpoints(
+        &String::from(RUST_ROOM),
+        &String::from("mock-token"),
+        Endpoints::new("http://127.0.0.1:9/v1", "http://127.0.0.1:9/stream/v1", "ws://127.0.0.1:9/faye"),
+    );
+    let mut monitor = ConnectionMonitor::new();
+
+    for _ in 0..3 {
+        let result = handler.messages_request().unwrap().perform::<Vec<Message>>();
+        match result {
+            Err(ApiError::Network(_)) => (),
+            ref other => panic!("expected a network error, got {:?}", other),
+        };
+        monitor.report(&result);
+    }
+
+    assert_eq!(monitor.state(), ConnectionState::Offline);
+}
+
+#[test]
+fn stream_delivers_messages_split_across_writes() {
+    let server = MockServer::start();
+    let received = Arc::new(Mutex::new(vec![]));
+
+    let mut easy = {
+        let received = received.clone();
+        server.handler(RUST_ROOM).stream_request(Box::new(move |message: Message| {
+            received.lock().unwrap().push(message.id);
+        })).unwrap()
+    };
+    easy.perform().unwrap();
+
+    assert_eq!(easy.response_code().unwrap(), 200);
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec!["rust-01", "rust-02", "rust-03", "rust-04"]
+    );
+}
+
+#[test]
+fn stream_rejects_wrong_token() {
+    let server = MockServer::start();
+    let received = Arc::new(Mutex::new(vec![]));
+
+    let mut easy = {
+        let received = received.clone();
+        server.handler_with_token(RUST_ROOM, "wrong-token").stream_request(Box::new(move |message: Message| {
+            received.lock().unwrap().push(message.id);
+        })).unwrap()
+    };
+    easy.perform().unwrap();
+
+    assert_eq!(easy.response_code().unwrap(), 401);
+    assert!(received.lock().unwrap().is_empty());
+}
@@ -0,0 +1,268 @@
+// A stand-in for GNOME Keyring on a private D-Bus daemon, answering the parts of the
+// Secret Service API used by keyring.rs. The tests need dbus-daemon installed and fail without it.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+use dbus;
+use dbus::arg::{Iter, Variant};
+use dbus::Path;
+
+const SERVICE: &'static str = "org.freedesktop.secrets";
+const SERVICE_PATH: &'static str = "/org/freedesktop/secrets";
+const DEFAULT_COLLECTION_PATH: &'static str = "/org/freedesktop/secrets/aliases/default";
+const SESSION_PATH: &'static str = "/org/freedesktop/secrets/session/1";
+const ITEM_PATH_PREFIX: &'static str = "/org/freedesktop/secrets/collection/login/";
+
+const LABEL_PROPERTY: &'static str = "org.freedesktop.Secret.Item.Label";
+const ATTRIBUTES_PROPERTY: &'static str = "org.freedesktop.Secret.Item.Attributes";
+
+// A dbus-daemon of its own, so tests never touch the user's keyring
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    // None when dbus-daemon is not installed or does not start; the caller then skips its test,
+    // saying so, as the keyring cannot be checked without a bus
+    pub fn start() -> Option<PrivateBus> {
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                println!("Skipping the keyring test, dbus-daemon is not available -> {}", e);
+                return None;
+            },
+        };
+
+        let mut address = String::new();
+        if let Some(stdout) = daemon.stdout.take() {
+            BufReader::new(stdout).read_line(&mut address).unwrap_or(0);
+        }
+
+        if address.trim().len() == 0 {
+            daemon.kill().unwrap_or(());
+            daemon.wait().ok();
+            println!("Skipping the keyring test, dbus-daemon did not print its address");
+            return None;
+        }
+
+        Some(PrivateBus {
+            daemon: daemon,
+            address: String::from(address.trim()),
+        })
+    }
+
+    pub fn connect(&self) -> dbus::Connection {
+        connect(&self.address)
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        self.daemon.kill().unwrap_or(());
+        self.daemon.wait().ok();
+    }
+}
+
+fn connect(address: &str) -> dbus::Connection {
+    let bus = dbus::Connection::open_private(address).unwrap();
+    bus.register().unwrap();
+    bus
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredItem {
+    pub label: String,
+    pub attributes: HashMap<String, String>,
+    pub secret: Vec<u8>,
+    pub locked: bool,
+}
+
+struct StubState {
+    // By object path
+    items: HashMap<String, StoredItem>,
+    next_id: u32,
+}
+
+pub struct SecretServiceStub {
+    state: Arc<Mutex<StubState>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SecretServiceStub {
+    // Returns once the service name is owned on the bus
+    pub fn start(bus: &PrivateBus) -> SecretServiceStub {
+        let state = Arc::new(Mutex::new(StubState {
+            items: HashMap::new(),
+            next_id: 1,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready, started) = mpsc::channel();
+
+        // dbus::Connection cannot be sent between threads, so it is opened on the stub's own
+        let thread = {
+            let address = bus.address.clone();
+            let state = state.clone();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                let connection = connect(&address);
+                connection.register_name(SERVICE, dbus::NameFlag::DoNotQueue as u32).unwrap();
+
+                for path in &[SERVICE_PATH, DEFAULT_COLLECTION_PATH, SESSION_PATH] {
+                    connection.register_object_path(path).unwrap();
+                }
+
+                ready.send(()).unwrap();
+
+                while !stop.load(Ordering::SeqCst) {
+                    for message in connection.incoming(100) {
+                        if message.msg_type() != dbus::MessageType::MethodCall {
+                            continue;
+                        }
+
+                        // Calls the stub does not know are left to time out
+                        if let Some(reply) = handle_call(&connection, &state, &message) {
+                            connection.send(reply).unwrap_or(0);
+                        }
+                    }
+                }
+            })
+        };
+
+        started.recv().unwrap();
+
+        SecretServiceStub {
+            state: state,
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn items(&self) -> Vec<StoredItem> {
+        self.state.lock().unwrap().items.values().cloned().collect()
+    }
+
+    // As if the user locked their keyring
+    pub fn lock_all(&self) {
+        for item in self.state.lock().unwrap().items.values_mut() {
+            item.locked = true;
+        }
+    }
+}
+
+impl Drop for SecretServiceStub {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+fn path(path: &str) -> Path<'static> {
+    Path::new(String::from(path)).unwrap()
+}
+
+fn handle_call(connection: &dbus::Connection, state: &Arc<Mutex<StubState>>, message: &dbus::Message) -> Option<dbus::Message> {
+    let object = String::from(&*message.path()?);
+    let member = String::from(&*message.member()?);
+    let mut state = state.lock().unwrap();
+
+    match &member[..] {
+        "OpenSession" => Some(message.method_return().append2(Variant(""), path(SESSION_PATH))),
+        "SearchItems" => {
+            let wanted: HashMap<String, String> = message.read1().ok()?;
+            let mut unlocked = vec![];
+            let mut locked = vec![];
+
+            for (item_path, item) in state.items.iter() {
+                if wanted.iter().all(|(key, value)| item.attributes.get(key) == Some(value)) {
+                    if item.locked {
+                        locked.push(path(item_path));
+                    } else {
+                        unlocked.push(path(item_path));
+                    }
+                }
+            }
+
+            Some(message.method_return().append2(unlocked, locked))
+        },
+        "Unlock" => {
+            let objects: Vec<Path> = message.read1().ok()?;
+            let mut unlocked = vec![];
+
+            for object in objects {
+                if let Some(item) = state.items.get_mut(&*object) {
+                    item.locked = false;
+                    unlocked.push(object.into_static());
+                }
+            }
+
+            Some(message.method_return().append2(unlocked, path("/")))
+        },
+        "CreateItem" if object == DEFAULT_COLLECTION_PATH => {
+            let (mut properties, secret, replace): (HashMap<String, Variant<Iter>>, (Path, Vec<u8>, Vec<u8>, String), bool) =
+                message.read3().ok()?;
+
+            let label = match properties.remove(LABEL_PROPERTY) {
+                Some(Variant(mut value)) => value.get::<&str>().map(String::from).unwrap_or_default(),
+                None => String::new(),
+            };
+            let attributes: HashMap<String, String> = match properties.remove(ATTRIBUTES_PROPERTY) {
+                Some(Variant(mut value)) => value.get().unwrap_or_default(),
+                None => HashMap::new(),
+            };
+
+            let existing = if replace {
+                state.items.iter().find(|&(_, item)| item.attributes == attributes).map(|(item_path, _)| item_path.clone())
+            } else {
+                None
+            };
+
+            let item_path = match existing {
+                Some(item_path) => item_path,
+                None => {
+                    let item_path = format!("{}{}", ITEM_PATH_PREFIX, state.next_id);
+                    state.next_id += 1;
+                    connection.register_object_path(&item_path[..]).unwrap();
+                    item_path
+                },
+            };
+
+            state.items.insert(item_path.clone(), StoredItem {
+                label: label,
+                attributes: attributes,
+                secret: secret.2,
+                locked: false,
+            });
+
+            Some(message.method_return().append2(path(&item_path[..]), path("/")))
+        },
+        "GetSecret" => {
+            let item = state.items.get(&object)?;
+            let secret = (path(SESSION_PATH), Vec::<u8>::new(), item.secret.clone(), "text/plain; charset=utf8");
+
+            Some(message.method_return().append1(secret))
+        },
+        "Delete" => {
+            state.items.remove(&object)?;
+            connection.unregister_object_path(&object[..]);
+
+            Some(message.method_return().append1(path("/")))
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,443 @@
+// Terminal frontend for tmux or ssh sessions without a display, built with the `tui`
+// cargo feature and started with `gitter_gtk tui`. It drives the same backend core
+// and AppState as the window; only drawing and key handling are its own.
+
+use std::cmp;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+use termion;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear, cursor, style};
+
+use accounts::Account;
+use backend;
+use backend::{BackendConfig, Command, CommandSender, Event};
+use cli;
+use config::Config;
+use outbox::{DeliveryState, OutboxItem};
+use state::{Action, AppState, Change, ComposerMode};
+use logging;
+use store::{SqliteStore, Store};
+use ui_channel;
+use {account_cache_path, load_account, message_text, open_cache, Message, OutgoingMessage, CACHED_MESSAGES_SHOWN};
+
+// How often the terminal size is checked while nothing else happens
+const RESIZE_POLL_MILLIS: u64 = 250;
+
+const SIDEBAR_MAX_WIDTH: usize = 28;
+
+const HELP: &'static str = "Enter send | Ctrl+N/P room | Ctrl+U unread room | PgUp/PgDn scroll | Up edit last | Esc cancel | Ctrl+Q quit";
+
+enum Input {
+    Key(Key),
+    Backend(Event),
+}
+
+struct Tui {
+    state: AppState,
+    commands: CommandSender,
+    cache: Option<SqliteStore>,
+    composer: String,
+    // Lines scrolled up from the newest message
+    scroll: usize,
+    // Height of the message view when last drawn, for paging
+    page: usize,
+}
+
+// Runs until the user quits; meanwhile log records only go to the log file
+pub fn run(account: Account, room: Option<String>, config: &Config) -> Result<(), String> {
+    let cache_path = account_cache_path(&account);
+    let cache = open_cache(&cache_path);
+
+    let (user, rooms, groups, from_cache) = load_account(&account, &cache)
+        .map_err(|e| format!("Could not load the account {} -> {}", account.name, e))?;
+
+    let mut state = AppState::new(&user, &rooms, &groups);
+
+    let room_id = match room {
+        Some(ref query) => match cli::find_room(&state.rooms, &query[..]) {
+            Some(room) => room.id.clone(),
+            None => return Err(format!("@{} is not in a room called {}", user.username, query)),
+        },
+        None => state.rooms.get(0).map(|room| room.id.clone()).unwrap_or(String::new()),
+    };
+
+    let (events, event_receiver) = ui_channel::plain_channel();
+    let commands = backend::start(BackendConfig {
+        token: account.token.clone(),
+        endpoints: account.endpoints.clone(),
+        user: user,
+        room_id: room_id.clone(),
+        cache_path: cache_path,
+        refresh_account: from_cache,
+        poll_interval_secs: config.poll_interval_secs,
+        notifications: config.notifications,
+    }, events);
+
+    if room_id.len() > 0 {
+        let cached = cached_messages(&cache, &room_id);
+        state.reduce(Action::SwitchRoom { room_id: room_id, cached: cached });
+    }
+
+    let mut tui = Tui {
+        state: state,
+        commands: commands,
+        cache: cache,
+        composer: String::new(),
+        scroll: 0,
+        page: 1,
+    };
+
+    logging::set_console(false);
+    let result = tui.run_terminal(event_receiver);
+    tui.send_command(Command::Shutdown);
+    logging::set_console(true);
+
+    result.map_err(|e| format!("Terminal error -> {}", e))
+}
+
+fn cached_messages(cache: &Option<SqliteStore>, room_id: &String) -> Vec<Message> {
+    match *cache {
+        Some(ref cache) => cache.load_messages(room_id, CACHED_MESSAGES_SHOWN).unwrap_or_else(|e| {
+            error!("Reading cached messages -> {}", e);
+            vec![]
+        }),
+        None => vec![],
+    }
+}
+
+impl Tui {
+    fn run_terminal(&mut self, event_receiver: mpsc::Receiver<Event>) -> io::Result<()> {
+        let tty = termion::get_tty()?;
+        let keys = tty.try_clone()?;
+        let tty_fd = tty.as_raw_fd();
+
+        let mut screen = AlternateScreen::from(tty.into_raw_mode()?);
+
+        let (inputs, receiver) = mpsc::channel();
+
+        {
+            let inputs = inputs.clone();
+            thread::spawn(move || {
+                for key in keys.keys() {
+                    let sent = match key {
+                        Ok(key) => inputs.send(Input::Key(key)).is_ok(),
+                        Err(_) => false,
+                    };
+
+                    if !sent {
+                        return;
+                    }
+                }
+            });
+        }
+
+        // Backend events join the keys, so one loop waits on both
+        thread::spawn(move || {
+            for event in event_receiver.iter() {
+                if inputs.send(Input::Backend(event)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut size = terminal_size(tty_fd);
+        self.draw(&mut screen, size)?;
+
+        loop {
+            match receiver.recv_timeout(Duration::from_millis(RESIZE_POLL_MILLIS)) {
+                Ok(Input::Key(key)) => {
+                    if !self.handle_key(key) {
+                        break;
+                    }
+                },
+                Ok(Input::Backend(event)) => self.handle_event(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if terminal_size(tty_fd) == size {
+                        continue;
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+
+            size = terminal_size(tty_fd);
+            self.draw(&mut screen, size)?;
+        }
+
+        write!(screen, "{}", cursor::Show)?;
+        screen.flush()
+    }
+
+    fn send_command(&self, command: Command) {
+        if let Err(e) = self.commands.unbounded_send(command) {
+            error!("Backend core stopped -> {}", e);
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let action = match event {
+            Event::MessagesAdded { room_id, messages } => Action::MessagesAdded { room_id: room_id, messages: messages },
+            Event::RoomsUpdated { rooms, groups } => Action::RoomsUpdated { rooms: rooms, groups: groups },
+            Event::ContextLoaded { room_id, message_id, messages } => Action::ContextLoaded {
+                room_id: room_id,
+                message_id: message_id,
+                messages: messages,
+            },
+            Event::Outbox(event) => Action::Outbox(event),
+            Event::ConnectionChanged(state) => Action::ConnectionChanged(state),
+            // There is no search in the terminal
+            Event::SearchResults(_) | Event::Export(_) => return,
+        };
+
+        self.dispatch(action);
+    }
+
+    // Returns false when the user quits
+    fn handle_key(&mut self, key: Key) -> bool {
+        match key {
+            Key::Ctrl('q') | Key::Ctrl('c') => return false,
+            Key::Char('\n') => self.submit_composer(),
+            Key::Char(c) => self.composer.push(c),
+            Key::Backspace => {
+                self.composer.pop();
+            },
+            Key::Esc => self.dispatch(Action::CancelComposer),
+            Key::Up if self.composer.len() == 0 => self.dispatch(Action::EditLastOwnMessage),
+            Key::Ctrl('n') => self.select_adjacent_room(true, false),
+            Key::Ctrl('p') => self.select_adjacent_room(false, false),
+            Key::Ctrl('u') => self.select_adjacent_room(true, true),
+            Key::PageUp => self.scroll += self.page,
+            Key::PageDown => self.scroll = self.scroll.saturating_sub(self.page),
+            _ => (),
+        };
+
+        true
+    }
+
+    // Applies an action to the state; everything is redrawn afterwards, so only the composer needs care
+    fn dispatch(&mut self, action: Action) {
+        for change in self.state.reduce(action) {
+            match change {
+                Change::ComposerChanged { clear_text } => {
+                    if clear_text {
+                        self.composer.clear();
+                    }
+
+                    if let ComposerMode::Editing(ref message) = self.state.composer {
+                        self.composer = message.text.clone();
+                    }
+                },
+                Change::CurrentRoomChanged | Change::MessagesReset => self.scroll = 0,
+                _ => (),
+            };
+        }
+    }
+
+    fn submit_composer(&mut self) {
+        let queued = match (self.state.current_room_id.clone(), self.state.outgoing_message(&self.composer[..])) {
+            (Some(room_id), Some(outgoing)) => OutboxItem::new(&room_id, outgoing),
+            _ => return,
+        };
+
+        self.send_command(Command::Send(queued.clone()));
+        self.dispatch(Action::Queued(queued));
+    }
+
+    fn select_adjacent_room(&mut self, forward: bool, unread_only: bool) {
+        let room_id = match self.state.adjacent_room(forward, unread_only) {
+            Some(room_id) => room_id,
+            None => return,
+        };
+
+        self.send_command(Command::SwitchRoom(room_id.clone()));
+
+        let cached = cached_messages(&self.cache, &room_id);
+        self.dispatch(Action::SwitchRoom { room_id: room_id, cached: cached });
+    }
+
+    fn draw<W: Write>(&mut self, screen: &mut W, (width, height): (usize, usize)) -> io::Result<()> {
+        let mut out = format!("{}{}", cursor::Hide, clear::All);
+
+        // Two rows for the composer and the help below the rooms and messages
+        let body_height = height.saturating_sub(2);
+        let sidebar_width = cmp::min(SIDEBAR_MAX_WIDTH, width / 3);
+        let chat_left = sidebar_width + 1;
+        let chat_width = width.saturating_sub(chat_left);
+
+        for (row, line) in self.sidebar_lines(sidebar_width, body_height).into_iter().enumerate() {
+            out.push_str(&goto(0, row));
+            out.push_str(&line[..]);
+        }
+
+        for row in 0..body_height {
+            out.push_str(&goto(sidebar_width, row));
+            out.push('│');
+        }
+
+        // Room name and connection state above the messages
+        let header = {
+            let room_name = self.state.current_room().map(|room| room.name.clone()).unwrap_or(String::new());
+            format!("{} · {}", room_name, self.state.connection.description())
+        };
+        out.push_str(&goto(chat_left, 0));
+        out.push_str(&format!("{}{}{}", style::Bold, fit(&header[..], chat_width), style::Reset));
+
+        let message_rows = body_height.saturating_sub(1);
+        self.page = cmp::max(1, message_rows.saturating_sub(1));
+
+        let lines = self.chat_lines(chat_width);
+        self.scroll = cmp::min(self.scroll, lines.len().saturating_sub(message_rows));
+        let end = lines.len() - self.scroll;
+        let start = end.saturating_sub(message_rows);
+
+        for (row, line) in lines[start..end].iter().enumerate() {
+            out.push_str(&goto(chat_left, row + 1));
+            out.push_str(&line[..]);
+        }
+
+        // Help first, so the cursor ends up in the composer
+        out.push_str(&goto(0, height.saturating_sub(1)));
+        out.push_str(&format!("{}{}{}", style::Faint, fit(HELP, width), style::Reset));
+
+        let prompt = match self.state.composer {
+            ComposerMode::Normal => String::from("> "),
+            ComposerMode::Editing(_) => String::from("edit> "),
+            ComposerMode::Replying(ref message) => format!("reply @{}> ", message.fromUser.username),
+        };
+        // The end of a long text stays in view while typing
+        let text_width = width.saturating_sub(prompt.chars().count() + 1);
+        let skip = self.composer.chars().count().saturating_sub(text_width);
+        let text: String = self.composer.chars().skip(skip).collect();
+
+        out.push_str(&goto(0, height.saturating_sub(2)));
+        out.push_str(&format!("{}{}{}", prompt, text, cursor::Show));
+
+        screen.write_all(out.as_bytes())?;
+        screen.flush()
+    }
+
+    // Sections and their rooms, scrolled so the current room is visible
+    fn sidebar_lines(&self, width: usize, height: usize) -> Vec<String> {
+        let mut lines = vec![];
+        let mut current_line = 0;
+
+        for section in self.state.sections.iter() {
+            lines.push(format!("{}{}{}", style::Bold, fit(&section.title[..], width), style::Reset));
+
+            for room in section.rooms.iter() {
+                let unread = if room.mentions > 0 {
+                    format!(" @{}", room.mentions)
+                } else if room.unreadItems > 0 {
+                    format!(" {}", room.unreadItems)
+                } else {
+                    String::new()
+                };
+
+                let name_width = width.saturating_sub(unread.chars().count() + 1);
+                let label = format!(" {}{}", fit(&room.name[..], name_width), unread);
+
+                if Some(&room.id) == self.state.current_room_id.as_ref() {
+                    current_line = lines.len();
+                    lines.push(format!("{}{}{}", style::Invert, label, style::Reset));
+                } else {
+                    lines.push(label);
+                }
+            }
+        }
+
+        let skip = (current_line + 1).saturating_sub(height);
+        lines.into_iter().skip(skip).take(height).collect()
+    }
+
+    // Visible messages, then those still on their way, wrapped to the width
+    fn chat_lines(&self, width: usize) -> Vec<String> {
+        let mut lines = vec![];
+
+        for message in self.state.messages.iter().filter(|message| self.state.is_visible(message)) {
+            lines.extend(wrap(&message_text(message)[..], width));
+        }
+
+        for pending in self.state.current_pending() {
+            let text = match pending.item.message {
+                OutgoingMessage::New { ref text, .. } | OutgoingMessage::Edit { ref text, .. } => text,
+            };
+
+            let status = match pending.item.state {
+                DeliveryState::Sending => "sending",
+                DeliveryState::Failed => "failed",
+            };
+
+            for line in wrap(&format!("({}) {}", status, text)[..], width) {
+                lines.push(format!("{}{}{}", style::Faint, line, style::Reset));
+            }
+        }
+
+        lines
+    }
+}
+
+// termion counts from 1
+fn goto(column: usize, row: usize) -> String {
+    format!("{}", cursor::Goto(column as u16 + 1, row as u16 + 1))
+}
+
+// Cuts text to at most `width` characters
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+// Breaks text into lines of at most `width` characters, at spaces where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let width = cmp::max(width, 1);
+
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+
+        for word in paragraph.split(' ') {
+            let line_length = line.chars().count();
+            let word_length = word.chars().count();
+
+            if line_length > 0 && line_length + 1 + word_length > width {
+                lines.push(mem::replace(&mut line, String::new()));
+            } else if line_length > 0 {
+                line.push(' ');
+            }
+
+            // Words longer than a line are split wherever they overflow
+            for c in word.chars() {
+                if line.chars().count() == width {
+                    lines.push(mem::replace(&mut line, String::new()));
+                }
+                line.push(c);
+            }
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+// (columns, rows) of the terminal; termion only asks stdout, which may be piped
+fn terminal_size(fd: RawFd) -> (usize, usize) {
+    unsafe {
+        let mut size: libc::winsize = mem::zeroed();
+
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 && size.ws_row > 0 {
+            (size.ws_col as usize, size.ws_row as usize)
+        } else {
+            (80, 24)
+        }
+    }
+}
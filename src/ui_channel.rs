@@ -0,0 +1,112 @@
+// Delivers backend events to the GTK main loop as soon as they are sent.
+// Senders may live on any thread; the receiver is attached on the GTK thread
+// and is woken through glib::idle_add instead of being polled on a timer.
+// Every signed in account has a channel of its own. The terminal UI reads its
+// events straight from the receiver of a plain channel instead.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use glib;
+
+use backend::Event;
+
+// The receiver of one account and the handler of its events
+struct Attached {
+    receiver: mpsc::Receiver<Event>,
+    handler: RefCell<Box<FnMut(Event)>>,
+}
+
+thread_local!(
+    static HANDLERS: RefCell<Vec<Rc<Attached>>> = RefCell::new(vec![])
+);
+
+#[derive(Clone)]
+pub struct EventSender {
+    sender: mpsc::Sender<Event>,
+    // Tells the frontend's main loop there is something to read
+    wake: fn(),
+}
+
+impl EventSender {
+    pub fn send(&self, event: Event) {
+        if self.sender.send(event).is_ok() {
+            (self.wake)();
+        }
+    }
+}
+
+pub struct EventReceiver {
+    receiver: mpsc::Receiver<Event>,
+}
+
+impl EventReceiver {
+    // Must be called on the GTK thread; `handler` runs there for every event
+    pub fn attach<F>(self, handler: F)
+    where F: FnMut(Event) + 'static
+    {
+        let receiver = self.receiver;
+
+        let attached = Rc::new(Attached {
+            receiver: receiver,
+            handler: RefCell::new(Box::new(handler)),
+        });
+
+        HANDLERS.with(move |cell| {
+            cell.borrow_mut().push(attached);
+        });
+
+        // Events sent before attaching are waiting in the channel
+        glib::idle_add(dispatch);
+    }
+}
+
+pub fn channel() -> (EventSender, EventReceiver) {
+    let (sender, receiver) = mpsc::channel();
+
+    (EventSender { sender: sender, wake: wake_gtk }, EventReceiver { receiver: receiver })
+}
+
+// For a frontend which waits on the receiver itself
+pub fn plain_channel() -> (EventSender, mpsc::Receiver<Event>) {
+    let (sender, receiver) = mpsc::channel();
+
+    (EventSender { sender: sender, wake: wake_nothing }, receiver)
+}
+
+fn wake_gtk() {
+    glib::idle_add(dispatch);
+}
+
+fn wake_nothing() {}
+
+// Runs on the GTK thread; drains everything queued so far and forgets
+// channels whose backend core has stopped. Handlers may attach channels or spin
+// a nested main loop, e.g. with dialog.run(), which dispatches again meanwhile;
+// so no borrow is held while they run, and a handler already running is left to
+// read its own events once it returns.
+fn dispatch() -> glib::Continue {
+    let handlers: Vec<Rc<Attached>> = HANDLERS.with(|cell| cell.borrow().clone());
+
+    for attached in handlers.iter() {
+        let mut handler = match attached.handler.try_borrow_mut() {
+            Ok(handler) => handler,
+            Err(_) => continue,
+        };
+
+        let disconnected = loop {
+            match attached.receiver.try_recv() {
+                Ok(event) => (&mut **handler)(event),
+                Err(mpsc::TryRecvError::Empty) => break false,
+                Err(mpsc::TryRecvError::Disconnected) => break true,
+            };
+        };
+
+        if disconnected {
+            HANDLERS.with(|cell| cell.borrow_mut().retain(|other| !Rc::ptr_eq(other, attached)));
+        }
+    }
+
+    glib::Continue(false)
+}